@@ -0,0 +1,37 @@
+extern crate nrfs;
+
+use std::time::Duration;
+
+use nrfs::bench::{self, BenchConfig, OpMix};
+
+/// Usage: bench [threads] [seconds] [read,write,create]
+pub fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut config = BenchConfig::default();
+
+    if let Some(threads) = args.first() {
+        config.threads = threads.parse().expect("threads must be a number");
+    }
+    if let Some(secs) = args.get(1) {
+        config.duration = Duration::from_secs(secs.parse().expect("seconds must be a number"));
+    }
+    if let Some(mix) = args.get(2) {
+        let weights: Vec<u32> = mix
+            .split(',')
+            .map(|w| w.parse().expect("mix must be read,write,create"))
+            .collect();
+        assert_eq!(weights.len(), 3, "mix must be read,write,create");
+        config.mix = OpMix {
+            read: weights[0],
+            write: weights[1],
+            create: weights[2],
+        };
+    }
+
+    println!(
+        "threads: {}, duration: {:?}, mix: {:?}",
+        config.threads, config.duration, config.mix
+    );
+    let results = bench::run(&config);
+    print!("{}", bench::report(&results));
+}
@@ -0,0 +1,244 @@
+//! Multicore scalability benchmark for MemFS.
+//!
+//! Spawns one thread per processing unit reported by the machine topology,
+//! runs a configurable mix of read/write/create operations against a shared
+//! MemFS instance and reports the throughput of every thread. Comparing the
+//! per-thread numbers for growing thread counts shows how well the
+//! distributed reader-writer lock scales. The threads aren't pinned, so the
+//! scheduler decides where they run.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Barrier;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::topology;
+use crate::{FileModes, FileSystem, FileSystemRead, MemFS};
+
+/// Relative weights of the operations executed by every benchmark thread.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OpMix {
+    pub read: u32,
+    pub write: u32,
+    pub create: u32,
+}
+
+impl Default for OpMix {
+    /// A read-mostly mix.
+    fn default() -> OpMix {
+        OpMix {
+            read: 90,
+            write: 10,
+            create: 0,
+        }
+    }
+}
+
+/// The operation a thread executes in a given iteration.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Op {
+    Read,
+    Write,
+    Create,
+}
+
+impl OpMix {
+    fn total(&self) -> u32 {
+        self.read + self.write + self.create
+    }
+
+    /// Deterministically map the iteration number onto the weighted mix.
+    fn op(&self, iteration: u64) -> Op {
+        let slot = (iteration % self.total() as u64) as u32;
+        if slot < self.read {
+            Op::Read
+        } else if slot < self.read + self.write {
+            Op::Write
+        } else {
+            Op::Create
+        }
+    }
+}
+
+/// Benchmark parameters.
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// Number of threads; defaults to one per processing unit.
+    pub threads: usize,
+    /// How long the operations are run.
+    pub duration: Duration,
+    /// Operation mix.
+    pub mix: OpMix,
+    /// Bytes read or written per operation.
+    pub io_size: usize,
+    /// Size of the file every thread reads from and writes to.
+    pub file_size: usize,
+}
+
+impl Default for BenchConfig {
+    fn default() -> BenchConfig {
+        BenchConfig {
+            threads: topology::machine().cores(),
+            duration: Duration::from_secs(5),
+            mix: Default::default(),
+            io_size: 4096,
+            file_size: 1024 * 1024,
+        }
+    }
+}
+
+/// Operations completed by one benchmark thread.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct CoreResult {
+    /// The index of the thread.
+    pub thread: usize,
+    pub reads: u64,
+    pub writes: u64,
+    pub creates: u64,
+    /// Time the thread spent running operations.
+    pub elapsed: Duration,
+}
+
+impl CoreResult {
+    /// Total number of operations.
+    pub fn ops(&self) -> u64 {
+        self.reads + self.writes + self.creates
+    }
+
+    /// Operations per second.
+    pub fn throughput(&self) -> f64 {
+        self.ops() as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Run the benchmark and return the result of every thread.
+pub fn run(config: &BenchConfig) -> Vec<CoreResult> {
+    assert!(config.mix.total() > 0, "operation mix can't be empty");
+    assert!(config.io_size > 0 && config.io_size <= config.file_size);
+
+    let memfs = Arc::new(MemFS::default());
+
+    // Every thread works on a private, pre-populated file so only the
+    // file-system locks are shared.
+    let content = vec![0xb; config.file_size];
+    let mut mnodes = Vec::with_capacity(config.threads);
+    for tid in 0..config.threads {
+        let mnode = memfs
+            .create(&format!("/bench-{}", tid), FileModes::S_IRWXU.into())
+            .expect("can't create benchmark file");
+        memfs
+            .write(mnode, &content, 0)
+            .expect("can't populate file");
+        mnodes.push(mnode);
+    }
+
+    let barrier = Arc::new(Barrier::new(config.threads + 1));
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut threads = Vec::with_capacity(config.threads);
+    for (tid, mnode) in mnodes.into_iter().enumerate() {
+        let memfs = memfs.clone();
+        let barrier = barrier.clone();
+        let stop = stop.clone();
+        let config = config.clone();
+
+        threads.push(thread::spawn(move || {
+            let mut result = CoreResult {
+                thread: tid,
+                ..Default::default()
+            };
+            let mut buffer = vec![0; config.io_size];
+            let slots = (config.file_size / config.io_size) as u64;
+
+            barrier.wait();
+            let start = Instant::now();
+            let mut iteration = 0;
+            while !stop.load(Ordering::Relaxed) {
+                let offset = ((iteration % slots) as usize) * config.io_size;
+                match config.mix.op(iteration) {
+                    Op::Read => {
+                        memfs.read(mnode, &mut buffer, offset).unwrap();
+                        result.reads += 1;
+                    }
+                    Op::Write => {
+                        memfs.write(mnode, &buffer, offset).unwrap();
+                        result.writes += 1;
+                    }
+                    Op::Create => {
+                        let name = format!("/bench-{}-{}", tid, result.creates);
+                        memfs.create(&name, FileModes::S_IRWXU.into()).unwrap();
+                        result.creates += 1;
+                    }
+                }
+                iteration += 1;
+            }
+            result.elapsed = start.elapsed();
+            result
+        }));
+    }
+
+    barrier.wait();
+    thread::sleep(config.duration);
+    stop.store(true, Ordering::Relaxed);
+
+    threads
+        .into_iter()
+        .map(|t| t.join().expect("benchmark thread panicked"))
+        .collect()
+}
+
+/// Render the results as a table with one row per thread and a total.
+pub fn report(results: &[CoreResult]) -> String {
+    let mut out = format!(
+        "{:>6} {:>12} {:>12} {:>12} {:>14}\n",
+        "thread", "reads", "writes", "creates", "ops/s"
+    );
+    let mut total = 0.0;
+    for r in results {
+        total += r.throughput();
+        out += &format!(
+            "{:>6} {:>12} {:>12} {:>12} {:>14.0}\n",
+            r.thread,
+            r.reads,
+            r.writes,
+            r.creates,
+            r.throughput()
+        );
+    }
+    out += &format!("{:>6} {:>53.0}\n", "total", total);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    /// A short run of every operation reports a row per thread.
+    fn test_bench() {
+        let config = BenchConfig {
+            threads: 2,
+            duration: Duration::from_millis(20),
+            mix: OpMix {
+                read: 1,
+                write: 1,
+                create: 1,
+            },
+            io_size: 512,
+            file_size: 4096,
+        };
+        let results = run(&config);
+        assert_eq!(results.len(), 2);
+        for (tid, result) in results.iter().enumerate() {
+            assert_eq!(result.thread, tid);
+        }
+        assert!(results.iter().map(CoreResult::ops).sum::<u64>() > 0);
+        let report = report(&results);
+        assert_eq!(report.lines().count(), 4);
+        assert!(report.lines().last().unwrap().starts_with(" total"));
+    }
+}
@@ -0,0 +1,128 @@
+//! Counting Bloom filter over the directory entries.
+//!
+//! Every entry (parent directory, name) sets a few counters; a lookup that
+//! finds one of its counters at zero knows the entry doesn't exist without
+//! locking the directory. This turns the negative lookups of PATH-style
+//! searches into a handful of atomic loads.
+//!
+//! Counters are incremented before an entry is added and decremented after it
+//! is removed, so the filter never misses an existing entry. A counter that
+//! reaches the maximum sticks there, since it can't tell how many entries it
+//! really counts anymore.
+
+use alloc::vec::Vec;
+use core::hash::{BuildHasher, Hash, Hasher};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::Mnode;
+
+/// Number of counters set by every entry.
+const HASHES: u64 = 4;
+
+pub(crate) struct BloomFilter<S> {
+    counters: Vec<AtomicU8>,
+    hasher: S,
+}
+
+impl<S: BuildHasher> BloomFilter<S> {
+    /// Create a filter with `counters` counters; zero disables the filter.
+    pub(crate) fn new(counters: usize, hasher: S) -> BloomFilter<S> {
+        BloomFilter {
+            counters: (0..counters).map(|_| AtomicU8::new(0)).collect(),
+            hasher,
+        }
+    }
+
+    /// The counters of the entry `name` in the directory `parent`.
+    fn counters(&self, parent: Mnode, name: &str) -> impl Iterator<Item = &AtomicU8> {
+        let mut hasher = self.hasher.build_hasher();
+        parent.hash(&mut hasher);
+        name.hash(&mut hasher);
+        let hash = hasher.finish();
+        // Derive all the positions from two halves of a single hash.
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let len = self.counters.len() as u64;
+        (0..HASHES)
+            .map(move |i| &self.counters[(h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize])
+    }
+
+    /// Count an entry that is about to be added.
+    pub(crate) fn insert(&self, parent: Mnode, name: &str) {
+        if self.counters.is_empty() {
+            return;
+        }
+        for counter in self.counters(parent, name) {
+            let _ =
+                counter.fetch_update(Ordering::Release, Ordering::Relaxed, |n| n.checked_add(1));
+        }
+    }
+
+    /// Stop counting an entry that was removed.
+    pub(crate) fn remove(&self, parent: Mnode, name: &str) {
+        if self.counters.is_empty() {
+            return;
+        }
+        for counter in self.counters(parent, name) {
+            let _ = counter.fetch_update(Ordering::Release, Ordering::Relaxed, |n| match n {
+                u8::MAX => None,
+                n => Some(n - 1),
+            });
+        }
+    }
+
+    /// Bytes of heap memory held by the counters.
+    pub(crate) fn allocated_size(&self) -> usize {
+        self.counters.capacity()
+    }
+
+    /// Check if the entry might exist; `false` means it definitely doesn't.
+    pub(crate) fn may_contain(&self, parent: Mnode, name: &str) -> bool {
+        self.counters.is_empty()
+            || self
+                .counters(parent, name)
+                .all(|counter| counter.load(Ordering::Acquire) > 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+    use hashbrown::hash_map::DefaultHashBuilder;
+
+    #[test]
+    /// Inserted entries are always found and removed ones are forgotten.
+    fn test_bloom_filter() {
+        let filter = BloomFilter::new(4096, DefaultHashBuilder::default());
+        for i in 0..100 {
+            filter.insert(1, &format!("bin-{}", i));
+        }
+        assert!((0..100).all(|i| filter.may_contain(1, &format!("bin-{}", i))));
+        let false_positives = (0..1000)
+            .filter(|i| filter.may_contain(2, &format!("bin-{}", i)))
+            .count();
+        assert!(false_positives < 50);
+
+        for i in 0..100 {
+            filter.remove(1, &format!("bin-{}", i));
+        }
+        assert!(filter
+            .counters
+            .iter()
+            .all(|c| c.load(Ordering::Relaxed) == 0));
+        assert!(BloomFilter::new(0, DefaultHashBuilder::default()).may_contain(1, "bin"));
+    }
+
+    #[test]
+    /// Saturated counters are never decremented again.
+    fn test_saturation() {
+        let filter = BloomFilter::new(1, DefaultHashBuilder::default());
+        for _ in 0..300 {
+            filter.insert(1, "a");
+        }
+        for _ in 0..300 {
+            filter.remove(1, "a");
+        }
+        assert!(filter.may_contain(1, "a"));
+    }
+}
@@ -0,0 +1,1054 @@
+//! Construction of a MemFS with non-default policies.
+//!
+//! `MemFS::default()` gives a case-sensitive file-system without limits,
+//! timestamps, change notifications or access control beyond the modes.
+//! [`MemFSBuilder`] lets the embedder pick these in one place.
+
+use alloc::alloc::{AllocError, Layout};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+use hashbrown::hash_map::DefaultHashBuilder;
+use lock_api::RawRwLock;
+
+use crate::pool::{Exhausted, PagePool};
+use crate::throttle::{Rate, Throttle};
+use crate::topology::{MachineTopology, Node};
+use crate::{FileFlags, FileModes, FileSystemError, MemFS, Metadata, Mnode, Modes, BASE_PAGE_SIZE};
+
+/// Source of the timestamps stored in the mnodes.
+pub trait Clock: Send + Sync {
+    /// The current time, in the unit of the embedder (e.g. ns since boot).
+    fn now(&self) -> u64;
+}
+
+/// Tells which CPU the caller runs on, e.g. read from a per-CPU register.
+pub trait CpuId: Send + Sync {
+    /// The current CPU, a number below the number of cores.
+    fn current(&self) -> usize;
+}
+
+/// Allocates the data pages of the files from the memory of a NUMA node,
+/// e.g. with the per-node frame allocators of the kernel.
+pub trait PageAllocator: Send + Sync {
+    /// Allocate memory for `layout` on `node`, or anywhere if the node is
+    /// `None` because it isn't known.
+    fn allocate(&self, layout: Layout, node: Option<Node>) -> Result<NonNull<[u8]>, AllocError>;
+
+    /// Free memory returned by `allocate()` for the same `layout`.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by `allocate()` of this allocator and
+    /// not have been freed yet.
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+}
+
+/// Slower storage that holds the pages of the files MemFS doesn't keep in
+/// memory, e.g. a disk or a remote server.
+///
+/// Writes only need to be readable when they return: the store may cache and
+/// reorder them until the next `flush()`. MemFS flushes wherever the order
+/// matters for crash consistency, so it doesn't depend on the write ordering
+/// of the device.
+pub trait StorageBackend: Send + Sync {
+    /// Fill `page` with the data written back for the page of `mnode` at
+    /// `offset`.
+    fn read_page(
+        &self,
+        mnode: Mnode,
+        offset: usize,
+        page: &mut [u8],
+    ) -> Result<(), FileSystemError>;
+
+    /// Store `page`, the page of `mnode` at `offset`; it is shorter than a
+    /// page at the end of the file.
+    fn write_page(&self, mnode: Mnode, offset: usize, page: &[u8]) -> Result<(), FileSystemError>;
+
+    /// Store the metadata of a file or directory, on `fsync`. Stores that
+    /// only keep pages can ignore it.
+    fn write_metadata(&self, _metadata: &Metadata) -> Result<(), FileSystemError> {
+        Ok(())
+    }
+
+    /// Make everything written so far durable before returning; a barrier
+    /// for the writes that follow.
+    fn flush(&self) -> Result<(), FileSystemError> {
+        Ok(())
+    }
+
+    /// Store the metadata of a file or directory and make it durable before
+    /// returning, like a write with Force Unit Access. Stores that support
+    /// FUA writes can do better than the write and flush of the default.
+    fn write_metadata_fua(&self, metadata: &Metadata) -> Result<(), FileSystemError> {
+        self.write_metadata(metadata)?;
+        self.flush()
+    }
+
+    /// Store the metadata of a file or directory with its path and make it
+    /// durable before returning, on `fsync`, so `MemFS::restore()` can
+    /// recreate the file. Stores that can't restore files only store the
+    /// metadata.
+    fn write_file_fua(&self, _path: &str, metadata: &Metadata) -> Result<(), FileSystemError> {
+        self.write_metadata_fua(metadata)
+    }
+
+    /// The paths and the metadata stored by `write_file_fua()`, e.g. by the
+    /// MemFS that used the store before a crash, see `MemFS::restore()`.
+    /// Stores that can't restore files have none.
+    fn files(&self) -> Result<Vec<(String, Metadata)>, FileSystemError> {
+        Ok(Vec::new())
+    }
+
+    /// Drop the pages and the metadata of `mnode`, once it was removed.
+    fn discard(&self, _mnode: Mnode) -> Result<(), FileSystemError> {
+        Ok(())
+    }
+}
+
+/// The NUMA node the [`PageAllocator`] gets the data pages of a file from.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Placement {
+    /// The node of the CPU that writes the page first, as told by the
+    /// [`CpuId`] hook.
+    FirstTouch,
+    /// Always the given node.
+    Node(Node),
+}
+
+/// A modification of the file-system, reported to the [`Observer`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Event<'a> {
+    Create {
+        path: &'a str,
+        mnode: Mnode,
+    },
+    Write {
+        mnode: Mnode,
+        offset: usize,
+        len: usize,
+    },
+    Truncate {
+        path: &'a str,
+        mnode: Mnode,
+    },
+    Delete {
+        path: &'a str,
+        mnode: Mnode,
+    },
+    Rename {
+        oldpath: &'a str,
+        newpath: &'a str,
+        mnode: Mnode,
+    },
+}
+
+/// Gets notified after every successful modification, e.g. to implement
+/// inotify or to keep an external cache coherent.
+///
+/// No file-system lock is held during the call, so the observer can call back
+/// into the file-system.
+pub trait Observer: Send + Sync {
+    fn notify(&self, event: &Event);
+}
+
+/// Who makes a call, as the embedder tracks it, e.g. the current process of
+/// the kernel.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Credentials {
+    pub pid: u64,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Tells the credentials of the caller; without it every call is made with
+/// `Credentials::default()`.
+pub trait Caller: Send + Sync {
+    fn credentials(&self) -> Credentials;
+}
+
+/// Decides whether the caller may do an operation, before MemFS does it, so
+/// mandatory access control can be layered on top of the modes. A denial is
+/// returned as is to the caller, usually as `PermissionError`.
+///
+/// Like the [`Observer`], the hooks are called without a file-system lock
+/// held, so a policy can look up other files; the operation can still fail
+/// after the hook allowed it.
+pub trait SecurityPolicy: Send + Sync {
+    /// Open the file or directory `mnode` at `path` with `flags`, as checked
+    /// by the front-ends that open files, see `FileSystem::may_open()`.
+    fn may_open(
+        &self,
+        _cred: &Credentials,
+        _path: &str,
+        _mnode: Mnode,
+        _flags: FileFlags,
+    ) -> Result<(), FileSystemError> {
+        Ok(())
+    }
+
+    /// Create a file or directory, of the `ftype` of `FileInfo`, at `path`
+    /// in the directory `parent`.
+    fn may_create(
+        &self,
+        _cred: &Credentials,
+        _path: &str,
+        _parent: Mnode,
+        _ftype: u64,
+        _modes: Modes,
+    ) -> Result<(), FileSystemError> {
+        Ok(())
+    }
+
+    /// Delete the file or directory `mnode` at `path`.
+    fn may_delete(
+        &self,
+        _cred: &Credentials,
+        _path: &str,
+        _mnode: Mnode,
+    ) -> Result<(), FileSystemError> {
+        Ok(())
+    }
+
+    /// Rename the file or directory `mnode` from `oldpath` to `newpath`.
+    fn may_rename(
+        &self,
+        _cred: &Credentials,
+        _oldpath: &str,
+        _newpath: &str,
+        _mnode: Mnode,
+    ) -> Result<(), FileSystemError> {
+        Ok(())
+    }
+}
+
+/// The policies a MemFS instance was built with.
+pub(crate) struct Policy {
+    pub(crate) max_files: usize,
+    pub(crate) max_bytes: usize,
+    pub(crate) case_sensitive: bool,
+    pub(crate) dentry_cache_slots: usize,
+    pub(crate) bloom_filter_counters: usize,
+    pub(crate) mnode_cache_entries: usize,
+    pub(crate) cpu_id: Option<Arc<dyn CpuId>>,
+    pub(crate) clock: Option<Arc<dyn Clock>>,
+    pub(crate) observer: Option<Arc<dyn Observer>>,
+    pub(crate) caller: Option<Arc<dyn Caller>>,
+    pub(crate) security: Option<Arc<dyn SecurityPolicy>>,
+    pub(crate) throttle: Option<Throttle>,
+    pub(crate) topology: Option<MachineTopology>,
+    pub(crate) page_allocator: Option<(Arc<dyn PageAllocator>, Placement)>,
+    pub(crate) large_pages: Option<(Arc<dyn PageAllocator>, usize)>,
+    pub(crate) chunk_align: usize,
+    pub(crate) backing_store: Option<(Arc<dyn StorageBackend>, usize)>,
+    pub(crate) checksums: bool,
+    pub(crate) page_pool: Option<Arc<PagePool>>,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            max_files: usize::MAX,
+            max_bytes: usize::MAX,
+            case_sensitive: true,
+            dentry_cache_slots: 256,
+            bloom_filter_counters: 8192,
+            mnode_cache_entries: 8,
+            cpu_id: None,
+            clock: None,
+            observer: None,
+            caller: None,
+            security: None,
+            throttle: None,
+            topology: None,
+            page_allocator: None,
+            large_pages: None,
+            chunk_align: BASE_PAGE_SIZE,
+            backing_store: None,
+            checksums: false,
+            page_pool: None,
+        }
+    }
+}
+
+/// Builds a MemFS with custom root modes, hasher, locks, capacity, limits and
+/// hooks.
+pub struct MemFSBuilder<S = DefaultHashBuilder, L = spin::RwLock<()>> {
+    root_modes: Modes,
+    capacity: usize,
+    policy: Policy,
+    hasher: S,
+    lock: PhantomData<fn() -> L>,
+}
+
+impl Default for MemFSBuilder {
+    fn default() -> MemFSBuilder {
+        MemFSBuilder::new()
+    }
+}
+
+impl MemFSBuilder {
+    /// Start from the defaults of `MemFS::default()`.
+    pub fn new() -> MemFSBuilder {
+        MemFSBuilder {
+            root_modes: FileModes::S_IRWXU.into(),
+            capacity: 0,
+            policy: Policy::default(),
+            hasher: DefaultHashBuilder::default(),
+            lock: PhantomData,
+        }
+    }
+}
+
+impl<S, L> MemFSBuilder<S, L> {
+    /// Hash the paths and mnode numbers with hashers built by `hasher`, e.g.
+    /// a fast non-cryptographic hash in a trusted kernel, or a keyed SipHash
+    /// if the paths are controlled by an attacker.
+    pub fn hasher<H>(self, hasher: H) -> MemFSBuilder<H, L> {
+        MemFSBuilder {
+            root_modes: self.root_modes,
+            capacity: self.capacity,
+            policy: self.policy,
+            hasher,
+            lock: PhantomData,
+        }
+    }
+
+    /// Lock the memnodes with `M`, any `lock_api` reader-writer lock: e.g.
+    /// the distributed `rwlock::RawRwLock` on machines with many cores, or
+    /// the default `spin::RwLock` on small ones.
+    pub fn lock<M>(self) -> MemFSBuilder<S, M> {
+        MemFSBuilder {
+            root_modes: self.root_modes,
+            capacity: self.capacity,
+            policy: self.policy,
+            hasher: self.hasher,
+            lock: PhantomData,
+        }
+    }
+
+    /// Modes of the root directory. Without write permission no files can
+    /// be created, deleted or renamed.
+    pub fn root_modes(mut self, modes: Modes) -> MemFSBuilder<S, L> {
+        self.root_modes = modes;
+        self
+    }
+
+    /// Number of files to pre-size the mnode table for.
+    pub fn capacity(mut self, n_files: usize) -> MemFSBuilder<S, L> {
+        self.capacity = n_files;
+        self
+    }
+
+    /// Maximum number of files; creating more fails with `NoSpace`.
+    pub fn max_files(mut self, max_files: usize) -> MemFSBuilder<S, L> {
+        self.policy.max_files = max_files;
+        self
+    }
+
+    /// Maximum number of bytes stored in all the files together; writes
+    /// beyond that fail with `NoSpace`.
+    pub fn max_bytes(mut self, max_bytes: usize) -> MemFSBuilder<S, L> {
+        self.policy.max_bytes = max_bytes;
+        self
+    }
+
+    /// If disabled, paths that only differ in ASCII case refer to the same
+    /// file. The case used at creation is kept in the mnode.
+    pub fn case_sensitive(mut self, case_sensitive: bool) -> MemFSBuilder<S, L> {
+        self.policy.case_sensitive = case_sensitive;
+        self
+    }
+
+    /// Number of entries of the dentry cache; zero disables it.
+    pub fn dentry_cache(mut self, slots: usize) -> MemFSBuilder<S, L> {
+        self.policy.dentry_cache_slots = slots;
+        self
+    }
+
+    /// Number of counters of the Bloom filter that answers lookups of
+    /// missing files without locking; zero disables it. A few counters per
+    /// file keep the false positives low.
+    pub fn bloom_filter(mut self, counters: usize) -> MemFSBuilder<S, L> {
+        self.policy.bloom_filter_counters = counters;
+        self
+    }
+
+    /// Number of recently used mnodes every CPU keeps a reference to; zero
+    /// disables the caches.
+    pub fn mnode_cache(mut self, entries: usize) -> MemFSBuilder<S, L> {
+        self.policy.mnode_cache_entries = entries;
+        self
+    }
+
+    /// Hook that tells the current CPU, so every CPU uses its own mnode
+    /// cache; without it the caches are picked per thread. Without the `std`
+    /// feature, every CPU also uses its own reader slot of the mnode table,
+    /// so a CPU must not switch to another lookup while one runs, e.g.
+    /// because preemption is disabled; without a hook there, the lookups
+    /// share a cache and the reader slots.
+    pub fn cpu_id(mut self, cpu_id: Arc<dyn CpuId>) -> MemFSBuilder<S, L> {
+        self.policy.cpu_id = Some(cpu_id);
+        self
+    }
+
+    /// Clock used to timestamp the mnodes; without one all times are zero.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> MemFSBuilder<S, L> {
+        self.policy.clock = Some(clock);
+        self
+    }
+
+    /// Observer notified about every modification.
+    pub fn observer(mut self, observer: Arc<dyn Observer>) -> MemFSBuilder<S, L> {
+        self.policy.observer = Some(observer);
+        self
+    }
+
+    /// Hook that tells the credentials of the caller, for the
+    /// `SecurityPolicy`.
+    pub fn caller(mut self, caller: Arc<dyn Caller>) -> MemFSBuilder<S, L> {
+        self.policy.caller = Some(caller);
+        self
+    }
+
+    /// Ask `security` before every open, create, delete and rename.
+    pub fn security(mut self, security: Arc<dyn SecurityPolicy>) -> MemFSBuilder<S, L> {
+        self.policy.security = Some(security);
+        self
+    }
+
+    /// Limit every process, as told by the `caller()` hook, to `rate`; see
+    /// `throttle`. The buckets are refilled by the time of the `clock()`
+    /// hook, so this needs one.
+    pub fn throttle(mut self, rate: Rate) -> MemFSBuilder<S, L> {
+        self.policy.throttle = Some(Throttle::new(rate));
+        self
+    }
+
+    /// Size the per-CPU structures for `topology` instead of the topology
+    /// queried from the machine, e.g. in tests, in VMs that report odd
+    /// topologies, or to leave room for CPUs that are hot-plugged later.
+    pub fn topology(mut self, topology: MachineTopology) -> MemFSBuilder<S, L> {
+        self.policy.topology = Some(topology);
+        self
+    }
+
+    /// Allocate the data pages of the files with `allocator`, on the NUMA
+    /// node picked by `placement`, instead of the global allocator.
+    pub fn page_allocator(
+        mut self,
+        allocator: Arc<dyn PageAllocator>,
+        placement: Placement,
+    ) -> MemFSBuilder<S, L> {
+        self.policy.page_allocator = Some((allocator, placement));
+        self
+    }
+
+    /// Keep the data of the files of at least `threshold` bytes in 2 MiB
+    /// chunks, aligned to 2 MiB and allocated with `allocator`, so they can be
+    /// mapped with large pages. The chunks come from the node of the
+    /// placement of the page allocator, if there is one.
+    pub fn large_pages(
+        mut self,
+        allocator: Arc<dyn PageAllocator>,
+        threshold: usize,
+    ) -> MemFSBuilder<S, L> {
+        self.policy.large_pages = Some((allocator, threshold));
+        self
+    }
+
+    /// Align the chunks holding the file data to `align` bytes, a power of
+    /// two, e.g. to the boundary a DMA engine requires. See
+    /// `MemFS::with_ranges()`. The default is the page size, so the pages can
+    /// be mapped, see `MemFS::pin()`.
+    pub fn chunk_align(mut self, align: usize) -> MemFSBuilder<S, L> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        self.policy.chunk_align = align;
+        self
+    }
+
+    /// Keep at most `max_pages` pages of the files in memory and evict the
+    /// others to `store`: clean pages are dropped, changed ones written back
+    /// first. Pinned pages and files in large pages stay in memory.
+    pub fn backing_store(
+        mut self,
+        store: Arc<dyn StorageBackend>,
+        max_pages: usize,
+    ) -> MemFSBuilder<S, L> {
+        self.policy.backing_store = Some((store, max_pages));
+        self
+    }
+
+    /// Keep a CRC32C checksum of every page of the files, updated by writes
+    /// and verified by reads, so data corrupted in memory or in the backing
+    /// store is reported as `DataCorruption` instead of being returned. Pages
+    /// aren't verified while they are pinned.
+    pub fn checksums(mut self, checksums: bool) -> MemFSBuilder<S, L> {
+        self.policy.checksums = checksums;
+        self
+    }
+
+    /// Keep the data of the files in at most `max_bytes` bytes of memory,
+    /// and do `exhausted` when a write needs more. See `pool`.
+    pub fn page_pool(mut self, max_bytes: usize, exhausted: Exhausted) -> MemFSBuilder<S, L> {
+        self.policy.page_pool = Some(Arc::new(PagePool::new(max_bytes, exhausted)));
+        self
+    }
+
+    /// Create the file-system.
+    pub fn build(self) -> MemFS<S, L>
+    where
+        S: BuildHasher + Clone + Send + Sync,
+        L: RawRwLock + Send + Sync,
+    {
+        MemFS::new(self.root_modes, self.capacity, self.policy, self.hasher)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FileSystem, FileSystemRead};
+    use alloc::alloc::{Allocator, Global};
+    use alloc::collections::BTreeMap;
+    use alloc::string::{String, ToString};
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+    use spin::Mutex;
+
+    struct TickClock(AtomicU64);
+
+    impl Clock for TickClock {
+        fn now(&self) -> u64 {
+            self.0.fetch_add(1, Ordering::Relaxed) + 1
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl Observer for Recorder {
+        fn notify(&self, event: &Event) {
+            let event = match event {
+                Event::Create { path, .. } => "create ".to_string() + path,
+                Event::Write { len, .. } => "write ".to_string() + &len.to_string(),
+                Event::Truncate { path, .. } => "truncate ".to_string() + path,
+                Event::Delete { path, .. } => "delete ".to_string() + path,
+                Event::Rename { newpath, .. } => "rename ".to_string() + newpath,
+            };
+            self.0.lock().push(event);
+        }
+    }
+
+    /// FNV-1a, seeded with a key.
+    #[derive(Clone)]
+    struct KeyedFnv(u64);
+
+    struct FnvHasher(u64);
+
+    impl core::hash::Hasher for FnvHasher {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            for byte in bytes {
+                self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+            }
+        }
+    }
+
+    impl BuildHasher for KeyedFnv {
+        type Hasher = FnvHasher;
+
+        fn build_hasher(&self) -> FnvHasher {
+            FnvHasher(0xcbf29ce484222325 ^ self.0)
+        }
+    }
+
+    #[test]
+    /// The maps can use a caller-supplied hasher.
+    fn test_hasher() {
+        let memfs = MemFSBuilder::new().hasher(KeyedFnv(0x5eed)).build();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[0xb; 10], 0), Ok(10));
+        assert_eq!(memfs.lookup("/nrfs").map(|m| *m), Some(mnode));
+        assert_eq!(memfs.rename("/nrfs", "/nrfs2"), Ok(true));
+        assert_eq!(memfs.file_info(mnode).unwrap().fsize, 10);
+        assert_eq!(memfs.delete("/nrfs2"), Ok(true));
+    }
+
+    #[test]
+    #[cfg(not(loom))]
+    /// The memnodes can be locked with any `lock_api` lock.
+    fn test_lock() {
+        let memfs = MemFSBuilder::new()
+            .lock::<crate::rwlock::RawRwLock>()
+            .build();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[0xb; 10], 0), Ok(10));
+        assert_eq!(memfs.rename("/nrfs", "/nrfs2"), Ok(true));
+        assert_eq!(memfs.file_info(mnode).unwrap().fsize, 10);
+        assert_eq!(memfs.delete("/nrfs2"), Ok(true));
+    }
+
+    #[test]
+    /// The per-CPU structures follow the topology given by the embedder.
+    fn test_topology() {
+        let cpus = (0..3)
+            .map(|cpu| crate::topology::CpuInfo {
+                node: None,
+                socket: cpu / 2,
+                core: cpu,
+                cpu,
+                l1: cpu,
+                l2: cpu,
+                l3: cpu / 2,
+            })
+            .collect();
+        let memfs = MemFSBuilder::new()
+            .topology(MachineTopology::from_cpus(cpus))
+            .build();
+        assert_eq!(memfs.mnodes.readers(), 3);
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.lookup("/nrfs").map(|m| *m), Some(mnode));
+    }
+
+    /// Allocates from the global allocator and remembers the requested nodes.
+    #[derive(Default)]
+    struct NodeRecorder(Mutex<Vec<Option<Node>>>);
+
+    impl PageAllocator for NodeRecorder {
+        fn allocate(
+            &self,
+            layout: Layout,
+            node: Option<Node>,
+        ) -> Result<NonNull<[u8]>, AllocError> {
+            self.0.lock().push(node);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    struct FixedCpu(usize);
+
+    impl CpuId for FixedCpu {
+        fn current(&self) -> usize {
+            self.0
+        }
+    }
+
+    #[test]
+    /// The data pages come from the node of the writer or the chosen node.
+    fn test_page_allocator() {
+        use crate::topology::{CpuInfo, NodeInfo};
+
+        let cpus = (0..2)
+            .map(|cpu| CpuInfo {
+                node: Some(NodeInfo {
+                    node: cpu,
+                    memory: 1 << 30,
+                }),
+                socket: cpu,
+                core: cpu,
+                cpu,
+                l1: cpu,
+                l2: cpu,
+                l3: cpu,
+            })
+            .collect();
+        let topology = MachineTopology::from_cpus(cpus);
+
+        let pages = Arc::new(NodeRecorder::default());
+        let memfs = MemFSBuilder::new()
+            .topology(topology.clone())
+            .cpu_id(Arc::new(FixedCpu(1)))
+            .page_allocator(pages.clone(), Placement::FirstTouch)
+            .build();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[0xb; 8192], 0), Ok(8192));
+        assert_eq!(*pages.0.lock(), [Some(1), Some(1)]);
+        let mut buffer = [0; 8192];
+        assert_eq!(memfs.read(mnode, &mut buffer, 0), Ok(8192));
+        assert!(buffer.iter().all(|b| *b == 0xb));
+
+        let pages = Arc::new(NodeRecorder::default());
+        let memfs = MemFSBuilder::new()
+            .topology(topology)
+            .page_allocator(pages.clone(), Placement::Node(0))
+            .build();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[0xb; 1000], 0), Ok(1000));
+        assert_eq!(*pages.0.lock(), [Some(0)]);
+    }
+
+    #[test]
+    /// Files move to the large page allocator once they reach the threshold.
+    fn test_large_pages() {
+        let pages = Arc::new(NodeRecorder::default());
+        let memfs = MemFSBuilder::new()
+            .large_pages(pages.clone(), 1 << 16)
+            .build();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[0xb; 4096], 0), Ok(4096));
+        assert!(pages.0.lock().is_empty());
+        assert_eq!(memfs.write(mnode, &[0xc; 4096], 1 << 16), Ok(4096));
+        assert_eq!(*pages.0.lock(), [None]);
+        let mut buffer = [0; 4096];
+        assert_eq!(memfs.read(mnode, &mut buffer, 0), Ok(4096));
+        assert!(buffer.iter().all(|b| *b == 0xb));
+        assert_eq!(memfs.read(mnode, &mut buffer, 1 << 16), Ok(4096));
+        assert!(buffer.iter().all(|b| *b == 0xc));
+    }
+
+    #[test]
+    /// The ranges of a file start at the chunk alignment and can be written.
+    fn test_chunk_align() {
+        let memfs = MemFSBuilder::new().chunk_align(4096).build();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[0xb; 10000], 0), Ok(10000));
+
+        let lens = memfs.with_ranges(mnode, 0, 10000, |ranges| {
+            for (ptr, len) in ranges {
+                assert_eq!(ptr.as_ptr() as usize % 4096, 0);
+                unsafe { core::ptr::write_bytes(ptr.as_ptr(), 0xc, *len) };
+            }
+            ranges.iter().map(|(_, len)| *len).collect::<Vec<_>>()
+        });
+        assert_eq!(lens, Ok(alloc::vec![4096, 4096, 1808]));
+        let mut buffer = [0; 10000];
+        assert_eq!(memfs.read(mnode, &mut buffer, 0), Ok(10000));
+        assert!(buffer.iter().all(|b| *b == 0xc));
+
+        assert_eq!(
+            memfs.with_ranges(mnode, 9000, 1001, |_| ()),
+            Err(FileSystemError::InvalidOffset)
+        );
+    }
+
+    /// Keeps the pages in a map and counts the transfers.
+    #[derive(Default)]
+    struct MapStore {
+        pages: Mutex<BTreeMap<(Mnode, usize), Vec<u8>>>,
+        reads: AtomicU64,
+    }
+
+    impl StorageBackend for MapStore {
+        fn read_page(
+            &self,
+            mnode: Mnode,
+            offset: usize,
+            page: &mut [u8],
+        ) -> Result<(), FileSystemError> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            let pages = self.pages.lock();
+            let data = pages
+                .get(&(mnode, offset))
+                .ok_or(FileSystemError::InvalidOffset)?;
+            page.copy_from_slice(&data[..page.len()]);
+            Ok(())
+        }
+
+        fn write_page(
+            &self,
+            mnode: Mnode,
+            offset: usize,
+            page: &[u8],
+        ) -> Result<(), FileSystemError> {
+            self.pages.lock().insert((mnode, offset), page.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    /// Only a bounded number of pages stays in memory; the others come back
+    /// from the backing store.
+    fn test_backing_store() {
+        let store = Arc::new(MapStore::default());
+        let memfs = MemFSBuilder::new().backing_store(store.clone(), 2).build();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        for page in 0..4u8 {
+            let data = [page; 4096];
+            assert_eq!(memfs.write(mnode, &data, page as usize * 4096), Ok(4096));
+        }
+        assert_eq!(memfs.write(mnode, &[9; 10], 4 * 4096), Ok(10));
+        assert!(store.pages.lock().len() >= 3);
+        assert_eq!(memfs.file_info(mnode).unwrap().fsize, 4 * 4096 + 10);
+
+        let mut buffer = [0; 4 * 4096 + 10];
+        assert_eq!(memfs.read(mnode, &mut buffer, 0), Ok(4 * 4096 + 10));
+        for page in 0..4 {
+            assert!(buffer[page * 4096..][..4096]
+                .iter()
+                .all(|b| *b == page as u8));
+        }
+        assert_eq!(buffer[4 * 4096..], [9; 10]);
+        assert!(store.reads.load(Ordering::Relaxed) >= 3);
+
+        // Changes to a page that was brought back are written back again.
+        assert_eq!(memfs.write(mnode, &[7; 1], 0), Ok(1));
+        let mut byte = [0; 1];
+        assert_eq!(memfs.read(mnode, &mut byte, 4 * 4096), Ok(1));
+        assert_eq!(memfs.read(mnode, &mut byte, 2 * 4096), Ok(1));
+        assert_eq!(memfs.read(mnode, &mut byte, 3 * 4096), Ok(1));
+        assert_eq!(memfs.read(mnode, &mut byte, 0), Ok(1));
+        assert_eq!(byte, [7]);
+
+        // Pages cleaned by msync still reach the store when they are evicted.
+        let other = memfs.create("/other", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(other, &[5; 4096], 0), Ok(4096));
+        assert_eq!(memfs.msync(other, 0, 4096, |_, _| Ok(())), Ok(1));
+        for page in 0..4 {
+            assert_eq!(memfs.write(mnode, &[8; 1], page * 4096), Ok(1));
+        }
+        assert!(store.pages.lock().contains_key(&(other, 0)));
+        assert_eq!(memfs.read(other, &mut byte, 0), Ok(1));
+        assert_eq!(byte, [5]);
+
+        // Faults past the end of a file bring its evicted last page back first.
+        let short = memfs.create("/short", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(short, &[6; 1000], 0), Ok(1000));
+        for page in 0..4 {
+            assert_eq!(memfs.write(mnode, &[8; 1], page * 4096), Ok(1));
+        }
+        assert!(store.pages.lock().contains_key(&(short, 0)));
+        drop(memfs.fault(short, 4096).unwrap());
+        assert_eq!(memfs.file_info(short).unwrap().fsize, 2 * 4096);
+        let mut buffer = [1; 2 * 4096];
+        assert_eq!(memfs.read(short, &mut buffer, 0), Ok(2 * 4096));
+        assert_eq!(buffer[..1000], [6; 1000]);
+        assert!(buffer[1000..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    /// Pages corrupted in the backing store aren't read back.
+    fn test_checksums() {
+        let store = Arc::new(MapStore::default());
+        let memfs = MemFSBuilder::new()
+            .backing_store(store.clone(), 1)
+            .checksums(true)
+            .build();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[1; 2 * 4096], 0), Ok(2 * 4096));
+        assert_eq!(memfs.write(mnode, &[2; 4096], 2 * 4096), Ok(4096));
+        let mut page = [0; 4096];
+        assert_eq!(memfs.read(mnode, &mut page, 0), Ok(4096));
+
+        let evicted = match store.pages.lock().get_mut(&(mnode, 4096)) {
+            Some(data) => {
+                data[7] ^= 1;
+                true
+            }
+            None => false,
+        };
+        assert!(evicted);
+        assert_eq!(
+            memfs.read(mnode, &mut page, 4096),
+            Err(FileSystemError::DataCorruption)
+        );
+        assert_eq!(FileSystemError::DataCorruption.errno(), crate::errno::EIO);
+    }
+
+    #[test]
+    /// A read-only root directory rejects namespace modifications.
+    fn test_root_modes() {
+        let memfs = MemFSBuilder::new()
+            .root_modes((FileModes::S_IRUSR | FileModes::S_IXUSR).into())
+            .build();
+        assert_eq!(
+            memfs.create("/nrfs", FileModes::S_IRWXU.into()),
+            Err(FileSystemError::PermissionError)
+        );
+        assert_eq!(
+            memfs.metadata(1).unwrap().modes,
+            (FileModes::S_IRUSR | FileModes::S_IXUSR).into()
+        );
+    }
+
+    #[test]
+    /// The file and byte limits are enforced and freed space can be reused.
+    fn test_limits() {
+        let memfs = MemFSBuilder::new().max_files(2).max_bytes(100).build();
+        let a = memfs.create("/a", FileModes::S_IRWXU.into()).unwrap();
+        let b = memfs.create("/b", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(
+            memfs.create("/c", FileModes::S_IRWXU.into()),
+            Err(FileSystemError::NoSpace)
+        );
+
+        assert_eq!(memfs.write(a, &[0xb; 60], 0), Ok(60));
+        assert_eq!(memfs.write(b, &[0xb; 60], 0), Err(FileSystemError::NoSpace));
+        assert_eq!(memfs.write(b, &[0xb; 40], 0), Ok(40));
+        // Overwriting doesn't need more space.
+        assert_eq!(memfs.write(a, &[0xa; 60], 0), Ok(60));
+
+        memfs.truncate("/a").unwrap();
+        assert_eq!(memfs.write(b, &[0xb; 100], 0), Ok(100));
+        memfs.delete("/b").unwrap();
+        assert!(memfs.create("/c", FileModes::S_IRWXU.into()).is_ok());
+        assert_eq!(memfs.write(a, &[0xb; 100], 0), Ok(100));
+    }
+
+    #[test]
+    /// Case-insensitive file-systems fold the case of the paths.
+    fn test_case_insensitive() {
+        let memfs = MemFSBuilder::new().case_sensitive(false).build();
+        let mnode = memfs.create("/ReadMe", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.lookup("/README").map(|m| *m), Some(mnode));
+        assert_eq!(
+            memfs.create("/readme", FileModes::S_IRWXU.into()),
+            Err(FileSystemError::AlreadyPresent)
+        );
+        assert_eq!(memfs.rename("/readme", "/Notes"), Ok(true));
+        assert_eq!(memfs.lookup("/NOTES").map(|m| *m), Some(mnode));
+        assert_eq!(memfs.delete("/notes"), Ok(true));
+
+        let memfs = MemFS::default();
+        memfs.create("/ReadMe", FileModes::S_IRWXU.into()).unwrap();
+        assert!(memfs.lookup("/README").is_none());
+    }
+
+    #[test]
+    /// The clock timestamps the mnodes and the observer sees every change.
+    fn test_hooks() {
+        let recorder = Arc::new(Recorder::default());
+        let memfs = MemFSBuilder::new()
+            .clock(Arc::new(TickClock(AtomicU64::new(0))))
+            .observer(recorder.clone())
+            .build();
+
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        let created = memfs.metadata(mnode).unwrap();
+        assert_eq!(created.ctime, created.mtime);
+        memfs.write(mnode, &[0xb; 10], 0).unwrap();
+        let written = memfs.metadata(mnode).unwrap();
+        assert!(written.mtime > created.mtime);
+        memfs.rename("/nrfs", "/nrfs2").unwrap();
+        let renamed = memfs.metadata(mnode).unwrap();
+        assert_eq!(renamed.mtime, written.mtime);
+        assert!(renamed.ctime > written.ctime);
+        memfs.truncate("/nrfs2").unwrap();
+        memfs.delete("/nrfs2").unwrap();
+        assert!(memfs.create("/nrfs", FileModes::S_IRWXU.into()).is_ok());
+
+        assert_eq!(
+            *recorder.0.lock(),
+            [
+                "create /nrfs",
+                "write 10",
+                "rename /nrfs2",
+                "truncate /nrfs2",
+                "delete /nrfs2",
+                "create /nrfs"
+            ]
+        );
+    }
+
+    /// The uid of the caller, as set by the test.
+    struct Uid(AtomicU32);
+
+    impl Caller for Uid {
+        fn credentials(&self) -> Credentials {
+            Credentials {
+                uid: self.0.load(Ordering::Relaxed),
+                ..Credentials::default()
+            }
+        }
+    }
+
+    /// Only root may change `/etc`, and nobody may open files for writing
+    /// in it.
+    struct ProtectEtc;
+
+    impl ProtectEtc {
+        fn check(cred: &Credentials, path: &str) -> Result<(), FileSystemError> {
+            match path.starts_with("/etc") && cred.uid != 0 {
+                true => Err(FileSystemError::PermissionError),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl SecurityPolicy for ProtectEtc {
+        fn may_open(
+            &self,
+            _: &Credentials,
+            path: &str,
+            _: Mnode,
+            flags: FileFlags,
+        ) -> Result<(), FileSystemError> {
+            match path.starts_with("/etc/") && flags.is_write() {
+                true => Err(FileSystemError::PermissionError),
+                false => Ok(()),
+            }
+        }
+
+        fn may_create(
+            &self,
+            cred: &Credentials,
+            path: &str,
+            _: Mnode,
+            _: u64,
+            _: Modes,
+        ) -> Result<(), FileSystemError> {
+            ProtectEtc::check(cred, path)
+        }
+
+        fn may_delete(
+            &self,
+            cred: &Credentials,
+            path: &str,
+            _: Mnode,
+        ) -> Result<(), FileSystemError> {
+            ProtectEtc::check(cred, path)
+        }
+
+        fn may_rename(
+            &self,
+            cred: &Credentials,
+            oldpath: &str,
+            newpath: &str,
+            _: Mnode,
+        ) -> Result<(), FileSystemError> {
+            ProtectEtc::check(cred, oldpath).and(ProtectEtc::check(cred, newpath))
+        }
+    }
+
+    #[test]
+    /// The security policy is asked with the credentials of the caller.
+    fn test_security() {
+        let uid = Arc::new(Uid(AtomicU32::new(0)));
+        let memfs = MemFSBuilder::new()
+            .caller(uid.clone())
+            .security(Arc::new(ProtectEtc))
+            .build();
+        let modes = FileModes::S_IRWXU.into();
+        memfs.mkdir("/etc", modes).unwrap();
+        let passwd = memfs.create("/etc/passwd", modes).unwrap();
+
+        uid.0.store(1000, Ordering::Relaxed);
+        let denied = FileSystemError::PermissionError;
+        assert_eq!(memfs.create("/etc/shadow", modes), Err(denied.clone()));
+        assert_eq!(memfs.delete("/etc/passwd"), Err(denied.clone()));
+        assert_eq!(memfs.rename("/etc/passwd", "/passwd"), Err(denied.clone()));
+        assert_eq!(
+            memfs.may_open("/etc/passwd", passwd, FileFlags::O_RDWR),
+            Err(denied)
+        );
+        assert_eq!(
+            memfs.may_open("/etc/passwd", passwd, FileFlags::O_RDONLY),
+            Ok(())
+        );
+        assert!(memfs.create("/home", modes).is_ok());
+        assert!(memfs.lookup("/etc/shadow").is_none());
+
+        uid.0.store(0, Ordering::Relaxed);
+        assert_eq!(memfs.rename("/etc/passwd", "/etc/passwd2"), Ok(true));
+        assert_eq!(memfs.delete("/etc/passwd2"), Ok(true));
+    }
+}
@@ -0,0 +1,192 @@
+//! Cancelling long-running operations, so a core isn't stuck finishing a
+//! multi-gigabyte write or the removal of a large tree for a process that was
+//! killed in the meantime.
+//!
+//! The caller hands the operation a [`Cancel`] and keeps a reference to it,
+//! e.g. in the process; whoever kills the process calls `cancel()`. The
+//! operation checks the token between steps of bounded size and stops with
+//! `Interrupted` at the next one. What was done until then stays done.
+
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lock_api::RawRwLock;
+
+use crate::merge::join;
+use crate::mnode::NodeType;
+use crate::{
+    split_last, try_to_string, FileSystem, FileSystemError, FileSystemRead, MemFS, Mnode,
+    LARGE_PAGE_SIZE,
+};
+
+/// Bytes written or copied between two checks of the token.
+pub(crate) const STEP: usize = LARGE_PAGE_SIZE;
+
+/// Entries listed at once by `remove_tree()`.
+const BATCH: usize = 64;
+
+/// A flag telling the operations that check it to stop.
+#[derive(Debug, Default)]
+pub struct Cancel {
+    cancelled: AtomicBool,
+}
+
+impl Cancel {
+    /// A token that isn't cancelled.
+    pub const fn new() -> Cancel {
+        Cancel {
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    /// Stop the operations that check the token at their next step.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether `cancel()` was called.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Fail with `Interrupted` if the token was cancelled.
+    pub(crate) fn check(&self) -> Result<(), FileSystemError> {
+        match self.is_cancelled() {
+            true => Err(FileSystemError::Interrupted),
+            false => Ok(()),
+        }
+    }
+}
+
+impl<S: BuildHasher + Clone + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
+    /// Write `buffer` to the file `mnode` at `offset` like `write()`, but a
+    /// `STEP` at a time, checking `cancel` before each. Like write(2)
+    /// interrupted by a signal, returns the bytes written until then, and
+    /// fails with `Interrupted` only if that is none.
+    pub fn write_cancellable(
+        &self,
+        mnode: Mnode,
+        buffer: &[u8],
+        offset: usize,
+        cancel: &Cancel,
+    ) -> Result<usize, FileSystemError> {
+        if buffer.is_empty() {
+            cancel.check()?;
+            return self.write(mnode, buffer, offset);
+        }
+        let mut written = 0;
+        for chunk in buffer.chunks(STEP) {
+            if cancel.is_cancelled() {
+                break;
+            }
+            match self.write(mnode, chunk, offset + written) {
+                Ok(len) => {
+                    written += len;
+                    if len < chunk.len() {
+                        break;
+                    }
+                }
+                Err(e) if written == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        if written == 0 {
+            cancel.check()?;
+        }
+        Ok(written)
+    }
+
+    /// Remove the file or directory at `pathname` with everything below it,
+    /// checking `cancel` before every entry. The root is emptied but stays.
+    /// A cancelled or failed removal leaves the entries it didn't get to.
+    pub fn remove_tree(&self, pathname: &str, cancel: &Cancel) -> Result<(), FileSystemError> {
+        let mnode = *self.lookup(pathname).ok_or(FileSystemError::InvalidFile)?;
+        if self.metadata(mnode)?.ftype != NodeType::Directory.into() {
+            cancel.check()?;
+            return self.delete(pathname).map(|_| ());
+        }
+
+        // The directories still to empty, each below the one before it.
+        let mut pending = Vec::new();
+        pending
+            .try_reserve(1)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        pending.push(try_to_string(pathname)?);
+        let mut subdirs = Vec::new();
+        while let Some(dir) = pending.last() {
+            cancel.check()?;
+            let entries = self.readdir(dir, None, BATCH)?;
+            if entries.is_empty() {
+                if split_last(dir).is_some() {
+                    self.rmdir(dir)?;
+                }
+                pending.pop();
+                continue;
+            }
+            // The files go right away, the directories once they are empty.
+            for entry in entries.iter() {
+                cancel.check()?;
+                let path = join(dir, &entry.name)?;
+                if entry.ftype == NodeType::Directory.into() {
+                    subdirs
+                        .try_reserve(1)
+                        .map_err(|_| FileSystemError::OutOfMemory)?;
+                    subdirs.push(path);
+                } else {
+                    self.delete(&path)?;
+                }
+            }
+            pending
+                .try_reserve(subdirs.len())
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            pending.append(&mut subdirs);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FileModes;
+
+    #[test]
+    /// A cancelled write stops at a step, a cancelled removal at an entry.
+    fn test_cancel() {
+        let memfs = MemFS::default();
+        let rwx = FileModes::S_IRWXU.into();
+        let cancel = Cancel::new();
+        let mnode = memfs.create("/file", rwx).unwrap();
+        let data = alloc::vec![0xb; 2 * STEP + 10];
+        assert_eq!(
+            memfs.write_cancellable(mnode, &data, 0, &cancel),
+            Ok(data.len())
+        );
+        memfs.mkdir("/dir", rwx).unwrap();
+        memfs.mkdir("/dir/sub", rwx).unwrap();
+        memfs.create("/dir/sub/a", rwx).unwrap();
+        memfs.create("/dir/b", rwx).unwrap();
+
+        cancel.cancel();
+        assert_eq!(
+            memfs.write_cancellable(mnode, &data, 0, &cancel),
+            Err(FileSystemError::Interrupted)
+        );
+        assert_eq!(
+            memfs.remove_tree("/dir", &cancel),
+            Err(FileSystemError::Interrupted)
+        );
+        assert!(memfs.lookup("/dir/sub/a").is_some());
+        assert_eq!(
+            memfs.keep_versions(mnode, 1, usize::MAX, &cancel),
+            Err(FileSystemError::Interrupted)
+        );
+
+        let cancel = Cancel::new();
+        assert_eq!(memfs.remove_tree("/dir", &cancel), Ok(()));
+        assert!(memfs.lookup("/dir").is_none());
+        assert_eq!(memfs.remove_tree("/", &cancel), Ok(()));
+        assert!(memfs.lookup("/file").is_none());
+        assert!(memfs.lookup("/").is_some());
+    }
+}
@@ -0,0 +1,275 @@
+//! Encryption of the data MemFS hands to its backing store.
+//!
+//! [`EncryptedStore`] wraps another [`StorageBackend`] and encrypts every page
+//! before it reaches it, so a persisted image doesn't hold plaintext. Every
+//! file has its own key, derived from a master key and the mnode, and every
+//! page is encrypted with its offset as the tweak, so equal pages don't look
+//! equal in the image. The cipher itself comes from the embedder.
+//!
+//! Metadata only holds sizes, modes and times, and is stored as it is.
+//! Embedders that persist directory entries can encrypt the names with the
+//! key of the directory, see [`EncryptedStore::seal_name`].
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::builder::StorageBackend;
+use crate::{FileSystemError, Metadata, Mnode};
+
+/// Size of the master key and of the keys of the files, in bytes.
+pub const KEY_SIZE: usize = 32;
+
+/// A key of the [`Cipher`].
+pub type Key = [u8; KEY_SIZE];
+
+/// A length-preserving cipher supplied by the embedder, e.g. AES-XTS.
+pub trait Cipher: Send + Sync {
+    /// Derive the key of the file `mnode` from the master key, e.g. with
+    /// HKDF. Called for every page, so it should be cheap.
+    fn derive_key(&self, master: &Key, mnode: Mnode) -> Key;
+
+    /// Encrypt `data` in place with `key`. `tweak` tells apart the blocks
+    /// encrypted with the same key, like the sector number of XTS.
+    fn encrypt(&self, key: &Key, tweak: u64, data: &mut [u8]);
+
+    /// Undo `encrypt()` with the same key and tweak.
+    fn decrypt(&self, key: &Key, tweak: u64, data: &mut [u8]);
+}
+
+/// The tweak of the names in a directory; pages use their offset, which is
+/// never this large.
+const NAME_TWEAK: u64 = u64::MAX;
+
+/// A [`StorageBackend`] that encrypts the pages of the files with per-file
+/// keys before they reach `inner`.
+pub struct EncryptedStore {
+    inner: Arc<dyn StorageBackend>,
+    cipher: Arc<dyn Cipher>,
+    master: Key,
+    /// Whether `seal_name()` encrypts names.
+    names: bool,
+}
+
+impl EncryptedStore {
+    /// Encrypt what goes to `inner` with `cipher`, with keys derived from
+    /// `master`.
+    pub fn new(inner: Arc<dyn StorageBackend>, cipher: Arc<dyn Cipher>, master: Key) -> Self {
+        EncryptedStore {
+            inner,
+            cipher,
+            master,
+            names: false,
+        }
+    }
+
+    /// Encrypt the names passed to `seal_name()` too; they are passed
+    /// through otherwise.
+    pub fn encrypt_names(mut self, names: bool) -> Self {
+        self.names = names;
+        self
+    }
+
+    fn key(&self, mnode: Mnode) -> Key {
+        self.cipher.derive_key(&self.master, mnode)
+    }
+
+    /// The form of the entry `name` of the directory `parent` to persist.
+    pub fn seal_name(&self, parent: Mnode, name: &str) -> Result<Vec<u8>, FileSystemError> {
+        let mut sealed = Vec::new();
+        sealed
+            .try_reserve(name.len())
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        sealed.extend_from_slice(name.as_bytes());
+        if self.names {
+            self.cipher
+                .encrypt(&self.key(parent), NAME_TWEAK, &mut sealed);
+        }
+        Ok(sealed)
+    }
+
+    /// Recover a name sealed by `seal_name()` for the same directory. Fails
+    /// with `DataCorruption` if it doesn't decrypt to a valid name.
+    pub fn open_name(&self, parent: Mnode, sealed: &[u8]) -> Result<Vec<u8>, FileSystemError> {
+        let mut name = Vec::new();
+        name.try_reserve(sealed.len())
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        name.extend_from_slice(sealed);
+        if self.names {
+            self.cipher
+                .decrypt(&self.key(parent), NAME_TWEAK, &mut name);
+        }
+        match core::str::from_utf8(&name) {
+            Ok(_) => Ok(name),
+            Err(_) => Err(FileSystemError::DataCorruption),
+        }
+    }
+}
+
+impl Drop for EncryptedStore {
+    fn drop(&mut self) {
+        // Don't leave the master key behind in freed memory.
+        for byte in self.master.iter_mut() {
+            unsafe { core::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+impl StorageBackend for EncryptedStore {
+    fn read_page(
+        &self,
+        mnode: Mnode,
+        offset: usize,
+        page: &mut [u8],
+    ) -> Result<(), FileSystemError> {
+        self.inner.read_page(mnode, offset, page)?;
+        self.cipher.decrypt(&self.key(mnode), offset as u64, page);
+        Ok(())
+    }
+
+    fn write_page(&self, mnode: Mnode, offset: usize, page: &[u8]) -> Result<(), FileSystemError> {
+        let mut sealed = Vec::new();
+        sealed
+            .try_reserve(page.len())
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        sealed.extend_from_slice(page);
+        self.cipher
+            .encrypt(&self.key(mnode), offset as u64, &mut sealed);
+        self.inner.write_page(mnode, offset, &sealed)
+    }
+
+    fn write_metadata(&self, metadata: &Metadata) -> Result<(), FileSystemError> {
+        self.inner.write_metadata(metadata)
+    }
+
+    fn flush(&self) -> Result<(), FileSystemError> {
+        self.inner.flush()
+    }
+
+    fn write_metadata_fua(&self, metadata: &Metadata) -> Result<(), FileSystemError> {
+        self.inner.write_metadata_fua(metadata)
+    }
+
+    /// The paths would be stored in the clear, so the files can't be
+    /// restored from an encrypted store.
+    fn write_file_fua(&self, _path: &str, metadata: &Metadata) -> Result<(), FileSystemError> {
+        self.inner.write_metadata_fua(metadata)
+    }
+
+    fn discard(&self, mnode: Mnode) -> Result<(), FileSystemError> {
+        self.inner.discard(mnode)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FileModes, FileSystem, FileSystemRead, MemFSBuilder};
+    use hashbrown::HashMap;
+    use spin::Mutex;
+
+    /// Xors the data with a keystream; only good enough for tests.
+    struct XorCipher;
+
+    impl XorCipher {
+        fn apply(key: &Key, tweak: u64, data: &mut [u8]) {
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte ^= key[i % KEY_SIZE] ^ (tweak as u8) ^ (i as u8);
+            }
+        }
+    }
+
+    impl Cipher for XorCipher {
+        fn derive_key(&self, master: &Key, mnode: Mnode) -> Key {
+            let mut key = *master;
+            key[0] ^= mnode as u8;
+            key
+        }
+
+        fn encrypt(&self, key: &Key, tweak: u64, data: &mut [u8]) {
+            XorCipher::apply(key, tweak, data);
+        }
+
+        fn decrypt(&self, key: &Key, tweak: u64, data: &mut [u8]) {
+            XorCipher::apply(key, tweak, data);
+        }
+    }
+
+    /// Keeps the pages written to it in memory.
+    #[derive(Default)]
+    struct MemStore {
+        pages: Mutex<HashMap<(Mnode, usize), Vec<u8>>>,
+    }
+
+    impl StorageBackend for MemStore {
+        fn read_page(
+            &self,
+            mnode: Mnode,
+            offset: usize,
+            page: &mut [u8],
+        ) -> Result<(), FileSystemError> {
+            let pages = self.pages.lock();
+            let data = pages
+                .get(&(mnode, offset))
+                .ok_or(FileSystemError::InvalidOffset)?;
+            page.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn write_page(
+            &self,
+            mnode: Mnode,
+            offset: usize,
+            page: &[u8],
+        ) -> Result<(), FileSystemError> {
+            self.pages.lock().insert((mnode, offset), page.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    /// The inner store only sees ciphertext, different for every file, and
+    /// MemFS reads back the plaintext.
+    fn test_encrypted_store() {
+        let inner = Arc::new(MemStore::default());
+        let store = EncryptedStore::new(inner.clone(), Arc::new(XorCipher), [0x5a; KEY_SIZE]);
+        let memfs = MemFSBuilder::new()
+            .backing_store(Arc::new(store), 1)
+            .build();
+        let a = memfs.create("/a", FileModes::S_IRWXU.into()).unwrap();
+        let b = memfs.create("/b", FileModes::S_IRWXU.into()).unwrap();
+        for mnode in [a, b] {
+            assert_eq!(memfs.write(mnode, &[7; 4096], 0), Ok(4096));
+            assert_eq!(memfs.write(mnode, &[8; 4096], 4096), Ok(4096));
+        }
+        assert_eq!(memfs.fsync(a), Ok(()));
+        assert_eq!(memfs.fsync(b), Ok(()));
+
+        {
+            let pages = inner.pages.lock();
+            let (a0, b0) = (&pages[&(a, 0)], &pages[&(b, 0)]);
+            assert!(a0.iter().any(|byte| *byte != 7));
+            assert_ne!(a0, b0);
+            assert_ne!(a0, &pages[&(a, 4096)]);
+        }
+        let mut page = [0; 4096];
+        assert_eq!(memfs.read(a, &mut page, 0), Ok(4096));
+        assert_eq!(page, [7; 4096]);
+        assert_eq!(memfs.read(b, &mut page, 4096), Ok(4096));
+        assert_eq!(page, [8; 4096]);
+    }
+
+    #[test]
+    /// Names are only encrypted if asked to, and decrypt with the key of the
+    /// same directory only.
+    fn test_names() {
+        let inner = Arc::new(MemStore::default());
+        let store = EncryptedStore::new(inner.clone(), Arc::new(XorCipher), [1; KEY_SIZE]);
+        assert_eq!(store.seal_name(1, "nrfs").unwrap(), b"nrfs");
+
+        let store = store.encrypt_names(true);
+        let sealed = store.seal_name(1, "nrfs").unwrap();
+        assert_ne!(sealed, b"nrfs");
+        assert_eq!(store.open_name(1, &sealed).unwrap(), b"nrfs");
+        assert_ne!(store.open_name(2, &sealed), Ok(b"nrfs".to_vec()));
+    }
+}
@@ -0,0 +1,162 @@
+//! Cache of path component lookups.
+//!
+//! Maps (parent directory, name) to the mnode of the entry, so resolving hot
+//! paths doesn't lock every directory on the way. The cache is direct-mapped:
+//! every entry has a single slot, picked by its hash, and a new entry simply
+//! replaces whatever was cached in that slot.
+//!
+//! Entries are added while the directory is read-locked and invalidated while
+//! it is write-locked, so a lookup racing with a removal can't leave a stale
+//! entry behind. Mnode numbers are never reused, so a cached entry can't
+//! point to a different file later. Entries share their names with the
+//! directories, so caching an entry doesn't allocate.
+
+use alloc::vec::Vec;
+use core::hash::{BuildHasher, Hash, Hasher};
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lock_api::RawRwLock;
+use spin::RwLock;
+
+use crate::{MemFS, Mnode, Name};
+
+/// Hits and misses of the dentry cache since the file-system was created.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct DentryCacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+struct Dentry {
+    parent: Mnode,
+    name: Name,
+    mnode: Mnode,
+}
+
+pub(crate) struct DentryCache<S> {
+    slots: Vec<RwLock<Option<Dentry>>>,
+    hasher: S,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl<S: BuildHasher> DentryCache<S> {
+    /// Create a cache with `slots` entries; zero disables the cache.
+    pub(crate) fn new(slots: usize, hasher: S) -> DentryCache<S> {
+        DentryCache {
+            slots: (0..slots).map(|_| RwLock::new(None)).collect(),
+            hasher,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// The slot of the entry `name` in the directory `parent`.
+    fn slot(&self, parent: Mnode, name: &str) -> Option<&RwLock<Option<Dentry>>> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        let mut hasher = self.hasher.build_hasher();
+        parent.hash(&mut hasher);
+        name.hash(&mut hasher);
+        Some(&self.slots[hasher.finish() as usize % self.slots.len()])
+    }
+
+    /// Get the cached mnode of the entry `name` in the directory `parent`.
+    pub(crate) fn get(&self, parent: Mnode, name: &str) -> Option<Mnode> {
+        let slot = self.slot(parent, name)?;
+        let mnode = match &*slot.read() {
+            Some(dentry) if dentry.parent == parent && &*dentry.name == name => Some(dentry.mnode),
+            _ => None,
+        };
+        match mnode {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        mnode
+    }
+
+    /// Cache an entry; the directory has to be locked.
+    pub(crate) fn insert(&self, parent: Mnode, name: &Name, mnode: Mnode) {
+        if let Some(slot) = self.slot(parent, name) {
+            *slot.write() = Some(Dentry {
+                parent,
+                name: Name::clone(name),
+                mnode,
+            });
+        }
+    }
+
+    /// Drop the entry `name` of the directory `parent`, if it is cached; the
+    /// directory has to be write-locked.
+    pub(crate) fn invalidate(&self, parent: Mnode, name: &str) {
+        if let Some(slot) = self.slot(parent, name) {
+            let mut slot = slot.write();
+            if let Some(dentry) = &*slot {
+                if dentry.parent == parent && &*dentry.name == name {
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    /// Bytes of heap memory held by the slots, not counting long names.
+    pub(crate) fn allocated_size(&self) -> usize {
+        self.slots.capacity() * size_of::<RwLock<Option<Dentry>>>()
+    }
+
+    fn stats(&self) -> DentryCacheStats {
+        DentryCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<S: BuildHasher + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
+    /// Get the hits and misses of the dentry cache.
+    pub fn dentry_cache_stats(&self) -> DentryCacheStats {
+        self.dcache.stats()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FileModes, FileSystem, FileSystemError, FileSystemRead, MemFSBuilder};
+
+    #[test]
+    /// Repeated walks hit the cache and removed entries are never returned.
+    fn test_dentry_cache() {
+        let memfs = MemFS::default();
+        memfs.mkdir("/a", FileModes::S_IRWXU.into()).unwrap();
+        memfs.mkdir("/a/b", FileModes::S_IRWXU.into()).unwrap();
+        let old = memfs.create("/a/b/c", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.truncate("/a/b/c"), Ok(true));
+        let before = memfs.dentry_cache_stats();
+        assert_eq!(memfs.truncate("/a/b/c"), Ok(true));
+        let after = memfs.dentry_cache_stats();
+        assert_eq!(after.hits - before.hits, 3);
+        assert_eq!(after.misses, before.misses);
+
+        assert_eq!(memfs.rename("/a/b", "/b"), Ok(true));
+        assert_eq!(memfs.truncate("/a/b/c"), Err(FileSystemError::InvalidFile));
+        assert_eq!(memfs.lookup("/b/c").map(|m| *m), Some(old));
+        assert_eq!(memfs.delete("/b/c"), Ok(true));
+        assert_eq!(memfs.truncate("/b/c"), Err(FileSystemError::InvalidFile));
+        let new = memfs.create("/b/c", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.truncate("/b/c"), Ok(true));
+        assert_eq!(memfs.metadata(new).unwrap().mnode, new);
+        assert_eq!(memfs.lookup("/b/c").map(|m| *m), Some(new));
+    }
+
+    #[test]
+    /// A cache without slots is never consulted.
+    fn test_disabled() {
+        let memfs = MemFSBuilder::new().dentry_cache(0).build();
+        memfs.mkdir("/a", FileModes::S_IRWXU.into()).unwrap();
+        memfs.create("/a/b", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.truncate("/a/b"), Ok(true));
+        assert_eq!(memfs.dentry_cache_stats(), DentryCacheStats::default());
+    }
+}
@@ -0,0 +1,187 @@
+//! Sharing of identical file pages.
+//!
+//! `MemFS::dedup()` scans the files and makes the full pages with the same
+//! contents share one copy of their data, like KSM does for the memory of
+//! processes. The shared pages are refcounted; a page gets its own copy again
+//! when it is written, and the copy is freed when its last user lets it go.
+//!
+//! The table of shared pages only holds weak references, keyed by a hash of
+//! the contents. Pages with the same hash are compared in full before they
+//! are shared, so a collision only costs a comparison.
+
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::hash::{BuildHasher, Hasher};
+use core::mem::size_of;
+use hashbrown::HashMap;
+use lock_api::RawRwLock;
+use spin::Mutex;
+
+use crate::file::{Pages, SharedPage};
+use crate::{MemFS, BASE_PAGE_SIZE};
+
+/// The pages shared by the files of a MemFS.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct DedupStats {
+    /// Distinct pages used by more than one file page.
+    pub pages: usize,
+    /// File pages using them.
+    pub references: usize,
+    /// Bytes the file pages would take on top of that without sharing.
+    pub saved_bytes: usize,
+}
+
+/// The shared pages with the same hash.
+type SameHash = Vec<Weak<Vec<u8, Pages>>>;
+
+pub(crate) struct PageTable<S> {
+    pages: Mutex<HashMap<u64, SameHash>>,
+    hasher: S,
+}
+
+impl<S: BuildHasher> PageTable<S> {
+    pub(crate) fn new(hasher: S) -> PageTable<S> {
+        PageTable {
+            pages: Mutex::new(HashMap::new()),
+            hasher,
+        }
+    }
+
+    /// A page with the same contents as `page`, which is `page` itself if the
+    /// table doesn't know one yet. Pages that can't be recorded for lack of
+    /// memory stay unshared.
+    pub(crate) fn share(&self, page: SharedPage) -> SharedPage {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write(&page);
+        let hash = hasher.finish();
+
+        let mut pages = self.pages.lock();
+        if pages.try_reserve(1).is_err() {
+            return page;
+        }
+        let same = pages.entry(hash).or_default();
+        same.retain(|other| other.strong_count() > 0);
+        for other in same.iter() {
+            if let Some(other) = other.upgrade() {
+                if other[..] == page[..] {
+                    return other;
+                }
+            }
+        }
+        if same.try_reserve(1).is_ok() {
+            same.push(Arc::downgrade(&page));
+        }
+        page
+    }
+
+    /// Forget the pages nobody uses anymore and free the spare capacity of
+    /// the table. Returns the number of bytes freed.
+    pub(crate) fn compact(&self) -> usize {
+        let mut pages = self.pages.lock();
+        pages.retain(|_, same| {
+            same.retain(|page| page.strong_count() > 0);
+            !same.is_empty()
+        });
+        let mut copy = HashMap::new();
+        if copy.try_reserve(pages.len()).is_err() || copy.capacity() >= pages.capacity() {
+            return 0;
+        }
+        let freed = (pages.capacity() - copy.capacity()) * size_of::<(u64, SameHash)>();
+        copy.extend(pages.drain());
+        *pages = copy;
+        freed
+    }
+
+    /// Bytes of heap memory held by the table, not counting the pages.
+    pub(crate) fn allocated_size(&self) -> usize {
+        let pages = self.pages.lock();
+        pages.capacity() * size_of::<(u64, SameHash)>()
+            + pages
+                .values()
+                .map(|same| same.capacity() * size_of::<Weak<Vec<u8, Pages>>>())
+                .sum::<usize>()
+    }
+
+    /// Count the shared pages, forgetting the ones nobody uses anymore.
+    pub(crate) fn stats(&self) -> DedupStats {
+        let mut stats = DedupStats::default();
+        let mut pages = self.pages.lock();
+        pages.retain(|_, same| {
+            same.retain(|page| page.strong_count() > 0);
+            for page in same.iter() {
+                let users = page.strong_count();
+                if users > 1 {
+                    stats.pages += 1;
+                    stats.references += users;
+                    stats.saved_bytes += (users - 1) * BASE_PAGE_SIZE;
+                }
+            }
+            !same.is_empty()
+        });
+        stats
+    }
+}
+
+impl<S: BuildHasher + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
+    /// Share the data of the identical full pages of all the files, and
+    /// return what is shared now. Only resident pages of files in base pages
+    /// that aren't pinned take part. The pages stay shared until they are
+    /// written, so the scan can be repeated, e.g. when memory runs low.
+    pub fn dedup(&self) -> DedupStats {
+        for memnode in self.mnodes.read(self.reader_tid()).values() {
+            memnode.write().dedup(|page| self.pages.share(page));
+        }
+        self.dedup_stats()
+    }
+
+    /// What the pages shared by `dedup()` save now.
+    pub fn dedup_stats(&self) -> DedupStats {
+        self.pages.stats()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FileModes, FileSystem, FileSystemRead};
+
+    #[test]
+    /// Identical pages are shared across files and within a file, and get
+    /// their own copy again when they are written.
+    fn test_dedup() {
+        let memfs = MemFS::default();
+        let a = memfs.create("/a", FileModes::S_IRWXU.into()).unwrap();
+        let b = memfs.create("/b", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(a, &[1; 2 * 4096], 0), Ok(2 * 4096));
+        assert_eq!(memfs.write(a, &[2; 4096 + 10], 2 * 4096), Ok(4096 + 10));
+        assert_eq!(memfs.write(b, &[1; 4096], 0), Ok(4096));
+        assert_eq!(memfs.write(b, &[3; 4096], 4096), Ok(4096));
+
+        let stats = memfs.dedup();
+        assert_eq!(
+            stats,
+            DedupStats {
+                pages: 1,
+                references: 3,
+                saved_bytes: 2 * 4096
+            }
+        );
+        assert_eq!(memfs.dedup(), stats);
+
+        let mut page = [0; 4096];
+        assert_eq!(memfs.read(b, &mut page, 0), Ok(4096));
+        assert_eq!(page, [1; 4096]);
+
+        assert_eq!(memfs.write(a, &[4; 1], 4096), Ok(1));
+        assert_eq!(memfs.read(a, &mut page, 4096), Ok(4096));
+        assert_eq!(page[..2], [4, 1]);
+        assert_eq!(memfs.read(a, &mut page, 0), Ok(4096));
+        assert_eq!(page, [1; 4096]);
+        assert_eq!(memfs.dedup_stats().saved_bytes, 4096);
+
+        assert_eq!(memfs.delete("/a"), Ok(true));
+        assert_eq!(memfs.dedup_stats(), DedupStats::default());
+        assert_eq!(memfs.read(b, &mut page, 0), Ok(4096));
+        assert_eq!(page, [1; 4096]);
+    }
+}
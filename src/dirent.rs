@@ -0,0 +1,199 @@
+//! Directory entries packed into a buffer in the layout of a kernel ABI.
+//!
+//! `MemFS::getdents()` writes the entries of a directory straight into the
+//! buffer the syscall layer hands to userspace, one record after the other
+//! like `getdents64()`, instead of returning them as `DirEntry`s the syscall
+//! layer has to marshal again. Where the fields of a record go is told by a
+//! [`DirentLayout`]; `DirentLayout::LINUX_DIRENT64` is `struct
+//! linux_dirent64`.
+
+use core::hash::BuildHasher;
+use lock_api::RawRwLock;
+
+use crate::fd::{Fd, FileDescriptor};
+use crate::mnode::NodeType;
+use crate::{FileSystemError, MemFS};
+
+/// The `d_type` of a directory.
+pub const DT_DIR: u8 = 4;
+/// The `d_type` of a regular file.
+pub const DT_REG: u8 = 8;
+
+/// The `d_type` of an entry naming an mnode of type `kind`.
+fn d_type(kind: NodeType) -> u8 {
+    match kind {
+        NodeType::Directory => DT_DIR,
+        NodeType::File => DT_REG,
+    }
+}
+
+/// An integer field of a record, in native byte order.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Field {
+    /// Offset of the field from the start of the record.
+    pub offset: usize,
+    /// Width of the field: 1, 2, 4 or 8 bytes.
+    pub size: usize,
+}
+
+/// Where the fields of a directory entry go in a record.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DirentLayout {
+    /// The mnode of the entry, `d_ino`.
+    pub ino: Field,
+    /// The cookie to continue the listing after the entry from, `d_off`.
+    pub off: Option<Field>,
+    /// The length of the record, `d_reclen`.
+    pub reclen: Field,
+    /// The type of the entry, `d_type`.
+    pub kind: Option<Field>,
+    /// Offset of the name, which is followed by a NUL byte.
+    pub name: usize,
+    /// Records are padded to a multiple of this many bytes, a power of two.
+    pub align: usize,
+}
+
+impl DirentLayout {
+    /// `struct linux_dirent64`, as filled by `getdents64()`.
+    pub const LINUX_DIRENT64: DirentLayout = DirentLayout {
+        ino: Field { offset: 0, size: 8 },
+        off: Some(Field { offset: 8, size: 8 }),
+        reclen: Field {
+            offset: 16,
+            size: 2,
+        },
+        kind: Some(Field {
+            offset: 18,
+            size: 1,
+        }),
+        name: 19,
+        align: 8,
+    };
+
+    /// The length of the record of an entry named `name`.
+    fn reclen(&self, name: &str) -> usize {
+        (self.name + name.len() + 1 + self.align - 1) & !(self.align - 1)
+    }
+}
+
+impl Field {
+    /// Write `value` into the field of `record`, failing with `Overflow` if
+    /// it doesn't fit.
+    fn write(&self, record: &mut [u8], value: u64) -> Result<(), FileSystemError> {
+        if self.size < 8 && value >> (8 * self.size) != 0 {
+            return Err(FileSystemError::Overflow);
+        }
+        let bytes = value.to_ne_bytes();
+        let bytes = match cfg!(target_endian = "little") {
+            true => &bytes[..self.size],
+            false => &bytes[8 - self.size..],
+        };
+        record[self.offset..self.offset + self.size].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+impl<S: BuildHasher + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
+    /// Write the entries of the directory open as `fd` from its offset into
+    /// `buffer` as records of `layout`, as many as fit, and move the offset
+    /// past them, like `getdents64()`. The offsets are cookies, see
+    /// `readdir_from()`. Returns the number of bytes written, 0 at the end
+    /// of the directory. Fails with `BufferTooSmall` if the next record
+    /// doesn't fit, and with `Overflow` if a value doesn't fit its field.
+    pub fn getdents(
+        &self,
+        fd: &Fd,
+        buffer: &mut [u8],
+        layout: &DirentLayout,
+    ) -> Result<usize, FileSystemError> {
+        if fd.is_path() {
+            return Err(FileSystemError::InvalidFileDescriptor);
+        }
+        let memnode = self
+            .memnode(fd.get_mnode())
+            .ok_or(FileSystemError::InvalidFile)?;
+        let dir = memnode.read();
+        dir.check_readable_dir()?;
+
+        let mut written = 0;
+        let mut last = None;
+        for (name, mnode, kind, cookie) in dir.entries_from_cookie(fd.get_offset() as u64) {
+            let reclen = layout.reclen(name);
+            let record = match buffer.get_mut(written..written + reclen) {
+                Some(record) => record,
+                None if written == 0 => return Err(FileSystemError::BufferTooSmall),
+                None => break,
+            };
+            record.iter_mut().for_each(|byte| *byte = 0);
+            layout.ino.write(record, mnode)?;
+            if let Some(off) = layout.off {
+                off.write(record, cookie)?;
+            }
+            layout.reclen.write(record, reclen as u64)?;
+            if let Some(field) = layout.kind {
+                field.write(record, d_type(kind) as u64)?;
+            }
+            record[layout.name..layout.name + name.len()].copy_from_slice(name.as_bytes());
+            written += reclen;
+            last = Some(cookie);
+        }
+        if let Some(cookie) = last {
+            fd.update_offset(cookie as usize);
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FileFlags, FileModes, FileSystem};
+    use core::convert::TryInto;
+
+    #[test]
+    /// Records are packed as `linux_dirent64`s, and listings continue from
+    /// the offset of the descriptor.
+    fn test_getdents() {
+        let memfs = MemFS::default();
+        let rwx = FileModes::S_IRWXU.into();
+        let dir = memfs.mkdir("/dir", rwx).unwrap();
+        let file = memfs.create("/dir/file", rwx).unwrap();
+        let long = "x".repeat(20);
+        memfs.mkdir(&alloc::format!("/dir/{}", long), rwx).unwrap();
+        let mut fd = Fd::init_fd();
+        fd.update_fd(dir, FileFlags::O_RDONLY);
+        let layout = DirentLayout::LINUX_DIRENT64;
+
+        let mut buffer = [0xff; 64];
+        assert_eq!(
+            memfs.getdents(&fd, &mut buffer[..16], &layout),
+            Err(FileSystemError::BufferTooSmall)
+        );
+        assert_eq!(memfs.getdents(&fd, &mut buffer[..48], &layout), Ok(24));
+        let u64_at = |at: usize| u64::from_ne_bytes(buffer[at..at + 8].try_into().unwrap());
+        assert_eq!(u64_at(0), file);
+        assert_eq!(u64_at(8), fd.get_offset() as u64);
+        assert_eq!(u16::from_ne_bytes([buffer[16], buffer[17]]), 24);
+        assert_eq!(buffer[18], DT_REG);
+        assert_eq!(&buffer[19..24], b"file\0");
+        assert_eq!(buffer[24], 0xff);
+
+        assert_eq!(memfs.getdents(&fd, &mut buffer, &layout), Ok(40));
+        assert_eq!(buffer[18], DT_DIR);
+        assert_eq!(&buffer[19..40], alloc::format!("{}\0", long).as_bytes());
+        assert_eq!(memfs.getdents(&fd, &mut buffer, &layout), Ok(0));
+
+        // Other layouts pack the records without padding.
+        let narrow = DirentLayout {
+            ino: Field { offset: 0, size: 1 },
+            off: None,
+            reclen: Field { offset: 1, size: 1 },
+            kind: None,
+            name: 2,
+            align: 1,
+        };
+        fd.update_offset(0);
+        assert_eq!(memfs.getdents(&fd, &mut buffer, &narrow), Ok(7 + 23));
+        assert_eq!(buffer[..7], [file as u8, 7, b'f', b'i', b'l', b'e', 0]);
+    }
+}
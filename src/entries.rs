@@ -0,0 +1,604 @@
+//! The entries of a directory, kept in an arena per directory.
+//!
+//! The records of the entries, a name, an mnode and its type each, are the
+//! slots of one
+//! vector, and a removed entry leaves its slot to the next one added. The
+//! order of the names is kept by runs of slot indices, each sorted and holding
+//! at most `RUN` of them, and sorted among themselves. Adding an entry to a
+//! directory of tens of thousands of them moves at most a run of indices, and
+//! only allocates when the arena or a run is full. Directories of `HASHED`
+//! entries or more also keep a hashed index of the slots, so looking up a
+//! name, or checking it is new, doesn't get slower as they grow.
+//!
+//! Every entry also gets a cookie when it is added, from a counter of the
+//! directory, so a listing can be continued from a number, e.g. the offset of
+//! a directory file descriptor. Cookies are not reused, and a listing resumed
+//! from a cookie neither skips nor repeats the entries that were there all
+//! along, whatever was added or removed in the meantime.
+//!
+//! A rename removes entries before it adds one, and must not lose them if
+//! adding fails. `Entries::reserve()` makes room for one entry ahead, spare
+//! run included, so the next insertion can't fail for lack of memory.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::mem::size_of;
+
+use crate::htree::HashIndex;
+use crate::mnode::NodeType;
+use crate::{FileSystemError, Mnode, Name};
+
+/// The most slot indices in a run; a full run is split in two.
+const RUN: usize = 256;
+
+/// Directories of this many entries get a hashed index, which they keep
+/// until they are down to half of that.
+const HASHED: usize = 1024;
+
+/// The slot of a cookie whose entry was removed.
+const GONE: u32 = u32::MAX;
+
+#[derive(Debug)]
+enum Slot {
+    /// A used slot: the name, the mnode, its type and the cookie of an
+    /// entry.
+    Used(Name, Arc<Mnode>, NodeType, u64),
+    /// A free slot, with the next free one.
+    Free(Option<u32>),
+}
+
+/// The entries of a directory, by name.
+#[derive(Debug, Default)]
+pub(crate) struct Entries {
+    slots: Vec<Slot>,
+    /// The first free slot.
+    free: Option<u32>,
+    /// The used slots in the order of their names; no run is empty.
+    runs: Vec<Vec<u32>>,
+    len: usize,
+    /// The used slots by the hashes of their names, in large directories.
+    index: Option<HashIndex>,
+    /// The cookies in increasing order with their slots, `GONE` for the
+    /// removed entries until they are swept.
+    cookies: Vec<(u64, u32)>,
+    /// Cookies in `cookies` whose entry was removed.
+    gone: usize,
+    /// The cookie of the last entry added; listings start from 0.
+    last_cookie: u64,
+    /// A run of `RUN` slots set aside by `reserve()`, for the next insertion
+    /// to fall back on if it can't allocate.
+    spare: Option<Vec<u32>>,
+}
+
+#[cfg(test)]
+std::thread_local! {
+    /// Makes `Entries::reserve()` fail on this thread, to test the error
+    /// paths of its callers.
+    pub(crate) static FAIL_RESERVE: core::cell::Cell<bool> =
+        const { core::cell::Cell::new(false) };
+}
+
+/// An empty run with room for `capacity` slots, or the spare run if there is
+/// no memory for one.
+fn new_run(capacity: usize, spare: &mut Option<Vec<u32>>) -> Result<Vec<u32>, FileSystemError> {
+    let mut run = Vec::new();
+    match run.try_reserve(capacity) {
+        Ok(()) => Ok(run),
+        Err(_) => spare.take().ok_or(FileSystemError::OutOfMemory),
+    }
+}
+
+impl Entries {
+    /// The record in the used slot `slot`.
+    fn record(&self, slot: u32) -> (&Name, &Arc<Mnode>) {
+        match &self.slots[slot as usize] {
+            Slot::Used(name, mnode, _, _) => (name, mnode),
+            Slot::Free(_) => unreachable!("free slot {} in a run", slot),
+        }
+    }
+
+    /// The type and the cookie of the entry in the used slot `slot`.
+    fn tags(&self, slot: u32) -> (NodeType, u64) {
+        match &self.slots[slot as usize] {
+            Slot::Used(_, _, kind, cookie) => (*kind, *cookie),
+            Slot::Free(_) => unreachable!("free slot {} in a run", slot),
+        }
+    }
+
+    /// The run that holds `name`, or the one it would be added to, and its
+    /// position in the run: `Ok` if it is there, `Err` where it would go.
+    fn find(&self, name: &str) -> (usize, Result<usize, usize>) {
+        let last = |run: &Vec<u32>| &**self.record(run[run.len() - 1]).0;
+        // The first run whose last name doesn't sort before `name`.
+        let run = self
+            .runs
+            .partition_point(|run| last(run) < name)
+            .min(self.runs.len().saturating_sub(1));
+        match self.runs.get(run) {
+            Some(slots) => (
+                run,
+                slots.binary_search_by(|slot| (**self.record(*slot).0).cmp(name)),
+            ),
+            None => (0, Err(0)),
+        }
+    }
+
+    /// The entry `name`, if there is one.
+    pub(crate) fn get(&self, name: &str) -> Option<(&Name, &Arc<Mnode>)> {
+        if let Some(index) = &self.index {
+            return index
+                .get(index.hash(name))
+                .map(|slot| self.record(slot))
+                .find(|record| &**record.0 == name);
+        }
+        match self.find(name) {
+            (run, Ok(pos)) => Some(self.record(self.runs[run][pos])),
+            (_, Err(_)) => None,
+        }
+    }
+
+    /// Make room for one more entry, so the next `insert()` of a new name
+    /// doesn't fail for lack of memory or slots, whatever is removed until
+    /// then.
+    pub(crate) fn reserve(&mut self) -> Result<(), FileSystemError> {
+        #[cfg(test)]
+        if FAIL_RESERVE.with(|fail| fail.get()) {
+            return Err(FileSystemError::OutOfMemory);
+        }
+        if self.free.is_none() {
+            self.slots
+                .try_reserve(1)
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            if u32::try_from(self.slots.len()).ok() == Some(GONE) {
+                return Err(FileSystemError::NoSpace);
+            }
+        }
+        self.cookies
+            .try_reserve(1)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        // A split adds a run, and any run may be the one to split or grow.
+        self.runs
+            .try_reserve(1)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        if self.spare.is_none() {
+            let mut spare = Vec::new();
+            spare
+                .try_reserve_exact(RUN)
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            self.spare = Some(spare);
+        }
+        Ok(())
+    }
+
+    /// Add the entry `name` for `mnode` of type `kind`, failing with
+    /// `AlreadyPresent` if it exists. Uses up the room made by `reserve()`.
+    pub(crate) fn insert(
+        &mut self,
+        name: Name,
+        mnode: Arc<Mnode>,
+        kind: NodeType,
+    ) -> Result<(), FileSystemError> {
+        if self.index.is_some() && self.get(&name).is_some() {
+            return Err(FileSystemError::AlreadyPresent);
+        }
+        let (mut run, pos) = self.find(&name);
+        let mut pos = match pos {
+            Ok(_) => return Err(FileSystemError::AlreadyPresent),
+            Err(pos) => pos,
+        };
+        // Allocate first, so a failure leaves the entries as they were; a
+        // split run is as good as the full one.
+        let mut spare = self.spare.take();
+        let slot = match self.free {
+            Some(slot) => slot,
+            None => {
+                self.slots
+                    .try_reserve(1)
+                    .map_err(|_| FileSystemError::OutOfMemory)?;
+                u32::try_from(self.slots.len())
+                    .ok()
+                    .filter(|slot| *slot != GONE)
+                    .ok_or(FileSystemError::NoSpace)?
+            }
+        };
+        self.cookies
+            .try_reserve(1)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        if self.runs.is_empty() {
+            let mut first = new_run(1, &mut spare)?;
+            self.runs
+                .try_reserve(1)
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            first.push(slot);
+            self.runs.push(first);
+        } else {
+            if self.runs[run].len() == RUN {
+                let mut tail = new_run(RUN, &mut spare)?;
+                self.runs
+                    .try_reserve(1)
+                    .map_err(|_| FileSystemError::OutOfMemory)?;
+                tail.extend(self.runs[run].drain(RUN / 2..));
+                self.runs.insert(run + 1, tail);
+                if pos > RUN / 2 {
+                    run += 1;
+                    pos -= RUN / 2;
+                }
+            }
+            if self.runs[run].try_reserve(1).is_err() {
+                // The spare run has room for a full one.
+                let mut moved = spare.take().ok_or(FileSystemError::OutOfMemory)?;
+                moved.append(&mut self.runs[run]);
+                self.runs[run] = moved;
+            }
+            self.runs[run].insert(pos, slot);
+        }
+        // Without memory for the index lookups only take longer.
+        if let Some(index) = self.index.as_mut() {
+            let hash = index.hash(&name);
+            if index.insert(hash, slot).is_err() {
+                self.index = None;
+            }
+        }
+
+        self.last_cookie += 1;
+        let record = Slot::Used(name, mnode, kind, self.last_cookie);
+        match self.free {
+            Some(_) => {
+                if let Slot::Free(next) = self.slots[slot as usize] {
+                    self.free = next;
+                }
+                self.slots[slot as usize] = record;
+            }
+            None => self.slots.push(record),
+        }
+        self.cookies.push((self.last_cookie, slot));
+        self.len += 1;
+        // Without memory for the index lookups only take longer.
+        if self.len == HASHED {
+            self.index = self.build_index().ok();
+        }
+        Ok(())
+    }
+
+    /// A hashed index of the used slots.
+    fn build_index(&self) -> Result<HashIndex, FileSystemError> {
+        let mut index = HashIndex::new()?;
+        for slot in self.runs.iter().flatten() {
+            let hash = index.hash(self.record(*slot).0);
+            index.insert(hash, *slot)?;
+        }
+        Ok(index)
+    }
+
+    /// Remove the entry `name`, returning its mnode if there was one.
+    pub(crate) fn remove(&mut self, name: &str) -> Option<Arc<Mnode>> {
+        let (run, pos) = match self.find(name) {
+            (run, Ok(pos)) => (run, pos),
+            (_, Err(_)) => return None,
+        };
+        let slot = self.runs[run].remove(pos);
+        if self.runs[run].is_empty() {
+            self.runs.remove(run);
+        }
+        if let Some(index) = self.index.as_mut() {
+            index.remove(index.hash(name), slot);
+        }
+        let (_, cookie) = self.tags(slot);
+        if let Ok(pos) = self
+            .cookies
+            .binary_search_by_key(&cookie, |(cookie, _)| *cookie)
+        {
+            self.cookies[pos].1 = GONE;
+            self.gone += 1;
+        }
+        // Sweeping the removed cookies keeps the vector from growing with
+        // the churn, and costs a constant time per removal overall.
+        if 2 * self.gone > self.cookies.len() {
+            self.cookies.retain(|(_, slot)| *slot != GONE);
+            self.gone = 0;
+        }
+        let record = core::mem::replace(&mut self.slots[slot as usize], Slot::Free(self.free));
+        self.free = Some(slot);
+        self.len -= 1;
+        if self.len < HASHED / 2 {
+            self.index = None;
+        }
+        if self.len == 0 {
+            // The last entry gives back the memory of the arena, but not its
+            // cookies, unless room was reserved for the next one.
+            match self.spare {
+                Some(_) => {
+                    self.slots.clear();
+                    self.free = None;
+                    self.cookies.clear();
+                    self.gone = 0;
+                }
+                None => {
+                    let last_cookie = self.last_cookie;
+                    *self = Entries::default();
+                    self.last_cookie = last_cookie;
+                }
+            }
+        }
+        match record {
+            Slot::Used(_, mnode, _, _) => Some(mnode),
+            Slot::Free(_) => None,
+        }
+    }
+
+    /// The entries whose names sort after `after`, or all of them, in the
+    /// order of their names, with their types and cookies.
+    pub(crate) fn iter_after(
+        &self,
+        after: Option<&str>,
+    ) -> impl Iterator<Item = (&Name, &Arc<Mnode>, NodeType, u64)> {
+        let (first, pos) = match after.map(|after| self.find(after)) {
+            Some((run, Ok(pos))) => (run, pos + 1),
+            Some((run, Err(pos))) => (run, pos),
+            None => (0, 0),
+        };
+        self.runs
+            .iter()
+            .enumerate()
+            .skip(first)
+            .flat_map(move |(run, slots)| match run == first {
+                true => &slots[pos..],
+                false => &slots[..],
+            })
+            .map(move |slot| {
+                let (name, mnode) = self.record(*slot);
+                let (kind, cookie) = self.tags(*slot);
+                (name, mnode, kind, cookie)
+            })
+    }
+
+    /// The entries whose cookies are greater than `cookie`, in the order of
+    /// their cookies, with their types and them.
+    pub(crate) fn iter_from_cookie(
+        &self,
+        cookie: u64,
+    ) -> impl Iterator<Item = (&Name, &Arc<Mnode>, NodeType, u64)> {
+        let first = self.cookies.partition_point(|(other, _)| *other <= cookie);
+        self.cookies[first..]
+            .iter()
+            .filter(|(_, slot)| *slot != GONE)
+            .map(move |(cookie, slot)| {
+                let (name, mnode) = self.record(*slot);
+                let (kind, _) = self.tags(*slot);
+                (name, mnode, kind, *cookie)
+            })
+    }
+
+    /// The number of entries.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Bytes of heap memory held by the arena, the runs, the cookies and the
+    /// names.
+    pub(crate) fn allocated_size(&self) -> usize {
+        let runs: usize = self.runs.iter().map(|run| run.capacity()).sum();
+        let names: usize = self
+            .iter_after(None)
+            .map(|(name, _, _, _)| name.allocated_size())
+            .sum();
+        self.slots.capacity() * size_of::<Slot>()
+            + self.cookies.capacity() * size_of::<(u64, u32)>()
+            + self.runs.capacity() * size_of::<Vec<u32>>()
+            + runs * size_of::<u32>()
+            + self.spare.as_ref().map_or(0, |spare| spare.capacity()) * size_of::<u32>()
+            + names
+            + self
+                .index
+                .as_ref()
+                .map_or(0, |index| index.allocated_size())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+    use alloc::string::String;
+
+    #[test]
+    /// Entries stay sorted across split runs and reused slots.
+    fn test_entries() {
+        let mut entries = Entries::default();
+        let names: Vec<String> = (0..3 * RUN)
+            .map(|n| format!("{:05}", n * 7 % (3 * RUN)))
+            .collect();
+        for (n, name) in names.iter().enumerate() {
+            assert_eq!(
+                entries.insert(Name::new(name), Arc::new(n as Mnode), NodeType::File),
+                Ok(())
+            );
+        }
+        assert_eq!(
+            entries.insert(Name::new(&names[0]), Arc::new(0), NodeType::File),
+            Err(FileSystemError::AlreadyPresent)
+        );
+        assert_eq!(entries.len(), 3 * RUN);
+        assert!(entries.runs.len() > 2);
+        assert!(entries
+            .runs
+            .iter()
+            .all(|run| !run.is_empty() && run.len() <= RUN));
+        let sorted: Vec<&str> = entries
+            .iter_after(None)
+            .map(|(name, _, _, _)| &**name)
+            .collect();
+        let mut expected: Vec<&str> = names.iter().map(|name| &**name).collect();
+        expected.sort_unstable();
+        assert_eq!(sorted, expected);
+
+        assert_eq!(entries.get("00014").map(|(_, mnode)| **mnode), Some(2));
+        assert_eq!(entries.remove("00014").map(|mnode| *mnode), Some(2));
+        assert!(entries.get("00014").is_none());
+        assert_eq!(entries.remove("00014"), None);
+        let after: Vec<&str> = entries
+            .iter_after(Some("00013"))
+            .take(2)
+            .map(|(name, _, _, _)| &**name)
+            .collect();
+        assert_eq!(after, ["00015", "00016"]);
+        let slots = entries.slots.len();
+        entries
+            .insert(Name::new("zzz"), Arc::new(9), NodeType::Directory)
+            .unwrap();
+        assert_eq!(entries.slots.len(), slots);
+        assert_eq!(
+            entries.iter_after(Some("zz")).next().map(|entry| entry.2),
+            Some(NodeType::Directory)
+        );
+        assert_eq!(entries.iter_after(Some("zz")).count(), 1);
+        assert_eq!(entries.iter_after(Some("zzz")).count(), 0);
+
+        for name in entries
+            .iter_after(None)
+            .map(|(name, _, _, _)| Name::clone(name))
+            .collect::<Vec<_>>()
+        {
+            entries.remove(&name).unwrap();
+        }
+        assert_eq!(entries.len(), 0);
+        assert_eq!(entries.allocated_size(), 0);
+    }
+
+    #[test]
+    /// Large directories look their entries up by hash until they shrink.
+    fn test_hashed_entries() {
+        let mut entries = Entries::default();
+        for n in 0..2 * HASHED {
+            let name = format!("file{}", n);
+            assert_eq!(
+                entries.insert(Name::new(&name), Arc::new(n as Mnode), NodeType::File),
+                Ok(())
+            );
+            assert_eq!(entries.index.is_some(), n + 1 >= HASHED);
+        }
+        assert_eq!(
+            entries.insert(Name::new("file7"), Arc::new(0), NodeType::File),
+            Err(FileSystemError::AlreadyPresent)
+        );
+        assert_eq!(
+            entries.get("file1234").map(|(_, mnode)| **mnode),
+            Some(1234)
+        );
+        assert!(entries.get("file").is_none());
+
+        for n in 0..3 * HASHED / 2 + 1 {
+            assert!(entries.remove(&format!("file{}", n)).is_some());
+        }
+        assert!(entries.index.is_none());
+        assert!(entries.get("file7").is_none());
+        assert_eq!(
+            entries.get("file2000").map(|(_, mnode)| **mnode),
+            Some(2000)
+        );
+        assert_eq!(entries.iter_after(None).count(), HASHED / 2 - 1);
+    }
+
+    #[test]
+    /// A listing resumed from a cookie sees the entries that stayed exactly
+    /// once, whatever else changed.
+    fn test_cookies() {
+        let mut entries = Entries::default();
+        for n in 0..10 {
+            let name = format!("{}", n);
+            entries
+                .insert(Name::new(&name), Arc::new(n), NodeType::File)
+                .unwrap();
+        }
+        let first: Vec<(Mnode, u64)> = entries
+            .iter_from_cookie(0)
+            .take(4)
+            .map(|(_, mnode, _, cookie)| (**mnode, cookie))
+            .collect();
+        assert_eq!(
+            first.iter().map(|(mnode, _)| *mnode).collect::<Vec<_>>(),
+            [0, 1, 2, 3]
+        );
+        let (_, cookie) = first[3];
+
+        // Entries before and after the cookie come and go.
+        entries.remove("2").unwrap();
+        entries.remove("3").unwrap();
+        entries.remove("5").unwrap();
+        entries
+            .insert(Name::new("00"), Arc::new(10), NodeType::File)
+            .unwrap();
+        entries
+            .insert(Name::new("2"), Arc::new(11), NodeType::File)
+            .unwrap();
+        let rest: Vec<Mnode> = entries
+            .iter_from_cookie(cookie)
+            .map(|(_, mnode, _, _)| **mnode)
+            .collect();
+        assert_eq!(rest, [4, 6, 7, 8, 9, 10, 11]);
+        let cookies: Vec<u64> = entries.iter_from_cookie(0).map(|(_, _, _, c)| c).collect();
+        assert!(cookies.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // Removals are swept from the cookies, and cookies aren't reused.
+        for n in [0, 1, 4, 6, 7] {
+            entries.remove(&format!("{}", n)).unwrap();
+        }
+        assert!(entries.cookies.len() < 2 * entries.len());
+        let last = *cookies.last().unwrap();
+        for name in ["8", "9", "00", "2"] {
+            entries.remove(name).unwrap();
+        }
+        entries
+            .insert(Name::new("new"), Arc::new(12), NodeType::File)
+            .unwrap();
+        assert_eq!(entries.iter_from_cookie(0).next().unwrap().3, last + 1);
+    }
+
+    #[test]
+    /// The room made by `reserve()` stays until the next insertion, even if
+    /// the directory empties in the meantime.
+    fn test_reserve() {
+        let mut entries = Entries::default();
+        entries
+            .insert(Name::new("a"), Arc::new(1), NodeType::File)
+            .unwrap();
+        entries.reserve().unwrap();
+        assert_eq!(entries.remove("a").map(|mnode| *mnode), Some(1));
+        assert!(entries.slots.capacity() > 0 && entries.cookies.capacity() > 0);
+        assert!(entries.runs.capacity() > 0);
+        assert_eq!(
+            entries.spare.as_ref().map(|spare| spare.capacity()),
+            Some(RUN)
+        );
+        entries
+            .insert(Name::new("b"), Arc::new(2), NodeType::File)
+            .unwrap();
+        assert!(entries.spare.is_none());
+        assert_eq!(entries.get("b").map(|(_, mnode)| **mnode), Some(2));
+        assert_eq!(entries.iter_from_cookie(0).next().unwrap().3, 2);
+
+        // A full run can be split into the spare one.
+        for n in 0..RUN - 1 {
+            let name = format!("c{:03}", n);
+            entries
+                .insert(Name::new(&name), Arc::new(n as Mnode), NodeType::File)
+                .unwrap();
+        }
+        assert_eq!(entries.runs.len(), 1);
+        entries.reserve().unwrap();
+        let mut spare = entries.spare.take();
+        let tail = new_run(usize::MAX, &mut spare).unwrap();
+        assert_eq!(tail.capacity(), RUN);
+        assert_eq!(
+            new_run(usize::MAX, &mut spare),
+            Err(FileSystemError::OutOfMemory)
+        );
+        entries.spare = Some(tail);
+        entries
+            .insert(Name::new("d"), Arc::new(3), NodeType::File)
+            .unwrap();
+        assert_eq!(entries.runs.len(), 2);
+        assert!(entries.spare.is_none());
+    }
+}
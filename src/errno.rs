@@ -0,0 +1,144 @@
+//! POSIX errno values for file-system errors.
+//!
+//! The syscall layer returns `-errno` to userspace. Keeping the conversion next
+//! to `FileSystemError` means a new variant fails to compile here instead of
+//! silently falling through a match in the kernel.
+
+use crate::FileSystemError;
+
+/// A POSIX error number (Linux numbering).
+pub type Errno = i32;
+
+pub const EPERM: Errno = 1;
+pub const ENOENT: Errno = 2;
+pub const EINTR: Errno = 4;
+pub const EIO: Errno = 5;
+pub const EBADF: Errno = 9;
+pub const EAGAIN: Errno = 11;
+pub const ENOMEM: Errno = 12;
+pub const EACCES: Errno = 13;
+pub const EFAULT: Errno = 14;
+pub const EBUSY: Errno = 16;
+pub const EEXIST: Errno = 17;
+pub const ENOTDIR: Errno = 20;
+pub const EISDIR: Errno = 21;
+pub const EINVAL: Errno = 22;
+pub const EMFILE: Errno = 24;
+pub const EFBIG: Errno = 27;
+pub const ENOSPC: Errno = 28;
+pub const EROFS: Errno = 30;
+pub const ENAMETOOLONG: Errno = 36;
+pub const ENOTEMPTY: Errno = 39;
+pub const EOVERFLOW: Errno = 75;
+pub const EOPNOTSUPP: Errno = 95;
+
+impl FileSystemError {
+    /// The errno that corresponds to this error.
+    pub fn errno(&self) -> Errno {
+        match self {
+            FileSystemError::InvalidFileDescriptor => EBADF,
+            FileSystemError::InvalidFile => ENOENT,
+            FileSystemError::InvalidFlags => EINVAL,
+            FileSystemError::InvalidOffset => EINVAL,
+            FileSystemError::PermissionError => EACCES,
+            FileSystemError::AlreadyPresent => EEXIST,
+            FileSystemError::DirectoryError => EISDIR,
+            FileSystemError::OpenFileLimit => EMFILE,
+            FileSystemError::OutOfMemory => ENOMEM,
+            FileSystemError::NotADirectory => ENOTDIR,
+            FileSystemError::IsADirectory => EISDIR,
+            FileSystemError::DirectoryNotEmpty => ENOTEMPTY,
+            FileSystemError::NameTooLong => ENAMETOOLONG,
+            FileSystemError::NoSpace => ENOSPC,
+            FileSystemError::NotSupported => EOPNOTSUPP,
+            FileSystemError::WouldBlock => EAGAIN,
+            FileSystemError::Busy => EBUSY,
+            FileSystemError::DataCorruption => EIO,
+            FileSystemError::BadAddress => EFAULT,
+            FileSystemError::ReadOnly => EROFS,
+            FileSystemError::FileTooLarge => EFBIG,
+            FileSystemError::Interrupted => EINTR,
+            FileSystemError::BufferTooSmall => EINVAL,
+            FileSystemError::Overflow => EOVERFLOW,
+            FileSystemError::InvalidArgument => EINVAL,
+        }
+    }
+}
+
+/// Convert FileSystemError to an errno.
+impl From<FileSystemError> for Errno {
+    fn from(err: FileSystemError) -> Errno {
+        err.errno()
+    }
+}
+
+/// The symbolic name of an errno, e.g. `"ENOENT"`.
+pub fn name(errno: Errno) -> &'static str {
+    match errno {
+        EPERM => "EPERM",
+        ENOENT => "ENOENT",
+        EINTR => "EINTR",
+        EIO => "EIO",
+        EBADF => "EBADF",
+        EAGAIN => "EAGAIN",
+        ENOMEM => "ENOMEM",
+        EACCES => "EACCES",
+        EFAULT => "EFAULT",
+        EBUSY => "EBUSY",
+        EEXIST => "EEXIST",
+        ENOTDIR => "ENOTDIR",
+        EISDIR => "EISDIR",
+        EINVAL => "EINVAL",
+        EMFILE => "EMFILE",
+        ENOSPC => "ENOSPC",
+        ENAMETOOLONG => "ENAMETOOLONG",
+        ENOTEMPTY => "ENOTEMPTY",
+        EFBIG => "EFBIG",
+        EROFS => "EROFS",
+        EOVERFLOW => "EOVERFLOW",
+        EOPNOTSUPP => "EOPNOTSUPP",
+        _ => "EUNKNOWN",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    /// Every error has an errno with a name.
+    fn test_errno_names() {
+        let errors = [
+            FileSystemError::InvalidFileDescriptor,
+            FileSystemError::InvalidFile,
+            FileSystemError::InvalidFlags,
+            FileSystemError::InvalidOffset,
+            FileSystemError::PermissionError,
+            FileSystemError::AlreadyPresent,
+            FileSystemError::DirectoryError,
+            FileSystemError::OpenFileLimit,
+            FileSystemError::OutOfMemory,
+            FileSystemError::NotADirectory,
+            FileSystemError::IsADirectory,
+            FileSystemError::DirectoryNotEmpty,
+            FileSystemError::NameTooLong,
+            FileSystemError::NoSpace,
+            FileSystemError::NotSupported,
+            FileSystemError::WouldBlock,
+            FileSystemError::Busy,
+            FileSystemError::DataCorruption,
+            FileSystemError::BadAddress,
+            FileSystemError::ReadOnly,
+            FileSystemError::FileTooLarge,
+            FileSystemError::Interrupted,
+            FileSystemError::BufferTooSmall,
+            FileSystemError::Overflow,
+            FileSystemError::InvalidArgument,
+        ];
+        for error in errors.iter() {
+            assert_ne!(name(error.errno()), "EUNKNOWN", "{:?}", error);
+        }
+        assert_eq!(name(FileSystemError::ReadOnly.errno()), "EROFS");
+        assert_eq!(name(FileSystemError::FileTooLarge.errno()), "EFBIG");
+    }
+}
@@ -0,0 +1,111 @@
+//! File-system errors annotated with where they happened.
+//!
+//! `FileSystemError` stays a plain enum since it is what the syscall layer
+//! turns into an errno. Code that has more information at hand (the operation,
+//! the path it was walking, the mnode it was using) can attach it with
+//! [`ResultExt::context`], so an `InvalidFile` coming out of a long sequence of
+//! operations can be traced back to its origin.
+
+use alloc::string::{String, ToString};
+use core::fmt;
+
+use crate::errno::Errno;
+use crate::{FileSystemError, Mnode};
+
+/// The file-system operation that failed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Operation {
+    Create,
+    Lookup,
+    Read,
+    Write,
+    FileInfo,
+    Delete,
+    Truncate,
+    Rename,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Operation::Create => "create",
+            Operation::Lookup => "lookup",
+            Operation::Read => "read",
+            Operation::Write => "write",
+            Operation::FileInfo => "file_info",
+            Operation::Delete => "delete",
+            Operation::Truncate => "truncate",
+            Operation::Rename => "rename",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A `FileSystemError` together with the operation, path and mnode involved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorContext {
+    pub error: FileSystemError,
+    pub op: Operation,
+    pub path: Option<String>,
+    pub mnode: Option<Mnode>,
+}
+
+impl ErrorContext {
+    pub fn new(error: FileSystemError, op: Operation) -> ErrorContext {
+        ErrorContext {
+            error,
+            op,
+            path: None,
+            mnode: None,
+        }
+    }
+
+    /// Attach the path the operation was working on.
+    pub fn path(mut self, path: &str) -> ErrorContext {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// Attach the mnode the operation was working on.
+    pub fn mnode(mut self, mnode: Mnode) -> ErrorContext {
+        self.mnode = Some(mnode);
+        self
+    }
+
+    /// The errno of the underlying error.
+    pub fn errno(&self) -> Errno {
+        self.error.errno()
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.op)?;
+        if let Some(path) = &self.path {
+            write!(f, " of '{}'", path)?;
+        }
+        if let Some(mnode) = self.mnode {
+            write!(f, " (mnode {})", mnode)?;
+        }
+        write!(f, " failed: {}", self.error)
+    }
+}
+
+/// Drop the context again, e.g. to return the error from a `FileSystem` method.
+impl From<ErrorContext> for FileSystemError {
+    fn from(err: ErrorContext) -> FileSystemError {
+        err.error
+    }
+}
+
+/// Adds context to the error of a file-system `Result`.
+pub trait ResultExt<T> {
+    /// Annotate the error with the operation that failed.
+    fn context(self, op: Operation) -> Result<T, ErrorContext>;
+}
+
+impl<T> ResultExt<T> for Result<T, FileSystemError> {
+    fn context(self, op: Operation) -> Result<T, ErrorContext> {
+        self.map_err(|error| ErrorContext::new(error, op))
+    }
+}
@@ -1,4 +1,10 @@
 use super::*;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use crate::builder::CpuId;
+use crate::rcu::Rcu;
+use spin::MutexGuard;
 
 /// Abstract definition of a file descriptor.
 pub trait FileDescriptor {
@@ -10,12 +16,99 @@ pub trait FileDescriptor {
     fn update_offset(&self, new_offset: usize);
 }
 
+/// Read-ahead of the first sequential read, in bytes.
+pub const READ_AHEAD_MIN: usize = 16 * 1024;
+/// Read-ahead once the reads turned out to be sequential, in bytes.
+pub const READ_AHEAD_MAX: usize = 128 * 1024;
+
+/// The status flags `fcntl(F_SETFL)` can change; the access mode and the
+/// creation flags stay as the file was opened with.
+pub const SETFL_MASK: FileFlags =
+    FileFlags::from_bits_truncate(FileFlags::O_APPEND.bits() | FileFlags::O_NONBLOCK.bits());
+
+// `Fd` keeps the flags in a `usize`.
+const_assert!(FileFlags::all().bits() <= u32::MAX as u64);
+
 /// A file descriptor representaion.
+///
+/// This is the open file description of POSIX: the descriptors of a table
+/// refer to it, and descriptors duplicated or passed to another table share
+/// it, with its flags and offset.
 #[derive(Debug, Default)]
 pub struct Fd {
     mnode: Mnode,
-    flags: FileFlags,
+    /// The bits of the `FileFlags`, changed by `set_status_flags()`. They
+    /// fit a `usize`, so this works on targets without 64-bit atomics.
+    flags: AtomicUsize,
     offset: AtomicUsize,
+    /// Held by the reads and writes at the offset, see `lock_offset()`.
+    offset_lock: Mutex<()>,
+    /// Where the next read starts if the reads are sequential.
+    next: AtomicUsize,
+    /// Bytes to read ahead of the next sequential read.
+    window: AtomicUsize,
+}
+
+impl Fd {
+    /// The access mode and the status flags of the open file, like
+    /// `fcntl(F_GETFL)`; the creation flags aren't kept.
+    pub fn status_flags(&self) -> FileFlags {
+        self.get_flags() - (FileFlags::O_CREAT | FileFlags::O_TRUNC | FileFlags::O_CLOEXEC)
+    }
+
+    /// Change the status flags of the open file, like `fcntl(F_SETFL)`: only
+    /// the flags in `SETFL_MASK` are taken from `flags`, the others are
+    /// ignored. The change is seen through all the descriptors sharing the
+    /// file. Path descriptors have no status flags to change.
+    pub fn set_status_flags(&self, flags: FileFlags) -> Result<(), FileSystemError> {
+        if self.is_path() {
+            return Err(FileSystemError::InvalidFileDescriptor);
+        }
+        let _ = self
+            .flags
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |old| {
+                let old = FileFlags::from_bits_truncate(old as u64);
+                Some(((old - SETFL_MASK) | (flags & SETFL_MASK)).bits() as usize)
+            });
+        Ok(())
+    }
+
+    /// Whether the descriptor was opened with `O_PATH`: it only refers to
+    /// the file, for `fstat()` and as the directory of the `*at` operations,
+    /// and can't be used to read or write it.
+    pub fn is_path(&self) -> bool {
+        self.get_flags().is_path()
+    }
+
+    /// Serialize the reads and writes at the offset of the open file, like
+    /// `f_pos_lock` in Linux, so threads using descriptors that share it
+    /// don't read or write at the same offset. Held from reading the offset
+    /// until it is moved past the data.
+    pub fn lock_offset(&self) -> MutexGuard<'_, ()> {
+        self.offset_lock.lock()
+    }
+
+    /// Account for a read of `len` bytes at `offset` and return the range to
+    /// read ahead, if any, as (offset, length). The window doubles with every
+    /// sequential read up to `READ_AHEAD_MAX` and closes on a random one. The
+    /// `access` advised for the file overrides the detection.
+    pub fn read_ahead(&self, offset: usize, len: usize, access: Advice) -> Option<(usize, usize)> {
+        let end = offset.saturating_add(len);
+        let sequential = self.next.swap(end, Ordering::Relaxed) == offset;
+        let window = match access {
+            Advice::Random => 0,
+            Advice::Sequential => READ_AHEAD_MAX,
+            _ if sequential => {
+                (self.window.load(Ordering::Relaxed) * 2).clamp(READ_AHEAD_MIN, READ_AHEAD_MAX)
+            }
+            _ => 0,
+        };
+        self.window.store(window, Ordering::Relaxed);
+        match window {
+            0 => None,
+            _ => Some((end, window)),
+        }
+    }
 }
 
 impl FileDescriptor for Fd {
@@ -23,14 +116,22 @@ impl FileDescriptor for Fd {
         Fd {
             // Intial values are just the place-holders and shouldn't be used.
             mnode: core::u64::MAX,
-            flags: Default::default(),
+            flags: AtomicUsize::new(0),
             offset: AtomicUsize::new(0),
+            offset_lock: Mutex::new(()),
+            next: AtomicUsize::new(0),
+            window: AtomicUsize::new(0),
         }
     }
 
     fn update_fd(&mut self, mnode: Mnode, flags: FileFlags) {
+        // A path descriptor can't read or write, whatever else was asked for.
+        let flags = match flags.is_path() {
+            true => flags & (FileFlags::O_PATH | FileFlags::O_CLOEXEC),
+            false => flags,
+        };
         self.mnode = mnode;
-        self.flags = flags;
+        *self.flags.get_mut() = flags.bits() as usize;
     }
 
     fn get_mnode(&self) -> Mnode {
@@ -38,7 +139,7 @@ impl FileDescriptor for Fd {
     }
 
     fn get_flags(&self) -> FileFlags {
-        self.flags.clone()
+        FileFlags::from_bits_truncate(self.flags.load(Ordering::Acquire) as u64)
     }
 
     fn get_offset(&self) -> usize {
@@ -49,3 +150,441 @@ impl FileDescriptor for Fd {
         self.offset.store(new_offset, Ordering::Release);
     }
 }
+
+/// A descriptor of a table: the open file it refers to and its own flags.
+#[derive(Debug, Clone)]
+struct Slot {
+    file: Arc<Fd>,
+    fd_flags: FdFlags,
+}
+
+/// The descriptor table of a process.
+///
+/// The number of descriptors a process can have open is a runtime limit,
+/// similar to `RLIMIT_NOFILE`, which defaults to `MAX_FILES_PER_PROCESS`.
+#[derive(Debug)]
+pub struct FdTable {
+    fds: Vec<Option<Slot>>,
+    limit: usize,
+}
+
+impl Default for FdTable {
+    fn default() -> FdTable {
+        FdTable::with_limit(MAX_FILES_PER_PROCESS)
+    }
+}
+
+impl FdTable {
+    /// Create an empty table that allows `limit` open descriptors.
+    pub fn with_limit(limit: usize) -> FdTable {
+        FdTable {
+            fds: Vec::new(),
+            limit,
+        }
+    }
+
+    /// The maximum number of open descriptors.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Change the maximum number of open descriptors. Descriptors that are
+    /// already open stay valid when the limit is lowered below them, but no
+    /// descriptor above the limit is handed out anymore.
+    pub fn set_limit(&mut self, limit: usize) -> Result<(), FileSystemError> {
+        if limit == 0 {
+            return Err(FileSystemError::InvalidFlags);
+        }
+        self.limit = limit;
+        Ok(())
+    }
+
+    /// Number of open descriptors.
+    pub fn len(&self) -> usize {
+        self.fds.iter().filter(|fd| fd.is_some()).count()
+    }
+
+    /// Check if no descriptor is open.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Open a descriptor for the mnode, using the lowest free number.
+    pub fn allocate(&mut self, mnode: Mnode, flags: FileFlags) -> Result<FD, FileSystemError> {
+        let mut fd = Fd::init_fd();
+        fd.update_fd(mnode, flags);
+        let fd_flags = match flags.contains(FileFlags::O_CLOEXEC) {
+            true => FdFlags::FD_CLOEXEC,
+            false => FdFlags::empty(),
+        };
+        self.install(Arc::new(fd), fd_flags)
+    }
+
+    /// Add a descriptor for an open file, e.g. one passed by another process
+    /// like with `SCM_RIGHTS`, using the lowest free number. The open file is
+    /// shared, with its offset.
+    pub fn install(&mut self, file: Arc<Fd>, fd_flags: FdFlags) -> Result<FD, FileSystemError> {
+        let slot = self.fds.iter().take(self.limit).position(|fd| fd.is_none());
+        let slot = match slot {
+            Some(slot) => slot,
+            None if self.fds.len() < self.limit => {
+                self.fds
+                    .try_reserve(1)
+                    .map_err(|_| FileSystemError::OutOfMemory)?;
+                self.fds.push(None);
+                self.fds.len() - 1
+            }
+            None => {
+                ratelimited!(info, "all {} descriptors are in use", self.limit);
+                return Err(FileSystemError::OpenFileLimit);
+            }
+        };
+
+        self.fds[slot] = Some(Slot { file, fd_flags });
+        Ok(slot as FD)
+    }
+
+    /// Duplicate a descriptor, like `dup()`: the new one, with the lowest
+    /// free number, shares the open file but not the close-on-exec flag.
+    pub fn dup(&mut self, fd: FD) -> Result<FD, FileSystemError> {
+        let file = self.file(fd)?;
+        self.install(file, FdFlags::empty())
+    }
+
+    /// Copy the table for a child process, like `fork()` does: the
+    /// descriptors of the copy share the open files of this table.
+    pub fn fork(&self) -> Result<FdTable, FileSystemError> {
+        let mut fds = Vec::new();
+        fds.try_reserve(self.fds.len())
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        fds.extend(self.fds.iter().cloned());
+        Ok(FdTable {
+            fds,
+            limit: self.limit,
+        })
+    }
+
+    fn slot(&self, fd: FD) -> Result<&Slot, FileSystemError> {
+        match usize::try_from(fd).ok().and_then(|fd| self.fds.get(fd)) {
+            Some(Some(slot)) => Ok(slot),
+            _ => Err(FileSystemError::InvalidFileDescriptor),
+        }
+    }
+
+    /// Get an open descriptor.
+    pub fn get(&self, fd: FD) -> Result<&Fd, FileSystemError> {
+        self.slot(fd).map(|slot| &*slot.file)
+    }
+
+    /// The open file of a descriptor, to pass it to another table.
+    pub fn file(&self, fd: FD) -> Result<Arc<Fd>, FileSystemError> {
+        self.slot(fd).map(|slot| Arc::clone(&slot.file))
+    }
+
+    /// The flags of a descriptor, like `fcntl(F_GETFD)`.
+    pub fn fd_flags(&self, fd: FD) -> Result<FdFlags, FileSystemError> {
+        self.slot(fd).map(|slot| slot.fd_flags)
+    }
+
+    /// Replace the flags of a descriptor, like `fcntl(F_SETFD)`; the other
+    /// descriptors of the open file keep theirs.
+    pub fn set_fd_flags(&mut self, fd: FD, fd_flags: FdFlags) -> Result<(), FileSystemError> {
+        match usize::try_from(fd).ok().and_then(|fd| self.fds.get_mut(fd)) {
+            Some(Some(slot)) => {
+                slot.fd_flags = fd_flags;
+                Ok(())
+            }
+            _ => Err(FileSystemError::InvalidFileDescriptor),
+        }
+    }
+
+    /// Close the descriptors marked close-on-exec, as an exec does, and
+    /// return how many were closed.
+    pub fn close_on_exec(&mut self) -> usize {
+        let mut closed = 0;
+        for slot in self.fds.iter_mut() {
+            if let Some(fd) = slot {
+                if fd.fd_flags.contains(FdFlags::FD_CLOEXEC) {
+                    *slot = None;
+                    closed += 1;
+                }
+            }
+        }
+        closed
+    }
+
+    /// Close a descriptor and return its open file, which stays open as long
+    /// as other descriptors refer to it.
+    pub fn deallocate(&mut self, fd: FD) -> Result<Arc<Fd>, FileSystemError> {
+        match usize::try_from(fd)
+            .ok()
+            .and_then(|fd| self.fds.get_mut(fd))
+            .and_then(|fd| fd.take())
+        {
+            Some(slot) => Ok(slot.file),
+            None => Err(FileSystemError::InvalidFileDescriptor),
+        }
+    }
+}
+
+/// A descriptor table shared by the threads of a process.
+///
+/// Lookups are on the path of every system call, so they read a published
+/// version of the table and never wait, even while other threads open or
+/// close descriptors. Changes copy the table and publish the copy, and wait
+/// for the lookups still using the old version; the open files themselves
+/// aren't copied.
+pub struct SharedFdTable {
+    table: Rcu<FdTable>,
+    cpu_id: Option<Arc<dyn CpuId>>,
+}
+
+impl SharedFdTable {
+    /// Share `table` between threads; lookups from up to `readers` threads
+    /// at a time don't write to shared counters. Without the `std` feature
+    /// the lookups always do, see `with_cpu_id()`.
+    pub fn new(table: FdTable, readers: usize) -> SharedFdTable {
+        SharedFdTable {
+            table: Rcu::new(table, readers),
+            cpu_id: None,
+        }
+    }
+
+    /// Share `table` between the `readers` CPUs `cpu_id` tells apart, which
+    /// is what the lookups go by without the `std` feature, see
+    /// `MemFSBuilder::cpu_id()`; lookups on the other CPUs write to shared
+    /// counters.
+    pub fn with_cpu_id(table: FdTable, readers: usize, cpu_id: Arc<dyn CpuId>) -> SharedFdTable {
+        SharedFdTable {
+            table: Rcu::new(table, readers),
+            cpu_id: Some(cpu_id),
+        }
+    }
+
+    /// The open file of a descriptor.
+    pub fn get(&self, fd: FD) -> Result<Arc<Fd>, FileSystemError> {
+        let reader = reader_id(self.cpu_id.as_deref());
+        self.table.read(reader).file(fd)
+    }
+
+    /// Change the table with `change`, e.g. to open or close descriptors.
+    /// If `change` fails, the table stays as it is.
+    pub fn update<R>(
+        &self,
+        change: impl FnOnce(&mut FdTable) -> Result<R, FileSystemError>,
+    ) -> Result<R, FileSystemError> {
+        let mut result = None;
+        self.table.update(|table| {
+            let mut copy = table.fork()?;
+            result = Some(change(&mut copy)?);
+            Ok::<_, FileSystemError>(copy)
+        })?;
+        // `change` ran and succeeded if the update did.
+        result.ok_or(FileSystemError::InvalidFileDescriptor)
+    }
+
+    /// Open a descriptor for the mnode, see `FdTable::allocate()`.
+    pub fn allocate(&self, mnode: Mnode, flags: FileFlags) -> Result<FD, FileSystemError> {
+        self.update(|table| table.allocate(mnode, flags))
+    }
+
+    /// Close a descriptor, see `FdTable::deallocate()`.
+    pub fn deallocate(&self, fd: FD) -> Result<Arc<Fd>, FileSystemError> {
+        self.update(|table| table.deallocate(fd))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    /// Descriptors are handed out lowest first and reused after a close.
+    fn test_allocate() {
+        let mut fds = FdTable::default();
+        assert_eq!(fds.limit(), MAX_FILES_PER_PROCESS);
+        assert_eq!(fds.allocate(2, FileFlags::O_RDWR), Ok(0));
+        assert_eq!(fds.allocate(3, FileFlags::O_RDONLY), Ok(1));
+        assert_eq!(fds.get(1).unwrap().get_mnode(), 3);
+        assert_eq!(fds.get(1).unwrap().get_flags(), FileFlags::O_RDONLY);
+
+        assert_eq!(fds.deallocate(0).unwrap().get_mnode(), 2);
+        assert_eq!(
+            fds.get(0).err(),
+            Some(FileSystemError::InvalidFileDescriptor)
+        );
+        assert_eq!(
+            fds.deallocate(0).err(),
+            Some(FileSystemError::InvalidFileDescriptor)
+        );
+        assert_eq!(fds.allocate(4, FileFlags::O_RDWR), Ok(0));
+        assert_eq!(fds.len(), 2);
+    }
+
+    #[test]
+    /// Close-on-exec is a flag of the descriptor, set from O_CLOEXEC, and only
+    /// the status flags of the open file can be changed.
+    fn test_fcntl_flags() {
+        let mut fds = FdTable::default();
+        let flags = FileFlags::O_RDWR | FileFlags::O_CREAT | FileFlags::O_CLOEXEC;
+        assert_eq!(fds.allocate(2, flags), Ok(0));
+        assert_eq!(fds.allocate(3, FileFlags::O_RDONLY), Ok(1));
+        assert_eq!(fds.fd_flags(0), Ok(FdFlags::FD_CLOEXEC));
+        assert_eq!(fds.get(0).unwrap().status_flags(), FileFlags::O_RDWR);
+        assert_eq!(fds.fd_flags(1), Ok(FdFlags::empty()));
+
+        let fd = fds.get(1).unwrap();
+        fd.set_status_flags(FileFlags::O_WRONLY | FileFlags::O_NONBLOCK | FileFlags::O_TRUNC)
+            .unwrap();
+        assert_eq!(
+            fd.status_flags(),
+            FileFlags::O_RDONLY | FileFlags::O_NONBLOCK
+        );
+        assert!(fd.get_flags().is_nonblocking());
+        fd.set_status_flags(FileFlags::O_APPEND).unwrap();
+        assert_eq!(fd.status_flags(), FileFlags::O_RDONLY | FileFlags::O_APPEND);
+        assert_eq!(fds.set_fd_flags(1, FdFlags::FD_CLOEXEC), Ok(()));
+        assert_eq!(
+            fds.set_fd_flags(7, FdFlags::empty()),
+            Err(FileSystemError::InvalidFileDescriptor)
+        );
+
+        assert_eq!(fds.allocate(4, FileFlags::O_RDONLY), Ok(2));
+        assert_eq!(fds.close_on_exec(), 2);
+        assert!(fds.get(0).is_err() && fds.get(1).is_err());
+        assert_eq!(fds.get(2).unwrap().get_mnode(), 4);
+    }
+
+    #[test]
+    /// Duplicated, forked and passed descriptors share the open file, with its
+    /// offset and status flags, but each has its own close-on-exec flag.
+    fn test_shared_file() {
+        let mut parent = FdTable::default();
+        assert_eq!(
+            parent.allocate(2, FileFlags::O_RDWR | FileFlags::O_CLOEXEC),
+            Ok(0)
+        );
+        assert_eq!(parent.dup(0), Ok(1));
+        assert_eq!(parent.fd_flags(1), Ok(FdFlags::empty()));
+        parent.get(0).unwrap().update_offset(10);
+        assert_eq!(parent.get(1).unwrap().get_offset(), 10);
+
+        let mut child = parent.fork().unwrap();
+        assert_eq!(child.fd_flags(0), Ok(FdFlags::FD_CLOEXEC));
+        child.get(1).unwrap().update_offset(20);
+        assert_eq!(parent.get(0).unwrap().get_offset(), 20);
+        child
+            .get(0)
+            .unwrap()
+            .set_status_flags(FileFlags::O_APPEND)
+            .unwrap();
+        assert!(parent.get(1).unwrap().get_flags().is_append());
+        assert_eq!(child.close_on_exec(), 1);
+        assert_eq!(parent.len(), 2);
+
+        let mut other = FdTable::default();
+        let file = parent.deallocate(0).unwrap();
+        assert_eq!(other.install(file, FdFlags::empty()), Ok(0));
+        assert_eq!(parent.deallocate(1).unwrap().get_offset(), 20);
+        other.get(0).unwrap().update_offset(30);
+        assert_eq!(child.get(1).unwrap().get_offset(), 30);
+        assert_eq!(
+            parent.dup(0).err(),
+            Some(FileSystemError::InvalidFileDescriptor)
+        );
+    }
+
+    #[test]
+    /// Lookups see every descriptor that stays open while other threads open
+    /// and close descriptors.
+    fn test_shared_table() {
+        let fds = Arc::new(SharedFdTable::new(FdTable::default(), 4));
+        assert_eq!(fds.allocate(2, FileFlags::O_RDWR), Ok(0));
+        assert_eq!(
+            fds.update(|table| table.set_limit(0)),
+            Err(FileSystemError::InvalidFlags)
+        );
+
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let fds = Arc::clone(&fds);
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        assert_eq!(fds.get(0).unwrap().get_mnode(), 2);
+                    }
+                })
+            })
+            .collect();
+        for mnode in 3..100 {
+            let fd = fds.allocate(mnode, FileFlags::O_RDONLY).unwrap();
+            assert_eq!(fds.get(fd).unwrap().get_mnode(), mnode);
+            assert_eq!(fds.deallocate(fd).unwrap().get_mnode(), mnode);
+            assert!(fds.get(fd).is_err());
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    #[test]
+    /// The read-ahead window grows with sequential reads and closes on a seek.
+    fn test_read_ahead() {
+        let fd = Fd::init_fd();
+        let normal = Advice::Normal;
+        assert_eq!(fd.read_ahead(0, 4096, normal), Some((4096, READ_AHEAD_MIN)));
+        assert_eq!(
+            fd.read_ahead(4096, 4096, normal),
+            Some((8192, 2 * READ_AHEAD_MIN))
+        );
+        assert_eq!(
+            fd.read_ahead(8192, 4096, normal),
+            Some((12288, 4 * READ_AHEAD_MIN))
+        );
+        assert_eq!(
+            fd.read_ahead(12288, 4096, normal),
+            Some((16384, READ_AHEAD_MAX))
+        );
+        assert_eq!(
+            fd.read_ahead(16384, 4096, normal),
+            Some((20480, READ_AHEAD_MAX))
+        );
+        assert_eq!(fd.read_ahead(0, 4096, normal), None);
+        assert_eq!(
+            fd.read_ahead(4096, 4096, normal),
+            Some((8192, READ_AHEAD_MIN))
+        );
+
+        assert_eq!(fd.read_ahead(8192, 4096, Advice::Random), None);
+        assert_eq!(
+            fd.read_ahead(0, 10, Advice::Sequential),
+            Some((10, READ_AHEAD_MAX))
+        );
+    }
+
+    #[test]
+    /// The open-file limit can be changed at runtime.
+    fn test_limit() {
+        let mut fds = FdTable::with_limit(2);
+        assert_eq!(fds.allocate(2, FileFlags::O_RDWR), Ok(0));
+        assert_eq!(fds.allocate(2, FileFlags::O_RDWR), Ok(1));
+        assert_eq!(
+            fds.allocate(2, FileFlags::O_RDWR),
+            Err(FileSystemError::OpenFileLimit)
+        );
+
+        assert_eq!(fds.set_limit(0), Err(FileSystemError::InvalidFlags));
+        assert_eq!(fds.set_limit(3), Ok(()));
+        assert_eq!(fds.allocate(2, FileFlags::O_RDWR), Ok(2));
+
+        // Lowering the limit keeps the open descriptors.
+        assert_eq!(fds.set_limit(1), Ok(()));
+        assert!(fds.get(2).is_ok());
+        fds.deallocate(1).unwrap();
+        assert_eq!(
+            fds.allocate(2, FileFlags::O_RDWR),
+            Err(FileSystemError::OpenFileLimit)
+        );
+        fds.deallocate(0).unwrap();
+        assert_eq!(fds.allocate(2, FileFlags::O_RDWR), Ok(0));
+    }
+}
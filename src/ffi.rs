@@ -0,0 +1,235 @@
+//! A C interface to MemFS, for kernel components and test harnesses written
+//! in C. Enabled with the `ffi` feature.
+//!
+//! A `struct nrfs` from `nrfs_new()` holds a file-system and the descriptor
+//! table of its caller. The functions return a non-negative value on success
+//! and the negated errno on failure, like the system calls. Paths are
+//! NUL-terminated strings and the buffers are plain memory of the caller:
+//! the calls go through the same dispatch as those of a kernel, see
+//! [`Syscalls`], with the memory of the caller as the user memory.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+use crate::builder::CpuId;
+use crate::errno::{Errno, EFAULT, EINVAL};
+use crate::fd::{FdTable, SharedFdTable};
+use crate::syscalls::{to_return, SyscallOp, Syscalls, UserMemory};
+use crate::{
+    topology, FileSystem, FileSystemError, FileSystemRead, MemFS, MemFSBuilder, Metadata,
+    MAX_PATH_LEN,
+};
+
+/// A file-system and the descriptors open on it.
+pub struct Nrfs {
+    fs: MemFS,
+    fds: SharedFdTable,
+}
+
+/// The memory of the caller, which it vouches for.
+struct CallerMemory;
+
+impl UserMemory for CallerMemory {
+    fn read_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        if addr == 0 {
+            return Err(EFAULT);
+        }
+        let src = unsafe { core::slice::from_raw_parts(addr as *const u8, buf.len()) };
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_bytes(&self, addr: u64, data: &[u8]) -> Result<(), Errno> {
+        if addr == 0 {
+            return Err(EFAULT);
+        }
+        let dst = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, data.len()) };
+        dst.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Tells the current CPU with a function of the caller.
+struct CurrentCpu(extern "C" fn() -> usize);
+
+impl CpuId for CurrentCpu {
+    fn current(&self) -> usize {
+        (self.0)()
+    }
+}
+
+impl Nrfs {
+    fn call(&self, op: SyscallOp, args: [u64; 4]) -> i64 {
+        to_return(Syscalls::new(&self.fs, &self.fds, &CallerMemory).dispatch(op as u64, args))
+    }
+}
+
+/// Create an empty file-system; free it with `nrfs_free()`.
+///
+/// `current_cpu` returns the CPU the caller runs on, and the caller must
+/// not be preempted while it runs a call, see `MemFSBuilder::cpu_id()`. It
+/// may be NULL, where the calls share the reader slots without the `std`
+/// feature.
+#[no_mangle]
+pub extern "C" fn nrfs_new(current_cpu: Option<extern "C" fn() -> usize>) -> *mut Nrfs {
+    let readers = topology::machine().cpu_ids();
+    let builder = MemFSBuilder::new();
+    let (builder, fds) = match current_cpu {
+        Some(current_cpu) => {
+            let cpu_id: Arc<dyn CpuId> = Arc::new(CurrentCpu(current_cpu));
+            let fds = SharedFdTable::with_cpu_id(FdTable::default(), readers, cpu_id.clone());
+            (builder.cpu_id(cpu_id), fds)
+        }
+        None => (builder, SharedFdTable::new(FdTable::default(), readers)),
+    };
+    Box::into_raw(Box::new(Nrfs {
+        fs: builder.build(),
+        fds,
+    }))
+}
+
+/// Free a file-system from `nrfs_new()`, with its files and descriptors.
+///
+/// # Safety
+///
+/// `nrfs` must come from `nrfs_new()` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn nrfs_free(nrfs: *mut Nrfs) {
+    if !nrfs.is_null() {
+        drop(Box::from_raw(nrfs));
+    }
+}
+
+/// Create the file `path` and return its mnode.
+///
+/// # Safety
+///
+/// `nrfs` must come from `nrfs_new()` and `path` must be NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn nrfs_create(nrfs: *const Nrfs, path: *const u8, modes: u64) -> i64 {
+    let nrfs = &*nrfs;
+    let path = match CallerMemory.read_str(path as u64, MAX_PATH_LEN) {
+        Ok(path) => path,
+        Err(errno) => return -(errno as i64),
+    };
+    let result = core::str::from_utf8(&path)
+        .map_err(|_| EINVAL)
+        .and_then(|path| Ok(nrfs.fs.create(path, modes)?));
+    to_return(result)
+}
+
+/// Open `path` with the `FileFlags` `flags`, creating it with `modes` if
+/// asked to, and return the descriptor.
+///
+/// # Safety
+///
+/// `nrfs` must come from `nrfs_new()` and `path` must be NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn nrfs_open(
+    nrfs: *const Nrfs,
+    path: *const u8,
+    flags: u64,
+    modes: u64,
+) -> i64 {
+    (*nrfs).call(SyscallOp::Open, [path as u64, flags, modes, 0])
+}
+
+/// Read up to `len` bytes at the offset of `fd` into `buf`.
+///
+/// # Safety
+///
+/// `nrfs` must come from `nrfs_new()` and `buf` must be valid for `len`
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nrfs_read(nrfs: *const Nrfs, fd: u64, buf: *mut u8, len: usize) -> i64 {
+    (*nrfs).call(SyscallOp::Read, [fd, buf as u64, len as u64, 0])
+}
+
+/// Write `len` bytes of `buf` at the offset of `fd`.
+///
+/// # Safety
+///
+/// `nrfs` must come from `nrfs_new()` and `buf` must be valid for `len`
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nrfs_write(nrfs: *const Nrfs, fd: u64, buf: *const u8, len: usize) -> i64 {
+    (*nrfs).call(SyscallOp::Write, [fd, buf as u64, len as u64, 0])
+}
+
+/// Close `fd`.
+///
+/// # Safety
+///
+/// `nrfs` must come from `nrfs_new()`.
+#[no_mangle]
+pub unsafe extern "C" fn nrfs_close(nrfs: *const Nrfs, fd: u64) -> i64 {
+    (*nrfs).call(SyscallOp::Close, [fd, 0, 0, 0])
+}
+
+/// Get the metadata of `path` into `stat`.
+///
+/// # Safety
+///
+/// `nrfs` must come from `nrfs_new()`, `path` must be NUL-terminated and
+/// `stat` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn nrfs_stat(nrfs: *const Nrfs, path: *const u8, stat: *mut Metadata) -> i64 {
+    let nrfs = &*nrfs;
+    if stat.is_null() {
+        return -(EFAULT as i64);
+    }
+    let path = match CallerMemory.read_str(path as u64, MAX_PATH_LEN) {
+        Ok(path) => path,
+        Err(errno) => return -(errno as i64),
+    };
+    let result = core::str::from_utf8(&path)
+        .map_err(|_| EINVAL)
+        .and_then(|path| {
+            let mnode = nrfs.fs.lookup(path).ok_or(FileSystemError::InvalidFile)?;
+            *stat = nrfs.fs.metadata(*mnode)?;
+            Ok(0)
+        });
+    to_return(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::errno::{EBADF, ENOENT};
+    use crate::FileFlags;
+
+    extern "C" fn cpu() -> usize {
+        0
+    }
+
+    #[test]
+    /// A file created, written and read back through the C functions.
+    fn test_ffi() {
+        unsafe {
+            let nrfs = nrfs_new(Some(cpu));
+            assert_eq!(nrfs_create(nrfs, b"/a\0".as_ptr(), 7), 2);
+            let flags = u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT);
+            let fd = nrfs_open(nrfs, b"/b\0".as_ptr(), flags, 7);
+            assert_eq!(fd, 0);
+            assert_eq!(nrfs_write(nrfs, 0, b"nrfs".as_ptr(), 4), 4);
+            assert_eq!(nrfs_close(nrfs, 0), 0);
+            assert_eq!(nrfs_close(nrfs, 0), -(EBADF as i64));
+
+            let fd = nrfs_open(nrfs, b"/b\0".as_ptr(), 1, 0) as u64;
+            let mut buf = [0; 8];
+            assert_eq!(nrfs_read(nrfs, fd, buf.as_mut_ptr(), 8), 4);
+            assert_eq!(&buf[..4], b"nrfs");
+
+            let mut stat = Metadata::default();
+            assert_eq!(nrfs_stat(nrfs, b"/b\0".as_ptr(), &mut stat), 0);
+            assert_eq!(stat.fsize, 4);
+            assert_eq!(
+                nrfs_stat(nrfs, b"/c\0".as_ptr(), &mut stat),
+                -(ENOENT as i64)
+            );
+            let fd = nrfs_open(nrfs, b"/b\0".as_ptr(), 2, 0) as u64;
+            assert_eq!(nrfs_write(nrfs, fd, core::ptr::null(), 8), -(EFAULT as i64));
+            nrfs_free(nrfs);
+        }
+    }
+}
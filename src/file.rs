@@ -1,32 +1,300 @@
+use crate::builder::PageAllocator;
 use crate::io::*;
-use crate::{FileSystemError, Modes};
+use crate::pool::PagePool;
+use crate::tail::MAX_TAIL;
+use crate::topology::Node;
+use crate::{FileSystemError, Modes, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+use alloc::alloc::{AllocError, Allocator, Global, Layout};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::mem::size_of;
-use x86::bits64::paging::BASE_PAGE_SIZE;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-#[derive(Debug, Eq, PartialEq)]
-/// The buffer is used by the file. Each buffer is BASE_PAGE_SIZE
-/// long and a file consists of many such buffers.
+/// Allocates the buffers of a file: from the page allocator hook, on the
+/// NUMA node picked by the placement, or from the global allocator without a
+/// hook.
+#[derive(Clone)]
+pub struct Pages {
+    allocator: Option<Arc<dyn PageAllocator>>,
+    node: Option<Node>,
+    /// Every allocation is aligned to at least this many bytes.
+    align: usize,
+    /// Every allocation is charged to the pool, if there is one.
+    pool: Option<Arc<PagePool>>,
+}
+
+impl Default for Pages {
+    fn default() -> Pages {
+        Pages {
+            allocator: None,
+            node: None,
+            align: 1,
+            pool: None,
+        }
+    }
+}
+
+impl Pages {
+    /// Allocate with `allocator` on `node`, `None` if the node is unknown.
+    pub fn new(allocator: Arc<dyn PageAllocator>, node: Option<Node>) -> Pages {
+        Pages {
+            allocator: Some(allocator),
+            node,
+            align: 1,
+            pool: None,
+        }
+    }
+
+    /// Align every allocation to `align` bytes, a power of two.
+    pub fn aligned(mut self, align: usize) -> Pages {
+        self.align = align;
+        self
+    }
+
+    /// Charge every allocation to `pool`, failing the ones it refuses.
+    pub fn pooled(mut self, pool: Option<Arc<PagePool>>) -> Pages {
+        self.pool = pool;
+        self
+    }
+
+    /// The layout actually allocated for `layout`.
+    fn layout(&self, layout: Layout) -> Result<Layout, AllocError> {
+        layout.align_to(self.align).map_err(|_| AllocError)
+    }
+}
+
+unsafe impl Allocator for Pages {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let layout = self.layout(layout)?;
+        if let Some(pool) = &self.pool {
+            if !pool.charge(layout.size()) {
+                return Err(AllocError);
+            }
+        }
+        let allocated = match &self.allocator {
+            Some(allocator) => allocator.allocate(layout, self.node),
+            None => Global.allocate(layout),
+        };
+        if let (Err(_), Some(pool)) = (&allocated, &self.pool) {
+            pool.credit(layout.size());
+        }
+        allocated
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // The layout was valid when the memory was allocated.
+        let layout = self.layout(layout).unwrap();
+        match &self.allocator {
+            Some(allocator) => allocator.deallocate(ptr, layout),
+            None => Global.deallocate(ptr, layout),
+        }
+        if let Some(pool) = &self.pool {
+            pool.credit(layout.size());
+        }
+    }
+}
+
+/// Where the buffers a write adds to a file come from.
+#[derive(Clone, Default)]
+pub struct ChunkSource {
+    /// The base pages of small files.
+    pub pages: Pages,
+    /// The large pages of the files of at least the given size, if any.
+    pub large: Option<(Pages, usize)>,
+}
+
+#[derive(Debug)]
+/// The buffer is used by the file. Each buffer is a chunk of BASE_PAGE_SIZE
+/// or LARGE_PAGE_SIZE bytes and a file consists of many such buffers.
 struct Buffer {
-    data: Vec<u8>,
+    data: Vec<u8, Pages>,
+    /// The length of the data while it is evicted to the backing store; the
+    /// data is empty then.
+    evicted: Option<usize>,
+    /// Set when the buffer is accessed, cleared by the eviction clock.
+    referenced: AtomicBool,
+    /// Whether the backing store holds the data as it is in memory, so
+    /// evicting the buffer doesn't need to write it back.
+    stored: bool,
+    /// The data while it is shared with the buffers of the same contents,
+    /// see `File::dedup()`; `data` is empty then.
+    shared: Option<SharedPage>,
+    /// The data while it is packed with the tails of other files, see
+    /// `File::pack_tail()`; `data` is empty then.
+    tail: Option<Tail>,
+}
+
+/// A page whose data is shared by the buffers holding the same contents.
+pub(crate) type SharedPage = Arc<Vec<u8, Pages>>;
+
+/// The last buffer of a small file, packed in a page with the last buffers of other files.
+#[derive(Debug)]
+struct Tail {
+    page: SharedPage,
+    offset: usize,
+    len: usize,
 }
 
 impl Buffer {
-    /// This function tries to allocate a vector of BASE_PAGE_SIZE long
+    /// This function tries to allocate a vector of `chunk` bytes from `pages`
     /// and returns a buffer in case of the success; error otherwise.
-    pub fn try_alloc_buffer() -> Result<Buffer, FileSystemError> {
-        let mut data = Vec::new();
-        match data.try_reserve(BASE_PAGE_SIZE) {
-            Ok(_) => Ok(Buffer { data }),
+    pub fn try_alloc_buffer(chunk: usize, pages: &Pages) -> Result<Buffer, FileSystemError> {
+        let mut data = Vec::new_in(pages.clone());
+        match data.try_reserve(chunk) {
+            Ok(_) => Ok(Buffer {
+                data,
+                evicted: None,
+                referenced: AtomicBool::new(false),
+                stored: false,
+                shared: None,
+                tail: None,
+            }),
             Err(_) => Err(FileSystemError::OutOfMemory),
         }
     }
+
+    /// Bytes of the file held by the buffer, resident or not.
+    fn len(&self) -> usize {
+        self.evicted.unwrap_or(self.bytes().len())
+    }
+
+    /// The data of the buffer, shared or not.
+    fn bytes(&self) -> &[u8] {
+        match (&self.shared, &self.tail) {
+            (Some(page), _) => page,
+            (None, Some(tail)) => &tail.page[tail.offset..][..tail.len],
+            (None, None) => &self.data,
+        }
+    }
+
+    /// Give the buffer its own copy of its data before it changes, unless no other buffer
+    /// shares it anymore.
+    fn unshare(&mut self) -> Result<(), FileSystemError> {
+        if let Some(tail) = self.tail.take() {
+            let mut data = Vec::new_in(self.data.allocator().clone());
+            if data.try_reserve(tail.len).is_err() {
+                self.tail = Some(tail);
+                return Err(FileSystemError::OutOfMemory);
+            }
+            data.extend_from_slice(&tail.page[tail.offset..][..tail.len]);
+            self.data = data;
+            return Ok(());
+        }
+        let page = match self.shared.take() {
+            Some(page) => page,
+            None => return Ok(()),
+        };
+        let page = match Arc::try_unwrap(page) {
+            Ok(data) => {
+                self.data = data;
+                return Ok(());
+            }
+            Err(page) => page,
+        };
+        let mut data = Vec::new_in(page.allocator().clone());
+        if data.try_reserve(page.len()).is_err() {
+            self.shared = Some(page);
+            return Err(FileSystemError::OutOfMemory);
+        }
+        data.extend_from_slice(&page);
+        self.data = data;
+        Ok(())
+    }
+}
+
+// The referenced and stored bits are only hints for eviction, not part of the
+// contents.
+impl PartialEq for Buffer {
+    fn eq(&self, other: &Buffer) -> bool {
+        self.bytes() == other.bytes() && self.evicted == other.evicted
+    }
+}
+
+impl Eq for Buffer {}
+
+/// A past state of a file, see `File::keep_versions()`. Its chunks are shared with the
+/// file until the file writes them.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Version {
+    number: u64,
+    size: usize,
+    chunk: usize,
+    chunks: Vec<SharedPage>,
+}
+
+/// The versions a file keeps, oldest first, within their bounds.
+#[derive(Debug, Eq, PartialEq)]
+struct History {
+    versions: VecDeque<Version>,
+    /// The number of the next version.
+    next: u64,
+    max_count: usize,
+    max_bytes: usize,
+}
+
+/// A version listed by `File::versions()`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct VersionInfo {
+    /// Numbers grow with each version and aren't reused.
+    pub number: u64,
+    /// The size of the file in that version.
+    pub size: usize,
+}
+
+/// What `File::evict()` did with a buffer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Eviction {
+    /// The buffer was written back if needed and freed.
+    Evicted,
+    /// The buffer stays resident for now: it was used since the clock last
+    /// passed, it is pinned, or it belongs to a file in large pages.
+    Kept,
+    /// There is no such resident buffer anymore.
+    Gone,
+}
+
+/// Files of at most this many bytes keep their data in the mnode, without buffers.
+const INLINE: usize = 128;
+
+/// The data of a small file, kept in the file itself: most files of a typical namespace,
+/// configuration files, scripts and the like, are a few lines, and a buffer of a page for
+/// each of them would mostly hold nothing.
+#[derive(Debug, Eq, PartialEq)]
+struct Inline {
+    len: usize,
+    data: [u8; INLINE],
+}
+
+impl Inline {
+    /// The data of the file.
+    fn bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
 /// File type has a list of buffers and modes to access the file
 pub struct File {
     mcache: Vec<Buffer>,
+    /// The data of the file while it has no buffers; it moves to a buffer once the file grows
+    /// past INLINE bytes or its memory is handed out.
+    inline: Inline,
+    /// Size of the buffers: BASE_PAGE_SIZE, or LARGE_PAGE_SIZE once the file
+    /// moved to large pages.
+    chunk: usize,
+    /// Number of `pin()`s not undone yet.
+    pins: usize,
+    /// One bit per page, set while the page has changes that weren't flushed.
+    dirty: Vec<u64>,
+    /// The access pattern advised for the file: Normal, Sequential or Random.
+    access: Advice,
+    /// The CRC32C of every page, if checksums are enabled; the pages past its
+    /// end aren't verified.
+    sums: Option<Vec<u32>>,
+    /// The previous versions of the file, if it keeps them.
+    history: Option<History>,
     modes: FileModes,
     // TODO: Add more file related attributes
 }
@@ -42,6 +310,16 @@ impl File {
         }
         Ok(File {
             mcache: mcache,
+            inline: Inline {
+                len: 0,
+                data: [0; INLINE],
+            },
+            chunk: BASE_PAGE_SIZE,
+            pins: 0,
+            dirty: Vec::new(),
+            access: Advice::Normal,
+            sums: None,
+            history: None,
             modes,
         })
     }
@@ -52,16 +330,16 @@ impl File {
     pub fn get_size(&self) -> usize {
         let buffer_num = self.mcache.len();
         match buffer_num {
-            0 => 0,
-            1 => self.mcache[buffer_num - 1].data.len(),
+            0 => self.inline.len,
+            1 => self.mcache[buffer_num - 1].len(),
             _ => {
-                match self.mcache[buffer_num - 1].data.len() {
+                match self.mcache[buffer_num - 1].len() {
                     // If resize_file()/write() added some empty buffers to be filled
                     // later, then scan all the buffers to get the file-size.
                     0 => {
                         let mut len = 0;
                         for buf in &self.mcache {
-                            match buf.data.len() {
+                            match buf.len() {
                                 0 => break,
                                 curr_buff_len => len += curr_buff_len,
                             }
@@ -69,26 +347,130 @@ impl File {
                         len
                     }
                     // If file is filled till last buffer
-                    last_buffer_len => ((buffer_num - 1) * BASE_PAGE_SIZE + last_buffer_len),
+                    last_buffer_len => ((buffer_num - 1) * self.chunk + last_buffer_len),
                 }
             }
         }
     }
 
+    /// Bytes of heap memory held by the data of the file, including unused capacity.
+    pub fn data_size(&self) -> usize {
+        self.mcache
+            .iter()
+            .map(|buffer| match (&buffer.shared, &buffer.tail) {
+                // Shared pages count for their share, packed tails for their length.
+                (Some(page), _) => page.capacity() / Arc::strong_count(page),
+                (None, Some(tail)) => tail.len,
+                (None, None) => buffer.data.capacity(),
+            })
+            .sum()
+    }
+
+    /// Bytes of heap memory held by the file besides its data: the list of its buffers, its
+    /// dirty bits and its checksums.
+    pub fn bookkeeping_size(&self) -> usize {
+        self.mcache.capacity() * size_of::<Buffer>()
+            + self.dirty.capacity() * size_of::<u64>()
+            + self.sums.as_ref().map_or(0, |sums| sums.capacity()) * size_of::<u32>()
+    }
+
+    /// Bytes allocated for the data of the file that don't hold any, e.g. the room its last
+    /// buffer keeps for growth.
+    pub fn slack(&self) -> usize {
+        self.mcache
+            .iter()
+            .map(|buffer| buffer.data.capacity() - buffer.data.len())
+            .sum()
+    }
+
     /// This method returns the mode in which file is created.
     pub fn get_mode(&self) -> FileModes {
         self.modes
     }
 
+    /// Change the modes of the file.
+    pub fn set_mode(&mut self, modes: Modes) {
+        self.modes = FileModes::from(modes);
+    }
+
+    /// Whether the data of the file is kept in large pages.
+    pub fn is_large(&self) -> bool {
+        self.chunk == LARGE_PAGE_SIZE
+    }
+
+    /// Move the data of the file into chunks of LARGE_PAGE_SIZE allocated from
+    /// `pages`. On failure the file is left as it was.
+    fn use_large_pages(&mut self, curr_file_len: usize, pages: &Pages) -> bool {
+        let chunks = ceil(curr_file_len, LARGE_PAGE_SIZE);
+        let mut mcache = Vec::new();
+        if mcache.try_reserve(chunks.max(64)).is_err() {
+            return false;
+        }
+        for chunk in 0..chunks {
+            let start = chunk * LARGE_PAGE_SIZE;
+            let end = curr_file_len.min(start + LARGE_PAGE_SIZE);
+            let mut buffer = match Buffer::try_alloc_buffer(LARGE_PAGE_SIZE, pages) {
+                Ok(buffer) => buffer,
+                Err(_) => return false,
+            };
+            buffer.data.resize(end - start, 0);
+            if self.read_file(&mut buffer.data, start, end).is_err() {
+                return false;
+            }
+            mcache.push(buffer);
+        }
+        self.mcache = mcache;
+        self.chunk = LARGE_PAGE_SIZE;
+        true
+    }
+
     /// This method is internally used by write_file() method. The additional length
-    /// is initialzed to zero.
-    pub fn increase_file_size(&mut self, curr_file_len: usize, new_len: usize) -> bool {
+    /// is initialzed to zero, in new buffers allocated from `source`. A file that
+    /// grows to the threshold of the large pages first moves to them.
+    pub fn increase_file_size(
+        &mut self,
+        curr_file_len: usize,
+        new_len: usize,
+        source: &ChunkSource,
+    ) -> bool {
         if new_len == 0 {
             return true;
         }
+        let small = match &source.large {
+            Some((_, threshold)) => new_len < *threshold,
+            None => true,
+        };
+        if self.mcache.is_empty() && new_len <= INLINE && small {
+            self.inline.data[curr_file_len..new_len].fill(0);
+            self.inline.len = new_len;
+            return true;
+        }
+        if self.spill(&source.pages).is_err() {
+            return false;
+        }
+        // A packed tail gets its own buffer again to grow in.
+        if let Some(buffer) = self.mcache.last_mut() {
+            if buffer.tail.is_some() && buffer.unshare().is_err() {
+                return false;
+            }
+        }
+
+        let pages = match &source.large {
+            Some((large, threshold))
+                if self.is_large()
+                    || (new_len >= *threshold && !self.is_pinned() && !self.is_evicted()) =>
+            {
+                if !self.is_large() && !self.use_large_pages(curr_file_len, large) {
+                    return false;
+                }
+                large
+            }
+            _ => &source.pages,
+        };
+        let chunk = self.chunk;
 
         let free_in_last_buffer = match self.mcache.last() {
-            Some(buffer) => BASE_PAGE_SIZE - buffer.data.len(),
+            Some(buffer) => chunk - buffer.len(),
             None => 0,
         };
 
@@ -96,40 +478,49 @@ impl File {
         match add_new <= free_in_last_buffer {
             // Don't need to add new buffer
             true => {
-                let offset = self.mcache.last().unwrap().data.len();
-                self.mcache
-                    .last_mut()
-                    .unwrap()
-                    .data
-                    .resize(offset + add_new, 0);
+                let data = &mut self.mcache.last_mut().unwrap().data;
+                let offset = data.len();
+                // A compacted buffer gets room for a whole chunk again.
+                if data.try_reserve_exact(chunk - offset).is_err() {
+                    return false;
+                }
+                data.resize(offset + add_new, 0);
                 return true;
             }
 
             // Add new buffer
             false => {
-                if self.mcache.len() > 0 {
-                    self.mcache
-                        .last_mut()
-                        .unwrap()
-                        .data
-                        .resize(BASE_PAGE_SIZE, 0);
-                }
+                // Allocate all the memory before touching the file, so a failed
+                // allocation leaves the file as it was.
                 let remaining = add_new - free_in_last_buffer;
-                let new_buffers = ceil(remaining, BASE_PAGE_SIZE);
-                let mut vec = Vec::with_capacity(new_buffers);
+                let new_buffers = ceil(remaining, chunk);
+                let mut vec = Vec::new();
+                if vec.try_reserve(new_buffers).is_err()
+                    || self.mcache.try_reserve(new_buffers).is_err()
+                {
+                    return false;
+                }
                 for _i in 0..new_buffers {
-                    match Buffer::try_alloc_buffer() {
+                    match Buffer::try_alloc_buffer(chunk, pages) {
                         Ok(mut buffer) => {
-                            buffer.data.resize(BASE_PAGE_SIZE, 0);
+                            buffer.data.resize(chunk, 0);
                             vec.push(buffer);
                         }
                         Err(_) => return false,
                     }
                 }
 
+                if free_in_last_buffer > 0 {
+                    let data = &mut self.mcache.last_mut().unwrap().data;
+                    if data.try_reserve_exact(chunk - data.len()).is_err() {
+                        return false;
+                    }
+                    data.resize(chunk, 0);
+                }
+
                 // Filled all the buffers with zeros, resize the last buffer.
-                if new_len % BASE_PAGE_SIZE != 0 {
-                    let sure_bytes_to_write = (new_buffers - 1) * BASE_PAGE_SIZE;
+                if new_len % chunk != 0 {
+                    let sure_bytes_to_write = (new_buffers - 1) * chunk;
                     let bytes_in_last_buffer = new_len - (self.get_size() + sure_bytes_to_write);
                     vec.last_mut().unwrap().data.resize(bytes_in_last_buffer, 0);
                 }
@@ -139,6 +530,498 @@ impl File {
         }
     }
 
+    /// Move the inline data of the file into a buffer allocated from `pages`, e.g. before
+    /// its memory is handed out. Does nothing if the file has buffers or no data.
+    pub fn spill(&mut self, pages: &Pages) -> Result<(), FileSystemError> {
+        if !self.mcache.is_empty() || self.inline.len == 0 {
+            return Ok(());
+        }
+        self.mcache
+            .try_reserve(1)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        let mut buffer = Buffer::try_alloc_buffer(self.chunk, pages)?;
+        buffer.data.extend_from_slice(self.inline.bytes());
+        self.mcache.push(buffer);
+        self.inline.len = 0;
+        Ok(())
+    }
+
+    /// The memory backing the bytes from start_offset till end_offset(not inclusive), as one
+    /// (pointer, length) pair per buffer. Every buffer is a single allocation from the page
+    /// allocator, so it is physically contiguous if the allocator hands out contiguous memory.
+    pub fn ranges(
+        &mut self,
+        start_offset: usize,
+        end_offset: usize,
+    ) -> Result<Vec<(NonNull<u8>, usize)>, FileSystemError> {
+        // The memory may be written through the ranges.
+        self.unshare(start_offset, end_offset)?;
+        let mut ranges = Vec::new();
+        let first = offset_to_buffernum(start_offset, self.chunk);
+        ranges
+            .try_reserve(ceil(end_offset, self.chunk).saturating_sub(first))
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+
+        let mut offset = start_offset;
+        while offset < end_offset {
+            let buffer_num = offset_to_buffernum(offset, self.chunk);
+            let offset_in_buffer = offset - buffer_num * self.chunk;
+            let data = &mut self.mcache[buffer_num].data;
+            let len = (data.len() - offset_in_buffer).min(end_offset - offset);
+            let ptr = NonNull::from(&mut data[offset_in_buffer]);
+            ranges.push((ptr, len));
+            offset += len;
+        }
+        Ok(ranges)
+    }
+
+    /// The access pattern advised for the file.
+    pub fn access(&self) -> Advice {
+        self.access
+    }
+
+    /// Take the advice for the bytes from start_offset till end_offset(not inclusive) into
+    /// account. Like on Linux, the access patterns apply to the whole file. WillNeed and
+    /// DontNeed only concern pages that can be brought back from elsewhere; all the data of a
+    /// file without a backing store has to stay in memory.
+    pub fn advise(&mut self, _start_offset: usize, _end_offset: usize, advice: Advice) {
+        match advice {
+            Advice::Normal | Advice::Sequential | Advice::Random => self.access = advice,
+            Advice::WillNeed | Advice::DontNeed => {}
+        }
+    }
+
+    /// Bring the bytes from start_offset till end_offset(not inclusive) into the CPU caches
+    /// ahead of a read. Returns the number of bytes within the file.
+    pub fn prefetch(&self, start_offset: usize, end_offset: usize) -> usize {
+        let end_offset = end_offset.min(self.get_size());
+        let mut offset = start_offset;
+        while offset < end_offset {
+            let buffer_num = offset_to_buffernum(offset, self.chunk);
+            let buffer = &self.mcache[buffer_num];
+            let start = offset - buffer_num * self.chunk;
+            let end = buffer.len().min(start + end_offset - offset);
+            if buffer.evicted.is_none() {
+                for line in buffer.bytes()[start..end].chunks(CACHE_LINE_SIZE) {
+                    prefetch(line.as_ptr());
+                }
+            }
+            offset += end - start;
+        }
+        end_offset.saturating_sub(start_offset)
+    }
+
+    /// Number of buffers of the file, resident or not.
+    pub fn buffers(&self) -> usize {
+        self.mcache.len()
+    }
+
+    /// Whether some buffers of the file are evicted to the backing store.
+    pub fn is_evicted(&self) -> bool {
+        self.mcache.iter().any(|buffer| buffer.evicted.is_some())
+    }
+
+    /// Whether the bytes from start_offset till end_offset(not inclusive) are all in memory.
+    pub fn is_resident(&self, start_offset: usize, end_offset: usize) -> bool {
+        let first = offset_to_buffernum(start_offset, self.chunk);
+        let last = ceil(end_offset, self.chunk).min(self.mcache.len());
+        match self.mcache.get(first..last) {
+            Some(buffers) => buffers.iter().all(|buffer| buffer.evicted.is_none()),
+            None => true,
+        }
+    }
+
+    /// Bring the evicted buffers holding the bytes from start_offset till end_offset(not
+    /// inclusive) back into memory: `read` fills each of them given its offset in the file.
+    /// Returns the numbers of the buffers brought back.
+    pub fn page_in<F>(
+        &mut self,
+        start_offset: usize,
+        end_offset: usize,
+        mut read: F,
+    ) -> Result<Vec<usize>, FileSystemError>
+    where
+        F: FnMut(usize, &mut [u8]) -> Result<(), FileSystemError>,
+    {
+        let first = offset_to_buffernum(start_offset, self.chunk);
+        let last = ceil(end_offset, self.chunk).min(self.mcache.len());
+        let mut loaded = Vec::new();
+        for buffer_num in first..last {
+            let sum = match &self.sums {
+                Some(sums) => sums.get(buffer_num * self.chunk / BASE_PAGE_SIZE).copied(),
+                None => None,
+            };
+            let buffer = &mut self.mcache[buffer_num];
+            let len = match buffer.evicted {
+                Some(len) => len,
+                None => continue,
+            };
+            loaded
+                .try_reserve(1)
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            let mut data = Vec::new_in(buffer.data.allocator().clone());
+            data.try_reserve(self.chunk)
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            data.resize(len, 0);
+            read(buffer_num * self.chunk, &mut data)?;
+            // The page stays evicted if the store returned something else.
+            match sum {
+                Some(sum) if sum != crc32c(&data) => return Err(FileSystemError::DataCorruption),
+                _ => {}
+            }
+            buffer.data = data;
+            buffer.evicted = None;
+            buffer.stored = true;
+            loaded.push(buffer_num);
+        }
+        Ok(loaded)
+    }
+
+    /// Free the buffer `buffer_num` unless it was used since the last call for it, handing it
+    /// to `write` first unless the backing store already holds its data. Only files in base pages are
+    /// evicted, as the dirty bits and the backing store work with pages. Files keeping versions
+    /// stay resident, as their versions share the buffers.
+    pub fn evict<F>(&mut self, buffer_num: usize, write: F) -> Result<Eviction, FileSystemError>
+    where
+        F: FnOnce(usize, &[u8]) -> Result<(), FileSystemError>,
+    {
+        let dirty = self.is_dirty(buffer_num);
+        let (pinned, large) = (self.is_pinned(), self.is_large());
+        let versioned = self.history.is_some();
+        let buffer = match self.mcache.get_mut(buffer_num) {
+            Some(buffer) if buffer.evicted.is_none() => buffer,
+            _ => return Ok(Eviction::Gone),
+        };
+        if pinned || large || versioned || buffer.referenced.swap(false, Ordering::Relaxed) {
+            return Ok(Eviction::Kept);
+        }
+        if !buffer.stored {
+            write(buffer_num * BASE_PAGE_SIZE, buffer.bytes())?;
+        }
+        let pages = buffer.data.allocator().clone();
+        buffer.evicted = Some(buffer.len());
+        buffer.data = Vec::new_in(pages);
+        buffer.shared = None;
+        buffer.tail = None;
+        if dirty {
+            self.dirty[buffer_num / 64] &= !(1 << (buffer_num % 64));
+        }
+        Ok(Eviction::Evicted)
+    }
+
+    /// Give the buffers holding the bytes from start_offset till end_offset(not inclusive)
+    /// their own copy of the data they share with other buffers.
+    fn unshare(&mut self, start_offset: usize, end_offset: usize) -> Result<(), FileSystemError> {
+        let first = offset_to_buffernum(start_offset, self.chunk);
+        let last = ceil(end_offset, self.chunk).min(self.mcache.len());
+        for buffer in self.mcache.iter_mut().take(last).skip(first) {
+            buffer.unshare()?;
+        }
+        Ok(())
+    }
+
+    /// Hand the data of every full page of the file to `share`, and keep the page it returns
+    /// instead: one with the same contents. Only the resident pages of files in base pages
+    /// that aren't pinned are shared, as the others may be written without `write_file()`.
+    /// Returns the number of pages that share their data with another one now.
+    pub fn dedup<F>(&mut self, mut share: F) -> usize
+    where
+        F: FnMut(SharedPage) -> SharedPage,
+    {
+        if self.is_pinned() || self.is_large() {
+            return 0;
+        }
+        let mut shared = 0;
+        for buffer in self.mcache.iter_mut() {
+            if buffer.evicted.is_some()
+                || buffer.shared.is_some()
+                || buffer.data.len() != BASE_PAGE_SIZE
+            {
+                continue;
+            }
+            let mut page = match Arc::try_new_uninit() {
+                Ok(page) => page,
+                Err(_) => break,
+            };
+            let pages = buffer.data.allocator().clone();
+            let data = core::mem::replace(&mut buffer.data, Vec::new_in(pages));
+            Arc::get_mut(&mut page).unwrap().write(data);
+            let page = unsafe { page.assume_init() };
+            let page = share(page);
+            if Arc::strong_count(&page) > 1 {
+                shared += 1;
+            }
+            buffer.shared = Some(page);
+        }
+        shared
+    }
+
+    /// The data of the last buffer of the file, if it can be packed with the tails of other
+    /// files: a resident buffer of at most MAX_TAIL bytes that isn't shared or packed yet, of a
+    /// file in base pages that isn't pinned.
+    pub fn tail(&self) -> Option<&[u8]> {
+        if self.is_pinned() || self.is_large() {
+            return None;
+        }
+        let buffer = self.mcache.last()?;
+        match buffer.evicted.is_none()
+            && buffer.shared.is_none()
+            && buffer.tail.is_none()
+            && !buffer.data.is_empty()
+            && buffer.data.len() <= MAX_TAIL
+        {
+            true => Some(&buffer.data),
+            false => None,
+        }
+    }
+
+    /// Keep the data of the last buffer of the file in the `len` bytes of `page` from
+    /// `offset` instead, and free the buffer, if `tail()` still holds the same bytes. The
+    /// tail gets a buffer of its own again when it is written. Returns whether it is packed.
+    pub fn pack_tail(&mut self, page: &SharedPage, offset: usize, len: usize) -> bool {
+        if self.tail() != Some(&page[offset..][..len]) {
+            return false;
+        }
+        let buffer = self.mcache.last_mut().unwrap();
+        let pages = buffer.data.allocator().clone();
+        buffer.data = Vec::new_in(pages);
+        buffer.tail = Some(Tail {
+            page: Arc::clone(page),
+            offset,
+            len,
+        });
+        true
+    }
+
+    /// Free the memory the file holds beyond its data: the room left in its last buffer for
+    /// growth and the spare capacity of its bookkeeping. The buffers of pinned files and of
+    /// files in large pages stay as they are. Returns the number of bytes freed.
+    pub fn compact(&mut self) -> usize {
+        let mut freed = shrink(&mut self.mcache) + shrink(&mut self.dirty);
+        if let Some(sums) = self.sums.as_mut() {
+            freed += shrink(sums);
+        }
+        if !self.is_pinned() && !self.is_large() {
+            for buffer in self.mcache.iter_mut() {
+                freed += shrink(&mut buffer.data);
+            }
+        }
+        freed
+    }
+
+    /// Whether some pages of the file are pinned; their buffers must not be freed or moved.
+    pub fn is_pinned(&self) -> bool {
+        self.pins > 0
+    }
+
+    /// Pin the pages backing the bytes from start_offset, a multiple of BASE_PAGE_SIZE, till
+    /// end_offset(not inclusive), and return them. The last page is zero-filled past the end
+    /// of the data. Fails with NotSupported if the buffers aren't page-aligned.
+    pub fn pin(
+        &mut self,
+        start_offset: usize,
+        end_offset: usize,
+    ) -> Result<Vec<NonNull<u8>>, FileSystemError> {
+        self.unshare(start_offset, end_offset)?;
+        let mut pages = Vec::new();
+        pages
+            .try_reserve(ceil(end_offset - start_offset, BASE_PAGE_SIZE))
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        // The last page is mapped whole, so a compacted buffer needs its room back.
+        let last = offset_to_buffernum(end_offset - 1, self.chunk);
+        let data = &mut self.mcache[last].data;
+        data.try_reserve_exact(self.chunk - data.len())
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+
+        let mut offset = start_offset;
+        while offset < end_offset {
+            let buffer_num = offset_to_buffernum(offset, self.chunk);
+            let data = &mut self.mcache[buffer_num].data;
+            let page = data
+                .as_mut_ptr()
+                .wrapping_add(offset - buffer_num * self.chunk);
+            if page as usize & (BASE_PAGE_SIZE - 1) != 0 {
+                return Err(FileSystemError::NotSupported);
+            }
+            pages.push(NonNull::new(page).unwrap());
+            offset += BASE_PAGE_SIZE;
+        }
+
+        for byte in self.mcache[last].data.spare_capacity_mut() {
+            byte.write(0);
+        }
+        self.pins += 1;
+        Ok(pages)
+    }
+
+    /// Undo one pin().
+    pub fn unpin(&mut self) {
+        self.pins -= 1;
+        // The pages may have been changed through the pins.
+        if self.pins == 0 {
+            self.update_checksums(0, usize::MAX);
+        }
+    }
+
+    /// Keep the checksums of the pages from now on.
+    pub fn enable_checksums(&mut self) {
+        if self.sums.is_none() {
+            self.sums = Some(Vec::new());
+            self.update_checksums(0, usize::MAX);
+        }
+    }
+
+    /// The data of the page `page`, unless its buffer is evicted.
+    fn page(&self, page: usize) -> Option<&[u8]> {
+        if self.mcache.is_empty() {
+            return match page {
+                0 => Some(self.inline.bytes()),
+                _ => None,
+            };
+        }
+        let offset = page * BASE_PAGE_SIZE;
+        let buffer_num = offset_to_buffernum(offset, self.chunk);
+        let buffer = self.mcache.get(buffer_num)?;
+        if buffer.evicted.is_some() {
+            return None;
+        }
+        let start = offset - buffer_num * self.chunk;
+        let data = buffer.bytes();
+        data.get(start..data.len().min(start + BASE_PAGE_SIZE))
+    }
+
+    /// Compute the checksums of the pages holding the bytes from start_offset till
+    /// end_offset(not inclusive) again, if checksums are enabled. Pages that can't get a
+    /// checksum for lack of memory aren't verified.
+    pub fn update_checksums(&mut self, start_offset: usize, end_offset: usize) {
+        let mut sums = match self.sums.take() {
+            Some(sums) => sums,
+            None => return,
+        };
+        // Pages added to the file since the last update get their checksum too.
+        let first = (start_offset / BASE_PAGE_SIZE).min(sums.len());
+        let mut last = ceil(end_offset.min(self.get_size()), BASE_PAGE_SIZE);
+        if last > sums.len() && sums.try_reserve(last - sums.len()).is_err() {
+            last = sums.len();
+        }
+        for page in first..last {
+            match (self.page(page), sums.get_mut(page)) {
+                (Some(data), Some(sum)) => *sum = crc32c(data),
+                (Some(data), None) => sums.push(crc32c(data)),
+                // Evicted pages keep their checksum.
+                (None, Some(_)) => {}
+                (None, None) => break,
+            }
+        }
+        self.sums = Some(sums);
+    }
+
+    /// Check the pages holding the bytes from start_offset till end_offset(not inclusive)
+    /// against their checksums. Pinned pages aren't checked, as they can change anytime.
+    pub fn verify(&self, start_offset: usize, end_offset: usize) -> Result<(), FileSystemError> {
+        let sums = match &self.sums {
+            Some(sums) if !self.is_pinned() => sums,
+            _ => return Ok(()),
+        };
+        let first = start_offset / BASE_PAGE_SIZE;
+        let last = ceil(end_offset, BASE_PAGE_SIZE);
+        for (page, sum) in sums.iter().enumerate().take(last).skip(first) {
+            match self.page(page) {
+                Some(data) if crc32c(data) != *sum => return Err(FileSystemError::DataCorruption),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark the pages holding the bytes from start_offset till end_offset(not inclusive) as
+    /// changed. Returns false if the dirty bits couldn't be allocated.
+    pub fn mark_dirty(&mut self, start_offset: usize, end_offset: usize) -> bool {
+        if start_offset >= end_offset {
+            return true;
+        }
+        let pages = ceil(end_offset, BASE_PAGE_SIZE);
+        let words = ceil(pages, 64);
+        if words > self.dirty.len() {
+            if self.dirty.try_reserve(words - self.dirty.len()).is_err() {
+                return false;
+            }
+            self.dirty.resize(words, 0);
+        }
+        for page in start_offset / BASE_PAGE_SIZE..pages {
+            self.dirty[page / 64] |= 1 << (page % 64);
+        }
+        let first = offset_to_buffernum(start_offset, self.chunk);
+        let last = ceil(end_offset, self.chunk).min(self.mcache.len());
+        if let Some(buffers) = self.mcache.get_mut(first..last) {
+            buffers.iter_mut().for_each(|buffer| buffer.stored = false);
+        }
+        true
+    }
+
+    /// Whether the page `page` has changes that weren't flushed.
+    fn is_dirty(&self, page: usize) -> bool {
+        match self.dirty.get(page / 64) {
+            Some(word) => word & (1 << (page % 64)) != 0,
+            None => false,
+        }
+    }
+
+    /// The offsets of the changed pages among the ones holding the bytes from start_offset
+    /// till end_offset(not inclusive).
+    pub fn dirty_pages(
+        &self,
+        start_offset: usize,
+        end_offset: usize,
+    ) -> impl Iterator<Item = usize> + '_ {
+        let last = ceil(end_offset, BASE_PAGE_SIZE).min(self.dirty.len() * 64);
+        (start_offset / BASE_PAGE_SIZE..last)
+            .filter(move |page| self.is_dirty(*page))
+            .map(|page| page * BASE_PAGE_SIZE)
+    }
+
+    /// Hand the changed pages among the ones holding the bytes from start_offset till
+    /// end_offset(not inclusive) to `flush`, with their offset and their data within the
+    /// file, and mark them clean once it succeeds. Set `stored` if `flush` writes to the
+    /// backing store, so the pages aren't written again when they are evicted. Stops at the
+    /// first error. Returns the number of pages flushed.
+    pub fn flush<F>(
+        &mut self,
+        start_offset: usize,
+        end_offset: usize,
+        stored: bool,
+        mut flush: F,
+    ) -> Result<usize, FileSystemError>
+    where
+        F: FnMut(usize, &[u8]) -> Result<(), FileSystemError>,
+    {
+        let end_offset = end_offset.min(self.get_size());
+        let last = ceil(end_offset, BASE_PAGE_SIZE).min(self.dirty.len() * 64);
+        let mut flushed = 0;
+        for page in start_offset / BASE_PAGE_SIZE..last {
+            if !self.is_dirty(page) {
+                continue;
+            }
+            let offset = page * BASE_PAGE_SIZE;
+            let buffer_num = offset_to_buffernum(offset, self.chunk);
+            let data = match self.mcache.get(buffer_num) {
+                Some(buffer) => {
+                    let start = offset - buffer_num * self.chunk;
+                    let data = buffer.bytes();
+                    &data[start..data.len().min(start + BASE_PAGE_SIZE)]
+                }
+                None => self.inline.bytes(),
+            };
+            flush(offset, data)?;
+            if let Some(buffer) = self.mcache.get_mut(buffer_num) {
+                buffer.stored = stored;
+            }
+            self.dirty[page / 64] &= !(1 << (page % 64));
+            flushed += 1;
+        }
+        Ok(flushed)
+    }
+
     /// This method is internally call on a read() system-call. It reads the content of the
     /// file and copies it in a user provided slice. The data is read from start_offset till
     /// end_offset(not inclusive).
@@ -148,15 +1031,21 @@ impl File {
         start_offset: usize,
         end_offset: usize,
     ) -> Result<usize, FileSystemError> {
-        let mut buffer_num = offset_to_buffernum(start_offset, BASE_PAGE_SIZE);
-        let mut offset_in_buffer = start_offset - (buffer_num * BASE_PAGE_SIZE);
+        self.verify(start_offset, end_offset)?;
+        if self.mcache.is_empty() {
+            let data = &self.inline.bytes()[start_offset..end_offset];
+            user_slice[..data.len()].copy_from_slice(data);
+            return Ok(data.len());
+        }
+        let mut buffer_num = offset_to_buffernum(start_offset, self.chunk);
+        let mut offset_in_buffer = start_offset - (buffer_num * self.chunk);
         let mut copied = 0;
         let mut dst_start = 0;
         let mut dst_end;
 
         let len = end_offset - start_offset;
         while copied < len {
-            let useful_data_curr_buffer = self.mcache[buffer_num].data.len() - offset_in_buffer;
+            let useful_data_curr_buffer = self.mcache[buffer_num].len() - offset_in_buffer;
             let remaining = len - copied;
 
             let src_start = offset_in_buffer;
@@ -170,8 +1059,9 @@ impl File {
                 src_end = src_start + remaining;
                 copied += remaining;
             }
-            user_slice[dst_start..dst_end]
-                .copy_from_slice(&self.mcache[buffer_num].data[src_start..src_end]);
+            let buffer = &self.mcache[buffer_num];
+            buffer.referenced.store(true, Ordering::Relaxed);
+            user_slice[dst_start..dst_end].copy_from_slice(&buffer.bytes()[src_start..src_end]);
             buffer_num += 1;
             dst_start = dst_end;
             offset_in_buffer = 0;
@@ -183,31 +1073,47 @@ impl File {
     /// This method is internally called on a write() system-call. The user provided the
     /// data in a user-slice and the method copies that data into the file buffers. Beside
     /// the slice the user also provides the length of the data and it can also specify an
-    /// arbitrary offset in the file to write the data.
+    /// arbitrary offset in the file to write the data. Buffers added to the file are
+    /// allocated from `source`.
     pub fn write_file(
         &mut self,
         user_slice: &[u8],
         len: usize,
         start_offset: usize,
+        source: &ChunkSource,
     ) -> Result<usize, FileSystemError> {
         // If offset is specified, then resize the file to the offset + len.
         // If offset is more than file size then fill the file with zeros till the offset.
         let curr_file_len = self.get_size();
-        let new_len = start_offset + len;
+        let new_len = match start_offset.checked_add(len) {
+            Some(new_len) => new_len,
+            None => return Err(FileSystemError::NoSpace),
+        };
+        let version = self.snapshot()?;
+        self.unshare(start_offset, new_len)?;
+        if !self.mark_dirty(start_offset, new_len) {
+            return Err(FileSystemError::OutOfMemory);
+        }
         if new_len > curr_file_len {
-            if new_len > 0 && !self.increase_file_size(curr_file_len, new_len) {
+            if new_len > 0 && !self.increase_file_size(curr_file_len, new_len, source) {
                 return Err(FileSystemError::OutOfMemory);
             }
         }
+        if self.mcache.is_empty() {
+            self.inline.data[start_offset..new_len].copy_from_slice(&user_slice[..len]);
+            self.update_checksums(start_offset.min(curr_file_len), new_len);
+            self.keep(version);
+            return Ok(len);
+        }
 
-        let mut buffer_num = offset_to_buffernum(start_offset, BASE_PAGE_SIZE);
-        let mut offset_in_buffer = start_offset - (buffer_num * BASE_PAGE_SIZE);
+        let mut buffer_num = offset_to_buffernum(start_offset, self.chunk);
+        let mut offset_in_buffer = start_offset - (buffer_num * self.chunk);
         let mut copied = 0;
         let mut dst_start = 0;
         let mut dst_end;
 
         while copied < len {
-            let useful_data_curr_buffer = BASE_PAGE_SIZE - offset_in_buffer;
+            let useful_data_curr_buffer = self.chunk - offset_in_buffer;
             let remaining = len - copied;
 
             let src_start = offset_in_buffer;
@@ -222,22 +1128,243 @@ impl File {
                 copied += remaining;
             }
 
-            self.mcache[buffer_num].data[src_start..src_end]
-                .copy_from_slice(&user_slice[dst_start..dst_end]);
+            let buffer = &mut self.mcache[buffer_num];
+            *buffer.referenced.get_mut() = true;
+            buffer.data[src_start..src_end].copy_from_slice(&user_slice[dst_start..dst_end]);
             buffer_num += 1;
             dst_start = dst_end;
             offset_in_buffer = 0;
         }
 
+        self.update_checksums(start_offset.min(curr_file_len), new_len);
+        self.keep(version);
         Ok(len)
     }
 
-    /// Truncate the file in reasponse of O_TRUNC flag.
-    pub fn file_truncate(&mut self) {
+    /// Truncate the file in reasponse of O_TRUNC flag. Fails only if the file keeps versions
+    /// and there is no memory for the one it replaces.
+    pub fn file_truncate(&mut self) -> Result<(), FileSystemError> {
+        let version = self.snapshot()?;
+        self.keep(version);
         self.mcache.clear();
+        self.inline.len = 0;
+        self.dirty.clear();
+        if let Some(sums) = self.sums.as_mut() {
+            sums.clear();
+        }
+        self.chunk = BASE_PAGE_SIZE;
+        Ok(())
+    }
+
+    /// Keep the previous version of the file on every write from now on, dropping the oldest
+    /// ones past `max_count` versions or `max_bytes` bytes in all. A version costs little:
+    /// it shares the pages of the file until they are written, except for pinned files and
+    /// files in large pages, which are copied. All the buffers of the file must be resident.
+    pub fn keep_versions(&mut self, max_count: usize, max_bytes: usize) {
+        let history = self.history.get_or_insert_with(|| History {
+            versions: VecDeque::new(),
+            next: 0,
+            max_count,
+            max_bytes,
+        });
+        history.max_count = max_count;
+        history.max_bytes = max_bytes;
+        history.prune();
+    }
+
+    /// Stop keeping versions and drop the ones kept.
+    pub fn drop_versions(&mut self) {
+        self.history = None;
+    }
+
+    /// The versions the file keeps, oldest first.
+    pub fn versions(&self) -> Result<Vec<VersionInfo>, FileSystemError> {
+        let versions = match &self.history {
+            Some(history) => &history.versions,
+            None => return Ok(Vec::new()),
+        };
+        let mut infos = Vec::new();
+        infos
+            .try_reserve(versions.len())
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        infos.extend(versions.iter().map(|version| VersionInfo {
+            number: version.number,
+            size: version.size,
+        }));
+        Ok(infos)
+    }
+
+    /// Read the version `number` of the file from `offset` into `user_slice`, like
+    /// `read_file()`. Fails with InvalidFile if the version isn't kept.
+    pub fn read_version(
+        &self,
+        number: u64,
+        user_slice: &mut [u8],
+        offset: usize,
+    ) -> Result<usize, FileSystemError> {
+        let version = self
+            .history
+            .as_ref()
+            .and_then(|history| history.versions.iter().find(|v| v.number == number))
+            .ok_or(FileSystemError::InvalidFile)?;
+        if offset > version.size {
+            return Err(FileSystemError::InvalidOffset);
+        }
+        let len = user_slice.len().min(version.size - offset);
+        let mut copied = 0;
+        while copied < len {
+            let at = offset + copied;
+            let chunk = &version.chunks[at / version.chunk];
+            let from = at % version.chunk;
+            let n = (chunk.len() - from).min(len - copied);
+            user_slice[copied..copied + n].copy_from_slice(&chunk[from..from + n]);
+            copied += n;
+        }
+        Ok(copied)
+    }
+
+    /// The current contents of the file as a version, if it keeps versions. The full buffers
+    /// of files in base pages that aren't pinned move to shared pages; the others are copied,
+    /// as they may be changed without `write_file()` or grow in place.
+    fn snapshot(&mut self) -> Result<Option<Version>, FileSystemError> {
+        if self.history.is_none() {
+            return Ok(None);
+        }
+        let (pinned, large, chunk) = (self.is_pinned(), self.is_large(), self.chunk);
+        let mut chunks = Vec::new();
+        chunks
+            .try_reserve(self.mcache.len().max(1))
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        if self.mcache.is_empty() && self.inline.len > 0 {
+            // Inline data is copied into a page of its own.
+            let mut data = Vec::new_in(Pages::default());
+            data.try_reserve_exact(self.inline.len)
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            data.extend_from_slice(self.inline.bytes());
+            chunks.push(Arc::try_new(data).map_err(|_| FileSystemError::OutOfMemory)?);
+        }
+        for buffer in self.mcache.iter_mut() {
+            if buffer.evicted.is_some() {
+                return Err(FileSystemError::NotSupported);
+            }
+            if let Some(page) = &buffer.shared {
+                chunks.push(Arc::clone(page));
+                continue;
+            }
+            let copy = pinned || large || buffer.data.len() != chunk;
+            let pages = buffer.data.allocator().clone();
+            let mut page = Arc::try_new_uninit().map_err(|_| FileSystemError::OutOfMemory)?;
+            let data = match copy {
+                true => {
+                    let mut data = Vec::new_in(pages);
+                    data.try_reserve_exact(buffer.bytes().len())
+                        .map_err(|_| FileSystemError::OutOfMemory)?;
+                    data.extend_from_slice(buffer.bytes());
+                    data
+                }
+                false => core::mem::replace(&mut buffer.data, Vec::new_in(pages)),
+            };
+            Arc::get_mut(&mut page).unwrap().write(data);
+            let page = unsafe { page.assume_init() };
+            if !copy {
+                buffer.shared = Some(Arc::clone(&page));
+            }
+            chunks.push(page);
+        }
+        let history = self.history.as_mut().unwrap();
+        history.next += 1;
+        Ok(Some(Version {
+            number: history.next - 1,
+            size: self.get_size(),
+            chunk: self.chunk,
+            chunks,
+        }))
+    }
+
+    /// Add `version` to the history of the file, if there is one.
+    fn keep(&mut self, version: Option<Version>) {
+        if let (Some(history), Some(version)) = (self.history.as_mut(), version) {
+            if history.versions.try_reserve(1).is_ok() {
+                history.versions.push_back(version);
+                history.prune();
+            }
+        }
+    }
+}
+
+impl History {
+    /// Drop the oldest versions until the rest are within the bounds.
+    fn prune(&mut self) {
+        let mut bytes: usize = self.versions.iter().map(|version| version.size).sum();
+        while self.versions.len() > self.max_count || bytes > self.max_bytes {
+            match self.versions.pop_front() {
+                Some(version) => bytes -= version.size,
+                None => break,
+            }
+        }
     }
 }
 
+/// Move the elements of `vec` into an allocation of their size, unless there is no memory
+/// for it. Returns the number of bytes freed.
+fn shrink<T, A: Allocator + Clone>(vec: &mut Vec<T, A>) -> usize {
+    if vec.capacity() == vec.len() {
+        return 0;
+    }
+    let mut exact = Vec::new_in(vec.allocator().clone());
+    if exact.try_reserve_exact(vec.len()).is_err() || exact.capacity() >= vec.capacity() {
+        return 0;
+    }
+    let freed = (vec.capacity() - exact.capacity()) * size_of::<T>();
+    exact.append(vec);
+    *vec = exact;
+    freed
+}
+
+/// Bytes a prefetch brings into the CPU caches.
+const CACHE_LINE_SIZE: usize = 64;
+
+/// Ask the CPU to load the cache line holding `ptr`, without waiting for it.
+#[inline]
+fn prefetch(ptr: *const u8) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use core::arch::x86_64::{_mm_prefetch, _MM_HINT_T1};
+        _mm_prefetch::<_MM_HINT_T1>(ptr as *const i8);
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = ptr;
+}
+
+/// The CRC32C (Castagnoli) lookup table, one entry per byte value.
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0x82f6_3b78,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+}
+
+/// The CRC32C of `data`.
+fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
 /// This is used to determine, how many buffers to add dependeing on the number
 /// of bytes and buffer-size.
 fn ceil(bytes: usize, buffer_size: usize) -> usize {
@@ -287,7 +1414,7 @@ pub mod test {
     #[test]
     /// This method test the size of the allocated buffer.
     fn test_buffer_alloc() {
-        let buffer = Buffer::try_alloc_buffer().unwrap();
+        let buffer = Buffer::try_alloc_buffer(BASE_PAGE_SIZE, &Pages::default()).unwrap();
         assert_eq!(buffer.data.len(), 0);
         assert_eq!(buffer.data.capacity(), BASE_PAGE_SIZE);
     }
@@ -313,13 +1440,37 @@ pub mod test {
         assert_eq!(file.get_size(), 0);
 
         for i in 0..10000 {
-            assert_eq!(file.increase_file_size(file.get_size(), i), true);
+            assert_eq!(
+                file.increase_file_size(file.get_size(), i, &ChunkSource::default()),
+                true
+            );
             assert_eq!(file.get_size(), i);
-            let buffer_num = ceil(i, BASE_PAGE_SIZE);
+            let buffer_num = match i <= INLINE {
+                true => 0,
+                false => ceil(i, BASE_PAGE_SIZE),
+            };
             assert_eq!(file.mcache.len(), buffer_num);
         }
     }
 
+    #[test]
+    /// A write that can't be backed by memory fails and leaves the file unchanged.
+    fn test_write_file_out_of_memory() {
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        let buffer: &mut [u8] = &mut [0xb; 1000];
+        assert_eq!(
+            file.write_file(buffer, 1000, 0, &ChunkSource::default()),
+            Ok(1000)
+        );
+
+        assert_eq!(
+            file.write_file(buffer, 1000, usize::MAX / 2, &ChunkSource::default()),
+            Err(FileSystemError::OutOfMemory)
+        );
+        assert_eq!(file.get_size(), 1000);
+        assert_eq!(file.mcache.len(), 1);
+    }
+
     #[test]
     /// Tests the writing to a file and later check if the content was written properly or not.
     fn test_write_file() {
@@ -330,7 +1481,8 @@ pub mod test {
 
         let buffer: &mut [u8] = &mut [0xb; 10000];
         for i in 0..10000 {
-            file.write_file(buffer, i, 0).unwrap();
+            file.write_file(buffer, i, 0, &ChunkSource::default())
+                .unwrap();
             assert_eq!(file.get_size(), i);
         }
 
@@ -351,7 +1503,10 @@ pub mod test {
         let wbuffer: &mut [u8] = &mut [0xb; 10000];
         let rbuffer: &mut [u8] = &mut [0; 10000];
 
-        assert_eq!(file.write_file(wbuffer, 10000, 0), Ok(10000));
+        assert_eq!(
+            file.write_file(wbuffer, 10000, 0, &ChunkSource::default()),
+            Ok(10000)
+        );
         assert_eq!(file.get_size(), 10000);
 
         for i in 0..10000 {
@@ -366,10 +1521,13 @@ pub mod test {
         let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
         let wbuffer: &mut [u8] = &mut [0xb; 10000];
 
-        assert_eq!(file.write_file(wbuffer, 10000, 0), Ok(10000));
+        assert_eq!(
+            file.write_file(wbuffer, 10000, 0, &ChunkSource::default()),
+            Ok(10000)
+        );
         assert_eq!(file.get_size(), 10000);
 
-        file.file_truncate();
+        file.file_truncate().unwrap();
         assert_eq!(file.get_size(), 0);
         assert_eq!(file.mcache.len(), 0);
     }
@@ -384,13 +1542,15 @@ pub mod test {
 
         let buffer: &mut [u8] = &mut [0xb; 10000];
         for i in 0..10000 {
-            file.write_file(buffer, i, 0).unwrap();
+            file.write_file(buffer, i, 0, &ChunkSource::default())
+                .unwrap();
             assert_eq!(file.get_size(), i);
         }
 
         let buffer: &mut [u8] = &mut [0xa; 7000];
         for i in 0..4096 {
-            file.write_file(buffer, i, 0).unwrap();
+            file.write_file(buffer, i, 0, &ChunkSource::default())
+                .unwrap();
             assert_eq!(file.get_size(), 9999);
         }
 
@@ -403,4 +1563,239 @@ pub mod test {
             assert_eq!(file.mcache[1].data[i], 0xb);
         }
     }
+
+    #[test]
+    /// A file that grows past the threshold moves its data to aligned large
+    /// pages, and back to base pages once truncated.
+    fn test_large_pages() {
+        let source = ChunkSource {
+            pages: Pages::default(),
+            large: Some((
+                Pages::default().aligned(LARGE_PAGE_SIZE),
+                4 * BASE_PAGE_SIZE,
+            )),
+        };
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        let data: Vec<u8> = (0..3 * BASE_PAGE_SIZE).map(|i| i as u8).collect();
+        assert_eq!(
+            file.write_file(&data, data.len(), 0, &source),
+            Ok(data.len())
+        );
+        assert!(!file.is_large());
+        assert_eq!(file.mcache.len(), 3);
+
+        assert_eq!(
+            file.write_file(&data, data.len(), data.len(), &source),
+            Ok(data.len())
+        );
+        assert!(file.is_large());
+        assert_eq!(file.get_size(), 2 * data.len());
+        assert_eq!(file.mcache.len(), 1);
+        assert_eq!(file.mcache[0].data.as_ptr() as usize % LARGE_PAGE_SIZE, 0);
+        let mut rbuffer = alloc::vec![0; 2 * data.len()];
+        assert_eq!(
+            file.read_file(&mut rbuffer, 0, 2 * data.len()),
+            Ok(2 * data.len())
+        );
+        assert_eq!(rbuffer[..data.len()], data[..]);
+        assert_eq!(rbuffer[data.len()..], data[..]);
+
+        // Growing a large file adds large pages.
+        let end = LARGE_PAGE_SIZE + 1;
+        assert_eq!(file.write_file(&[1], 1, end - 1, &source), Ok(1));
+        assert_eq!(file.get_size(), end);
+        assert_eq!(file.mcache.len(), 2);
+
+        file.file_truncate().unwrap();
+        assert_eq!(file.write_file(&data, 10, 0, &source), Ok(10));
+        assert!(!file.is_large());
+    }
+
+    #[test]
+    /// A small file keeps its data inline until it grows past INLINE bytes.
+    fn test_inline() {
+        let source = ChunkSource::default();
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        file.enable_checksums();
+        assert_eq!(file.write_file(b"key = value", 11, 0, &source), Ok(11));
+        assert_eq!(file.write_file(b"!", 1, 20, &source), Ok(1));
+        assert_eq!(file.get_size(), 21);
+        assert_eq!((file.buffers(), file.data_size()), (0, 0));
+        let mut rbuffer = [0xff; 21];
+        assert_eq!(file.read_file(&mut rbuffer, 0, 21), Ok(21));
+        assert_eq!(&rbuffer[..11], b"key = value");
+        assert_eq!(rbuffer[11..], [0, 0, 0, 0, 0, 0, 0, 0, 0, b'!']);
+
+        file.inline.data[0] = b'K';
+        assert_eq!(
+            file.read_file(&mut rbuffer, 0, 21),
+            Err(FileSystemError::DataCorruption)
+        );
+        file.inline.data[0] = b'k';
+
+        let data = [0xb; INLINE];
+        assert_eq!(file.write_file(&data, INLINE, 21, &source), Ok(INLINE));
+        assert_eq!(file.buffers(), 1);
+        assert_eq!(file.inline.len, 0);
+        let mut rbuffer = [0; INLINE + 21];
+        assert_eq!(
+            file.read_file(&mut rbuffer, 0, INLINE + 21),
+            Ok(INLINE + 21)
+        );
+        assert_eq!(&rbuffer[..11], b"key = value");
+        assert_eq!(rbuffer[21..], data[..]);
+
+        file.file_truncate().unwrap();
+        assert_eq!(file.write_file(b"x", 1, 0, &source), Ok(1));
+        assert_eq!((file.get_size(), file.buffers()), (1, 0));
+        file.spill(&source.pages).unwrap();
+        assert_eq!((file.get_size(), file.buffers()), (1, 1));
+        assert_eq!(file.ranges(0, 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    /// The ranges of a byte range cover it buffer by buffer.
+    fn test_ranges() {
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        let wbuffer: &[u8; 10000] = &[0xb; 10000];
+        file.write_file(wbuffer, 10000, 0, &ChunkSource::default())
+            .unwrap();
+
+        let ranges = file.ranges(100, 9000).unwrap();
+        let lens: Vec<usize> = ranges.iter().map(|(_, len)| *len).collect();
+        assert_eq!(
+            lens,
+            [
+                BASE_PAGE_SIZE - 100,
+                BASE_PAGE_SIZE,
+                9000 - 2 * BASE_PAGE_SIZE
+            ]
+        );
+        assert_eq!(
+            ranges[0].0.as_ptr(),
+            file.mcache[0].data[100..].as_ptr() as *mut u8
+        );
+        assert_eq!(
+            ranges[1].0.as_ptr(),
+            file.mcache[1].data.as_ptr() as *mut u8
+        );
+        assert!(file.ranges(100, 100).unwrap().is_empty());
+    }
+
+    #[test]
+    /// Writes dirty the pages they touch until the pages are flushed.
+    fn test_dirty_pages() {
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        let source = ChunkSource::default();
+        file.write_file(&[0xb; 10000], 10000, 0, &source).unwrap();
+        assert_eq!(
+            file.dirty_pages(0, 10000).collect::<Vec<_>>(),
+            [0, 4096, 8192]
+        );
+        assert_eq!(file.flush(0, 10000, false, |_, _| Ok(())), Ok(3));
+        assert_eq!(file.dirty_pages(0, 10000).count(), 0);
+
+        file.write_file(&[0xc; 2], 2, 4095, &source).unwrap();
+        assert!(file.mark_dirty(9000, 9001));
+        assert_eq!(
+            file.dirty_pages(4096, 10000).collect::<Vec<_>>(),
+            [4096, 8192]
+        );
+        let mut flushed = Vec::new();
+        let result = file.flush(0, usize::MAX, false, |offset, data| {
+            flushed.push((offset, data.len(), data[0]));
+            match offset {
+                8192 => Err(FileSystemError::NoSpace),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(result, Err(FileSystemError::NoSpace));
+        assert_eq!(
+            flushed,
+            [(0, 4096, 0xb), (4096, 4096, 0xc), (8192, 1808, 0xb)]
+        );
+        assert_eq!(file.dirty_pages(0, 10000).collect::<Vec<_>>(), [8192]);
+    }
+
+    #[test]
+    /// Reads of pages that changed behind the back of the file fail.
+    fn test_checksums() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        let source = ChunkSource::default();
+        file.write_file(&[0xb; 5000], 5000, 0, &source).unwrap();
+        file.enable_checksums();
+        file.write_file(&[0xc; 10], 10, 9000, &source).unwrap();
+        assert_eq!(file.sums.as_ref().map(|sums| sums.len()), Some(3));
+
+        let mut buffer = [0; 10];
+        file.mcache[1].data[100] ^= 1;
+        assert_eq!(file.read_file(&mut buffer, 0, 10), Ok(10));
+        assert_eq!(
+            file.read_file(&mut buffer, 4096, 4106),
+            Err(FileSystemError::DataCorruption)
+        );
+        // Pinned pages may change anytime; they get new checksums when unpinned.
+        file.pins += 1;
+        assert_eq!(file.read_file(&mut buffer, 4096, 4106), Ok(10));
+        file.unpin();
+        assert_eq!(file.read_file(&mut buffer, 4096, 4106), Ok(10));
+        assert_eq!(file.read_file(&mut buffer, 9000, 9010), Ok(10));
+        assert_eq!(buffer, [0xc; 10]);
+    }
+
+    #[test]
+    /// Writes keep the previous version, which shares the untouched pages.
+    fn test_versions() {
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        let source = ChunkSource::default();
+        file.write_file(&[0xa; 10000], 10000, 0, &source).unwrap();
+        assert!(file.versions().unwrap().is_empty());
+
+        file.keep_versions(2, 30000);
+        file.write_file(&[0xb; 10], 10, 5000, &source).unwrap();
+        let versions = file.versions().unwrap();
+        assert_eq!(
+            versions,
+            [VersionInfo {
+                number: 0,
+                size: 10000
+            }]
+        );
+        let mut buffer = [0; 20];
+        assert_eq!(file.read_version(0, &mut buffer, 4995), Ok(20));
+        assert_eq!(buffer, [0xa; 20]);
+        assert_eq!(file.read_version(0, &mut buffer, 9990), Ok(10));
+        assert_eq!(
+            file.read_version(0, &mut buffer, 10001),
+            Err(FileSystemError::InvalidOffset)
+        );
+        assert_eq!(
+            file.read_version(1, &mut buffer, 0),
+            Err(FileSystemError::InvalidFile)
+        );
+        // Only the page written got its own copy.
+        assert!(file.mcache[0].shared.is_some());
+        assert!(file.mcache[1].shared.is_none());
+        assert!(file.mcache[2].shared.is_none());
+
+        // Growing the file past a partial last buffer leaves the version intact.
+        file.write_file(&[0xc; 10], 10, 12000, &source).unwrap();
+        file.file_truncate().unwrap();
+        let numbers: Vec<u64> = file.versions().unwrap().iter().map(|v| v.number).collect();
+        assert_eq!(numbers, [1, 2]);
+        assert_eq!(file.read_version(1, &mut buffer, 4995), Ok(20));
+        assert_eq!(&buffer[..5], &[0xa; 5]);
+        assert_eq!(&buffer[5..15], &[0xb; 10]);
+        assert_eq!(file.read_version(2, &mut buffer, 11995), Ok(15));
+        assert_eq!(&buffer[..5], &[0; 5]);
+        assert_eq!(&buffer[5..15], &[0xc; 10]);
+
+        // The byte bound drops the oldest versions too.
+        file.keep_versions(2, 12010);
+        assert_eq!(file.versions().unwrap().len(), 1);
+        file.drop_versions();
+        assert!(file.versions().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,251 @@
+//! Typed handles of files and directories.
+//!
+//! An mnode number says neither what the mnode is nor what the caller may do
+//! with it, so passing the wrong one is only noticed, if at all, when the
+//! operation fails. A `FileHandle` or `DirHandle` is checked once, when
+//! `MemFS::open_file()` or `MemFS::open_dir()` makes it: the operations that
+//! take one can't be given a directory for a file or the other way around,
+//! and only do what the handle has the `Rights` for.
+//!
+//! A handle also carries the generation of its mnode. Every mnode of every
+//! file-system gets a different one, so a handle of a removed mnode fails
+//! with `InvalidFile` rather than reaching another mnode that got the same
+//! number, and so does a handle used with another file-system.
+
+use alloc::vec::Vec;
+use bitflags::*;
+use core::hash::BuildHasher;
+use lock_api::RawRwLock;
+
+use crate::mnode::{NodeType, Stat};
+use crate::{Cursor, DirEntry, FileFlags, FileSystem, FileSystemError, MemFS, Mnode};
+
+/// Tells an mnode from the others with the same number.
+pub type Generation = u64;
+
+bitflags! {
+    /// What a handle allows.
+    pub struct Rights: u8 {
+        /// Read the file, or list the directory.
+        const READ = 0x1;
+        /// Write the file.
+        const WRITE = 0x2;
+    }
+}
+
+/// A file opened with `MemFS::open_file()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHandle {
+    mnode: Mnode,
+    generation: Generation,
+    rights: Rights,
+}
+
+impl FileHandle {
+    /// The mnode of the file.
+    pub fn mnode(&self) -> Mnode {
+        self.mnode
+    }
+
+    /// What the handle allows.
+    pub fn rights(&self) -> Rights {
+        self.rights
+    }
+}
+
+/// A directory opened with `MemFS::open_dir()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirHandle {
+    mnode: Mnode,
+    generation: Generation,
+    rights: Rights,
+}
+
+impl DirHandle {
+    /// The mnode of the directory.
+    pub fn mnode(&self) -> Mnode {
+        self.mnode
+    }
+
+    /// What the handle allows.
+    pub fn rights(&self) -> Rights {
+        self.rights
+    }
+}
+
+impl<S: BuildHasher + Clone + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
+    /// Open the file `pathname` with `rights`, which its modes must allow.
+    pub fn open_file(&self, pathname: &str, rights: Rights) -> Result<FileHandle, FileSystemError> {
+        let (mnode, stat) = self.open(pathname, rights)?;
+        if stat.node_type != NodeType::File {
+            return Err(FileSystemError::IsADirectory);
+        }
+        Ok(FileHandle {
+            mnode,
+            generation: stat.generation,
+            rights,
+        })
+    }
+
+    /// Open the directory `pathname` with `rights`, which its modes must
+    /// allow.
+    pub fn open_dir(&self, pathname: &str, rights: Rights) -> Result<DirHandle, FileSystemError> {
+        let (mnode, stat) = self.open(pathname, rights)?;
+        if stat.node_type != NodeType::Directory {
+            return Err(FileSystemError::NotADirectory);
+        }
+        Ok(DirHandle {
+            mnode,
+            generation: stat.generation,
+            rights,
+        })
+    }
+
+    /// Read from the file of `handle` at `offset`, see `FileSystemRead::read`.
+    pub fn read_file(
+        &self,
+        handle: &FileHandle,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<usize, FileSystemError> {
+        self.check(handle.mnode, handle.generation, handle.rights, Rights::READ)?;
+        self.read_with(handle.mnode, buffer, offset, true)
+    }
+
+    /// Write to the file of `handle` at `offset`, see `FileSystem::write`.
+    pub fn write_file(
+        &self,
+        handle: &FileHandle,
+        buffer: &[u8],
+        offset: usize,
+    ) -> Result<usize, FileSystemError> {
+        self.check(
+            handle.mnode,
+            handle.generation,
+            handle.rights,
+            Rights::WRITE,
+        )?;
+        self.write_with(handle.mnode, buffer, offset, true)
+    }
+
+    /// List up to `max` entries of the directory of `handle`, see
+    /// `FileSystemRead::readdir`.
+    pub fn list_dir(
+        &self,
+        handle: &DirHandle,
+        after: Option<&str>,
+        max: usize,
+    ) -> Result<Vec<DirEntry>, FileSystemError> {
+        self.check(handle.mnode, handle.generation, handle.rights, Rights::READ)?;
+        self.list(handle.mnode, Cursor::Name(after), max)
+    }
+
+    /// Find `pathname` and check that its modes and the security policy
+    /// allow `rights`.
+    fn open(&self, pathname: &str, rights: Rights) -> Result<(Mnode, Stat), FileSystemError> {
+        let key = self.key(pathname)?;
+        let mnode = self.resolve(&key)?;
+        let flags = match (
+            rights.contains(Rights::READ),
+            rights.contains(Rights::WRITE),
+        ) {
+            (true, true) => FileFlags::O_RDWR,
+            (false, true) => FileFlags::O_WRONLY,
+            _ => FileFlags::O_RDONLY,
+        };
+        self.may_open(pathname, mnode, flags)?;
+        let stat = self
+            .memnode(mnode)
+            .ok_or(FileSystemError::InvalidFile)?
+            .stat();
+        if (rights.contains(Rights::READ) && !stat.modes.is_readable())
+            || (rights.contains(Rights::WRITE) && !stat.modes.is_writable())
+        {
+            return Err(FileSystemError::PermissionError);
+        }
+        Ok((mnode, stat))
+    }
+
+    /// Check that a handle still refers to its mnode and allows `needed`.
+    fn check(
+        &self,
+        mnode: Mnode,
+        generation: Generation,
+        rights: Rights,
+        needed: Rights,
+    ) -> Result<(), FileSystemError> {
+        if !rights.contains(needed) {
+            return Err(FileSystemError::PermissionError);
+        }
+        match self.memnode(mnode) {
+            Some(memnode) if memnode.stat().generation == generation => Ok(()),
+            _ => Err(FileSystemError::InvalidFile),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FileModes, FileSystem, FileSystemRead};
+
+    #[test]
+    /// Handles check the type and the rights once, and the generation on
+    /// every use.
+    fn test_handles() {
+        let memfs = MemFS::default();
+        memfs.mkdir("/dir", FileModes::S_IRWXU.into()).unwrap();
+        memfs
+            .create("/dir/file", FileModes::S_IRWXU.into())
+            .unwrap();
+        memfs.create("/ro", FileModes::S_IRUSR.into()).unwrap();
+
+        let file = memfs.open_file("/dir/file", Rights::all()).unwrap();
+        assert_eq!(memfs.write_file(&file, b"nrfs", 0), Ok(4));
+        let mut buf = [0; 4];
+        assert_eq!(memfs.read_file(&file, &mut buf, 0), Ok(4));
+        assert_eq!(&buf, b"nrfs");
+
+        let dir = memfs.open_dir("/dir", Rights::READ).unwrap();
+        let entries = memfs.list_dir(&dir, None, 8).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].mnode, file.mnode());
+
+        assert_eq!(
+            memfs.open_file("/dir", Rights::READ),
+            Err(FileSystemError::IsADirectory)
+        );
+        assert_eq!(
+            memfs.open_dir("/dir/file", Rights::READ),
+            Err(FileSystemError::NotADirectory)
+        );
+        assert_eq!(
+            memfs.open_file("/ro", Rights::WRITE),
+            Err(FileSystemError::PermissionError)
+        );
+        let ro = memfs.open_file("/ro", Rights::READ).unwrap();
+        assert_eq!(
+            memfs.write_file(&ro, b"nrfs", 0),
+            Err(FileSystemError::PermissionError)
+        );
+
+        // A handle only works with the file-system that made it, even where
+        // another has an mnode with the same number, and only until its
+        // mnode is removed.
+        let other = MemFS::default();
+        other.mkdir("/dir", FileModes::S_IRWXU.into()).unwrap();
+        other
+            .create("/dir/file", FileModes::S_IRWXU.into())
+            .unwrap();
+        assert_eq!(other.lookup("/dir/file").map(|m| *m), Some(file.mnode()));
+        assert_eq!(
+            other.read_file(&file, &mut buf, 0),
+            Err(FileSystemError::InvalidFile)
+        );
+        memfs.delete("/dir/file").unwrap();
+        assert_eq!(
+            memfs.read_file(&file, &mut buf, 0),
+            Err(FileSystemError::InvalidFile)
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! A hashed index of the entries of a huge directory.
+//!
+//! Like the htree of ext3, the index has two levels: a table indexed by the
+//! top `depth` bits of the hash of a name points to leaves, which hold the
+//! hashes of the names and the slots of their entries. A full leaf is split in
+//! two by one more bit of the hash, and the table doubles only when the leaf
+//! already used all its bits. Adding an entry to a directory of millions of
+//! them then touches one leaf, and at worst copies the table of pointers, but
+//! never rehashes the entries.
+
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::hash::{BuildHasher, Hasher};
+use core::mem::size_of;
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use crate::FileSystemError;
+
+/// The most entries in a leaf, unless the hashes in it can't be told apart.
+const LEAF: usize = 128;
+
+/// Bits of the hash the table uses at most; leaves past that grow instead.
+const MAX_DEPTH: u32 = 20;
+
+#[derive(Debug)]
+struct Leaf {
+    /// The leaf holds the hashes whose top `depth` bits are the same.
+    depth: u32,
+    /// The hashes and the slots of the entries.
+    entries: Vec<(u64, u32)>,
+}
+
+/// The slots of the entries of a directory, by the hashes of their names.
+#[derive(Debug)]
+pub(crate) struct HashIndex {
+    hasher: DefaultHashBuilder,
+    depth: u32,
+    /// The leaf of every value of the top `depth` bits of a hash.
+    table: Vec<u32>,
+    leaves: Vec<Leaf>,
+}
+
+/// The top `depth` bits of `hash`.
+fn prefix(hash: u64, depth: u32) -> usize {
+    match depth {
+        0 => 0,
+        _ => (hash >> (64 - depth)) as usize,
+    }
+}
+
+impl HashIndex {
+    /// An empty index.
+    pub(crate) fn new() -> Result<HashIndex, FileSystemError> {
+        let mut table = Vec::new();
+        let mut leaves = Vec::new();
+        table
+            .try_reserve(1)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        leaves
+            .try_reserve(1)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        table.push(0);
+        leaves.push(Leaf {
+            depth: 0,
+            entries: Vec::new(),
+        });
+        Ok(HashIndex {
+            hasher: DefaultHashBuilder::default(),
+            depth: 0,
+            table,
+            leaves,
+        })
+    }
+
+    /// The hash of `name`.
+    pub(crate) fn hash(&self, name: &str) -> u64 {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write(name.as_bytes());
+        hasher.finish()
+    }
+
+    fn leaf(&self, hash: u64) -> usize {
+        self.table[prefix(hash, self.depth)] as usize
+    }
+
+    /// The slots of the entries whose names have the hash `hash`.
+    pub(crate) fn get(&self, hash: u64) -> impl Iterator<Item = u32> + '_ {
+        self.leaves[self.leaf(hash)]
+            .entries
+            .iter()
+            .filter(move |(other, _)| *other == hash)
+            .map(|(_, slot)| *slot)
+    }
+
+    /// Add the slot `slot` of an entry whose name has the hash `hash`. On
+    /// failure the index holds the same entries, maybe split differently.
+    pub(crate) fn insert(&mut self, hash: u64, slot: u32) -> Result<(), FileSystemError> {
+        loop {
+            let leaf = self.leaf(hash);
+            let Leaf { depth, entries } = &mut self.leaves[leaf];
+            // Splitting can't separate equal hashes.
+            if entries.len() < LEAF
+                || *depth == MAX_DEPTH
+                || entries.iter().all(|(other, _)| *other == hash)
+            {
+                entries
+                    .try_reserve(1)
+                    .map_err(|_| FileSystemError::OutOfMemory)?;
+                entries.push((hash, slot));
+                return Ok(());
+            }
+            self.split(leaf)?;
+        }
+    }
+
+    /// Split the full leaf `leaf` by the next bit of the hashes, doubling the
+    /// table first if the leaf uses all of its bits.
+    fn split(&mut self, leaf: usize) -> Result<(), FileSystemError> {
+        let depth = self.leaves[leaf].depth;
+        if depth == self.depth {
+            let mut table = Vec::new();
+            table
+                .try_reserve_exact(2 * self.table.len())
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            table.extend(self.table.iter().flat_map(|leaf| [*leaf, *leaf]));
+            self.table = table;
+            self.depth += 1;
+        }
+        let new = u32::try_from(self.leaves.len()).map_err(|_| FileSystemError::NoSpace)?;
+        let mut entries = Vec::new();
+        entries
+            .try_reserve(LEAF)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        self.leaves
+            .try_reserve(1)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+
+        // The hashes with the next bit set move to the new leaf. The leaf is
+        // full, so it has a hash to tell its pointers in the table by.
+        let bit = 1 << (63 - depth);
+        let first = prefix(self.leaves[leaf].entries[0].0, depth) << (self.depth - depth);
+        let span = 1 << (self.depth - depth);
+        let old = &mut self.leaves[leaf];
+        old.depth += 1;
+        entries.extend(old.entries.iter().filter(|(hash, _)| hash & bit != 0));
+        old.entries.retain(|(hash, _)| hash & bit == 0);
+        self.leaves.push(Leaf {
+            depth: depth + 1,
+            entries,
+        });
+        // So do the pointers of the table to the old leaf with that bit set.
+        for pointer in &mut self.table[first + span / 2..first + span] {
+            *pointer = new;
+        }
+        Ok(())
+    }
+
+    /// Remove the slot `slot` of an entry whose name has the hash `hash`.
+    pub(crate) fn remove(&mut self, hash: u64, slot: u32) {
+        let leaf = self.leaf(hash);
+        let entries = &mut self.leaves[leaf].entries;
+        if let Some(pos) = entries.iter().position(|entry| *entry == (hash, slot)) {
+            entries.swap_remove(pos);
+        }
+    }
+
+    /// Bytes of heap memory held by the index.
+    pub(crate) fn allocated_size(&self) -> usize {
+        let entries: usize = self.leaves.iter().map(|leaf| leaf.entries.capacity()).sum();
+        self.table.capacity() * size_of::<u32>()
+            + self.leaves.capacity() * size_of::<Leaf>()
+            + entries * size_of::<(u64, u32)>()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    /// Leaves split as they fill, and equal hashes stay findable.
+    fn test_hash_index() {
+        let mut index = HashIndex::new().unwrap();
+        for slot in 0..10 * LEAF as u32 {
+            let hash = index.hash(&slot.to_string());
+            index.insert(hash, slot).unwrap();
+        }
+        assert!(index.depth > 0);
+        assert!(index.leaves.len() >= 10);
+        assert!(index.leaves.iter().all(|leaf| leaf.entries.len() <= LEAF));
+        for slot in 0..10 * LEAF as u32 {
+            let hash = index.hash(&slot.to_string());
+            assert_eq!(index.get(hash).collect::<Vec<_>>(), [slot]);
+        }
+
+        // Hashes that can't be told apart end up in one leaf.
+        for slot in 0..2 * LEAF as u32 {
+            index.insert(7, 100_000 + slot).unwrap();
+        }
+        assert_eq!(index.get(7).count(), 2 * LEAF);
+        index.remove(7, 100_000);
+        assert_eq!(index.get(7).count(), 2 * LEAF - 1);
+        assert!(index.get(7).all(|slot| slot != 100_000));
+    }
+}
@@ -0,0 +1,344 @@
+//! Introspection of a MemFS instance.
+//!
+//! Renders the namespace as a tree, together with the metadata of every mnode
+//! and the memory held by the file-system. The output only needs a
+//! `core::fmt::Write`, so it can be printed from a kernel debugger console.
+//! `MemFS::iter()` walks the same tree for programs, e.g. backups.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::hash::BuildHasher;
+use core::mem::size_of;
+use lock_api::RawRwLock;
+
+use crate::mnode::{MemNode, NodeType};
+use crate::{FileInfo, FileModes, FileSystemError, MemFS, Mnode, Name, ROOT_MNODE};
+
+/// Aggregate memory usage of a file-system.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct MemoryUsage {
+    pub files: usize,
+    pub directories: usize,
+    /// Bytes stored in the files.
+    pub data_bytes: usize,
+    /// Heap bytes held by the mnodes and the file data, including the
+    /// allocated but unused capacity.
+    pub allocated_bytes: usize,
+}
+
+/// Where the heap memory of a file-system goes, in bytes.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct MemoryBreakdown {
+    /// Memory holding the data of the files; shared pages count once.
+    pub file_data: usize,
+    /// Memory allocated for the data of the files that doesn't hold any,
+    /// e.g. the room their last buffers keep for growth. `MemFS::compact()`
+    /// frees most of it.
+    pub file_slack: usize,
+    /// The mnodes, their names and directory entries, and the buffer lists,
+    /// dirty bits and checksums of the files.
+    pub metadata: usize,
+    /// The mnode table and the other lookup structures: the dentry cache,
+    /// the Bloom filter, the mnode caches and the table of shared pages.
+    pub hash_maps: usize,
+}
+
+impl MemoryBreakdown {
+    /// All the memory accounted for.
+    pub fn total(&self) -> usize {
+        self.file_data + self.file_slack + self.metadata + self.hash_maps
+    }
+}
+
+/// The entries of a file-system as `MemFS::iter()` found them: the path,
+/// mnode and type and size of every file and directory, parents first.
+pub struct Iter {
+    entries: vec::IntoIter<(String, Mnode, FileInfo)>,
+}
+
+impl Iterator for Iter {
+    type Item = (String, Mnode, FileInfo);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+/// Render the owner permissions like `ls` does, e.g. `rw-`.
+fn mode_string(modes: FileModes) -> [u8; 3] {
+    [
+        if modes.is_readable() { b'r' } else { b'-' },
+        if modes.is_writable() { b'w' } else { b'-' },
+        if modes.is_executable() { b'x' } else { b'-' },
+    ]
+}
+
+impl<S: BuildHasher + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
+    /// Count the mnodes and the memory they hold.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage::default();
+        for memnode in self.mnodes.read(self.reader_tid()).values() {
+            let memnode = memnode.read();
+            match memnode.get_mnode_type() {
+                NodeType::Directory => usage.directories += 1,
+                NodeType::File => usage.files += 1,
+            }
+            usage.data_bytes += memnode.get_file_size();
+            usage.allocated_bytes += size_of::<MemNode>() + memnode.allocated_size();
+        }
+        usage
+    }
+
+    /// Break the heap memory of the file-system down by what it is used for.
+    pub fn memory_breakdown(&self) -> MemoryBreakdown {
+        let mut breakdown = MemoryBreakdown::default();
+        {
+            let mnodes = self.mnodes.read(self.reader_tid());
+            for memnode in mnodes.values() {
+                let memnode = memnode.read();
+                let slack = memnode.slack();
+                breakdown.file_data += memnode.allocated_size() - memnode.metadata_size() - slack;
+                breakdown.file_slack += slack;
+                breakdown.metadata += size_of::<MemNode>() + memnode.metadata_size();
+            }
+            breakdown.hash_maps = mnodes.allocated_size();
+        }
+        breakdown.hash_maps += self.dcache.allocated_size()
+            + self.bloom.allocated_size()
+            + self.mcache.allocated_size()
+            + self.pages.allocated_size()
+            + self.tails.allocated_size();
+        breakdown
+    }
+
+    /// Bytes allocated for the data of the file `mnode` that don't hold any.
+    pub fn slack(&self, mnode: Mnode) -> Result<usize, FileSystemError> {
+        match self.memnode(mnode) {
+            Some(memnode) => Ok(memnode.read().slack()),
+            None => Err(FileSystemError::InvalidFile),
+        }
+    }
+
+    /// List every entry of the namespace, the root included, depth-first and
+    /// in name order within every directory. The namespace doesn't change
+    /// while it is listed, so the entries form a tree as it was at one point
+    /// in time; the sizes of the files may still change.
+    pub fn iter(&self) -> Result<Iter, FileSystemError> {
+        let mut entries = Vec::new();
+        {
+            // Renames move whole subtrees, so they wait for the walk; the
+            // other changes wait for their directory, which stays locked.
+            let _renaming = self.rename_lock.lock();
+            let mnodes = self.mnodes.read(self.reader_tid());
+            // Directories are locked before their entries, like walks do.
+            let mut locked = Vec::new();
+            let mut stack = vec![(String::from("/"), ROOT_MNODE)];
+            while let Some((path, mnode)) = stack.pop() {
+                let memnode = match mnodes.get(mnode) {
+                    Some(memnode) => memnode.read(),
+                    None => continue,
+                };
+                let stat = memnode.stat();
+                entries
+                    .try_reserve(1)
+                    .map_err(|_| FileSystemError::OutOfMemory)?;
+                if stat.node_type == NodeType::Directory {
+                    let first = stack.len();
+                    stack
+                        .try_reserve(memnode.num_entries())
+                        .map_err(|_| FileSystemError::OutOfMemory)?;
+                    for (name, entry) in memnode.entries() {
+                        let mut child = String::new();
+                        child
+                            .try_reserve(path.len() + 1 + name.len())
+                            .map_err(|_| FileSystemError::OutOfMemory)?;
+                        if mnode != ROOT_MNODE {
+                            child.push_str(&path);
+                        }
+                        child.push('/');
+                        child.push_str(name);
+                        stack.push((child, entry));
+                    }
+                    stack[first..].reverse();
+                    locked
+                        .try_reserve(1)
+                        .map_err(|_| FileSystemError::OutOfMemory)?;
+                    locked.push(memnode);
+                }
+                let info = FileInfo {
+                    fsize: stat.size as u64,
+                    ftype: stat.node_type.into(),
+                };
+                entries.push((path, mnode, info));
+            }
+        }
+        Ok(Iter {
+            entries: entries.into_iter(),
+        })
+    }
+
+    /// Write the namespace tree with one entry per line, followed by the
+    /// memory usage.
+    pub fn dump(&self, w: &mut dyn fmt::Write) -> fmt::Result {
+        {
+            let mnodes = self.mnodes.read(self.reader_tid());
+
+            // Depth-first, with the entries of every directory in name order.
+            let mut stack: Vec<(Name, Mnode, usize)> = vec![("/".into(), ROOT_MNODE, 0)];
+            while let Some((name, mnode, depth)) = stack.pop() {
+                write!(
+                    w,
+                    "{:indent$}{} (mnode {}",
+                    "",
+                    name,
+                    mnode,
+                    indent = 2 * depth
+                )?;
+                match mnodes.get(mnode).map(|memnode| memnode.read()) {
+                    Some(memnode) => {
+                        let modes = mode_string(memnode.get_mode());
+                        let modes = core::str::from_utf8(&modes).unwrap_or("???");
+                        match memnode.get_mnode_type() {
+                            NodeType::File => writeln!(
+                                w,
+                                ", file, {} bytes, {})",
+                                memnode.get_file_size(),
+                                modes
+                            )?,
+                            NodeType::Directory => writeln!(w, ", dir, {})", modes)?,
+                        }
+
+                        let entries = memnode
+                            .entries()
+                            .map(|(name, mnode)| (name.clone(), mnode, depth + 1));
+                        let first = stack.len();
+                        stack.extend(entries);
+                        stack[first..].reverse();
+                    }
+                    None => writeln!(w, ", missing)")?,
+                }
+            }
+        }
+
+        let usage = self.memory_usage();
+        writeln!(
+            w,
+            "{} files, {} directories, {} bytes of data, {} bytes allocated",
+            usage.files, usage.directories, usage.data_bytes, usage.allocated_bytes
+        )
+    }
+}
+
+/// Prints the same tree as [`MemFS::dump`].
+impl<S: BuildHasher + Send + Sync, L: RawRwLock + Send + Sync> fmt::Debug for MemFS<S, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.dump(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FileSystem, FileSystemRead};
+    use alloc::format;
+
+    #[test]
+    /// The dump lists every entry below its directory.
+    fn test_dump() {
+        let memfs = MemFS::default();
+        let log = memfs.create("/log", FileModes::S_IRWXU.into()).unwrap();
+        memfs.write(log, &[0xb; 100], 0).unwrap();
+        memfs.create("/a-b", FileModes::S_IRUSR.into()).unwrap();
+        memfs.mkdir("/var", FileModes::S_IRWXU.into()).unwrap();
+        memfs.mkdir("/var/log", FileModes::S_IRWXU.into()).unwrap();
+        memfs
+            .create("/var/log/1", FileModes::S_IRUSR.into())
+            .unwrap();
+
+        let dump = format!("{:?}", memfs);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(
+            lines[..6],
+            [
+                "/ (mnode 1, dir, rwx)",
+                "  a-b (mnode 3, file, 0 bytes, r--)",
+                "  log (mnode 2, file, 100 bytes, rwx)",
+                "  var (mnode 4, dir, rwx)",
+                "    log (mnode 5, dir, rwx)",
+                "      1 (mnode 6, file, 0 bytes, r--)",
+            ]
+        );
+        assert!(lines[6].starts_with("3 files, 3 directories, 100 bytes of data, "));
+    }
+
+    #[test]
+    /// The memory usage accounts for the file data.
+    fn test_memory_usage() {
+        let memfs = MemFS::default();
+        let empty = memfs.memory_usage();
+        assert_eq!(empty.files, 0);
+        assert_eq!(empty.directories, 1);
+
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        memfs.write(mnode, &[0xb; 10000], 0).unwrap();
+        let usage = memfs.memory_usage();
+        assert_eq!(usage.files, 1);
+        assert_eq!(usage.data_bytes, 10000);
+        assert!(usage.allocated_bytes >= empty.allocated_bytes + 10000);
+    }
+
+    #[test]
+    /// The breakdown tells the data of the files from the room they keep for
+    /// growth, which compaction frees.
+    fn test_memory_breakdown() {
+        let memfs = MemFS::default();
+        let empty = memfs.memory_breakdown();
+        assert_eq!(empty.file_data + empty.file_slack, 0);
+        assert!(empty.metadata > 0);
+        assert!(empty.hash_maps > 0);
+
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        memfs.write(mnode, &[0xb; 10000], 0).unwrap();
+        let breakdown = memfs.memory_breakdown();
+        assert_eq!(breakdown.file_data, 10000);
+        assert_eq!(breakdown.file_slack, 3 * 4096 - 10000);
+        assert_eq!(memfs.slack(mnode), Ok(3 * 4096 - 10000));
+        assert!(breakdown.metadata > empty.metadata);
+        let usage = memfs.memory_usage();
+        assert_eq!(
+            breakdown.file_data + breakdown.file_slack + breakdown.metadata,
+            usage.allocated_bytes
+        );
+
+        memfs.compact();
+        assert_eq!(memfs.memory_breakdown().file_slack, 0);
+        assert_eq!(memfs.slack(42), Err(FileSystemError::InvalidFile));
+    }
+
+    #[test]
+    /// Every entry is listed once, below its directory.
+    fn test_iter() {
+        let memfs = MemFS::default();
+        let log = memfs.create("/log", FileModes::S_IRWXU.into()).unwrap();
+        memfs.write(log, &[0xb; 100], 0).unwrap();
+        memfs.mkdir("/var", FileModes::S_IRWXU.into()).unwrap();
+        memfs.mkdir("/var/log", FileModes::S_IRWXU.into()).unwrap();
+        let one = memfs
+            .create("/var/log/1", FileModes::S_IRUSR.into())
+            .unwrap();
+
+        let entries: Vec<_> = memfs.iter().unwrap().collect();
+        let paths: Vec<&str> = entries.iter().map(|(path, _, _)| path.as_str()).collect();
+        assert_eq!(paths, ["/", "/log", "/var", "/var/log", "/var/log/1"]);
+        assert_eq!(entries[1].1, log);
+        assert_eq!(entries[1].2, memfs.file_info(log).unwrap());
+        assert_eq!(entries[4].1, one);
+        assert_eq!(entries[2].2.ftype, NodeType::Directory.into());
+    }
+}
@@ -1,3 +1,4 @@
+use alloc::string::String;
 use bitflags::*;
 
 /// Struct used in `file_getinfo` systemcall.
@@ -7,6 +8,53 @@ pub struct FileInfo {
     pub fsize: u64,
 }
 
+/// The metadata of an mnode, as returned by `MemFS::metadata`. It is also
+/// the `stat` struct of the C interface, so its layout is fixed.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Metadata {
+    pub mnode: u64,
+    pub ftype: u64,
+    pub fsize: u64,
+    pub modes: u64,
+    /// Time of the last status change.
+    pub ctime: u64,
+    /// Time of the last modification of the content.
+    pub mtime: u64,
+    /// Grows with every change of the content or the status, like
+    /// `i_version`: a client whose cached copy has the same version is up
+    /// to date, even if the clock didn't move between two changes.
+    pub version: u64,
+}
+
+/// An entry of a directory, as returned by `readdir`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub mnode: u64,
+    /// The type of the mnode, as in `FileInfo`, so listings don't need a
+    /// `stat()` per entry to tell directories from files.
+    pub ftype: u64,
+    /// Where a listing continues after this entry, see `MemFS::readdir_from`.
+    pub cookie: u64,
+}
+
+/// How a file or a range of it is going to be used, as told by `MemFS::advise`
+/// (`posix_fadvise`/`madvise`).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Advice {
+    /// No particular access pattern; the default.
+    Normal,
+    /// The file will be read from start to end.
+    Sequential,
+    /// The file will be accessed in random order.
+    Random,
+    /// The range will be accessed soon.
+    WillNeed,
+    /// The range won't be accessed soon.
+    DontNeed,
+}
+
 bitflags! {
     /// File flags to open the file
     pub struct FileFlags:u64 {
@@ -14,9 +62,12 @@ bitflags! {
         const O_RDONLY = 0x0001; /* open for reading only */
         const O_WRONLY = 0x0002; /* open for writing only */
         const O_RDWR = 0x0003; /* open for reading and writing */
+        const O_NONBLOCK = 0x0004; /* don't wait for data or room */
         const O_CREAT = 0x0200; /* create if nonexistant */
         const O_TRUNC = 0x0400; /* truncate to zero length */
         const O_APPEND = 0x02000; /* append at the EOF */
+        const O_CLOEXEC = 0x100000; /* set FD_CLOEXEC on the descriptor */
+        const O_PATH = 0x400000; /* refer to the file without access to it */
     }
 }
 
@@ -63,6 +114,28 @@ impl FileFlags {
     pub fn is_append(&self) -> bool {
         (*self & FileFlags::O_APPEND) == FileFlags::O_APPEND
     }
+
+    pub fn is_nonblocking(&self) -> bool {
+        (*self & FileFlags::O_NONBLOCK) == FileFlags::O_NONBLOCK
+    }
+
+    pub fn is_path(&self) -> bool {
+        (*self & FileFlags::O_PATH) == FileFlags::O_PATH
+    }
+}
+
+bitflags! {
+    /// Flags of a descriptor rather than of the open file, as read and set by
+    /// `fcntl(F_GETFD/F_SETFD)`.
+    pub struct FdFlags: u64 {
+        const FD_CLOEXEC = 0x1; /* close the descriptor on exec */
+    }
+}
+
+impl Default for FdFlags {
+    fn default() -> FdFlags {
+        FdFlags::empty()
+    }
 }
 
 bitflags! {
@@ -0,0 +1,288 @@
+//! Leases that let remote clients cache files, for network and userspace
+//! adapters (9P, FUSE, NFS-like servers) built on the file-system.
+//!
+//! A client holding a read lease on an mnode may serve reads from its cache;
+//! one holding a write lease may also cache writes. Many clients can share
+//! read leases, a write lease is exclusive. Before an access conflicts with
+//! a lease, the lease is recalled through the [`Transport`] of the adapter,
+//! which returns once the client wrote back and dropped what it cached; the
+//! access goes ahead after that, so it never sees stale data.
+//!
+//! The adapter calls `Leases::access()` before it serves an uncached call of
+//! a client, and local callers that share the file-system with the clients
+//! do the same without a client.
+
+use alloc::vec::Vec;
+use hashbrown::hash_map::DefaultHashBuilder;
+use hashbrown::HashMap;
+use spin::Mutex;
+
+use crate::{FileSystemError, Mnode};
+
+/// Tells the clients of an adapter apart, e.g. a 9P session or FUSE
+/// connection.
+pub type ClientId = u64;
+
+/// What a client may cache of a file.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Lease {
+    /// The data and metadata, for reading.
+    Read,
+    /// The data and metadata, including writes not sent yet.
+    Write,
+}
+
+/// Delivers recalls to the clients.
+pub trait Transport: Send + Sync {
+    /// Ask `client` to give up its `lease` on `mnode`: to send its cached
+    /// writes and drop its cache of the file. Returns once the client did, or
+    /// didn't answer in time; the lease is revoked either way. No lock is held
+    /// during the call, so the transport can call back into the leases and the
+    /// file-system, e.g. for the writes of the client.
+    fn recall(&self, client: ClientId, mnode: Mnode, lease: Lease);
+}
+
+/// The clients holding a lease on an mnode.
+#[derive(Debug)]
+enum Holders {
+    Read(Vec<ClientId>),
+    Write(ClientId),
+}
+
+impl Holders {
+    /// The lease of `client`, if it holds one.
+    fn lease(&self, client: ClientId) -> Option<Lease> {
+        match self {
+            Holders::Read(readers) if readers.contains(&client) => Some(Lease::Read),
+            Holders::Write(writer) if *writer == client => Some(Lease::Write),
+            _ => None,
+        }
+    }
+
+    /// The leases of the other clients that an access by `client` conflicts
+    /// with: all of them for a write, a write lease for a read.
+    fn conflicts(
+        &self,
+        client: Option<ClientId>,
+        write: bool,
+    ) -> impl Iterator<Item = (ClientId, Lease)> + '_ {
+        let (readers, writer) = match self {
+            Holders::Read(readers) if write => (&readers[..], None),
+            Holders::Read(_) => (&[][..], None),
+            Holders::Write(writer) => (&[][..], Some(*writer)),
+        };
+        let readers = readers.iter().map(|reader| (*reader, Lease::Read));
+        let writer = writer.map(|writer| (writer, Lease::Write));
+        readers
+            .chain(writer)
+            .filter(move |(holder, _)| Some(*holder) != client)
+    }
+}
+
+/// The leases handed out on the files of a file-system.
+pub struct Leases<T> {
+    transport: T,
+    holders: Mutex<HashMap<Mnode, Holders, DefaultHashBuilder>>,
+}
+
+impl<T: Transport> Leases<T> {
+    /// No leases yet; recalls go through `transport`.
+    pub fn new(transport: T) -> Leases<T> {
+        Leases {
+            transport,
+            holders: Mutex::new(HashMap::default()),
+        }
+    }
+
+    /// Give `client` a `lease` on `mnode`, recalling the conflicting leases
+    /// of the other clients first. A client upgrades or downgrades the lease
+    /// it holds by asking for the other one.
+    pub fn grant(
+        &self,
+        client: ClientId,
+        mnode: Mnode,
+        lease: Lease,
+    ) -> Result<(), FileSystemError> {
+        self.recall(Some(client), mnode, lease == Lease::Write, Some(lease))
+    }
+
+    /// Recall the leases of the other clients that an access to `mnode` by
+    /// `client`, or by a local caller if it is `None`, conflicts with.
+    pub fn access(
+        &self,
+        client: Option<ClientId>,
+        mnode: Mnode,
+        write: bool,
+    ) -> Result<(), FileSystemError> {
+        self.recall(client, mnode, write, None)
+    }
+
+    /// The lease `client` holds on `mnode`, if any.
+    pub fn lease(&self, client: ClientId, mnode: Mnode) -> Option<Lease> {
+        let holders = self.holders.lock();
+        holders
+            .get(&mnode)
+            .and_then(|holders| holders.lease(client))
+    }
+
+    /// Give back the lease of `client` on `mnode`, e.g. when the client closes
+    /// the file.
+    pub fn release(&self, client: ClientId, mnode: Mnode) {
+        let mut holders = self.holders.lock();
+        let empty = match holders.get_mut(&mnode) {
+            Some(Holders::Read(readers)) => {
+                readers.retain(|reader| *reader != client);
+                readers.is_empty()
+            }
+            Some(Holders::Write(writer)) => *writer == client,
+            None => false,
+        };
+        if empty {
+            holders.remove(&mnode);
+        }
+    }
+
+    /// Drop every lease of `client` without recalling them, e.g. when its
+    /// connection is gone.
+    pub fn forget(&self, client: ClientId) {
+        let mut holders = self.holders.lock();
+        holders.retain(|_, holders| match holders {
+            Holders::Read(readers) => {
+                readers.retain(|reader| *reader != client);
+                !readers.is_empty()
+            }
+            Holders::Write(writer) => *writer != client,
+        });
+    }
+
+    /// Recall the leases on `mnode` that conflict with an access by `client`
+    /// until there are none, then give `client` the lease `grant`, if any.
+    /// The recalls are made without holding the lock, so a new conflicting
+    /// lease may have been granted meanwhile, which is recalled in turn.
+    fn recall(
+        &self,
+        client: Option<ClientId>,
+        mnode: Mnode,
+        write: bool,
+        grant: Option<Lease>,
+    ) -> Result<(), FileSystemError> {
+        loop {
+            let mut conflicts = Vec::new();
+            {
+                let mut holders = self.holders.lock();
+                match holders.get(&mnode) {
+                    Some(current) if current.conflicts(client, write).next().is_some() => {
+                        conflicts
+                            .try_reserve(current.conflicts(client, write).count())
+                            .map_err(|_| FileSystemError::OutOfMemory)?;
+                        conflicts.extend(current.conflicts(client, write));
+                    }
+                    _ => {
+                        if let (Some(client), Some(lease)) = (client, grant) {
+                            insert(&mut holders, client, mnode, lease)?;
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+            for (holder, lease) in conflicts {
+                self.transport.recall(holder, mnode, lease);
+                self.release(holder, mnode);
+            }
+        }
+    }
+}
+
+/// Record the `lease` of `client` on `mnode`, which doesn't conflict with
+/// the other leases on it.
+fn insert(
+    holders: &mut HashMap<Mnode, Holders, DefaultHashBuilder>,
+    client: ClientId,
+    mnode: Mnode,
+    lease: Lease,
+) -> Result<(), FileSystemError> {
+    // Allocate first, so a failure leaves the leases as they were.
+    let mut fresh = Vec::new();
+    fresh
+        .try_reserve(1)
+        .map_err(|_| FileSystemError::OutOfMemory)?;
+    holders
+        .try_reserve(1)
+        .map_err(|_| FileSystemError::OutOfMemory)?;
+    let holder = match (lease, holders.remove(&mnode)) {
+        (Lease::Write, _) => Holders::Write(client),
+        (Lease::Read, Some(Holders::Read(mut readers))) => {
+            if !readers.contains(&client) {
+                if readers.try_reserve(1).is_err() {
+                    holders.insert(mnode, Holders::Read(readers));
+                    return Err(FileSystemError::OutOfMemory);
+                }
+                readers.push(client);
+            }
+            Holders::Read(readers)
+        }
+        // No lease yet, or the write lease of `client` downgraded.
+        (Lease::Read, _) => {
+            fresh.push(client);
+            Holders::Read(fresh)
+        }
+    };
+    holders.insert(mnode, holder);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Remembers the recalls.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(ClientId, Mnode, Lease)>>);
+
+    impl Transport for &Recorder {
+        fn recall(&self, client: ClientId, mnode: Mnode, lease: Lease) {
+            self.0.lock().push((client, mnode, lease));
+        }
+    }
+
+    #[test]
+    /// Conflicting accesses recall the leases, the others share them.
+    fn test_leases() {
+        let recorder = Recorder::default();
+        let leases = Leases::new(&recorder);
+        let recalls = || core::mem::take(&mut *recorder.0.lock());
+
+        assert_eq!(leases.grant(1, 2, Lease::Read), Ok(()));
+        assert_eq!(leases.grant(2, 2, Lease::Read), Ok(()));
+        assert_eq!(leases.access(None, 2, false), Ok(()));
+        assert_eq!(leases.grant(2, 3, Lease::Write), Ok(()));
+        assert!(recalls().is_empty());
+
+        // Client 2 upgrades: only client 1 has to give up its lease.
+        assert_eq!(leases.grant(2, 2, Lease::Write), Ok(()));
+        assert_eq!(recalls(), [(1, 2, Lease::Read)]);
+        assert_eq!(leases.lease(1, 2), None);
+        assert_eq!(leases.lease(2, 2), Some(Lease::Write));
+        assert_eq!(leases.access(Some(2), 2, true), Ok(()));
+        assert!(recalls().is_empty());
+
+        // Reads conflict with write leases only.
+        assert_eq!(leases.access(Some(1), 2, false), Ok(()));
+        assert_eq!(recalls(), [(2, 2, Lease::Write)]);
+        assert_eq!(leases.grant(1, 3, Lease::Read), Ok(()));
+        assert_eq!(recalls(), [(2, 3, Lease::Write)]);
+        assert_eq!(leases.lease(1, 3), Some(Lease::Read));
+
+        assert_eq!(leases.grant(1, 3, Lease::Write), Ok(()));
+        assert_eq!(leases.grant(1, 3, Lease::Read), Ok(()));
+        assert_eq!(leases.lease(1, 3), Some(Lease::Read));
+        leases.release(1, 3);
+        assert_eq!(leases.lease(1, 3), None);
+
+        assert_eq!(leases.grant(3, 4, Lease::Read), Ok(()));
+        leases.forget(3);
+        assert_eq!(leases.access(None, 4, true), Ok(()));
+        assert!(recalls().is_empty());
+        assert!(leases.holders.lock().is_empty());
+    }
+}
@@ -4,8 +4,9 @@
 #![feature(get_mut_unchecked)]
 #![feature(negative_impls)]
 #![feature(try_reserve)]
+#![feature(allocator_api)]
 
-#[cfg(test)]
+#[cfg(any(test, feature = "std"))]
 extern crate std;
 
 extern crate alloc;
@@ -14,26 +15,105 @@ extern crate hashbrown;
 #[macro_use]
 extern crate static_assertions;
 
-use alloc::string::{String, ToString};
+use alloc::borrow::Cow;
+use alloc::collections::VecDeque;
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::hash::BuildHasher;
+use core::ptr::NonNull;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
+use bloom::BloomFilter;
+pub use builder::MemFSBuilder;
+use builder::{Credentials, Event, Placement, Policy, StorageBackend};
+use cancel::Cancel;
 use custom_error_core::custom_error;
-use hashbrown::HashMap;
+use dcache::DentryCache;
+use dedup::PageTable;
+use fd::{Fd, FileDescriptor};
+pub use file::VersionInfo;
+use file::{ChunkSource, Eviction, Pages};
+use hashbrown::hash_map::DefaultHashBuilder;
 pub use io::*;
-use mnode::{MemNode, NodeType};
-use rwlock::RwLock as NrLock;
-use spin::RwLock;
+use lock_api::RawRwLock;
+use lru::{MnodeCache, MnodeRef};
+use metrics::Counters;
+use mnode::{MemNode, MnodeCell, MnodeWriteGuard, NodeType};
+use name::Name;
+use pool::{Exhausted, PagePool};
+use slab::MnodeSlab;
+use spin::Mutex;
+use table::{MnodeMap, MnodeTable};
+use tail::TailPages;
+use user::UserSlice;
+
+// The logging macros are used by the modules below.
+#[macro_use]
+mod logging;
 
-mod fd;
+#[cfg(feature = "std")]
+pub mod bench;
+mod bloom;
+pub mod builder;
+pub mod cancel;
+pub mod crypt;
+pub mod dcache;
+pub mod dedup;
+pub mod dirent;
+mod entries;
+pub mod errno;
+pub mod error;
+pub mod fd;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 mod file;
+pub mod handle;
+mod htree;
+pub mod introspect;
 pub mod io;
+pub mod lease;
+mod lru;
+pub mod merge;
+pub mod metrics;
 mod mnode;
-mod rwlock;
-mod topology;
+pub mod mount;
+mod name;
+mod padded;
+pub mod pmem;
+pub mod poll;
+pub mod pool;
+#[cfg(any(test, feature = "std"))]
+pub mod posix;
+mod rcu;
+pub mod ring;
+pub mod rwlock;
+mod seqlock;
+pub mod slab;
+#[cfg(feature = "std")]
+pub mod stdio;
+pub mod syscalls;
+mod table;
+pub mod tail;
+pub mod throttle;
+pub mod topology;
+pub mod trace;
+pub mod user;
+pub mod workload;
+pub mod writeback;
 
-/// The maximum number of open files for a process.
+/// The default maximum number of open files for a process.
 pub const MAX_FILES_PER_PROCESS: usize = 1024;
+/// The maximum length of a single path component.
+pub const MAX_NAME_LEN: usize = 255;
+/// The maximum length of a path.
+pub const MAX_PATH_LEN: usize = 4096;
+/// The size of a page of file data, in bytes.
+pub const BASE_PAGE_SIZE: usize = 4096;
+/// The size of a large page, the unit files grow in past their first
+/// chunks, in bytes.
+pub const LARGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
 
 /// Mnode number.
 pub type Mnode = u64;
@@ -64,198 +144,2512 @@ custom_error! {
     DirectoryError = "Can't read or write to a directory",
     OpenFileLimit = "Maximum files are opened for a process",
     OutOfMemory = "Unable to allocate memory for file",
+    NotADirectory = "A component of the path is not a directory",
+    IsADirectory = "Operation not allowed on a directory",
+    DirectoryNotEmpty = "Directory is not empty",
+    NameTooLong = "File name or path is too long",
+    NoSpace = "No space left in the file-system",
+    NotSupported = "Operation is not supported",
+    WouldBlock = "Operation would block",
+    Busy = "File is in use",
+    DataCorruption = "File data doesn't match its checksum",
+    BadAddress = "Buffer is outside of the memory of the process",
+    ReadOnly = "Path can't be changed through this mount",
+    FileTooLarge = "File would grow past the size limit",
+    Interrupted = "Operation was cancelled",
+    BufferTooSmall = "Buffer can't hold a directory entry",
+    Overflow = "Value doesn't fit in the field of a record",
+    InvalidArgument = "Supplied argument was invalid",
 }
 
-/// Abstract definition of file-system interface operations.
-pub trait FileSystem {
-    fn create(&self, pathname: &str, modes: Modes) -> Result<Mnode, FileSystemError>;
-    fn write(
+/// Copy `s` into a newly allocated `String`, reporting allocation failures
+/// instead of aborting.
+pub(crate) fn try_to_string(s: &str) -> Result<String, FileSystemError> {
+    let mut string = String::new();
+    string
+        .try_reserve(s.len())
+        .map_err(|_| FileSystemError::OutOfMemory)?;
+    string.push_str(s);
+    Ok(string)
+}
+
+/// The non-empty components of a path.
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|name| !name.is_empty())
+}
+
+/// Split a path into the path of its parent directory and its last
+/// component; the root doesn't have a parent.
+fn split_last(path: &str) -> Option<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    if path.is_empty() {
+        return None;
+    }
+    match path.rfind('/') {
+        Some(idx) => Some((&path[..idx], &path[idx + 1..])),
+        None => Some(("", path)),
+    }
+}
+
+/// Check if `path` is somewhere below the directory `dir`.
+fn is_below(dir: &str, path: &str) -> bool {
+    let mut path = components(path);
+    components(dir).all(|name| path.next() == Some(name)) && path.next().is_some()
+}
+
+/// The operations of a file-system that only read it, so read-only
+/// file-systems implement just these.
+pub trait FileSystemRead {
+    fn read(
         &self,
         mnode_num: Mnode,
-        buffer: &[u8],
+        buffer: &mut [u8],
         offset: usize,
     ) -> Result<usize, FileSystemError>;
-    fn read(
+    fn lookup(&self, pathname: &str) -> Option<Arc<Mnode>>;
+    fn file_info(&self, mnode: Mnode) -> Result<FileInfo, FileSystemError>;
+    fn readdir(
+        &self,
+        pathname: &str,
+        after: Option<&str>,
+        max: usize,
+    ) -> Result<Vec<DirEntry>, FileSystemError>;
+
+    /// Read from the file at `offset` into a buffer of userspace, without
+    /// dereferencing it, and return the number of bytes read. The data is
+    /// copied in chunks; if the buffer faults after some of them, the bytes
+    /// copied until then are returned.
+    fn read_user(
         &self,
         mnode_num: Mnode,
-        buffer: &mut [u8],
+        buffer: &UserSlice,
+        offset: usize,
+    ) -> Result<usize, FileSystemError> {
+        user::read(self, mnode_num, buffer, offset)
+    }
+}
+
+/// Abstract definition of file-system interface operations.
+pub trait FileSystem: FileSystemRead {
+    fn create(&self, pathname: &str, modes: Modes) -> Result<Mnode, FileSystemError>;
+    fn mkdir(&self, pathname: &str, modes: Modes) -> Result<Mnode, FileSystemError>;
+    fn write(
+        &self,
+        mnode_num: Mnode,
+        buffer: &[u8],
         offset: usize,
     ) -> Result<usize, FileSystemError>;
-    fn lookup(&self, pathname: &str) -> Option<Arc<Mnode>>;
-    fn file_info(&self, mnode: Mnode) -> FileInfo;
     fn delete(&self, pathname: &str) -> Result<bool, FileSystemError>;
+    fn rmdir(&self, pathname: &str) -> Result<bool, FileSystemError>;
     fn truncate(&self, pathname: &str) -> Result<bool, FileSystemError>;
     fn rename(&self, oldname: &str, newname: &str) -> Result<bool, FileSystemError>;
+    fn fsync(&self, mnode: Mnode) -> Result<(), FileSystemError>;
+    fn fdatasync(&self, mnode: Mnode) -> Result<(), FileSystemError>;
+
+    /// Write `buffer` at the end of the file as it is once the file is
+    /// locked for the write, like writes to files open with `O_APPEND`, so
+    /// appends made at the same time don't overwrite each other. Returns the
+    /// offset past the data written and the number of bytes written.
+    fn append(&self, mnode_num: Mnode, buffer: &[u8]) -> Result<(usize, usize), FileSystemError>;
+
+    /// Check that the caller may open `mnode`, found at `pathname`, with
+    /// `flags`. The file-system doesn't know about opens, so the front-ends
+    /// that open files call this once they found the file.
+    fn may_open(
+        &self,
+        _pathname: &str,
+        _mnode: Mnode,
+        _flags: FileFlags,
+    ) -> Result<(), FileSystemError> {
+        Ok(())
+    }
+
+    /// Write a buffer of userspace to the file at `offset`, like
+    /// `read_user()`.
+    fn write_user(
+        &self,
+        mnode_num: Mnode,
+        buffer: &UserSlice,
+        offset: usize,
+    ) -> Result<usize, FileSystemError> {
+        user::write(self, mnode_num, buffer, offset)
+    }
+
+    /// Append a buffer of userspace to the file, like `append()`. Every
+    /// chunk copied from userspace is appended on its own, so appends made
+    /// at the same time may land between the chunks of a large buffer.
+    fn append_user(
+        &self,
+        mnode_num: Mnode,
+        buffer: &UserSlice,
+    ) -> Result<(usize, usize), FileSystemError> {
+        user::append(self, mnode_num, buffer)
+    }
+}
+
+// Mount tables and overlays keep backends of different types as `&dyn
+// FileSystem` or `Arc<dyn FileSystem>`, so the traits must not get generic
+// methods; helpers that need them are free functions, like `user::read()`.
+assert_obj_safe!(FileSystemRead, FileSystem);
+
+/// Implement the traits for pointers to file-systems, passing every call on,
+/// the provided methods too, so overrides like `Mount::may_open()` apply.
+macro_rules! forward_file_system {
+    ($($pointer:ty),*) => {$(
+        impl<T: FileSystemRead + ?Sized> FileSystemRead for $pointer {
+            fn read(
+                &self,
+                mnode_num: Mnode,
+                buffer: &mut [u8],
+                offset: usize,
+            ) -> Result<usize, FileSystemError> {
+                (**self).read(mnode_num, buffer, offset)
+            }
+
+            fn lookup(&self, pathname: &str) -> Option<Arc<Mnode>> {
+                (**self).lookup(pathname)
+            }
+
+            fn file_info(&self, mnode: Mnode) -> Result<FileInfo, FileSystemError> {
+                (**self).file_info(mnode)
+            }
+
+            fn readdir(
+                &self,
+                pathname: &str,
+                after: Option<&str>,
+                max: usize,
+            ) -> Result<Vec<DirEntry>, FileSystemError> {
+                (**self).readdir(pathname, after, max)
+            }
+
+            fn read_user(
+                &self,
+                mnode_num: Mnode,
+                buffer: &UserSlice,
+                offset: usize,
+            ) -> Result<usize, FileSystemError> {
+                (**self).read_user(mnode_num, buffer, offset)
+            }
+        }
+
+        impl<T: FileSystem + ?Sized> FileSystem for $pointer {
+            fn create(&self, pathname: &str, modes: Modes) -> Result<Mnode, FileSystemError> {
+                (**self).create(pathname, modes)
+            }
+
+            fn mkdir(&self, pathname: &str, modes: Modes) -> Result<Mnode, FileSystemError> {
+                (**self).mkdir(pathname, modes)
+            }
+
+            fn write(
+                &self,
+                mnode_num: Mnode,
+                buffer: &[u8],
+                offset: usize,
+            ) -> Result<usize, FileSystemError> {
+                (**self).write(mnode_num, buffer, offset)
+            }
+
+            fn delete(&self, pathname: &str) -> Result<bool, FileSystemError> {
+                (**self).delete(pathname)
+            }
+
+            fn rmdir(&self, pathname: &str) -> Result<bool, FileSystemError> {
+                (**self).rmdir(pathname)
+            }
+
+            fn truncate(&self, pathname: &str) -> Result<bool, FileSystemError> {
+                (**self).truncate(pathname)
+            }
+
+            fn rename(&self, oldname: &str, newname: &str) -> Result<bool, FileSystemError> {
+                (**self).rename(oldname, newname)
+            }
+
+            fn fsync(&self, mnode: Mnode) -> Result<(), FileSystemError> {
+                (**self).fsync(mnode)
+            }
+
+            fn fdatasync(&self, mnode: Mnode) -> Result<(), FileSystemError> {
+                (**self).fdatasync(mnode)
+            }
+
+            fn append(
+                &self,
+                mnode_num: Mnode,
+                buffer: &[u8],
+            ) -> Result<(usize, usize), FileSystemError> {
+                (**self).append(mnode_num, buffer)
+            }
+
+            fn may_open(
+                &self,
+                pathname: &str,
+                mnode: Mnode,
+                flags: FileFlags,
+            ) -> Result<(), FileSystemError> {
+                (**self).may_open(pathname, mnode, flags)
+            }
+
+            fn write_user(
+                &self,
+                mnode_num: Mnode,
+                buffer: &UserSlice,
+                offset: usize,
+            ) -> Result<usize, FileSystemError> {
+                (**self).write_user(mnode_num, buffer, offset)
+            }
+
+            fn append_user(
+                &self,
+                mnode_num: Mnode,
+                buffer: &UserSlice,
+            ) -> Result<(usize, usize), FileSystemError> {
+                (**self).append_user(mnode_num, buffer)
+            }
+        }
+    )*};
 }
 
+forward_file_system!(&T, Arc<T>);
+
 /// The in-memory file-system representation.
-//#[derive(Debug)]
-pub struct MemFS {
-    mnodes: NrLock<HashMap<Mnode, RwLock<MemNode>>>,
-    files: RwLock<HashMap<String, Arc<Mnode>>>,
-    _root: (String, Mnode),
-    nextmemnode: AtomicUsize,
+///
+/// `S` builds the hashers of the internal maps, see `MemFSBuilder::hasher`.
+pub struct MemFS<S = DefaultHashBuilder, L = spin::RwLock<()>>
+where
+    S: Send + Sync,
+    L: RawRwLock,
+{
+    /// The mnode table; lookups never wait for creates and deletes.
+    mnodes: MnodeTable<L>,
+    root: Arc<Mnode>,
+    nextmemnode: MnodeCounter,
+    /// Limits and hooks chosen with the `MemFSBuilder`.
+    policy: Policy,
+    /// Bytes stored in all the files together.
+    used_bytes: AtomicUsize,
+    /// Number of files and directories, without the root.
+    nfiles: AtomicUsize,
+    /// Caches the entries of the directories resolved by walks.
+    dcache: DentryCache<S>,
+    /// Recently used mnodes of every CPU.
+    mcache: MnodeCache<L>,
+    /// Answers lookups of entries that don't exist without locking.
+    bloom: BloomFilter<S>,
+    /// Serializes renames, so the ancestry of the two directories a rename
+    /// locks can't change in the meantime.
+    rename_lock: Mutex<()>,
+    /// Resident buffers of the files, in the order the eviction clock visits
+    /// them; only tracked with a backing store.
+    resident: Mutex<VecDeque<(Mnode, usize)>>,
+    /// Files with changed pages, in the order `flush_some()` writes them
+    /// back; only kept with a backing store.
+    writeback: Mutex<VecDeque<Mnode>>,
+    /// The pages shared by `dedup()`.
+    pages: PageTable<S>,
+    /// The pages packed by `pack_tails()`.
+    tails: TailPages,
+    /// The reads and writes of every CPU.
+    counters: Counters,
+    /// Allocates the cells of the mnodes.
+    slab: MnodeSlab,
 }
 
-impl MemFS {
+/// Pages of a file pinned in memory by `MemFS::pin()`, e.g. while they are
+/// mapped into an address space. Dropping it unpins them.
+pub struct PinnedPages<L: RawRwLock = spin::RwLock<()>> {
+    memnode: MnodeRef<L>,
+    pages: Vec<NonNull<u8>>,
+}
+
+impl<L: RawRwLock> PinnedPages<L> {
+    /// The pinned pages in file order, `BASE_PAGE_SIZE` bytes each.
+    pub fn pages(&self) -> &[NonNull<u8>] {
+        &self.pages
+    }
+}
+
+impl<L: RawRwLock> Drop for PinnedPages<L> {
+    fn drop(&mut self) {
+        self.memnode.write().unpin();
+    }
+}
+
+/// The mnode number of the root directory.
+const ROOT_MNODE: Mnode = 1;
+
+/// Where a listing of a directory starts.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Cursor<'a> {
+    /// After the entry with this name, in the order of the names, or from
+    /// the first one.
+    Name(Option<&'a str>),
+    /// After the entry with this cookie, in the order of the cookies.
+    Cookie(u64),
+}
+
+/// Hands out the mnode numbers. Targets without 64-bit atomics count in a
+/// `usize`, so there the numbers wrap after 2^32 files were created.
+#[cfg(target_has_atomic = "64")]
+type MnodeCounter = core::sync::atomic::AtomicU64;
+#[cfg(not(target_has_atomic = "64"))]
+type MnodeCounter = AtomicUsize;
+
+impl<S: BuildHasher + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
     /// Get the next available memnode number.
-    fn get_next_mno(&self) -> usize {
-        self.nextmemnode.fetch_add(1, Ordering::Relaxed)
+    fn get_next_mno(&self) -> Mnode {
+        self.nextmemnode.fetch_add(1, Ordering::Relaxed) as Mnode
+    }
+
+    /// The reader id the caller looks up the mnode table with, see
+    /// `reader_id()`.
+    fn reader_tid(&self) -> usize {
+        reader_id(self.policy.cpu_id.as_deref())
+    }
+
+    /// The CPU whose mnode cache and counters are used: the current one if
+    /// there is a hook that tells it, else the reader id of the caller.
+    fn cpu(&self) -> usize {
+        match &self.policy.cpu_id {
+            Some(cpu_id) => cpu_id.current(),
+            None => self.reader_tid(),
+        }
+    }
+
+    /// Get a reference to an mnode, from the cache of the CPU if possible.
+    fn memnode(&self, mnode_num: Mnode) -> Option<MnodeRef<L>> {
+        let cpu = self.cpu();
+        if let Some(memnode) = self.mcache.get(cpu, mnode_num) {
+            return Some(memnode);
+        }
+        let mnodes = self.mnodes.read(self.reader_tid());
+        let memnode = mnodes.get(mnode_num)?;
+        self.mcache.insert(cpu, mnode_num, memnode);
+        Some(Arc::clone(memnode))
+    }
+
+    /// List up to `max` entries of the directory `mnode_num` from `from`,
+    /// see `readdir()` and `readdir_from()`.
+    fn list(
+        &self,
+        mnode_num: Mnode,
+        from: Cursor,
+        max: usize,
+    ) -> Result<Vec<DirEntry>, FileSystemError> {
+        let memnode = self
+            .memnode(mnode_num)
+            .ok_or(FileSystemError::InvalidFile)?;
+        let dir = memnode.read();
+        dir.check_readable_dir()?;
+
+        let mut entries = Vec::new();
+        entries
+            .try_reserve(core::cmp::min(max, dir.num_entries()))
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        let mut push = |entry: (&Name, Mnode, NodeType, u64)| -> Result<(), FileSystemError> {
+            let (name, mnode, kind, cookie) = entry;
+            entries.push(DirEntry {
+                name: try_to_string(name)?,
+                mnode,
+                ftype: kind.into(),
+                cookie,
+            });
+            Ok(())
+        };
+        match from {
+            Cursor::Name(after) => dir.entries_after(after).take(max).try_for_each(&mut push)?,
+            Cursor::Cookie(cookie) => dir
+                .entries_from_cookie(cookie)
+                .take(max)
+                .try_for_each(&mut push)?,
+        }
+        Ok(entries)
+    }
+
+    /// Walk `path` from the root, one directory at a time, and return the
+    /// mnode it names.
+    fn walk(&self, mnodes: &MnodeMap<L>, path: &str) -> Result<Mnode, FileSystemError> {
+        components(path).try_fold(ROOT_MNODE, |dir, name| {
+            if let Some(mnode) = self.dcache.get(dir, name) {
+                return Ok(mnode);
+            }
+            let dir_node = mnodes.get(dir).ok_or(FileSystemError::InvalidFile)?;
+            let dir_node = dir_node.read();
+            let (name, mnode) = dir_node.entry(name)?;
+            self.dcache.insert(dir, name, **mnode);
+            Ok(**mnode)
+        })
+    }
+
+    /// Resolve `path` to its mnode number.
+    fn resolve(&self, path: &str) -> Result<Mnode, FileSystemError> {
+        // Every walk starts at the root.
+        self.walk(&self.mnodes.read(self.reader_tid()), path)
+    }
+
+    /// The paths linked to `mnode`, e.g. for the targets of `/proc/<pid>/fd`
+    /// or for error messages. Without hard links, a file or directory has one
+    /// path, or none while it is being created or removed. The names are
+    /// those of the directory entries, which are folded to lowercase if the
+    /// file-system is case-insensitive.
+    pub fn paths(&self, mnode: Mnode) -> Result<Vec<String>, FileSystemError> {
+        let mut paths = Vec::new();
+        paths
+            .try_reserve(1)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        if mnode == ROOT_MNODE {
+            paths.push(try_to_string("/")?);
+            return Ok(paths);
+        }
+        // Renames are the only way to change the link of a directory, so the
+        // ancestors stay the same while they are visited.
+        let _renaming = self.rename_lock.lock();
+        let mnodes = self.mnodes.read(self.reader_tid());
+        let mut names = Vec::new();
+        let mut current = mnode;
+        let mut len = 0;
+        while current != ROOT_MNODE {
+            let memnode = mnodes
+                .get(current)
+                .ok_or(FileSystemError::InvalidFile)?
+                .read();
+            let (parent, name) = match memnode.link() {
+                Some(link) => link,
+                None => return Ok(paths),
+            };
+            names
+                .try_reserve(1)
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            names.push(Name::clone(name));
+            len += 1 + name.len();
+            current = parent;
+        }
+        let mut path = String::new();
+        path.try_reserve(len)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        for name in names.iter().rev() {
+            path.push('/');
+            path.push_str(name);
+        }
+        paths.push(path);
+        Ok(paths)
+    }
+
+    /// The path that `path` names relative to the directory open as `dirfd`,
+    /// to pass to the path operations like the `*at` calls of POSIX do.
+    /// Absolute paths are taken as they are. `dirfd` can be a path
+    /// descriptor, see `O_PATH`. The directory is looked up again by the
+    /// operation, so a rename racing with it is seen by one of the two.
+    pub fn path_at(&self, dirfd: &Fd, path: &str) -> Result<String, FileSystemError> {
+        if path.starts_with('/') {
+            return try_to_string(path);
+        }
+        let dir = dirfd.get_mnode();
+        let memnode = self.memnode(dir).ok_or(FileSystemError::InvalidFile)?;
+        if memnode.read().get_mnode_type() != NodeType::Directory {
+            return Err(FileSystemError::NotADirectory);
+        }
+        let mut joined = match self.paths(dir)?.pop() {
+            Some(joined) => joined,
+            None => return Err(FileSystemError::InvalidFile),
+        };
+        joined
+            .try_reserve(1 + path.len())
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        if !joined.ends_with('/') {
+            joined.push('/');
+        }
+        joined.push_str(path);
+        Ok(joined)
+    }
+
+    /// The topology the per-CPU structures were sized for.
+    fn topology(&self) -> &topology::MachineTopology {
+        self.policy
+            .topology
+            .as_ref()
+            .unwrap_or_else(|| topology::machine())
+    }
+
+    /// The NUMA node a write allocates on, as picked by the placement of the
+    /// page allocator hook. First-touch placement needs the CPU id hook to
+    /// tell the node of the writer; without it the node is unknown.
+    fn node(&self) -> Option<topology::Node> {
+        match &self.policy.page_allocator {
+            Some((_, Placement::Node(node))) => Some(*node),
+            Some((_, Placement::FirstTouch)) => self.policy.cpu_id.as_ref().and_then(|cpu_id| {
+                self.topology()
+                    .node_of(cpu_id.current() as topology::Cpu)
+                    .map(|info| info.node)
+            }),
+            None => None,
+        }
+    }
+
+    /// Where a write allocates the buffers it adds to a file: from the page
+    /// allocator hooks on the node of the placement, or from the global
+    /// allocator without a hook; charged to the page pool, if any.
+    fn chunks(&self) -> ChunkSource {
+        let node = self.node();
+        let align = self.policy.chunk_align;
+        let pool = &self.policy.page_pool;
+        let pages = match &self.policy.page_allocator {
+            Some((allocator, _)) => Pages::new(Arc::clone(allocator), node),
+            None => Pages::default(),
+        };
+        let large = self
+            .policy
+            .large_pages
+            .as_ref()
+            .map(|(allocator, threshold)| {
+                let pages = Pages::new(Arc::clone(allocator), node);
+                let pages = pages.aligned(align.max(LARGE_PAGE_SIZE));
+                (pages.pooled(pool.clone()), *threshold)
+            });
+        let pages = pages.aligned(align).pooled(pool.clone());
+        ChunkSource { pages, large }
+    }
+
+    /// Bring the evicted pages among `len` bytes of `memnode` from `offset`
+    /// back from the backing store, if there is one.
+    fn page_in(
+        &self,
+        mnode: Mnode,
+        memnode: &mut MemNode,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), FileSystemError> {
+        let store = match &self.policy.backing_store {
+            Some((store, _)) => store,
+            None => return Ok(()),
+        };
+        let loaded = memnode
+            .page_in(offset, len, |offset, page| {
+                store.read_page(mnode, offset, page)
+            })
+            .map_err(|e| {
+                ratelimited!(
+                    warn,
+                    "paging in mnode {} at {} failed: {:?}",
+                    mnode,
+                    offset,
+                    e
+                );
+                e
+            })?;
+        self.track(mnode, loaded.into_iter());
+        Ok(())
+    }
+
+    /// Let the eviction clock visit the resident `buffers` of `mnode`. A
+    /// buffer that can't be tracked just stays in memory.
+    fn track(&self, mnode: Mnode, buffers: impl Iterator<Item = usize>) {
+        if self.policy.backing_store.is_none() {
+            return;
+        }
+        let mut resident = self.resident.lock();
+        for buffer in buffers {
+            if resident.try_reserve(1).is_err() {
+                return;
+            }
+            resident.push_back((mnode, buffer));
+        }
+    }
+
+    /// Evict buffers until at most the working set of the backing store is
+    /// resident. Must be called without holding the lock of any mnode.
+    fn evict(&self) {
+        let max_pages = match &self.policy.backing_store {
+            Some((_, max_pages)) => *max_pages,
+            None => return,
+        };
+        self.clock(false, |resident| resident <= max_pages);
+    }
+
+    /// Evict buffers until `enough` holds for the number of resident ones, or
+    /// the clock went around twice. Buffers used since the clock last passed
+    /// get a second chance; pinned ones and those that fail to write back
+    /// stay resident. With `clean`, only the buffers the backing store holds
+    /// already are evicted. Must be called without holding the lock of any
+    /// mnode.
+    fn clock(&self, clean: bool, enough: impl Fn(usize) -> bool) {
+        let store = match &self.policy.backing_store {
+            Some((store, _)) => store,
+            None => return,
+        };
+        let mut visits = self.resident.lock().len().saturating_mul(2);
+        while visits > 0 {
+            visits -= 1;
+            // The clock isn't locked while a buffer is evicted, as writes
+            // track their buffers with the file locked.
+            let (mnode, buffer) = {
+                let mut resident = self.resident.lock();
+                if enough(resident.len()) {
+                    break;
+                }
+                match resident.pop_front() {
+                    Some(entry) => entry,
+                    None => break,
+                }
+            };
+            let memnode = match self.memnode(mnode) {
+                Some(memnode) => memnode,
+                None => continue,
+            };
+            let evicted = memnode.write().evict(buffer, |offset, page| match clean {
+                true => Err(FileSystemError::Busy),
+                false => store.write_page(mnode, offset, page),
+            });
+            match evicted {
+                Ok(Eviction::Evicted) | Ok(Eviction::Gone) => {}
+                Ok(Eviction::Kept) => self.track(mnode, core::iter::once(buffer)),
+                // A changed buffer skipped by a clean eviction.
+                Err(_) if clean => self.track(mnode, core::iter::once(buffer)),
+                Err(e) => {
+                    ratelimited!(
+                        warn,
+                        "writing back buffer {} of mnode {} failed: {:?}",
+                        buffer,
+                        mnode,
+                        e
+                    );
+                    self.track(mnode, core::iter::once(buffer));
+                }
+            }
+        }
+    }
+
+    /// The current time according to the clock hook, zero without a clock.
+    fn now(&self) -> u64 {
+        self.policy.clock.as_ref().map_or(0, |clock| clock.now())
+    }
+
+    /// The credentials of the caller according to the caller hook.
+    fn credentials(&self) -> Credentials {
+        self.policy
+            .caller
+            .as_ref()
+            .map_or_else(Credentials::default, |caller| caller.credentials())
+    }
+
+    /// Charge the caller for an operation on `bytes` bytes, if the
+    /// file-system is throttled.
+    fn throttle(&self, bytes: usize) -> Result<(), FileSystemError> {
+        match &self.policy.throttle {
+            Some(throttle) => throttle.charge(self.credentials().pid, self.now(), bytes),
+            None => Ok(()),
+        }
+    }
+
+    /// Stop throttling the process `pid` and free its buckets, e.g. once it
+    /// exited; it starts with full buckets if it makes calls again.
+    pub fn forget_process(&self, pid: u64) {
+        if let Some(throttle) = &self.policy.throttle {
+            throttle.forget(pid);
+        }
+    }
+
+    /// Tell the observer about a modification.
+    fn notify(&self, event: Event) {
+        fs_log!(debug, "{:?}", event);
+        if let Some(observer) = &self.policy.observer {
+            observer.notify(&event);
+        }
+    }
+
+    /// The key of `pathname` in the directories; paths are folded to lower
+    /// case if the file-system is case-insensitive.
+    fn key<'a>(&self, pathname: &'a str) -> Result<Cow<'a, str>, FileSystemError> {
+        if self.policy.case_sensitive || !pathname.bytes().any(|b| b.is_ascii_uppercase()) {
+            return Ok(Cow::Borrowed(pathname));
+        }
+        let mut key = try_to_string(pathname)?;
+        key.make_ascii_lowercase();
+        Ok(Cow::Owned(key))
+    }
+
+    /// Account for `bytes` more file data, failing if that exceeds the limit.
+    fn reserve_bytes(&self, bytes: usize) -> Result<(), FileSystemError> {
+        let max_bytes = self.policy.max_bytes;
+        self.used_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|used| *used <= max_bytes)
+            })
+            .map(|_| ())
+            .map_err(|used| {
+                ratelimited!(
+                    info,
+                    "no space for {} more bytes, {} of {} used",
+                    bytes,
+                    used,
+                    max_bytes
+                );
+                FileSystemError::NoSpace
+            })
+    }
+
+    /// Account for one more mnode, failing if that exceeds the limit.
+    fn reserve_file(&self) -> Result<(), FileSystemError> {
+        let max_files = self.policy.max_files;
+        self.nfiles
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                Some(n + 1).filter(|n| *n <= max_files)
+            })
+            .map(|_| ())
+            .map_err(|_| FileSystemError::NoSpace)
+    }
+
+    /// Check that the path and its components fit in the length limits.
+    fn check_new_path(&self, pathname: &str) -> Result<(), FileSystemError> {
+        if pathname.len() > MAX_PATH_LEN || pathname.split('/').any(|c| c.len() > MAX_NAME_LEN) {
+            return Err(FileSystemError::NameTooLong);
+        }
+        Ok(())
+    }
+
+    /// Check that `victim`, the entry of a locked directory, can be removed
+    /// and replaced by an mnode of type `node_type`, and mark it as unlinked.
+    fn unlink_entry(
+        mnodes: &MnodeMap<L>,
+        victim: &Arc<Mnode>,
+        node_type: NodeType,
+    ) -> Result<(), FileSystemError> {
+        let mut memnode = match mnodes.get(**victim) {
+            Some(memnode) => memnode.write(),
+            None => return Err(FileSystemError::InvalidFile),
+        };
+        match (node_type, memnode.get_mnode_type()) {
+            (NodeType::File, NodeType::Directory) => return Err(FileSystemError::IsADirectory),
+            (NodeType::Directory, NodeType::File) => return Err(FileSystemError::NotADirectory),
+            (_, NodeType::Directory) if memnode.num_entries() > 0 => {
+                return Err(FileSystemError::DirectoryNotEmpty)
+            }
+            _ => {}
+        }
+        // The pathname has to be the only link to the memnode.
+        if Arc::strong_count(victim) > 1 {
+            return Err(FileSystemError::PermissionError);
+        }
+        memnode.set_unlinked();
+        Ok(())
     }
 }
 
-impl Default for MemFS {
+impl<S: BuildHasher + Clone + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
     /// Initialize the file system from the root directory.
-    fn default() -> MemFS {
-        let rootdir = "/";
-        let rootmnode = 1;
-
-        let mnodes = NrLock::<HashMap<Mnode, RwLock<MemNode>>>::default();
-        mnodes.write().insert(
-            rootmnode,
-            RwLock::new(
-                MemNode::new(
-                    rootmnode,
-                    rootdir,
-                    FileModes::S_IRWXU.into(),
-                    NodeType::Directory,
-                )
-                .unwrap(),
-            ),
-        );
-        let files = RwLock::new(HashMap::new());
-        files.write().insert(rootdir.to_string(), Arc::new(1));
-        let _root = (rootdir.to_string(), 1);
+    pub(crate) fn new(root_modes: Modes, n_files: usize, policy: Policy, hasher: S) -> MemFS<S, L> {
+        let mut root =
+            MemNode::new(ROOT_MNODE, Name::new("/"), root_modes, NodeType::Directory).unwrap();
+        root.set_modified(policy.clock.as_ref().map_or(0, |clock| clock.now()));
+        let slab = MnodeSlab::new();
+        let root = Arc::new_in(MnodeCell::new(root), slab.clone());
+        let readers = match &policy.topology {
+            Some(topology) => topology.cpu_ids(),
+            None => topology::machine().cpu_ids(),
+        };
+        let mnodes = MnodeTable::new(n_files + 1, readers);
+        mnodes.insert(ROOT_MNODE, root).unwrap();
+        let mcache = MnodeCache::new(mnodes.readers(), policy.mnode_cache_entries);
+        let counters = Counters::new(mnodes.readers());
+        let dcache = DentryCache::new(policy.dentry_cache_slots, hasher.clone());
+        let bloom = BloomFilter::new(policy.bloom_filter_counters, hasher.clone());
 
         MemFS {
             mnodes,
-            files,
-            _root,
-            nextmemnode: AtomicUsize::new(2),
+            root: Arc::new(ROOT_MNODE),
+            nextmemnode: MnodeCounter::new(2),
+            policy,
+            used_bytes: AtomicUsize::new(0),
+            nfiles: AtomicUsize::new(0),
+            dcache,
+            mcache,
+            bloom,
+            rename_lock: Mutex::new(()),
+            resident: Mutex::new(VecDeque::new()),
+            writeback: Mutex::new(VecDeque::new()),
+            pages: PageTable::new(hasher),
+            tails: TailPages::new(),
+            counters,
+            slab,
         }
     }
-}
 
-impl FileSystem for MemFS {
-    /// Create a file relative to the root directory.
-    fn create(&self, pathname: &str, modes: Modes) -> Result<Mnode, FileSystemError> {
-        // Check if the file with the same name already exists.
-        match self.files.read().get(&pathname.to_string()) {
-            Some(_) => return Err(FileSystemError::AlreadyPresent),
-            None => {}
+    /// Remove the file or empty directory at `pathname`.
+    fn remove(&self, pathname: &str, node_type: NodeType) -> Result<bool, FileSystemError> {
+        let key = self.key(pathname)?;
+        let (parent, name) = match split_last(&key) {
+            Some(split) => split,
+            None if node_type == NodeType::File => return Err(FileSystemError::IsADirectory),
+            None => return Err(FileSystemError::NotSupported),
+        };
+        if let Some(security) = &self.policy.security {
+            security.may_delete(&self.credentials(), pathname, self.resolve(&key)?)?;
         }
+        self.throttle(0)?;
 
-        let mnode_num = self.get_next_mno() as u64;
-        //TODO: For now all newly created mnode are for file. How to differentiate
-        // between a file and a directory. Take input from the user?
-        let memnode = match MemNode::new(mnode_num, pathname, modes, NodeType::File) {
-            Ok(memnode) => memnode,
-            Err(e) => return Err(e),
+        let mnode_num = {
+            let mnodes = self.mnodes.read(self.reader_tid());
+            let parent = self.walk(&mnodes, parent)?;
+            let mut dir = match mnodes.get(parent) {
+                Some(dir) => dir.write(),
+                None => return Err(FileSystemError::InvalidFile),
+            };
+            dir.check_writable_dir()?;
+            Self::unlink_entry(&mnodes, dir.lookup(name)?, node_type)?;
+            let mnode = dir.remove_entry(name)?;
+            self.dcache.invalidate(parent, name);
+            self.bloom.remove(parent, name);
+            dir.set_modified(self.now());
+            *mnode
         };
-        self.files
-            .write()
-            .insert(pathname.to_string(), Arc::new(mnode_num));
-        self.mnodes.write().insert(mnode_num, RwLock::new(memnode));
+        self.release(mnode_num);
 
-        Ok(mnode_num)
+        self.notify(Event::Delete {
+            path: pathname,
+            mnode: mnode_num,
+        });
+        Ok(true)
     }
 
-    /// Write data to a file.
-    fn write(
+    /// Free an mnode that was removed from the namespace.
+    fn release(&self, mnode_num: Mnode) {
+        let memnode = self.mnodes.remove(mnode_num);
+        // Nobody can find the mnode in the table anymore, so it can't be
+        // cached again.
+        self.mcache.purge(mnode_num);
+        // The mnode was unlinked, so its size can't change anymore.
+        if let Some(memnode) = memnode {
+            let size = memnode.read().get_file_size();
+            self.used_bytes.fetch_sub(size, Ordering::Relaxed);
+        }
+        // Whatever the store can't drop stays unused.
+        if let Some((store, _)) = &self.policy.backing_store {
+            let _ = store.discard(mnode_num);
+        }
+        self.nfiles.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Write data to a file, waiting for the lock of the file if `block`,
+    /// or failing with `WouldBlock` if it is taken otherwise.
+    pub(crate) fn write_with(
         &self,
         mnode_num: Mnode,
         buffer: &[u8],
         offset: usize,
+        block: bool,
     ) -> Result<usize, FileSystemError> {
-        match self.mnodes.read(mnode_num as usize - 1).get(&mnode_num) {
-            Some(mnode) => mnode.write().write(buffer, offset),
-            None => Err(FileSystemError::InvalidFile),
-        }
+        self.write_at(mnode_num, buffer, Some(offset), block)
+            .map(|(_, written)| written)
     }
 
-    /// Read data from a file.
-    fn read(
+    /// Write `buffer` at `offset`, or at the end of the file if there is
+    /// none, see `write_with()`. Returns the offset written at and the number
+    /// of bytes written.
+    fn write_at(
         &self,
         mnode_num: Mnode,
-        buffer: &mut [u8],
-        offset: usize,
-    ) -> Result<usize, FileSystemError> {
-        match self.mnodes.read(mnode_num as usize - 1).get(&mnode_num) {
-            Some(mnode) => mnode.read().read(buffer, offset),
-            None => Err(FileSystemError::InvalidFile),
-        }
-    }
+        buffer: &[u8],
+        offset: Option<usize>,
+        block: bool,
+    ) -> Result<(usize, usize), FileSystemError> {
+        fs_log!(
+            trace,
+            "write {} bytes at {:?} to mnode {}",
+            buffer.len(),
+            offset,
+            mnode_num
+        );
+        self.throttle(buffer.len())?;
+        let cpu = self.cpu();
+        let mut attempts = 0;
+        let written = loop {
+            let refused = self.refused();
+            match self.write_locked(mnode_num, buffer, offset, block, cpu) {
+                // The page pool is full; room is made without the lock of
+                // the file.
+                Err(FileSystemError::OutOfMemory) if self.refused() != refused => {
+                    if attempts == pool::ATTEMPTS || !self.make_room(buffer.len()) {
+                        ratelimited!(
+                            info,
+                            "page pool is full, {} bytes not written",
+                            buffer.len()
+                        );
+                        return Err(FileSystemError::NoSpace);
+                    }
+                    attempts += 1;
+                }
+                written => break written?,
+            }
+        };
+        let (offset, written) = written;
+        self.counters.write(cpu, written);
+        self.evict();
 
-    /// Check if a file exists in the file system or not.
-    fn lookup(&self, pathname: &str) -> Option<Arc<Mnode>> {
-        self.files
-            .read()
-            .get(&pathname.to_string())
-            .map(|mnode| Arc::clone(mnode))
+        self.notify(Event::Write {
+            mnode: mnode_num,
+            offset,
+            len: written,
+        });
+        Ok((offset, written))
     }
 
-    /// Find the size and type by giving the mnode number.
-    fn file_info(&self, mnode: Mnode) -> FileInfo {
-        match self.mnodes.read(mnode as usize - 1).get(&mnode) {
-            Some(mnode) => match mnode.read().get_mnode_type() {
-                NodeType::Directory => FileInfo {
-                    fsize: 0,
-                    ftype: NodeType::Directory.into(),
-                },
-                NodeType::File => FileInfo {
-                    fsize: mnode.read().get_file_size() as u64,
-                    ftype: NodeType::File.into(),
-                },
-            },
-            None => unreachable!("file_info: shouldn't reach here"),
-        }
-    }
+    /// The part of `write_at()` made with the file locked.
+    fn write_locked(
+        &self,
+        mnode_num: Mnode,
+        buffer: &[u8],
+        offset: Option<usize>,
+        block: bool,
+        cpu: usize,
+    ) -> Result<(usize, usize), FileSystemError> {
+        let memnode = self
+            .memnode(mnode_num)
+            .ok_or(FileSystemError::InvalidFile)?;
+        let mut memnode = match (memnode.try_write(), block) {
+            (Some(locked), _) => locked,
+            (None, true) => {
+                self.counters.contended(cpu);
+                memnode.write()
+            }
+            (None, false) => return Err(FileSystemError::WouldBlock),
+        };
 
-    /// Delete a file from the file-system.
-    fn delete(&self, pathname: &str) -> Result<bool, FileSystemError> {
-        match self.files.write().remove(&pathname.to_string()) {
-            Some(mnode) => {
-                // If the pathname is the only link to the memnode, then remove it.
-                match Arc::strong_count(&mnode) {
-                    1 => {
-                        self.mnodes.write().remove(&mnode);
-                        return Ok(true);
-                    }
-                    _ => {
-                        self.files.write().insert(pathname.to_string(), mnode);
-                        return Err(FileSystemError::PermissionError);
-                    }
-                }
+        let size = memnode.get_file_size();
+        let offset = offset.unwrap_or(size);
+        // Only the part of the write past the end of the file needs space.
+        let end = offset
+            .checked_add(buffer.len())
+            .ok_or(FileSystemError::NoSpace)?;
+        let start = offset.min(size);
+        self.page_in(mnode_num, &mut memnode, start, end - start)?;
+        let grow = end.saturating_sub(size);
+        self.reserve_bytes(grow)?;
+        let before = memnode.buffers();
+        let written = match memnode.write(buffer, offset, &self.chunks()) {
+            Ok(written) => written,
+            Err(e) => {
+                self.used_bytes.fetch_sub(grow, Ordering::Relaxed);
+                return Err(e);
             }
-            None => return Err(FileSystemError::InvalidFile),
         };
+        self.track(mnode_num, before..memnode.buffers());
+        self.queue_writeback(mnode_num, &mut memnode);
+        memnode.set_modified(self.now());
+        Ok((offset, written))
     }
 
-    fn truncate(&self, pathname: &str) -> Result<bool, FileSystemError> {
-        match self.files.read().get(&pathname.to_string()) {
-            Some(mnode) => match self.mnodes.read(0).get(mnode) {
-                Some(memnode) => memnode.write().file_truncate(),
-                None => return Err(FileSystemError::InvalidFile),
-            },
-            None => return Err(FileSystemError::InvalidFile),
-        }
+    /// The allocations refused by the page pool so far, zero without one.
+    fn refused(&self) -> usize {
+        self.policy
+            .page_pool
+            .as_ref()
+            .map_or(0, |pool| pool.refused())
     }
 
-    /// Rename a file from oldname to newname.
-    fn rename(&self, oldname: &str, newname: &str) -> Result<bool, FileSystemError> {
-        if self.files.read().get(oldname).is_none() {
-            return Err(FileSystemError::InvalidFile);
+    /// Make room for `bytes` more bytes in the full page pool the way it is
+    /// configured to; returns whether that may have worked. Must be called
+    /// without holding the lock of any mnode.
+    fn make_room(&self, bytes: usize) -> bool {
+        let pool = match &self.policy.page_pool {
+            Some(pool) => pool,
+            None => return false,
+        };
+        match pool.exhausted() {
+            Exhausted::Fail => false,
+            Exhausted::Evict => {
+                let used = pool.used();
+                self.clock(true, |_| {
+                    pool.used().saturating_add(bytes) <= pool.max_bytes()
+                });
+                pool.used() < used
+            }
+            Exhausted::Reclaim(reclaim) => reclaim.reclaim(bytes),
         }
+    }
 
-        // If the newfile exists then overwrite it with the oldfile.
-        if self.files.read().get(newname).is_some() {
-            self.delete(newname).unwrap();
-        }
+    /// The page pool the data of the files is allocated from, if the
+    /// file-system has one.
+    pub fn page_pool(&self) -> Option<&PagePool> {
+        self.policy.page_pool.as_deref()
+    }
 
-        let (_key, value) = self.files.write().remove_entry(oldname).unwrap();
-        match self.files.write().insert(newname.to_string(), value) {
-            None => return Ok(true),
-            Some(_) => return Err(FileSystemError::PermissionError),
-        }
+    /// Read data from a file, waiting for the lock of the file like
+    /// `write_with()`.
+    pub(crate) fn read_with(
+        &self,
+        mnode_num: Mnode,
+        buffer: &mut [u8],
+        offset: usize,
+        block: bool,
+    ) -> Result<usize, FileSystemError> {
+        fs_log!(
+            trace,
+            "read {} bytes at {} from mnode {}",
+            buffer.len(),
+            offset,
+            mnode_num
+        );
+        self.throttle(buffer.len())?;
+        let cpu = self.cpu();
+        let memnode = self
+            .memnode(mnode_num)
+            .ok_or(FileSystemError::InvalidFile)?;
+        {
+            let locked = match (memnode.try_read(), block) {
+                (Some(locked), _) => locked,
+                (None, true) => {
+                    self.counters.contended(cpu);
+                    memnode.read()
+                }
+                (None, false) => return Err(FileSystemError::WouldBlock),
+            };
+            if locked.is_resident(offset, buffer.len()) {
+                let read = locked.read(buffer, offset)?;
+                self.counters.read(cpu, read);
+                return Ok(read);
+            }
+        }
+        let read = {
+            let mut locked = match (memnode.try_write(), block) {
+                (Some(locked), _) => locked,
+                (None, true) => {
+                    self.counters.contended(cpu);
+                    memnode.write()
+                }
+                (None, false) => return Err(FileSystemError::WouldBlock),
+            };
+            self.page_in(mnode_num, &mut locked, offset, buffer.len())?;
+            locked.read(buffer, offset)
+        };
+        self.evict();
+        if let Ok(read) = read {
+            self.counters.read(cpu, read);
+        }
+        read
+    }
+
+    /// Give back the memory held beyond what the file-system stores: the room the files keep
+    /// for growth, and the spare capacity of the mnode table and of the tables of shared pages.
+    /// Meant for the memory pressure callbacks of the kernel. Returns the number of bytes freed.
+    pub fn compact(&self) -> usize {
+        let mut freed = 0;
+        for memnode in self.mnodes.read(self.reader_tid()).values() {
+            freed += memnode.write().compact();
+        }
+        freed + self.mnodes.compact() + self.pages.compact() + self.tails.compact()
+    }
+
+    /// List up to `max` entries of a directory in the order they were added,
+    /// starting after the entry whose `cookie` is given, or from the first
+    /// one with 0. Unlike names, cookies fit a file offset, e.g. for
+    /// `telldir()`/`seekdir()`. A listing continued from a cookie sees every
+    /// entry that stayed in the directory exactly once; entries added or
+    /// removed in the meantime may or may not show up.
+    pub fn readdir_from(
+        &self,
+        pathname: &str,
+        cookie: u64,
+        max: usize,
+    ) -> Result<Vec<DirEntry>, FileSystemError> {
+        let key = self.key(pathname)?;
+        self.list(self.resolve(&key)?, Cursor::Cookie(cookie), max)
+    }
+
+    /// List up to `max` entries of the directory open as `fd` from its
+    /// offset, and move the offset past them, like `getdents()`: the offset
+    /// is the cookie of the last entry listed, see `readdir_from()`.
+    pub fn readdir_fd(&self, fd: &Fd, max: usize) -> Result<Vec<DirEntry>, FileSystemError> {
+        if fd.is_path() {
+            return Err(FileSystemError::InvalidFileDescriptor);
+        }
+        let cookie = fd.get_offset() as u64;
+        let entries = self.list(fd.get_mnode(), Cursor::Cookie(cookie), max)?;
+        if let Some(entry) = entries.last() {
+            fd.update_offset(entry.cookie as usize);
+        }
+        Ok(entries)
+    }
+
+    /// The metadata of the file open as `fd`, like `fstat()`; works on path
+    /// descriptors too.
+    pub fn fstat(&self, fd: &Fd) -> Result<Metadata, FileSystemError> {
+        self.metadata(fd.get_mnode())
+    }
+
+    /// Get the type, size, modes, times and change counter of an mnode.
+    pub fn metadata(&self, mnode: Mnode) -> Result<Metadata, FileSystemError> {
+        match self.memnode(mnode) {
+            Some(memnode) => {
+                let stat = memnode.stat();
+                Ok(Metadata {
+                    mnode,
+                    ftype: stat.node_type.into(),
+                    fsize: stat.size as u64,
+                    modes: stat.modes.into(),
+                    ctime: stat.ctime,
+                    mtime: stat.mtime,
+                    version: stat.version,
+                })
+            }
+            None => Err(FileSystemError::InvalidFile),
+        }
+    }
+
+    /// Write the changed pages of `mnode` back to the backing store and make
+    /// them durable, then its metadata and path too if `metadata` is set, see
+    /// `restore()`. The pages are
+    /// flushed first, so the stored metadata never refers to data that a
+    /// crash could lose. Pages written back before, e.g. by `flush_some()`,
+    /// are made durable as well. Nothing needs to be done for a file-system
+    /// that only lives in memory.
+    fn sync(&self, mnode: Mnode, metadata: bool) -> Result<(), FileSystemError> {
+        let memnode = self.memnode(mnode).ok_or(FileSystemError::InvalidFile)?;
+        let store = match &self.policy.backing_store {
+            Some((store, _)) => store,
+            None => return Ok(()),
+        };
+        let synced = {
+            let mut memnode = memnode.write();
+            match memnode.get_mnode_type() {
+                NodeType::File => memnode
+                    .flush(0, usize::MAX, true, |offset, page| {
+                        store.write_page(mnode, offset, page)
+                    })
+                    .map(|_| ()),
+                NodeType::Directory => Ok(()),
+            }
+        };
+        let synced = synced
+            .and_then(|_| store.flush())
+            .and_then(|_| match metadata {
+                true => {
+                    let metadata = self.metadata(mnode)?;
+                    match self.paths(mnode)?.pop() {
+                        Some(path) => store.write_file_fua(&path, &metadata),
+                        None => store.write_metadata_fua(&metadata),
+                    }
+                }
+                false => Ok(()),
+            });
+        if let Err(e) = &synced {
+            ratelimited!(warn, "syncing mnode {} failed: {:?}", mnode, e);
+        }
+        synced
+    }
+
+    /// Recreate the files and directories whose metadata the backing store
+    /// kept with their paths, e.g. after the store was recovered from the
+    /// persistent memory a MemFS used before a crash, see `PmemStore`. They
+    /// keep their mnode numbers, so the pages of the store stay theirs, and
+    /// get their data and modes as of their last `fsync`; missing parent
+    /// directories are created with the modes of the owner. An entry that
+    /// can't be recreated anymore, e.g. because a newer file was synced at
+    /// its path, is discarded from the store.
+    ///
+    /// Has to be called before anything is created, and fails with `Busy`
+    /// otherwise. Returns the number of files and directories recreated.
+    pub fn restore(&self) -> Result<usize, FileSystemError> {
+        let store = match &self.policy.backing_store {
+            Some((store, _)) => store,
+            None => return Ok(0),
+        };
+        let mut files = store.files()?;
+        // Directories come before their entries, and newer files before the
+        // older ones at the same depth.
+        files.sort_by_key(|(path, metadata)| {
+            (components(path).count(), core::cmp::Reverse(metadata.mnode))
+        });
+        let last = files.iter().map(|(_, metadata)| metadata.mnode).max();
+        let next = core::cmp::max(last.unwrap_or(ROOT_MNODE), ROOT_MNODE) + 1;
+        if self
+            .nextmemnode
+            .compare_exchange(2, next as _, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(FileSystemError::Busy);
+        }
+
+        let mut restored = 0;
+        for (path, metadata) in files.iter() {
+            if metadata.mnode == ROOT_MNODE {
+                continue;
+            }
+            match self.restore_file(store.as_ref(), path, metadata) {
+                Ok(()) => restored += 1,
+                Err(FileSystemError::AlreadyPresent)
+                | Err(FileSystemError::NotADirectory)
+                | Err(FileSystemError::InvalidFile)
+                | Err(FileSystemError::NameTooLong) => {
+                    // Whatever the store can't drop stays unused.
+                    let _ = store.discard(metadata.mnode);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(restored)
+    }
+
+    /// Recreate the file or directory of `metadata` at `path`, with the
+    /// pages of the store, see `restore()`.
+    fn restore_file(
+        &self,
+        store: &dyn StorageBackend,
+        path: &str,
+        metadata: &Metadata,
+    ) -> Result<(), FileSystemError> {
+        if !path.starts_with('/') {
+            return Err(FileSystemError::InvalidFile);
+        }
+        for (end, _) in path.match_indices('/').skip(1) {
+            let modes = FileModes::S_IRWXU.into();
+            match self.create_node(&path[..end], modes, NodeType::Directory, None) {
+                Ok(_) | Err(FileSystemError::AlreadyPresent) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let node_type = match metadata.ftype {
+            ftype if ftype == NodeType::Directory.into() => NodeType::Directory,
+            _ => NodeType::File,
+        };
+        // The modes are set once the data is back, as they might not allow
+        // writing it.
+        let modes = FileModes::S_IRWXU.into();
+        let mnode = self.create_node(path, modes, node_type, Some(metadata.mnode))?;
+
+        let size = usize::try_from(metadata.fsize).map_err(|_| FileSystemError::FileTooLarge)?;
+        if node_type == NodeType::File && size > 0 {
+            let mut page = Vec::new();
+            page.try_reserve_exact(BASE_PAGE_SIZE)
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            page.resize(BASE_PAGE_SIZE, 0);
+            for offset in (0..size).step_by(BASE_PAGE_SIZE) {
+                // Holes were never written back.
+                match store.read_page(mnode, offset, &mut page) {
+                    Ok(()) => {}
+                    Err(FileSystemError::InvalidOffset) => page.fill(0),
+                    Err(e) => return Err(e),
+                }
+                let len = core::cmp::min(BASE_PAGE_SIZE, size - offset);
+                self.write(mnode, &page[..len], offset)?;
+            }
+        }
+        if let Some(memnode) = self.memnode(mnode) {
+            let mut memnode = memnode.write();
+            memnode.set_modes(metadata.modes);
+            memnode.set_modified(metadata.mtime);
+            memnode.set_changed(metadata.ctime);
+        }
+        Ok(())
+    }
+
+    /// Call `f` with the memory backing `len` bytes of the file `mnode` from
+    /// `offset`, as (pointer, length) pairs, e.g. to build the scatter-gather
+    /// list of a DMA transfer. Every pair is physically contiguous if the page
+    /// allocator hands out contiguous memory, and starts at a multiple of the
+    /// chunk alignment unless `offset` is in the middle of a chunk.
+    ///
+    /// The file stays locked while `f` runs, so its memory can't be freed or
+    /// moved; the pointers must not be used after `f` returns. Data written
+    /// through them bypasses the modes and timestamps of the file.
+    pub fn with_ranges<R>(
+        &self,
+        mnode: Mnode,
+        offset: usize,
+        len: usize,
+        f: impl FnOnce(&[(NonNull<u8>, usize)]) -> R,
+    ) -> Result<R, FileSystemError> {
+        let memnode = self.memnode(mnode).ok_or(FileSystemError::InvalidFile)?;
+        let r = {
+            let mut memnode = memnode.write();
+            self.page_in(mnode, &mut memnode, offset, len)?;
+            let ranges = memnode.ranges(offset, len, &self.chunks())?;
+            let r = f(&ranges);
+            memnode.update_checksums(offset, len);
+            r
+        };
+        self.evict();
+        Ok(r)
+    }
+
+    /// Pin the pages backing `len` bytes of the file `mnode` from `offset`, a
+    /// multiple of the page size, e.g. to map them into a user address space.
+    /// The last page is zero-filled past the end of the file.
+    ///
+    /// The pages stay valid until the `PinnedPages` is dropped, even if the
+    /// file is deleted in the meantime. Until then truncating the file fails
+    /// with `Busy`, and the file doesn't move to large pages. Pinning fails
+    /// with `NotSupported` if the chunks aren't page-aligned, see
+    /// `MemFSBuilder::chunk_align()`.
+    pub fn pin(
+        &self,
+        mnode: Mnode,
+        offset: usize,
+        len: usize,
+    ) -> Result<PinnedPages<L>, FileSystemError> {
+        let memnode = self.memnode(mnode).ok_or(FileSystemError::InvalidFile)?;
+        let pages = {
+            let mut locked = memnode.write();
+            self.page_in(mnode, &mut locked, offset, len)?;
+            locked.pin(offset, len, &self.chunks())?
+        };
+        self.evict();
+        Ok(PinnedPages { memnode, pages })
+    }
+
+    /// Back the page of the file `mnode` that holds `offset` and return it
+    /// pinned, e.g. from the page-fault handler of a lazily mapped file. A page
+    /// past the end of the file is allocated and zero-filled: the file grows
+    /// to the end of that page. Unpinning and errors are as for `pin()`.
+    pub fn fault(&self, mnode: Mnode, offset: usize) -> Result<PinnedPages<L>, FileSystemError> {
+        let memnode = self.memnode(mnode).ok_or(FileSystemError::InvalidFile)?;
+        let start = offset & !(BASE_PAGE_SIZE - 1);
+        let end = start
+            .checked_add(BASE_PAGE_SIZE)
+            .ok_or(FileSystemError::InvalidOffset)?;
+
+        let (pages, size) = {
+            let mut locked = memnode.write();
+            // Growing the file fills its last buffer, which has to be resident too.
+            let size = locked.get_file_size();
+            let from = start.min(size);
+            self.page_in(mnode, &mut locked, from, end - from)?;
+            let before = locked.buffers();
+            let grow = end.saturating_sub(size);
+            if grow > 0 {
+                self.reserve_bytes(grow)?;
+                if let Err(e) = locked.grow(end, &self.chunks()) {
+                    self.used_bytes.fetch_sub(grow, Ordering::Relaxed);
+                    return Err(e);
+                }
+                locked.set_modified(self.now());
+            }
+            self.track(mnode, before..locked.buffers());
+            self.queue_writeback(mnode, &mut locked);
+            (locked.pin(start, BASE_PAGE_SIZE, &self.chunks())?, size)
+        };
+        self.evict();
+
+        if end > size {
+            self.notify(Event::Write {
+                mnode,
+                offset: size,
+                len: end - size,
+            });
+        }
+        Ok(PinnedPages { memnode, pages })
+    }
+
+    /// Mark `len` bytes of the file `mnode` from `offset` as changed, e.g.
+    /// after a DMA transfer into them or when the dirty bit of a mapped page
+    /// is found set. Writes mark the pages they change on their own.
+    pub fn mark_dirty(
+        &self,
+        mnode: Mnode,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), FileSystemError> {
+        let memnode = self.memnode(mnode).ok_or(FileSystemError::InvalidFile)?;
+        {
+            let mut memnode = memnode.write();
+            self.page_in(mnode, &mut memnode, offset, len)?;
+            memnode.mark_dirty(offset, len)?;
+            self.queue_writeback(mnode, &mut memnode);
+        }
+        self.evict();
+        Ok(())
+    }
+
+    /// The offsets of the pages of the file `mnode` that changed since they
+    /// were last flushed by `msync()`.
+    pub fn dirty_pages(&self, mnode: Mnode) -> Result<Vec<usize>, FileSystemError> {
+        let memnode = self.memnode(mnode).ok_or(FileSystemError::InvalidFile)?;
+        let memnode = memnode.read();
+        memnode.dirty_pages()
+    }
+
+    /// Write back the changed pages among `len` bytes of the file `mnode` from
+    /// `offset`: `flush` gets the offset and the data of every such page, and
+    /// the page is clean once it returns `Ok`. Stops at the first error of
+    /// `flush`. Returns the number of pages written back.
+    ///
+    /// The file is locked while `flush` runs, so it must not call back into
+    /// the file-system for the same file.
+    pub fn msync<F>(
+        &self,
+        mnode: Mnode,
+        offset: usize,
+        len: usize,
+        flush: F,
+    ) -> Result<usize, FileSystemError>
+    where
+        F: FnMut(usize, &[u8]) -> Result<(), FileSystemError>,
+    {
+        let memnode = self.memnode(mnode).ok_or(FileSystemError::InvalidFile)?;
+        let mut memnode = memnode.write();
+        memnode.flush(offset, len, false, flush)
+    }
+
+    /// Advise how `len` bytes of the file `mnode` from `offset` are going to
+    /// be used, like `posix_fadvise` and `madvise`. Sequential and Random set
+    /// the access pattern of the whole file, which drives read-ahead; WillNeed
+    /// and DontNeed tell which pages to keep in memory.
+    pub fn advise(
+        &self,
+        mnode: Mnode,
+        offset: usize,
+        len: usize,
+        advice: Advice,
+    ) -> Result<(), FileSystemError> {
+        let memnode = self.memnode(mnode).ok_or(FileSystemError::InvalidFile)?;
+        let mut memnode = memnode.write();
+        memnode.advise(offset, len, advice)
+    }
+
+    /// Read ahead after a read of `len` bytes at `offset` through `fd`: if
+    /// the reads of the descriptor are sequential, or the file was advised to
+    /// be, the data that follows is brought into the CPU caches so the next
+    /// reads don't wait for memory. Returns the number of bytes read ahead.
+    pub fn read_ahead(&self, fd: &Fd, offset: usize, len: usize) -> Result<usize, FileSystemError> {
+        if fd.is_path() {
+            return Err(FileSystemError::InvalidFileDescriptor);
+        }
+        let memnode = self
+            .memnode(fd.get_mnode())
+            .ok_or(FileSystemError::InvalidFile)?;
+        let window = fd.read_ahead(offset, len, memnode.read().access()?);
+        let (start, window) = match window {
+            Some(window) => window,
+            None => return Ok(0),
+        };
+        {
+            // Pages still in memory don't need the write lock.
+            let locked = memnode.read();
+            if locked.is_resident(start, window) {
+                return locked.prefetch(start, window);
+            }
+        }
+        // Evicted pages are read back from the backing store ahead of time.
+        let fetched = {
+            let mut locked = memnode.write();
+            self.page_in(fd.get_mnode(), &mut locked, start, window)?;
+            locked.prefetch(start, window)?
+        };
+        self.evict();
+        Ok(fetched)
+    }
+
+    /// The access pattern advised for the file `mnode`, see `advise()`.
+    pub fn access(&self, mnode: Mnode) -> Result<Advice, FileSystemError> {
+        let memnode = self.memnode(mnode).ok_or(FileSystemError::InvalidFile)?;
+        let memnode = memnode.read();
+        memnode.access()
+    }
+
+    /// Keep the previous version of the file `mnode` on every write or
+    /// truncation from now on, at most `max_count` versions of `max_bytes`
+    /// bytes in all; the oldest are dropped first. Versions share the pages
+    /// the writes don't change with the file, which stays resident in memory.
+    /// Changes made through `pin()` or `with_ranges()` aren't versioned.
+    /// Paging the file in checks `cancel` between steps.
+    pub fn keep_versions(
+        &self,
+        mnode: Mnode,
+        max_count: usize,
+        max_bytes: usize,
+        cancel: &Cancel,
+    ) -> Result<(), FileSystemError> {
+        let memnode = self.memnode(mnode).ok_or(FileSystemError::InvalidFile)?;
+        let mut memnode = memnode.write();
+        let size = memnode.get_file_size();
+        for offset in (0..size).step_by(cancel::STEP) {
+            cancel.check()?;
+            let len = cancel::STEP.min(size - offset);
+            self.page_in(mnode, &mut memnode, offset, len)?;
+        }
+        cancel.check()?;
+        memnode.keep_versions(max_count, max_bytes)
+    }
+
+    /// Stop keeping the versions of the file `mnode` and drop the ones kept.
+    pub fn drop_versions(&self, mnode: Mnode) -> Result<(), FileSystemError> {
+        let memnode = self.memnode(mnode).ok_or(FileSystemError::InvalidFile)?;
+        let mut memnode = memnode.write();
+        memnode.drop_versions()
+    }
+
+    /// The versions kept of the file `mnode`, oldest first; none unless
+    /// `keep_versions()` was called for it.
+    pub fn versions(&self, mnode: Mnode) -> Result<Vec<VersionInfo>, FileSystemError> {
+        let memnode = self.memnode(mnode).ok_or(FileSystemError::InvalidFile)?;
+        let memnode = memnode.read();
+        memnode.versions()
+    }
+
+    /// Read the version `number` of the file `mnode` from `offset`, like
+    /// `read()`. Fails with `InvalidFile` once the version was dropped.
+    pub fn read_version(
+        &self,
+        mnode: Mnode,
+        number: u64,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<usize, FileSystemError> {
+        let memnode = self.memnode(mnode).ok_or(FileSystemError::InvalidFile)?;
+        let memnode = memnode.read();
+        memnode.read_version(number, buffer, offset)
+    }
+
+    /// Add a new file or directory at `pathname`, with the mnode number
+    /// `number` if it is given.
+    fn create_node(
+        &self,
+        pathname: &str,
+        modes: Modes,
+        node_type: NodeType,
+        number: Option<Mnode>,
+    ) -> Result<Mnode, FileSystemError> {
+        let key = self.key(pathname)?;
+        self.check_new_path(&key)?;
+        let (parent, name) = match (split_last(&key), split_last(pathname)) {
+            (Some((parent, name)), Some((_, display_name))) => (parent, (name, display_name)),
+            _ => return Err(FileSystemError::AlreadyPresent),
+        };
+        // Check if the file with the same name already exists.
+        if self.resolve(&key).is_ok() {
+            return Err(FileSystemError::AlreadyPresent);
+        }
+        if let Some(security) = &self.policy.security {
+            let parent = self.resolve(parent)?;
+            security.may_create(
+                &self.credentials(),
+                pathname,
+                parent,
+                node_type.into(),
+                modes,
+            )?;
+        }
+        self.throttle(0)?;
+
+        let mnode_num = number.unwrap_or_else(|| self.get_next_mno());
+        // The mnode keeps the name in the case it was created with, and
+        // shares it with the directory entry if the two are the same.
+        let display_name = Name::new(name.1);
+        let entry = if name.0 == name.1 {
+            Name::clone(&display_name)
+        } else {
+            Name::new(name.0)
+        };
+        let mut memnode = MemNode::new(mnode_num, display_name, modes, node_type)?;
+        if self.policy.checksums {
+            memnode.enable_checksums();
+        }
+        let now = self.now();
+        memnode.set_modified(now);
+        let mnode = Arc::try_new(mnode_num).map_err(|_| FileSystemError::OutOfMemory)?;
+        let memnode = Arc::try_new_in(MnodeCell::new(memnode), self.slab.clone())
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        self.reserve_file()?;
+
+        // Insert the mnode first, so the path never resolves to a missing mnode.
+        if let Err(e) = self.mnodes.insert(mnode_num, memnode) {
+            self.nfiles.fetch_sub(1, Ordering::Relaxed);
+            return Err(e);
+        }
+
+        // Another thread might have created the same path in the meantime.
+        let added = {
+            let mnodes = self.mnodes.read(self.reader_tid());
+            self.walk(&mnodes, parent)
+                .and_then(|parent| match mnodes.get(parent) {
+                    Some(dir) => {
+                        let mut dir = dir.write();
+                        self.bloom.insert(parent, name.0);
+                        if let Err(e) = dir.add_entry(Name::clone(&entry), mnode, node_type) {
+                            self.bloom.remove(parent, name.0);
+                            return Err(e);
+                        }
+                        dir.set_modified(now);
+                        if let Some(memnode) = mnodes.get(mnode_num) {
+                            memnode.write().set_link(parent, entry);
+                        }
+                        Ok(())
+                    }
+                    None => Err(FileSystemError::InvalidFile),
+                })
+        };
+        match added {
+            Ok(()) => {
+                self.notify(Event::Create {
+                    path: pathname,
+                    mnode: mnode_num,
+                });
+                Ok(mnode_num)
+            }
+            Err(e) => {
+                self.release(mnode_num);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl MemFS {
+    /// Initialize the file system with room for `n_files` files, so populating
+    /// it (e.g. unpacking an initramfs) doesn't repeatedly rehash the maps.
+    pub fn with_capacity(n_files: usize) -> MemFS {
+        MemFSBuilder::new().capacity(n_files).build()
+    }
+}
+
+impl Default for MemFS {
+    /// Initialize the file system from the root directory.
+    fn default() -> MemFS {
+        MemFSBuilder::new().build()
+    }
+}
+
+/// The reader id of the calling thread, handed out on its first call: one
+/// no other running thread has, reusing those of the threads that exited,
+/// so threads own their reader slots while there are enough of them.
+#[cfg(any(test, feature = "std"))]
+pub(crate) fn thread_reader() -> usize {
+    /// Gives the id back when the thread exits.
+    struct Reader(usize);
+
+    impl Drop for Reader {
+        fn drop(&mut self) {
+            FREE.lock().push(self.0);
+        }
+    }
+
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    static FREE: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+    std::thread_local! {
+        static READER: Reader = Reader(
+            FREE.lock()
+                .pop()
+                .unwrap_or_else(|| NEXT.fetch_add(1, Ordering::Relaxed)),
+        );
+    }
+    // Lookups made while the thread exits share a reader slot.
+    READER.try_with(|reader| reader.0).unwrap_or(usize::MAX)
+}
+
+/// The reader id the caller reads an `Rcu` with, see `Rcu::read()`: the
+/// one of the thread, see `thread_reader()`, as threads can be preempted
+/// while they read.
+#[cfg(any(test, feature = "std"))]
+pub(crate) fn reader_id(_cpu_id: Option<&dyn builder::CpuId>) -> usize {
+    thread_reader()
+}
+
+/// The reader id the caller reads an `Rcu` with, see `Rcu::read()`: the
+/// current CPU if there is a hook that tells it, and a CPU must not switch
+/// to another read while one runs, e.g. because preemption is disabled like
+/// for kernel RCU. CPUs past the reader slots and callers without a hook
+/// share the slots.
+#[cfg(not(any(test, feature = "std")))]
+pub(crate) fn reader_id(cpu_id: Option<&dyn builder::CpuId>) -> usize {
+    match cpu_id {
+        Some(cpu_id) => cpu_id.current(),
+        None => usize::MAX,
+    }
+}
+
+/// The directory an entry is renamed into: `to`, or `from` if the entry stays
+/// in the same directory.
+fn target_dir<'a, L: RawRwLock>(
+    from: &'a mut MemNode,
+    to: &'a mut Option<MnodeWriteGuard<L>>,
+) -> &'a mut MemNode {
+    match to {
+        Some(to) => to,
+        None => from,
+    }
+}
+
+impl<S: BuildHasher + Clone + Send + Sync, L: RawRwLock + Send + Sync> FileSystemRead
+    for MemFS<S, L>
+{
+    /// Read data from a file.
+    fn read(
+        &self,
+        mnode_num: Mnode,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<usize, FileSystemError> {
+        self.read_with(mnode_num, buffer, offset, true)
+    }
+
+    /// Check if a file exists in the file system or not.
+    fn lookup(&self, pathname: &str) -> Option<Arc<Mnode>> {
+        let key = self.key(pathname).ok()?;
+        let (parent, name) = match split_last(&key) {
+            Some(split) => split,
+            None => return Some(Arc::clone(&self.root)),
+        };
+        let mnodes = self.mnodes.read(self.reader_tid());
+        let parent = self.walk(&mnodes, parent).ok()?;
+        // Most misses are answered without locking the directory.
+        if !self.bloom.may_contain(parent, name) {
+            return None;
+        }
+        let dir = mnodes.get(parent)?.read();
+        dir.lookup(name).ok().cloned()
+    }
+
+    /// Find the size and type by giving the mnode number.
+    fn file_info(&self, mnode: Mnode) -> Result<FileInfo, FileSystemError> {
+        match self.memnode(mnode) {
+            Some(mnode) => {
+                let stat = mnode.stat();
+                Ok(FileInfo {
+                    fsize: stat.size as u64,
+                    ftype: stat.node_type.into(),
+                })
+            }
+            None => Err(FileSystemError::InvalidFile),
+        }
+    }
+
+    /// List up to `max` entries of a directory sorted by name, starting after
+    /// the entry named `after` so a listing can be continued.
+    fn readdir(
+        &self,
+        pathname: &str,
+        after: Option<&str>,
+        max: usize,
+    ) -> Result<Vec<DirEntry>, FileSystemError> {
+        let key = self.key(pathname)?;
+        self.list(self.resolve(&key)?, Cursor::Name(after), max)
+    }
+}
+
+impl<S: BuildHasher + Clone + Send + Sync, L: RawRwLock + Send + Sync> FileSystem for MemFS<S, L> {
+    /// Create a file.
+    fn create(&self, pathname: &str, modes: Modes) -> Result<Mnode, FileSystemError> {
+        self.create_node(pathname, modes, NodeType::File, None)
+    }
+
+    /// Create a directory.
+    fn mkdir(&self, pathname: &str, modes: Modes) -> Result<Mnode, FileSystemError> {
+        self.create_node(pathname, modes, NodeType::Directory, None)
+    }
+
+    /// Write data to a file.
+    fn write(
+        &self,
+        mnode_num: Mnode,
+        buffer: &[u8],
+        offset: usize,
+    ) -> Result<usize, FileSystemError> {
+        self.write_with(mnode_num, buffer, offset, true)
+    }
+
+    /// Write data at the end of a file.
+    fn append(&self, mnode_num: Mnode, buffer: &[u8]) -> Result<(usize, usize), FileSystemError> {
+        let (offset, written) = self.write_at(mnode_num, buffer, None, true)?;
+        Ok((offset + written, written))
+    }
+
+    /// Delete a file from the file-system.
+    fn delete(&self, pathname: &str) -> Result<bool, FileSystemError> {
+        self.remove(pathname, NodeType::File)
+    }
+
+    /// Delete an empty directory from the file-system.
+    fn rmdir(&self, pathname: &str) -> Result<bool, FileSystemError> {
+        self.remove(pathname, NodeType::Directory)
+    }
+
+    fn truncate(&self, pathname: &str) -> Result<bool, FileSystemError> {
+        let key = self.key(pathname)?;
+        let mnode_num = self.resolve(&key)?;
+        self.throttle(0)?;
+
+        match self.memnode(mnode_num) {
+            Some(memnode) => {
+                let mut memnode = memnode.write();
+                let size = memnode.get_file_size();
+                memnode.file_truncate()?;
+                memnode.set_modified(self.now());
+                self.used_bytes.fetch_sub(size, Ordering::Relaxed);
+            }
+            None => return Err(FileSystemError::InvalidFile),
+        }
+
+        self.notify(Event::Truncate {
+            path: pathname,
+            mnode: mnode_num,
+        });
+        Ok(true)
+    }
+
+    /// Rename a file or directory from oldname to newname.
+    fn rename(&self, oldname: &str, newname: &str) -> Result<bool, FileSystemError> {
+        let oldkey = self.key(oldname)?;
+        let newkey = self.key(newname)?;
+        let mnode = self.resolve(&oldkey)?;
+        let (oldparent, oldentry) = split_last(&oldkey).ok_or(FileSystemError::NotSupported)?;
+        if components(&oldkey).eq(components(&newkey)) {
+            return Ok(true);
+        }
+        if let Some(security) = &self.policy.security {
+            security.may_rename(&self.credentials(), oldname, newname, mnode)?;
+        }
+        self.throttle(0)?;
+        self.check_new_path(&newkey)?;
+        // The ancestors of the file aren't empty, so they can't be replaced.
+        if is_below(&newkey, &oldkey) {
+            return Err(FileSystemError::DirectoryNotEmpty);
+        }
+        let (newparent, newentry) =
+            split_last(&newkey).ok_or(FileSystemError::DirectoryNotEmpty)?;
+        let name = Name::new(newentry);
+
+        let now = self.now();
+        let (mnode_num, replaced) = {
+            let _renaming = self.rename_lock.lock();
+            let mnodes = self.mnodes.read(self.reader_tid());
+            let node_type = |mnode: Mnode| match mnodes.get(mnode) {
+                Some(memnode) => Ok(memnode.read().get_mnode_type()),
+                None => Err(FileSystemError::InvalidFile),
+            };
+
+            // A directory can't be moved below itself.
+            if is_below(&oldkey, &newkey) {
+                return match node_type(self.walk(&mnodes, &oldkey)?)? {
+                    NodeType::Directory => Err(FileSystemError::InvalidFlags),
+                    NodeType::File => Err(FileSystemError::NotADirectory),
+                };
+            }
+            let oldparent_mnode = self.walk(&mnodes, oldparent)?;
+            let newparent_mnode = self.walk(&mnodes, newparent)?;
+            let olddir = mnodes
+                .get(oldparent_mnode)
+                .ok_or(FileSystemError::InvalidFile)?;
+            let newdir = mnodes
+                .get(newparent_mnode)
+                .ok_or(FileSystemError::InvalidFile)?;
+
+            // Lock the ancestor first, like the walks do.
+            let (mut from, mut to) = if oldparent_mnode == newparent_mnode {
+                (olddir.write(), None)
+            } else if is_below(oldparent, newparent) {
+                let from = olddir.write();
+                (from, Some(newdir.write()))
+            } else {
+                let to = newdir.write();
+                (olddir.write(), Some(to))
+            };
+
+            let mnode_num = **from.lookup(oldentry)?;
+            let moved_type = node_type(mnode_num)?;
+            from.check_writable_dir()?;
+            let target = target_dir(&mut from, &mut to);
+            target.reserve_entries()?;
+            // If the newfile exists then overwrite it with the oldfile.
+            let replaced = match target.lookup(newentry) {
+                Ok(victim) => {
+                    Self::unlink_entry(&mnodes, victim, moved_type)?;
+                    Some(**victim)
+                }
+                Err(FileSystemError::InvalidFile) => None,
+                Err(e) => return Err(e),
+            };
+
+            // The target has room for the new entry, so nothing can fail
+            // anymore and the rename is all or nothing.
+            if replaced.is_some() {
+                target.remove_entry(newentry)?;
+                self.dcache.invalidate(newparent_mnode, newentry);
+            }
+            let mnode = from.remove_entry(oldentry)?;
+            self.dcache.invalidate(oldparent_mnode, oldentry);
+            self.bloom.remove(oldparent_mnode, oldentry);
+            from.set_modified(now);
+            if replaced.is_none() {
+                self.bloom.insert(newparent_mnode, newentry);
+            }
+            let target = target_dir(&mut from, &mut to);
+            // The entry keeps the type of the mnode it names, whatever it
+            // replaced.
+            target.add_entry(Name::clone(&name), mnode, moved_type)?;
+            target.set_modified(now);
+            if let Some(memnode) = mnodes.get(mnode_num) {
+                memnode.write().set_link(newparent_mnode, name);
+            }
+            (mnode_num, replaced)
+        };
+        if let Some(replaced) = replaced {
+            self.release(replaced);
+        }
+
+        if let Some(memnode) = self.memnode(mnode_num) {
+            memnode.write().set_changed(now);
+        }
+        self.notify(Event::Rename {
+            oldpath: oldname,
+            newpath: newname,
+            mnode: mnode_num,
+        });
+        Ok(true)
+    }
+
+    /// Write the changed data and the metadata of a file to the backing
+    /// store, if there is one.
+    fn fsync(&self, mnode: Mnode) -> Result<(), FileSystemError> {
+        self.sync(mnode, true)
+    }
+
+    /// Write the changed data of a file to the backing store, if there is one.
+    fn fdatasync(&self, mnode: Mnode) -> Result<(), FileSystemError> {
+        self.sync(mnode, false)
+    }
+
+    /// Ask the security policy, if there is one.
+    fn may_open(
+        &self,
+        pathname: &str,
+        mnode: Mnode,
+        flags: FileFlags,
+    ) -> Result<(), FileSystemError> {
+        match &self.policy.security {
+            Some(security) => security.may_open(&self.credentials(), pathname, mnode, flags),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    /// Invalid mnode numbers and paths are reported as errors instead of panicking.
+    fn test_invalid_arguments() {
+        let memfs = MemFS::default();
+        let mut buffer = [0; 16];
+        for mnode in [0, 2, 193, u64::MAX] {
+            assert_eq!(memfs.file_info(mnode), Err(FileSystemError::InvalidFile));
+            assert_eq!(
+                memfs.read(mnode, &mut buffer, 0),
+                Err(FileSystemError::InvalidFile)
+            );
+            assert_eq!(
+                memfs.write(mnode, &buffer, 0),
+                Err(FileSystemError::InvalidFile)
+            );
+        }
+        assert_eq!(memfs.file_info(1).unwrap().fsize, 0);
+        assert_eq!(memfs.truncate("/nrfs"), Err(FileSystemError::InvalidFile));
+        assert_eq!(memfs.delete("/nrfs"), Err(FileSystemError::InvalidFile));
+    }
+
+    #[test]
+    /// A file-system with capacity hints starts out like the default one.
+    fn test_with_capacity() {
+        let memfs = MemFS::with_capacity(1000);
+        assert!(memfs.mnodes.read(memfs.reader_tid()).capacity() >= 1001);
+        assert!(memfs.lookup("/").is_some());
+        for i in 0..1000 {
+            let path = alloc::format!("/file-{}", i);
+            memfs.create(&path, FileModes::S_IRWXU.into()).unwrap();
+        }
+        assert_eq!(memfs.memory_usage().files, 1000);
+    }
+
+    #[test]
+    /// Cached mnodes are dropped when their file is deleted.
+    fn test_mnode_cache() {
+        let memfs = MemFS::default();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[0xb; 10], 0), Ok(10));
+        assert_eq!(memfs.file_info(mnode).unwrap().fsize, 10);
+        assert_eq!(memfs.delete("/nrfs"), Ok(true));
+        assert_eq!(
+            memfs.write(mnode, &[0xb; 10], 0),
+            Err(FileSystemError::InvalidFile)
+        );
+        assert_eq!(memfs.file_info(mnode), Err(FileSystemError::InvalidFile));
+        assert_eq!(memfs.memory_usage().data_bytes, 0);
+    }
+
+    #[test]
+    /// With a CPU hook, every CPU uses its own mnode cache and counters, but
+    /// threads still read the mnode table through their own slots, as they
+    /// can be preempted while they read.
+    fn test_reader_slot_per_cpu() {
+        struct Cpu(AtomicUsize);
+        impl builder::CpuId for Cpu {
+            fn current(&self) -> usize {
+                self.0.load(Ordering::Relaxed)
+            }
+        }
+
+        let cpu = Arc::new(Cpu(AtomicUsize::new(0)));
+        let memfs = MemFSBuilder::new().cpu_id(cpu.clone()).build();
+        let tid = memfs.reader_tid();
+        assert_eq!(memfs.cpu(), 0);
+        cpu.0.store(1, Ordering::Relaxed);
+        assert_eq!(memfs.cpu(), 1);
+        assert_eq!(memfs.reader_tid(), tid);
+
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[1; 4], 0), Ok(4));
+        assert_eq!(memfs.file_info(mnode).unwrap().fsize, 4);
+    }
+
+    #[test]
+    /// Every thread keeps its reader slot for all the mnodes, and threads
+    /// that run at the same time get slots of their own.
+    fn test_reader_slot_per_thread() {
+        let memfs = Arc::new(MemFS::default());
+        let tid = memfs.reader_tid();
+        assert_eq!(memfs.cpu(), tid);
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let spawn = || {
+            let (memfs, barrier) = (memfs.clone(), barrier.clone());
+            std::thread::spawn(move || {
+                let tids = (memfs.reader_tid(), memfs.reader_tid());
+                barrier.wait();
+                tids
+            })
+        };
+        let (first, second) = (spawn(), spawn());
+        let (first, second) = (first.join().unwrap(), second.join().unwrap());
+        assert_eq!(first.0, first.1);
+        assert_ne!(first.0, tid);
+        assert_ne!(first.0, second.0);
+    }
+
+    #[test]
+    /// Directories are listed in name order and listings can be continued.
+    fn test_readdir() {
+        let memfs = MemFS::default();
+        memfs.mkdir("/dir", FileModes::S_IRWXU.into()).unwrap();
+        for name in ["c", "a", "b"] {
+            let path = alloc::format!("/dir/{}", name);
+            memfs.create(&path, FileModes::S_IRWXU.into()).unwrap();
+        }
+        let names = |entries: Vec<DirEntry>| -> Vec<String> {
+            entries.into_iter().map(|entry| entry.name).collect()
+        };
+
+        assert_eq!(
+            names(memfs.readdir("/dir", None, 10).unwrap()),
+            ["a", "b", "c"]
+        );
+        assert_eq!(names(memfs.readdir("/dir", Some("a"), 1).unwrap()), ["b"]);
+        assert_eq!(names(memfs.readdir("/dir", Some("b"), 10).unwrap()), ["c"]);
+        assert!(memfs.readdir("/dir", Some("c"), 10).unwrap().is_empty());
+        assert_eq!(names(memfs.readdir("/", None, 10).unwrap()), ["dir"]);
+        assert_eq!(
+            memfs.readdir("/dir/a", None, 10),
+            Err(FileSystemError::NotADirectory)
+        );
+        assert_eq!(
+            memfs.readdir("/missing", None, 10),
+            Err(FileSystemError::InvalidFile)
+        );
+    }
+
+    #[test]
+    /// Listings through a descriptor continue from its offset, across
+    /// changes to the directory.
+    fn test_readdir_fd() {
+        let memfs = MemFS::default();
+        let rwx = FileModes::S_IRWXU.into();
+        let dir = memfs.mkdir("/dir", rwx).unwrap();
+        for name in ["c", "a", "b", "d"] {
+            memfs.create(&alloc::format!("/dir/{}", name), rwx).unwrap();
+        }
+        let names = |entries: Vec<DirEntry>| -> Vec<String> {
+            entries.into_iter().map(|entry| entry.name).collect()
+        };
+        let mut fd = Fd::init_fd();
+        fd.update_fd(dir, FileFlags::O_RDONLY);
+
+        assert_eq!(names(memfs.readdir_fd(&fd, 2).unwrap()), ["c", "a"]);
+        let telldir = fd.get_offset();
+        memfs.delete("/dir/a").unwrap();
+        memfs.delete("/dir/b").unwrap();
+        memfs.create("/dir/a", rwx).unwrap();
+        assert_eq!(names(memfs.readdir_fd(&fd, 10).unwrap()), ["d", "a"]);
+        assert!(memfs.readdir_fd(&fd, 10).unwrap().is_empty());
+
+        fd.update_offset(telldir);
+        assert_eq!(names(memfs.readdir_fd(&fd, 1).unwrap()), ["d"]);
+        let all = memfs.readdir_from("/dir", 0, 10).unwrap();
+        assert_eq!(names(all.clone()), ["c", "d", "a"]);
+        assert_eq!(
+            names(memfs.readdir_from("/dir", all[0].cookie, 1).unwrap()),
+            ["d"]
+        );
+
+        fd.update_fd(dir, FileFlags::O_PATH);
+        assert_eq!(
+            memfs.readdir_fd(&fd, 10),
+            Err(FileSystemError::InvalidFileDescriptor)
+        );
+    }
+
+    #[test]
+    /// A rename that can't add the new entry leaves both names in place.
+    fn test_rename_out_of_memory() {
+        let memfs = MemFS::default();
+        let rwx = FileModes::S_IRWXU.into();
+        let a = memfs.create("/a", rwx).unwrap();
+        memfs.mkdir("/dir", rwx).unwrap();
+        let b = memfs.create("/dir/b", rwx).unwrap();
+
+        entries::FAIL_RESERVE.with(|fail| fail.set(true));
+        let replace = memfs.rename("/a", "/dir/b");
+        let moved = memfs.rename("/a", "/dir/c");
+        let renamed = memfs.rename("/dir/b", "/dir/d");
+        entries::FAIL_RESERVE.with(|fail| fail.set(false));
+        assert_eq!(replace, Err(FileSystemError::OutOfMemory));
+        assert_eq!(moved, Err(FileSystemError::OutOfMemory));
+        assert_eq!(renamed, Err(FileSystemError::OutOfMemory));
+        assert_eq!(memfs.lookup("/a").map(|mnode| *mnode), Some(a));
+        assert_eq!(memfs.lookup("/dir/b").map(|mnode| *mnode), Some(b));
+        assert_eq!(memfs.readdir("/dir", None, 10).unwrap().len(), 1);
+
+        assert_eq!(memfs.rename("/a", "/dir/b"), Ok(true));
+        assert_eq!(memfs.lookup("/dir/b").map(|mnode| *mnode), Some(a));
+        assert!(memfs.lookup("/a").is_none());
+    }
+
+    #[test]
+    /// Listings tell directories from files, across renames that move or
+    /// replace entries.
+    fn test_readdir_types() {
+        let memfs = MemFS::default();
+        let rwx = FileModes::S_IRWXU.into();
+        memfs.mkdir("/d", rwx).unwrap();
+        memfs.create("/f", rwx).unwrap();
+        memfs.mkdir("/e", rwx).unwrap();
+        memfs.create("/g", rwx).unwrap();
+        let types = |memfs: &MemFS| -> Vec<(String, u64)> {
+            memfs
+                .readdir("/", None, 10)
+                .unwrap()
+                .into_iter()
+                .map(|entry| (entry.name, entry.ftype))
+                .collect()
+        };
+        let (dir, file) = (NodeType::Directory.into(), NodeType::File.into());
+        assert_eq!(
+            types(&memfs),
+            [
+                ("d".into(), dir),
+                ("e".into(), dir),
+                ("f".into(), file),
+                ("g".into(), file)
+            ]
+        );
+
+        assert_eq!(memfs.rename("/d", "/e"), Ok(true));
+        assert_eq!(memfs.rename("/f", "/g"), Ok(true));
+        assert_eq!(memfs.rename("/g", "/a"), Ok(true));
+        assert_eq!(types(&memfs), [("a".into(), file), ("e".into(), dir)]);
+    }
+
+    #[test]
+    /// The status of a file can be read while the file is locked.
+    fn test_stat_without_lock() {
+        let memfs = MemFS::default();
+        let mnode = memfs.create("/file", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[1; 10], 0), Ok(10));
+        let memnode = memfs.memnode(mnode).unwrap();
+        let mut locked = memnode.write();
+        assert_eq!(memfs.file_info(mnode).unwrap().fsize, 10);
+        assert_eq!(locked.write(&[1; 10], 10, &Default::default()), Ok(10));
+        assert_eq!(memfs.metadata(mnode).unwrap().fsize, 10);
+        drop(locked);
+        assert_eq!(memfs.metadata(mnode).unwrap().fsize, 20);
+    }
+
+    #[test]
+    /// Renaming a file onto itself is a no-op.
+    fn test_rename_to_itself() {
+        let memfs = MemFS::default();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.rename("/nrfs", "/nrfs"), Ok(true));
+        assert_eq!(memfs.lookup("/nrfs").map(|m| *m), Some(mnode));
+    }
+
+    #[test]
+    /// Pinned pages share the file data and outlive the file; the file can't be
+    /// truncated while they are pinned.
+    fn test_pin() {
+        let memfs = MemFS::default();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[0xb; 5000], 0), Ok(5000));
+        assert_eq!(
+            memfs.pin(mnode, 1, 10).err(),
+            Some(FileSystemError::InvalidOffset)
+        );
+        assert_eq!(
+            memfs.pin(mnode, 0, 5001).err(),
+            Some(FileSystemError::InvalidOffset)
+        );
+
+        let pinned = memfs.pin(mnode, 0, 5000).unwrap();
+        let pages = pinned.pages();
+        assert_eq!(pages.len(), 2);
+        let page = unsafe { core::slice::from_raw_parts(pages[1].as_ptr(), 4096) };
+        assert!(page[..904].iter().all(|b| *b == 0xb));
+        assert!(page[904..].iter().all(|b| *b == 0));
+        unsafe { *pages[0].as_ptr() = 0xc };
+        let mut buffer = [0; 1];
+        assert_eq!(memfs.read(mnode, &mut buffer, 0), Ok(1));
+        assert_eq!(buffer, [0xc]);
+
+        assert_eq!(memfs.truncate("/nrfs"), Err(FileSystemError::Busy));
+        drop(pinned);
+        assert_eq!(memfs.truncate("/nrfs"), Ok(true));
+
+        assert_eq!(memfs.write(mnode, &[0xb; 10], 0), Ok(10));
+        let pinned = memfs.pin(mnode, 0, 10).unwrap();
+        assert_eq!(memfs.delete("/nrfs"), Ok(true));
+        assert_eq!(unsafe { *pinned.pages()[0].as_ptr() }, 0xb);
+    }
+
+    #[test]
+    /// A fault past the end of a file backs the page with zeros.
+    fn test_fault() {
+        let memfs = MemFS::default();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[0xb; 10], 0), Ok(10));
+
+        let pinned = memfs.fault(mnode, 10000).unwrap();
+        assert_eq!(memfs.file_info(mnode).unwrap().fsize, 3 * 4096);
+        let page = unsafe { core::slice::from_raw_parts_mut(pinned.pages()[0].as_ptr(), 4096) };
+        assert!(page.iter().all(|b| *b == 0));
+        page[10000 - 2 * 4096] = 0xc;
+        let mut buffer = [0; 1];
+        assert_eq!(memfs.read(mnode, &mut buffer, 10000), Ok(1));
+        assert_eq!(buffer, [0xc]);
+
+        let first = memfs.fault(mnode, 5).unwrap();
+        assert_eq!(memfs.file_info(mnode).unwrap().fsize, 3 * 4096);
+        assert_eq!(unsafe { *first.pages()[0].as_ptr() }, 0xb);
+        assert_eq!(memfs.truncate("/nrfs"), Err(FileSystemError::Busy));
+        drop((pinned, first));
+        assert_eq!(memfs.truncate("/nrfs"), Ok(true));
+    }
+
+    #[test]
+    /// Compaction frees the room kept for growth, and the files can grow and
+    /// be mapped again afterwards.
+    fn test_compact() {
+        let memfs = MemFS::default();
+        let mut mnodes = Vec::new();
+        for i in 0..100 {
+            let path = alloc::format!("/file{}", i);
+            let mnode = memfs.create(&path, FileModes::S_IRWXU.into()).unwrap();
+            assert_eq!(memfs.write(mnode, &[i as u8; 10], 0), Ok(10));
+            mnodes.push(mnode);
+        }
+        for i in 0..90 {
+            assert_eq!(memfs.delete(&alloc::format!("/file{}", i)), Ok(true));
+        }
+        let before = memfs.memory_usage().allocated_bytes;
+        let freed = memfs.compact();
+        assert!(freed >= 10 * (4096 - 10));
+        assert!(memfs.memory_usage().allocated_bytes <= before - 10 * (4096 - 10));
+        assert_eq!(memfs.compact(), 0);
+
+        let mnode = mnodes[99];
+        assert_eq!(memfs.write(mnode, &[1; 4096], 10), Ok(4096));
+        let mut buffer = [0; 4096 + 10];
+        assert_eq!(memfs.read(mnode, &mut buffer, 0), Ok(4096 + 10));
+        assert_eq!(buffer[..10], [99; 10]);
+        assert!(buffer[10..].iter().all(|b| *b == 1));
+
+        let mnode = mnodes[98];
+        assert!(memfs.compact() > 0);
+        let pinned = memfs.fault(mnode, 0).unwrap();
+        let page = unsafe { core::slice::from_raw_parts(pinned.pages()[0].as_ptr(), 4096) };
+        assert_eq!(page[..10], [98; 10]);
+        assert!(page[10..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    /// The path of an mnode follows the renames of the mnode and of its
+    /// ancestors, and goes away with the mnode.
+    fn test_paths() {
+        let memfs = MemFS::default();
+        assert_eq!(memfs.paths(ROOT_MNODE), Ok(alloc::vec!["/".into()]));
+        let dir = memfs.mkdir("/d", FileModes::S_IRWXU.into()).unwrap();
+        let file = memfs.create("/d/f", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.paths(file), Ok(alloc::vec!["/d/f".into()]));
+        assert_eq!(memfs.paths(dir), Ok(alloc::vec!["/d".into()]));
+
+        assert_eq!(memfs.rename("/d", "/e"), Ok(true));
+        assert_eq!(memfs.paths(file), Ok(alloc::vec!["/e/f".into()]));
+        assert_eq!(memfs.rename("/e/f", "/g"), Ok(true));
+        assert_eq!(memfs.paths(file), Ok(alloc::vec!["/g".into()]));
+
+        assert_eq!(memfs.delete("/g"), Ok(true));
+        assert_eq!(memfs.paths(file), Err(FileSystemError::InvalidFile));
+        let other = memfs.create("/h", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.paths(other), Ok(alloc::vec!["/h".into()]));
+    }
+
+    #[test]
+    /// Only the pages changed since the last msync are written back.
+    fn test_msync() {
+        let memfs = MemFS::default();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[0xb; 3 * 4096], 0), Ok(3 * 4096));
+        assert_eq!(memfs.dirty_pages(mnode), Ok(alloc::vec![0, 4096, 8192]));
+        assert_eq!(memfs.msync(mnode, 0, 4096, |_, _| Ok(())), Ok(1));
+        assert_eq!(memfs.dirty_pages(mnode), Ok(alloc::vec![4096, 8192]));
+        assert_eq!(memfs.msync(mnode, 0, usize::MAX, |_, _| Ok(())), Ok(2));
+
+        let pinned = memfs.pin(mnode, 4096, 4096).unwrap();
+        unsafe { *pinned.pages()[0].as_ptr() = 0xc };
+        assert_eq!(memfs.dirty_pages(mnode), Ok(Vec::new()));
+        assert_eq!(memfs.mark_dirty(mnode, 4096, 1), Ok(()));
+        let mut flushed = Vec::new();
+        let written = memfs.msync(mnode, 0, usize::MAX, |offset, data| {
+            flushed.push((offset, data[0]));
+            Ok(())
+        });
+        assert_eq!(written, Ok(1));
+        assert_eq!(flushed, [(4096, 0xc)]);
+        assert_eq!(
+            memfs.mark_dirty(mnode, 3 * 4096, 1),
+            Err(FileSystemError::InvalidOffset)
+        );
+    }
+
+    #[test]
+    /// Access patterns stick to the file; range advice doesn't change them.
+    fn test_advise() {
+        let memfs = MemFS::default();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.access(mnode), Ok(Advice::Normal));
+        assert_eq!(memfs.advise(mnode, 0, 0, Advice::Sequential), Ok(()));
+        assert_eq!(memfs.advise(mnode, 0, 4096, Advice::WillNeed), Ok(()));
+        assert_eq!(memfs.advise(mnode, 0, usize::MAX, Advice::DontNeed), Ok(()));
+        assert_eq!(memfs.access(mnode), Ok(Advice::Sequential));
+        assert_eq!(memfs.advise(mnode, 0, 0, Advice::Random), Ok(()));
+        assert_eq!(memfs.access(mnode), Ok(Advice::Random));
+
+        assert_eq!(
+            memfs.advise(ROOT_MNODE, 0, 0, Advice::Random),
+            Err(FileSystemError::IsADirectory)
+        );
+        assert_eq!(memfs.access(193), Err(FileSystemError::InvalidFile));
+    }
+
+    #[test]
+    /// Sequential reads through a descriptor read ahead, up to the end of the file.
+    fn test_read_ahead() {
+        let memfs = MemFS::default();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[0xb; 40000], 0), Ok(40000));
+        let mut fd = Fd::init_fd();
+        fd.update_fd(mnode, FileFlags::O_RDONLY);
+
+        assert_eq!(memfs.read_ahead(&fd, 0, 4096), Ok(fd::READ_AHEAD_MIN));
+        assert_eq!(memfs.read_ahead(&fd, 4096, 4096), Ok(40000 - 8192));
+        assert_eq!(memfs.read_ahead(&fd, 0, 4096), Ok(0));
+        memfs.advise(mnode, 0, 0, Advice::Random).unwrap();
+        assert_eq!(memfs.read_ahead(&fd, 4096, 4096), Ok(0));
+    }
+
+    #[test]
+    /// Path descriptors can't read or write, but can be stat'ed and serve as
+    /// the directory of the `*at` operations.
+    fn test_path_fd() {
+        let memfs = MemFS::default();
+        let dir = memfs.mkdir("/dir", FileModes::S_IRWXU.into()).unwrap();
+        let file = memfs.create("/dir/a", FileModes::S_IRWXU.into()).unwrap();
+        let mut fds = fd::FdTable::default();
+        let flags = FileFlags::O_PATH | FileFlags::O_RDWR | FileFlags::O_CLOEXEC;
+        assert_eq!(fds.allocate(dir, flags), Ok(0));
+        assert_eq!(fds.allocate(file, FileFlags::O_PATH), Ok(1));
+        let dirfd = fds.get(0).unwrap();
+        assert!(dirfd.is_path());
+        assert!(!dirfd.get_flags().is_read() && !dirfd.get_flags().is_write());
+        assert_eq!(fds.fd_flags(0), Ok(FdFlags::FD_CLOEXEC));
+        assert_eq!(memfs.fstat(dirfd), memfs.metadata(dir));
+
+        assert_eq!(memfs.path_at(dirfd, "a").unwrap(), "/dir/a");
+        assert_eq!(memfs.path_at(dirfd, "/a").unwrap(), "/a");
+        let path = memfs.path_at(dirfd, "b").unwrap();
+        let b = memfs.create(&path, FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(*memfs.lookup("/dir/b").unwrap(), b);
+        assert_eq!(memfs.rename("/dir", "/moved"), Ok(true));
+        assert_eq!(memfs.path_at(dirfd, "b").unwrap(), "/moved/b");
+
+        let filefd = fds.get(1).unwrap();
+        assert_eq!(memfs.fstat(filefd).unwrap().mnode, file);
+        assert_eq!(
+            memfs.path_at(filefd, "b"),
+            Err(FileSystemError::NotADirectory)
+        );
+        assert_eq!(
+            memfs.read_ahead(filefd, 0, 4096),
+            Err(FileSystemError::InvalidFileDescriptor)
+        );
+    }
+
+    #[test]
+    /// Every change bumps the change counter, even without a clock.
+    fn test_change_counter() {
+        let memfs = MemFS::default();
+        let mnode = memfs.create("/file", FileModes::S_IRWXU.into()).unwrap();
+        let version = |mnode| memfs.metadata(mnode).unwrap().version;
+        let created = version(mnode);
+        let root = version(ROOT_MNODE);
+
+        memfs.write(mnode, &[0xb; 10], 0).unwrap();
+        let written = version(mnode);
+        assert!(written > created);
+        assert_eq!(memfs.read(mnode, &mut [0; 10], 0), Ok(10));
+        assert_eq!(version(mnode), written);
+        memfs.mark_dirty(mnode, 0, 10).unwrap();
+        let dirtied = version(mnode);
+        assert!(dirtied > written);
+
+        memfs.rename("/file", "/moved").unwrap();
+        assert!(version(mnode) > dirtied);
+        assert!(version(ROOT_MNODE) > root);
+        assert_eq!(memfs.metadata(mnode).unwrap().mtime, 0);
+    }
+
+    #[test]
+    /// A file keeping versions can be read as it was before each write.
+    fn test_versions() {
+        let memfs = MemFS::default();
+        let mnode = memfs.create("/file", FileModes::S_IRWXU.into()).unwrap();
+        memfs.write(mnode, b"first", 0).unwrap();
+        memfs
+            .keep_versions(mnode, 8, usize::MAX, &Cancel::new())
+            .unwrap();
+        memfs.write(mnode, b"second", 0).unwrap();
+        assert_eq!(memfs.truncate("/file"), Ok(true));
+
+        let versions = memfs.versions(mnode).unwrap();
+        assert_eq!(versions.len(), 2);
+        let mut buffer = [0; 8];
+        let read = memfs.read_version(mnode, versions[0].number, &mut buffer, 0);
+        assert_eq!(&buffer[..read.unwrap()], b"first");
+        let read = memfs.read_version(mnode, versions[1].number, &mut buffer, 0);
+        assert_eq!(&buffer[..read.unwrap()], b"second");
+
+        memfs.drop_versions(mnode).unwrap();
+        assert!(memfs.versions(mnode).unwrap().is_empty());
+        assert_eq!(
+            memfs.keep_versions(1, 8, usize::MAX, &Cancel::new()),
+            Err(FileSystemError::IsADirectory)
+        );
     }
 }
@@ -0,0 +1,87 @@
+//! Diagnostics through the `log` facade, enabled with the `log` feature.
+//!
+//! Operations log their start at `trace` and their effect on the namespace at
+//! `debug`, all with the `nrfs` target, so an embedder only has to install a
+//! logger to see them. Failures that can repeat at the rate of the calls,
+//! e.g. a backing store that stopped working, are rate-limited per call site.
+//! Without the feature the macros compile to nothing; their arguments are
+//! still type-checked, so they don't rot.
+
+/// Log at `$level` (`trace`, `debug`, `info`, `warn` or `error`).
+macro_rules! fs_log {
+    ($level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "log")]
+        log::$level!(target: "nrfs", $($arg)+);
+        #[cfg(not(feature = "log"))]
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    }};
+}
+
+/// Log at `$level` like `fs_log!`, but only the first `BURST` times and then
+/// once every `EVERY` times from this call site.
+macro_rules! ratelimited {
+    ($level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "log")]
+        {
+            static LIMIT: crate::logging::RateLimit = crate::logging::RateLimit::new();
+            if let Some(seen) = LIMIT.allow() {
+                log::$level!(
+                    target: "nrfs",
+                    "{} (seen {} times)",
+                    format_args!($($arg)+),
+                    seen
+                );
+            }
+        }
+        #[cfg(not(feature = "log"))]
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    }};
+}
+
+/// Messages logged by a call site before it is rate-limited.
+#[cfg(feature = "log")]
+const BURST: usize = 10;
+
+/// A rate-limited call site logs one message in this many.
+#[cfg(feature = "log")]
+const EVERY: usize = 1000;
+
+/// Counts the messages of a call site.
+#[cfg(feature = "log")]
+pub(crate) struct RateLimit(core::sync::atomic::AtomicUsize);
+
+#[cfg(feature = "log")]
+impl RateLimit {
+    pub(crate) const fn new() -> RateLimit {
+        RateLimit(core::sync::atomic::AtomicUsize::new(0))
+    }
+
+    /// Whether to log the next message, and if so, how many there were.
+    pub(crate) fn allow(&self) -> Option<usize> {
+        let seen = self.0.fetch_add(1, core::sync::atomic::Ordering::Relaxed) + 1;
+        match seen <= BURST || seen % EVERY == BURST % EVERY {
+            true => Some(seen),
+            false => None,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "log"))]
+mod test {
+    use super::*;
+
+    #[test]
+    /// A burst gets through, then one message in `EVERY`.
+    fn test_rate_limit() {
+        let limit = RateLimit::new();
+        let allowed: alloc::vec::Vec<usize> =
+            (0..3 * EVERY).filter_map(|_| limit.allow()).collect();
+        let mut expected: alloc::vec::Vec<usize> = (1..=BURST).collect();
+        expected.extend([BURST + EVERY, BURST + 2 * EVERY]);
+        assert_eq!(allowed, expected);
+    }
+}
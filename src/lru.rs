@@ -0,0 +1,130 @@
+//! Per-CPU caches of recently used mnodes.
+//!
+//! Reads and writes address files by mnode number, which normally means a
+//! lookup in the mnode table under one of its reader slots. Every CPU keeps
+//! the last few mnodes it used in a small LRU list, so repeated IO on the
+//! same files skips the table altogether.
+//!
+//! Mnodes are only cached while a version of the table is held, and removed
+//! from all the caches once the versions that contain them are gone, so a
+//! removed mnode can't linger in a cache.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use lock_api::RawRwLock;
+use spin::Mutex;
+
+use crate::mnode::MnodeCell;
+use crate::slab::MnodeSlab;
+use crate::Mnode;
+
+/// A shared reference to an mnode of the table.
+pub(crate) type MnodeRef<L = spin::RwLock<()>> = Arc<MnodeCell<L>, MnodeSlab>;
+
+/// Cached mnodes, most recently used first.
+type LruList<L> = Mutex<Vec<(Mnode, MnodeRef<L>)>>;
+
+pub(crate) struct MnodeCache<L: RawRwLock> {
+    /// One LRU list per CPU.
+    lists: Vec<LruList<L>>,
+    entries: usize,
+}
+
+impl<L: RawRwLock> MnodeCache<L> {
+    /// Create `cpus` lists of `entries` mnodes each; zero entries disable the
+    /// cache.
+    pub(crate) fn new(cpus: usize, entries: usize) -> MnodeCache<L> {
+        let cpus = if entries > 0 { cpus.max(1) } else { 0 };
+        MnodeCache {
+            lists: (0..cpus)
+                .map(|_| Mutex::new(Vec::with_capacity(entries)))
+                .collect(),
+            entries,
+        }
+    }
+
+    /// The list used by `cpu`.
+    fn list(&self, cpu: usize) -> Option<&LruList<L>> {
+        match self.lists.len() {
+            0 => None,
+            len => Some(&self.lists[cpu % len]),
+        }
+    }
+
+    /// Get a cached mnode and mark it as the most recently used one.
+    pub(crate) fn get(&self, cpu: usize, mnode: Mnode) -> Option<MnodeRef<L>> {
+        let mut list = self.list(cpu)?.lock();
+        let idx = list.iter().position(|(cached, _)| *cached == mnode)?;
+        list[..=idx].rotate_right(1);
+        Some(Arc::clone(&list[0].1))
+    }
+
+    /// Cache an mnode, evicting the least recently used one if the list is
+    /// full; a version of the mnode table has to be held.
+    pub(crate) fn insert(&self, cpu: usize, mnode: Mnode, memnode: &MnodeRef<L>) {
+        let mut list = match self.list(cpu) {
+            Some(list) => list.lock(),
+            None => return,
+        };
+        if list.iter().any(|(cached, _)| *cached == mnode) {
+            return;
+        }
+        if list.len() == self.entries {
+            list.pop();
+        }
+        list.insert(0, (mnode, Arc::clone(memnode)));
+    }
+
+    /// Bytes of heap memory held by the lists.
+    pub(crate) fn allocated_size(&self) -> usize {
+        self.lists.capacity() * size_of::<LruList<L>>()
+            + self
+                .lists
+                .iter()
+                .map(|list| list.lock().capacity() * size_of::<(Mnode, MnodeRef<L>)>())
+                .sum::<usize>()
+    }
+
+    /// Drop an mnode from all the lists; no version of the mnode table may
+    /// contain it anymore.
+    pub(crate) fn purge(&self, mnode: Mnode) {
+        for list in &self.lists {
+            list.lock().retain(|(cached, _)| *cached != mnode);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mnode::{MemNode, NodeType};
+    use crate::FileModes;
+
+    fn memnode(mnode: Mnode) -> MnodeRef {
+        let modes = FileModes::S_IRWXU.into();
+        let memnode = MemNode::new(mnode, "f".into(), modes, NodeType::File);
+        Arc::new_in(MnodeCell::new(memnode.unwrap()), MnodeSlab::new())
+    }
+
+    #[test]
+    /// The least recently used mnode is evicted first.
+    fn test_lru() {
+        let cache = MnodeCache::new(2, 2);
+        cache.insert(0, 2, &memnode(2));
+        cache.insert(0, 3, &memnode(3));
+        assert!(cache.get(0, 2).is_some());
+        cache.insert(0, 4, &memnode(4));
+        assert!(cache.get(0, 3).is_none());
+        assert!(cache.get(0, 2).is_some());
+        assert!(cache.get(1, 2).is_none());
+
+        cache.purge(2);
+        assert!(cache.get(0, 2).is_none());
+        assert!(cache.get(0, 4).is_some());
+
+        let disabled = MnodeCache::new(2, 0);
+        disabled.insert(0, 2, &memnode(2));
+        assert!(disabled.get(0, 2).is_none());
+    }
+}
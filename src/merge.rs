@@ -0,0 +1,314 @@
+//! Grafting the tree of another file-system into this one, e.g. to layer
+//! package bundles or to combine file-systems staged on different cores.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::hash::BuildHasher;
+use lock_api::RawRwLock;
+
+use crate::cancel::Cancel;
+use crate::mnode::NodeType;
+use crate::{
+    try_to_string, FileModes, FileSystem, FileSystemError, FileSystemRead, MemFS, Mnode, Modes,
+    BASE_PAGE_SIZE,
+};
+
+/// What `MemFS::merge()` does with an entry whose path is already taken,
+/// unless both are directories, which are merged.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Conflict {
+    /// Add the entry as `name.1`, or the first of `name.2`, `name.3` and so
+    /// on that is free.
+    Rename,
+    /// Remove the entry in the way first; a directory in the way must be
+    /// empty.
+    Overwrite,
+    /// Keep the entry in the way and leave out the other one, with all that
+    /// is below it.
+    Skip,
+}
+
+/// Entries listed at once from the other file-system.
+const BATCH: usize = 64;
+
+/// Bytes copied at once from the other file-system.
+const COPY_SIZE: usize = 16 * BASE_PAGE_SIZE;
+
+impl<S: BuildHasher + Clone + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
+    /// Copy the files and directories of `other` to the same paths here,
+    /// with their modes, settling the paths that are taken with `conflict`.
+    ///
+    /// The copies are made through the operations of the file-system, so they
+    /// are checked, limited and observed like any other. A failure stops the
+    /// merge and leaves what was copied so far; everything in `other` has to
+    /// be readable. `cancel` is checked before every entry and every chunk
+    /// of a file copied.
+    pub fn merge<S2, L2>(
+        &self,
+        other: &MemFS<S2, L2>,
+        conflict: Conflict,
+        cancel: &Cancel,
+    ) -> Result<(), FileSystemError>
+    where
+        S2: BuildHasher + Clone + Send + Sync,
+        L2: RawRwLock + Send + Sync,
+    {
+        // Directories still to merge, as their paths in `other` and here.
+        let mut pending = Vec::new();
+        pending
+            .try_reserve(1)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        pending.push((try_to_string("/")?, try_to_string("/")?));
+        // The directories created, which get their modes once they are full.
+        let mut created = Vec::new();
+
+        while let Some((from, to)) = pending.pop() {
+            let mut after: Option<String> = None;
+            loop {
+                let entries = other.readdir(&from, after.as_deref(), BATCH)?;
+                for entry in entries.iter() {
+                    cancel.check()?;
+                    let metadata = other.metadata(entry.mnode)?;
+                    let is_dir = metadata.ftype == NodeType::Directory.into();
+                    let (target, exists) = match self.place(&to, &entry.name, is_dir, conflict)? {
+                        Some(place) => place,
+                        None => continue,
+                    };
+                    if !is_dir {
+                        let mnode = self.create(&target, FileModes::S_IRWXU.into())?;
+                        let size = metadata.fsize as usize;
+                        self.copy(other, entry.mnode, size, mnode, cancel)?;
+                        self.set_modes(mnode, metadata.modes)?;
+                        continue;
+                    }
+                    if !exists {
+                        let mnode = self.mkdir(&target, FileModes::S_IRWXU.into())?;
+                        created
+                            .try_reserve(1)
+                            .map_err(|_| FileSystemError::OutOfMemory)?;
+                        created.push((mnode, metadata.modes));
+                    }
+                    pending
+                        .try_reserve(1)
+                        .map_err(|_| FileSystemError::OutOfMemory)?;
+                    pending.push((join(&from, &entry.name)?, target));
+                }
+                match entries.last() {
+                    Some(last) => after = Some(try_to_string(&last.name)?),
+                    None => break,
+                }
+            }
+        }
+
+        for (mnode, modes) in created {
+            self.set_modes(mnode, modes)?;
+        }
+        Ok(())
+    }
+
+    /// Where the entry `name` of `other` goes in the directory `dir`: its
+    /// path and whether it is an existing directory to merge into, or `None`
+    /// if it is skipped.
+    fn place(
+        &self,
+        dir: &str,
+        name: &str,
+        is_dir: bool,
+        conflict: Conflict,
+    ) -> Result<Option<(String, bool)>, FileSystemError> {
+        let path = join(dir, name)?;
+        let existing = match self.lookup(&path) {
+            Some(mnode) => self.metadata(*mnode)?.ftype == NodeType::Directory.into(),
+            None => return Ok(Some((path, false))),
+        };
+        if is_dir && existing {
+            return Ok(Some((path, true)));
+        }
+        match conflict {
+            Conflict::Skip => Ok(None),
+            Conflict::Overwrite => {
+                match existing {
+                    true => self.rmdir(&path)?,
+                    false => self.delete(&path)?,
+                };
+                Ok(Some((path, false)))
+            }
+            Conflict::Rename => {
+                for n in 1u64.. {
+                    let mut renamed = String::new();
+                    renamed
+                        .try_reserve(path.len() + 21)
+                        .map_err(|_| FileSystemError::OutOfMemory)?;
+                    // The room for the number was reserved.
+                    write!(renamed, "{}.{}", path, n).unwrap();
+                    if self.lookup(&renamed).is_none() {
+                        return Ok(Some((renamed, false)));
+                    }
+                }
+                unreachable!()
+            }
+        }
+    }
+
+    /// Copy the `size` bytes of the file `from` of `other` into the file `to`,
+    /// checking `cancel` before every `COPY_SIZE` bytes.
+    fn copy<S2, L2>(
+        &self,
+        other: &MemFS<S2, L2>,
+        from: Mnode,
+        size: usize,
+        to: Mnode,
+        cancel: &Cancel,
+    ) -> Result<(), FileSystemError>
+    where
+        S2: BuildHasher + Clone + Send + Sync,
+        L2: RawRwLock + Send + Sync,
+    {
+        let mut buffer = Vec::new();
+        buffer
+            .try_reserve(size.min(COPY_SIZE))
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        buffer.resize(size.min(COPY_SIZE), 0);
+        let mut offset = 0;
+        while offset < size {
+            cancel.check()?;
+            let len = buffer.len().min(size - offset);
+            let read = other.read(from, &mut buffer[..len], offset)?;
+            if read == 0 {
+                break;
+            }
+            self.write(to, &buffer[..read], offset)?;
+            offset += read;
+        }
+        Ok(())
+    }
+
+    /// Give `mnode` the `modes` it had in the other file-system.
+    fn set_modes(&self, mnode: Mnode, modes: Modes) -> Result<(), FileSystemError> {
+        let memnode = self.memnode(mnode).ok_or(FileSystemError::InvalidFile)?;
+        let mut memnode = memnode.write();
+        memnode.set_modes(modes);
+        memnode.set_changed(self.now());
+        Ok(())
+    }
+}
+
+/// The path of the entry `name` of the directory `dir`.
+pub(crate) fn join(dir: &str, name: &str) -> Result<String, FileSystemError> {
+    let mut path = String::new();
+    path.try_reserve(dir.len() + 1 + name.len())
+        .map_err(|_| FileSystemError::OutOfMemory)?;
+    path.push_str(dir.trim_end_matches('/'));
+    path.push('/');
+    path.push_str(name);
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A bundle with a read-only file in a read-only directory, and a file
+    /// and a directory whose paths are taken in the file-system it is
+    /// merged into.
+    fn bundle() -> MemFS {
+        let bundle = MemFS::default();
+        let rwx = FileModes::S_IRWXU.into();
+        bundle.mkdir("/bin", rwx).unwrap();
+        let ls = bundle.create("/bin/ls", rwx).unwrap();
+        bundle.write(ls, &[0xb; 10000], 0).unwrap();
+        bundle.set_modes(ls, FileModes::S_IRUSR.into()).unwrap();
+        let bin = *bundle.lookup("/bin").unwrap();
+        let modes = (FileModes::S_IRUSR | FileModes::S_IXUSR).into();
+        bundle.set_modes(bin, modes).unwrap();
+        let readme = bundle.create("/README", rwx).unwrap();
+        bundle.write(readme, b"bundle", 0).unwrap();
+        bundle.mkdir("/etc", rwx).unwrap();
+        bundle
+    }
+
+    /// The file-system the bundle is merged into.
+    fn base() -> MemFS {
+        let base = MemFS::default();
+        let rwx = FileModes::S_IRWXU.into();
+        base.mkdir("/bin", rwx).unwrap();
+        base.create("/bin/sh", rwx).unwrap();
+        let readme = base.create("/README", rwx).unwrap();
+        base.write(readme, b"base", 0).unwrap();
+        base.create("/etc", rwx).unwrap();
+        base
+    }
+
+    fn contents(memfs: &MemFS, path: &str) -> Vec<u8> {
+        let mnode = *memfs.lookup(path).unwrap();
+        let mut buffer = [0; 16];
+        let len = memfs.read(mnode, &mut buffer, 0).unwrap();
+        buffer[..len].to_vec()
+    }
+
+    #[test]
+    /// Directories are merged and taken paths are settled by the policy.
+    fn test_merge() {
+        let memfs = base();
+        assert_eq!(
+            memfs.merge(&bundle(), Conflict::Rename, &Cancel::new()),
+            Ok(())
+        );
+        let ls = *memfs.lookup("/bin/ls").unwrap();
+        assert_eq!(memfs.file_info(ls).unwrap().fsize, 10000);
+        assert_eq!(memfs.metadata(ls).unwrap().modes, FileModes::S_IRUSR.into());
+        assert!(memfs.lookup("/bin/sh").is_some());
+        let bin = *memfs.lookup("/bin").unwrap();
+        assert_eq!(
+            memfs.metadata(bin).unwrap().modes,
+            FileModes::S_IRWXU.into()
+        );
+        assert_eq!(contents(&memfs, "/README"), b"base");
+        assert_eq!(contents(&memfs, "/README.1"), b"bundle");
+        assert!(memfs.lookup("/etc.1").is_some());
+        assert_eq!(
+            memfs.merge(&bundle(), Conflict::Rename, &Cancel::new()),
+            Ok(())
+        );
+        assert_eq!(contents(&memfs, "/README.2"), b"bundle");
+
+        let memfs = base();
+        assert_eq!(
+            memfs.merge(&bundle(), Conflict::Skip, &Cancel::new()),
+            Ok(())
+        );
+        assert_eq!(contents(&memfs, "/README"), b"base");
+        assert!(memfs.lookup("/README.1").is_none());
+        let etc = *memfs.lookup("/etc").unwrap();
+        assert_ne!(
+            memfs.metadata(etc).unwrap().ftype,
+            NodeType::Directory.into()
+        );
+        assert!(memfs.lookup("/bin/ls").is_some());
+
+        let memfs = base();
+        assert_eq!(
+            memfs.merge(&bundle(), Conflict::Overwrite, &Cancel::new()),
+            Ok(())
+        );
+        assert_eq!(contents(&memfs, "/README"), b"bundle");
+        let etc = *memfs.lookup("/etc").unwrap();
+        assert_eq!(
+            memfs.metadata(etc).unwrap().ftype,
+            NodeType::Directory.into()
+        );
+        assert!(memfs.lookup("/bin/sh").is_some());
+
+        // A directory in the way must be empty to be overwritten.
+        let memfs = MemFS::default();
+        memfs.mkdir("/README", FileModes::S_IRWXU.into()).unwrap();
+        memfs
+            .create("/README/a", FileModes::S_IRWXU.into())
+            .unwrap();
+        assert_eq!(
+            memfs.merge(&bundle(), Conflict::Overwrite, &Cancel::new()),
+            Err(FileSystemError::DirectoryNotEmpty)
+        );
+    }
+}
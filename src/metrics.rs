@@ -0,0 +1,277 @@
+//! Counters of the reads and writes made on every CPU, and the export of all
+//! the statistics of a file-system in the Prometheus text format, so a
+//! user-space deployment (e.g. a FUSE or 9P server) can be scraped as is.
+//!
+//! The counters are kept per reader slot of the mnode table, which is the
+//! current CPU when the file-system has a `CpuId` hook. They are only added
+//! to by their CPU, so counting doesn't bounce cache lines between CPUs.
+
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lock_api::RawRwLock;
+
+use crate::padded::CachePadded;
+use crate::{FileSystemError, MemFS};
+
+/// The reads and writes counted on one CPU since the file-system was created.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct CpuStats {
+    pub reads: usize,
+    pub writes: usize,
+    pub read_bytes: usize,
+    pub written_bytes: usize,
+    /// Reads and writes that found their file locked and waited for it.
+    pub contended: usize,
+}
+
+#[derive(Default)]
+struct CpuCounters {
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+    read_bytes: AtomicUsize,
+    written_bytes: AtomicUsize,
+    contended: AtomicUsize,
+}
+
+pub(crate) struct Counters {
+    cpus: Vec<CachePadded<CpuCounters>>,
+}
+
+impl Counters {
+    /// Counters for `cpus` CPUs.
+    pub(crate) fn new(cpus: usize) -> Counters {
+        Counters {
+            cpus: (0..cpus.max(1)).map(|_| CachePadded::default()).collect(),
+        }
+    }
+
+    fn cpu(&self, cpu: usize) -> &CpuCounters {
+        &self.cpus[cpu % self.cpus.len()]
+    }
+
+    /// Count a read of `bytes` on `cpu`.
+    pub(crate) fn read(&self, cpu: usize, bytes: usize) {
+        let counters = self.cpu(cpu);
+        counters.reads.fetch_add(1, Ordering::Relaxed);
+        counters.read_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a write of `bytes` on `cpu`.
+    pub(crate) fn write(&self, cpu: usize, bytes: usize) {
+        let counters = self.cpu(cpu);
+        counters.writes.fetch_add(1, Ordering::Relaxed);
+        counters.written_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a wait for the lock of a file on `cpu`.
+    pub(crate) fn contended(&self, cpu: usize) {
+        self.cpu(cpu).contended.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<S: BuildHasher + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
+    /// The reads and writes counted on every CPU, in the order of the CPUs.
+    pub fn cpu_stats(&self) -> Result<Vec<CpuStats>, FileSystemError> {
+        let mut stats = Vec::new();
+        stats
+            .try_reserve(self.counters.cpus.len())
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        stats.extend(self.counters.cpus.iter().map(|counters| CpuStats {
+            reads: counters.reads.load(Ordering::Relaxed),
+            writes: counters.writes.load(Ordering::Relaxed),
+            read_bytes: counters.read_bytes.load(Ordering::Relaxed),
+            written_bytes: counters.written_bytes.load(Ordering::Relaxed),
+            contended: counters.contended.load(Ordering::Relaxed),
+        }));
+        Ok(stats)
+    }
+
+    /// Render the per-CPU counters, the memory used and the statistics of the
+    /// caches in the Prometheus text exposition format, every metric named
+    /// with the `nrfs_` prefix. Walks all the mnodes, like
+    /// `memory_breakdown()`.
+    #[cfg(feature = "std")]
+    pub fn prometheus(&self) -> Result<std::string::String, FileSystemError> {
+        use core::fmt::Write;
+
+        let mut out = std::string::String::new();
+        let cpus = self.cpu_stats()?;
+        let per_cpu: [(&str, &str, CpuValue); 5] = [
+            ("reads_total", "Reads served.", |cpu| cpu.reads),
+            ("writes_total", "Writes served.", |cpu| cpu.writes),
+            ("read_bytes_total", "Bytes read.", |cpu| cpu.read_bytes),
+            ("written_bytes_total", "Bytes written.", |cpu| {
+                cpu.written_bytes
+            }),
+            (
+                "lock_contended_total",
+                "Waits for the lock of a file.",
+                |cpu| cpu.contended,
+            ),
+        ];
+        for (name, help, value) in per_cpu.iter() {
+            header(&mut out, name, "counter", help);
+            for (n, cpu) in cpus.iter().enumerate() {
+                writeln!(out, "nrfs_{}{{cpu=\"{}\"}} {}", name, n, value(cpu)).unwrap();
+            }
+        }
+
+        let usage = self.memory_usage();
+        header(&mut out, "files", "gauge", "Files and directories.");
+        for (kind, count) in [("file", usage.files), ("directory", usage.directories)] {
+            writeln!(out, "nrfs_files{{type=\"{}\"}} {}", kind, count).unwrap();
+        }
+        let memory = self.memory_breakdown();
+        header(
+            &mut out,
+            "memory_bytes",
+            "gauge",
+            "Heap memory held, by use.",
+        );
+        for (kind, bytes) in [
+            ("file_data", memory.file_data),
+            ("file_slack", memory.file_slack),
+            ("metadata", memory.metadata),
+            ("hash_maps", memory.hash_maps),
+        ] {
+            writeln!(out, "nrfs_memory_bytes{{kind=\"{}\"}} {}", kind, bytes).unwrap();
+        }
+
+        let dcache = self.dentry_cache_stats();
+        let dedup = self.dedup_stats();
+        let tails = self.tail_stats();
+        let slab = self.mnode_slab_stats();
+        for (name, kind, help, value) in [
+            (
+                "data_bytes",
+                "gauge",
+                "Bytes stored in the files.",
+                usage.data_bytes,
+            ),
+            (
+                "dentry_cache_hits_total",
+                "counter",
+                "Dentry cache hits.",
+                dcache.hits,
+            ),
+            (
+                "dentry_cache_misses_total",
+                "counter",
+                "Dentry cache misses.",
+                dcache.misses,
+            ),
+            (
+                "shared_pages",
+                "gauge",
+                "Pages shared by dedup().",
+                dedup.pages,
+            ),
+            (
+                "dedup_saved_bytes",
+                "gauge",
+                "Bytes saved by sharing pages.",
+                dedup.saved_bytes,
+            ),
+            (
+                "tail_pages",
+                "gauge",
+                "Pages holding tails packed by pack_tails().",
+                tails.pages,
+            ),
+            (
+                "mnode_slots",
+                "gauge",
+                "Slots for mnodes in the slabs.",
+                slab.slots,
+            ),
+            (
+                "mnode_slots_used",
+                "gauge",
+                "Slots holding an mnode.",
+                slab.used,
+            ),
+        ] {
+            header(&mut out, name, kind, help);
+            writeln!(out, "nrfs_{} {}", name, value).unwrap();
+        }
+        Ok(out)
+    }
+}
+
+/// Reads a per-CPU counter out of the `CpuStats`.
+#[cfg(feature = "std")]
+type CpuValue = fn(&CpuStats) -> usize;
+
+/// Write the `HELP` and `TYPE` lines of the metric `nrfs_<name>`.
+#[cfg(feature = "std")]
+fn header(out: &mut std::string::String, name: &str, kind: &str, help: &str) {
+    use core::fmt::Write;
+
+    writeln!(out, "# HELP nrfs_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE nrfs_{} {}", name, kind).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FileModes, FileSystem, FileSystemRead};
+
+    #[test]
+    /// Reads and writes are counted on the CPU that made them.
+    fn test_cpu_stats() {
+        let memfs = MemFS::default();
+        let mnode = memfs.create("/file", FileModes::S_IRWXU.into()).unwrap();
+        memfs.write(mnode, &[0xb; 100], 0).unwrap();
+        memfs.read(mnode, &mut [0; 10], 0).unwrap();
+        assert!(memfs.read(mnode, &mut [0; 10], 200).is_err());
+
+        let stats = memfs.cpu_stats().unwrap();
+        assert_eq!(stats.len(), memfs.mnodes.readers().max(1));
+        let total = stats
+            .iter()
+            .fold(CpuStats::default(), |total, cpu| CpuStats {
+                reads: total.reads + cpu.reads,
+                writes: total.writes + cpu.writes,
+                read_bytes: total.read_bytes + cpu.read_bytes,
+                written_bytes: total.written_bytes + cpu.written_bytes,
+                contended: total.contended + cpu.contended,
+            });
+        let expected = CpuStats {
+            reads: 1,
+            writes: 1,
+            read_bytes: 10,
+            written_bytes: 100,
+            contended: 0,
+        };
+        assert_eq!(total, expected);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    /// Every metric has its help and type, and a sample per CPU if it is
+    /// counted per CPU.
+    fn test_prometheus() {
+        let memfs = MemFS::default();
+        let mnode = memfs.create("/file", FileModes::S_IRWXU.into()).unwrap();
+        memfs.write(mnode, &[0xb; 100], 0).unwrap();
+
+        let text = memfs.prometheus().unwrap();
+        let cpus = memfs.cpu_stats().unwrap().len();
+        let samples = |name: &str| {
+            text.lines()
+                .filter(|line| line.starts_with(name) && line[name.len()..].starts_with(['{', ' ']))
+                .count()
+        };
+        assert_eq!(samples("nrfs_writes_total"), cpus);
+        assert_eq!(samples("nrfs_files"), 2);
+        assert!(text.contains("nrfs_files{type=\"file\"} 1\n"));
+        assert!(text.contains("nrfs_data_bytes 100\n"));
+        assert!(text.contains("nrfs_mnode_slots_used 2\n"));
+        assert!(text.contains("# TYPE nrfs_lock_contended_total counter\n"));
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let value = line.rsplit(' ').next().unwrap();
+            assert!(value.parse::<usize>().is_ok(), "{}", line);
+        }
+    }
+}
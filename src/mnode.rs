@@ -1,8 +1,16 @@
-use alloc::string::String;
-use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::Ordering;
+use lock_api::{RawRwLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+use crate::entries::Entries;
 use crate::file::*;
-use crate::{FileSystemError, Mnode, Modes};
+use crate::handle::Generation;
+use crate::seqlock::SeqLock;
+use crate::{Advice, FileModes, FileSystemError, Mnode, MnodeCounter, Modes, Name, BASE_PAGE_SIZE};
 
 /// Each memory-node can be of two types: directory or a file.
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -23,13 +31,122 @@ impl Into<u64> for NodeType {
     }
 }
 
+/// The status of an mnode, as reported by `file_info` and `metadata`.
+#[derive(Debug, Copy, Clone)]
+pub struct Stat {
+    pub node_type: NodeType,
+    pub generation: Generation,
+    pub size: usize,
+    pub modes: FileModes,
+    pub ctime: u64,
+    pub mtime: u64,
+    pub version: u64,
+}
+
+/// A memnode of the mnode table, along with a copy of its status that can be
+/// read without locking the memnode. `L` is the lock of the memnode.
+pub struct MnodeCell<L: RawRwLock = spin::RwLock<()>> {
+    stat: SeqLock<Stat>,
+    node: RwLock<L, MemNode>,
+}
+
+impl<L: RawRwLock> MnodeCell<L> {
+    pub fn new(node: MemNode) -> MnodeCell<L> {
+        MnodeCell {
+            stat: SeqLock::new(node.stat()),
+            node: RwLock::new(node),
+        }
+    }
+
+    /// Lock the memnode for reading.
+    pub fn read(&self) -> RwLockReadGuard<'_, L, MemNode> {
+        self.node.read()
+    }
+
+    /// Lock the memnode for writing; its status is published again when the
+    /// lock is released.
+    pub fn write(&self) -> MnodeWriteGuard<'_, L> {
+        MnodeWriteGuard {
+            stat: &self.stat,
+            node: self.node.write(),
+        }
+    }
+
+    /// Lock the memnode for reading if it isn't locked for writing.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, L, MemNode>> {
+        self.node.try_read()
+    }
+
+    /// Lock the memnode for writing if it isn't locked.
+    pub fn try_write(&self) -> Option<MnodeWriteGuard<'_, L>> {
+        Some(MnodeWriteGuard {
+            stat: &self.stat,
+            node: self.node.try_write()?,
+        })
+    }
+
+    /// Get the status of the memnode without locking it.
+    pub fn stat(&self) -> Stat {
+        self.stat.read()
+    }
+}
+
+pub struct MnodeWriteGuard<'a, L: RawRwLock = spin::RwLock<()>> {
+    stat: &'a SeqLock<Stat>,
+    node: RwLockWriteGuard<'a, L, MemNode>,
+}
+
+impl<L: RawRwLock> Deref for MnodeWriteGuard<'_, L> {
+    type Target = MemNode;
+
+    fn deref(&self) -> &MemNode {
+        &self.node
+    }
+}
+
+impl<L: RawRwLock> DerefMut for MnodeWriteGuard<'_, L> {
+    fn deref_mut(&mut self) -> &mut MemNode {
+        &mut self.node
+    }
+}
+
+impl<L: RawRwLock> Drop for MnodeWriteGuard<'_, L> {
+    fn drop(&mut self) {
+        self.stat.write(self.node.stat());
+    }
+}
+
+/// Numbers the memnodes of all the file-systems, so no two of them have the
+/// same generation.
+static GENERATION: MnodeCounter = MnodeCounter::new(1);
+
 /// Memnode representation, similar to Inode for a memory-fs.
 #[derive(Debug)]
 pub struct MemNode {
     mnode_num: Mnode,
-    name: String,
+    /// Tells this mnode from others with the same number, see `handle`.
+    generation: Generation,
+    name: Name,
     node_type: NodeType,
+    modes: FileModes,
+    /// Time of the last status change (creation, rename or modification).
+    ctime: u64,
+    /// Time of the last modification of the content.
+    mtime: u64,
+    /// Counts the changes of the content and of the status, see
+    /// `Metadata::version`.
+    version: u64,
     file: Option<File>,
+    /// The entries of a directory; always empty for files.
+    children: Entries,
+    /// Set when the mnode is removed, so a directory can't get new entries
+    /// and a file can't change its size anymore.
+    unlinked: bool,
+    /// Set while the file is in the writeback queue of the file-system.
+    queued: bool,
+    /// The directory holding the entry of the mnode and the name of the
+    /// entry; none for the root and for removed mnodes.
+    link: Option<(Mnode, Name)>,
 }
 
 /// Required for the testing
@@ -46,7 +163,7 @@ impl MemNode {
     /// Initialize a memory-node for a directory or a file.
     pub fn new(
         mnode_num: Mnode,
-        pathname: &str,
+        name: Name,
         modes: Modes,
         node_type: NodeType,
     ) -> Result<MemNode, FileSystemError> {
@@ -60,34 +177,413 @@ impl MemNode {
 
         Ok(MemNode {
             mnode_num,
-            name: pathname.to_string(),
+            generation: GENERATION.fetch_add(1, Ordering::Relaxed) as Generation,
+            name,
             node_type,
+            modes: FileModes::from(modes),
+            ctime: 0,
+            mtime: 0,
+            version: 0,
             file,
+            children: Entries::default(),
+            unlinked: false,
+            queued: false,
+            link: None,
         })
     }
 
-    /// Write to an in-memory file.
-    pub fn write(&mut self, buffer: &[u8], offset: usize) -> Result<usize, FileSystemError> {
+    /// Find the entry `name` of a directory.
+    pub fn lookup(&self, name: &str) -> Result<&Arc<Mnode>, FileSystemError> {
+        self.entry(name).map(|(_, mnode)| mnode)
+    }
+
+    /// Find the entry `name` of a directory, along with its stored name.
+    pub fn entry(&self, name: &str) -> Result<(&Name, &Arc<Mnode>), FileSystemError> {
+        if self.node_type != NodeType::Directory {
+            return Err(FileSystemError::NotADirectory);
+        }
+        self.children.get(name).ok_or(FileSystemError::InvalidFile)
+    }
+
+    /// Check that entries can be added to or removed from a directory.
+    pub fn check_writable_dir(&self) -> Result<(), FileSystemError> {
+        if self.node_type != NodeType::Directory {
+            return Err(FileSystemError::NotADirectory);
+        }
+        if self.unlinked {
+            return Err(FileSystemError::InvalidFile);
+        }
+        if !self.modes.is_writable() {
+            return Err(FileSystemError::PermissionError);
+        }
+        Ok(())
+    }
+
+    /// Check that the mnode is a directory whose entries can be listed.
+    pub fn check_readable_dir(&self) -> Result<(), FileSystemError> {
+        if self.node_type != NodeType::Directory {
+            return Err(FileSystemError::NotADirectory);
+        }
+        if !self.modes.is_readable() {
+            return Err(FileSystemError::PermissionError);
+        }
+        Ok(())
+    }
+
+    /// Make room for one more entry, so adding it can't fail for lack of
+    /// memory, even after entries are removed in between.
+    pub fn reserve_entries(&mut self) -> Result<(), FileSystemError> {
+        self.check_writable_dir()?;
+        self.children.reserve()
+    }
+
+    /// Add the entry `name` for `mnode` of type `kind` to a directory.
+    pub fn add_entry(
+        &mut self,
+        name: Name,
+        mnode: Arc<Mnode>,
+        kind: NodeType,
+    ) -> Result<(), FileSystemError> {
+        self.check_writable_dir()?;
+        self.children.insert(name, mnode, kind)
+    }
+
+    /// Remove the entry `name` from a directory.
+    pub fn remove_entry(&mut self, name: &str) -> Result<Arc<Mnode>, FileSystemError> {
+        self.check_writable_dir()?;
+        self.children
+            .remove(name)
+            .ok_or(FileSystemError::InvalidFile)
+    }
+
+    /// Iterate over the entries of a directory in the order of their names.
+    pub fn entries(&self) -> impl Iterator<Item = (&Name, Mnode)> {
+        self.entries_after(None)
+            .map(|(name, mnode, _, _)| (name, mnode))
+    }
+
+    /// Iterate over the entries of a directory whose names sort after
+    /// `after`, or over all of them, with their types and cookies.
+    pub fn entries_after(
+        &self,
+        after: Option<&str>,
+    ) -> impl Iterator<Item = (&Name, Mnode, NodeType, u64)> {
+        self.children
+            .iter_after(after)
+            .map(|(name, mnode, kind, cookie)| (name, **mnode, kind, cookie))
+    }
+
+    /// Iterate over the entries of a directory whose cookies are greater
+    /// than `cookie`, in the order of their cookies, with their types and
+    /// them.
+    pub fn entries_from_cookie(
+        &self,
+        cookie: u64,
+    ) -> impl Iterator<Item = (&Name, Mnode, NodeType, u64)> {
+        self.children
+            .iter_from_cookie(cookie)
+            .map(|(name, mnode, kind, cookie)| (name, **mnode, kind, cookie))
+    }
+
+    /// Get the number of entries of a directory.
+    pub fn num_entries(&self) -> usize {
+        self.children.len()
+    }
+
+    /// Mark the mnode as removed from the namespace.
+    pub fn set_unlinked(&mut self) {
+        self.unlinked = true;
+        self.link = None;
+    }
+
+    /// Record that the entry `name` of the directory `parent` links the
+    /// mnode now.
+    pub fn set_link(&mut self, parent: Mnode, name: Name) {
+        self.link = Some((parent, name));
+    }
+
+    /// The directory and the name of the entry linking the mnode, if any.
+    pub fn link(&self) -> Option<(Mnode, &Name)> {
+        self.link.as_ref().map(|(parent, name)| (*parent, name))
+    }
+
+    /// Write to an in-memory file, growing it with buffers from `source`.
+    pub fn write(
+        &mut self,
+        buffer: &[u8],
+        offset: usize,
+        source: &ChunkSource,
+    ) -> Result<usize, FileSystemError> {
+        if self.unlinked {
+            return Err(FileSystemError::InvalidFile);
+        }
+        let file = self.file.as_mut().ok_or(FileSystemError::IsADirectory)?;
         // Return if the user doesn't have write permissions for the file.
-        if self.node_type != NodeType::File || !self.file.as_ref().unwrap().get_mode().is_writable()
-        {
+        if !file.get_mode().is_writable() {
             return Err(FileSystemError::PermissionError);
         }
         let len: usize = buffer.len();
 
-        self.file.as_mut().unwrap().write_file(buffer, len, offset)
+        file.write_file(buffer, len, offset, source)
+    }
+
+    /// The memory backing `len` bytes of an in-memory file from `offset`, see
+    /// `File::ranges()`. Inline data moves to a buffer from `source` first.
+    pub fn ranges(
+        &mut self,
+        offset: usize,
+        len: usize,
+        source: &ChunkSource,
+    ) -> Result<Vec<(NonNull<u8>, usize)>, FileSystemError> {
+        let file = self.file.as_mut().ok_or(FileSystemError::IsADirectory)?;
+        match offset.checked_add(len) {
+            Some(end) if end <= file.get_size() => {
+                file.spill(&source.pages)?;
+                file.ranges(offset, end)
+            }
+            _ => Err(FileSystemError::InvalidOffset),
+        }
+    }
+
+    /// Pin the pages backing `len` bytes of an in-memory file from `offset`,
+    /// which must be page-aligned, see `File::pin()`. Inline data moves to a
+    /// buffer from `source` first.
+    pub fn pin(
+        &mut self,
+        offset: usize,
+        len: usize,
+        source: &ChunkSource,
+    ) -> Result<Vec<NonNull<u8>>, FileSystemError> {
+        let file = self.file.as_mut().ok_or(FileSystemError::IsADirectory)?;
+        match offset.checked_add(len) {
+            Some(end)
+                if len > 0 && end <= file.get_size() && offset & (BASE_PAGE_SIZE - 1) == 0 =>
+            {
+                file.spill(&source.pages)?;
+                file.pin(offset, end)
+            }
+            _ => Err(FileSystemError::InvalidOffset),
+        }
+    }
+
+    /// Grow an in-memory file to `len` bytes with zeros, allocating the buffers
+    /// from `source`.
+    pub fn grow(&mut self, len: usize, source: &ChunkSource) -> Result<(), FileSystemError> {
+        if self.unlinked {
+            return Err(FileSystemError::InvalidFile);
+        }
+        let file = self.file.as_mut().ok_or(FileSystemError::IsADirectory)?;
+        if !file.get_mode().is_writable() {
+            return Err(FileSystemError::PermissionError);
+        }
+        let size = file.get_size();
+        if len > size && !file.increase_file_size(size, len, source) {
+            return Err(FileSystemError::OutOfMemory);
+        }
+        file.update_checksums(size, len);
+        Ok(())
+    }
+
+    /// Mark `len` bytes of an in-memory file from `offset` as changed.
+    pub fn mark_dirty(&mut self, offset: usize, len: usize) -> Result<(), FileSystemError> {
+        let file = self.file.as_mut().ok_or(FileSystemError::IsADirectory)?;
+        match offset.checked_add(len) {
+            Some(end) if end <= file.get_size() => match file.mark_dirty(offset, end) {
+                true => {
+                    file.update_checksums(offset, end);
+                    self.version += 1;
+                    Ok(())
+                }
+                false => Err(FileSystemError::OutOfMemory),
+            },
+            _ => Err(FileSystemError::InvalidOffset),
+        }
+    }
+
+    /// The offsets of the changed pages of an in-memory file.
+    pub fn dirty_pages(&self) -> Result<Vec<usize>, FileSystemError> {
+        let file = self.file.as_ref().ok_or(FileSystemError::IsADirectory)?;
+        let mut pages = Vec::new();
+        pages
+            .try_reserve(file.dirty_pages(0, usize::MAX).count())
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        pages.extend(file.dirty_pages(0, usize::MAX));
+        Ok(pages)
+    }
+
+    /// Keep checksums of the pages of an in-memory file, see
+    /// `File::enable_checksums()`.
+    pub fn enable_checksums(&mut self) {
+        if let Some(file) = self.file.as_mut() {
+            file.enable_checksums();
+        }
+    }
+
+    /// Free the spare capacity of an in-memory file, see `File::compact()`.
+    /// Returns the number of bytes freed.
+    pub fn compact(&mut self) -> usize {
+        match self.file.as_mut() {
+            Some(file) => file.compact(),
+            None => 0,
+        }
+    }
+
+    /// Share the full pages of an in-memory file through `share`, see
+    /// `File::dedup()`.
+    pub fn dedup<F>(&mut self, share: F) -> usize
+    where
+        F: FnMut(SharedPage) -> SharedPage,
+    {
+        match self.file.as_mut() {
+            Some(file) => file.dedup(share),
+            None => 0,
+        }
+    }
+
+    /// The tail of an in-memory file that can be packed, see `File::tail()`.
+    pub fn tail(&self) -> Option<&[u8]> {
+        self.file.as_ref().and_then(|file| file.tail())
+    }
+
+    /// Pack the tail of an in-memory file in `page`, see `File::pack_tail()`.
+    pub fn pack_tail(&mut self, page: &SharedPage, offset: usize, len: usize) -> bool {
+        match self.file.as_mut() {
+            Some(file) => file.pack_tail(page, offset, len),
+            None => false,
+        }
+    }
+
+    /// Compute the checksums of `len` bytes of an in-memory file from
+    /// `offset` again, after they were changed in place.
+    pub fn update_checksums(&mut self, offset: usize, len: usize) {
+        if let Some(file) = self.file.as_mut() {
+            file.update_checksums(offset, offset.saturating_add(len));
+        }
+    }
+
+    /// Whether an in-memory file has changed pages.
+    pub fn is_dirty(&self) -> bool {
+        match self.file.as_ref() {
+            Some(file) => file.dirty_pages(0, usize::MAX).next().is_some(),
+            None => false,
+        }
+    }
+
+    /// Mark a file with changed pages as queued for writeback. Returns false
+    /// if it is clean or already queued.
+    pub fn queue_writeback(&mut self) -> bool {
+        if self.queued || !self.is_dirty() {
+            return false;
+        }
+        self.queued = true;
+        true
+    }
+
+    /// Mark the file as no longer queued for writeback.
+    pub fn dequeue_writeback(&mut self) {
+        self.queued = false;
+    }
+
+    /// Flush the changed pages among `len` bytes of an in-memory file from
+    /// `offset`, see `File::flush()`.
+    pub fn flush<F>(
+        &mut self,
+        offset: usize,
+        len: usize,
+        stored: bool,
+        flush: F,
+    ) -> Result<usize, FileSystemError>
+    where
+        F: FnMut(usize, &[u8]) -> Result<(), FileSystemError>,
+    {
+        let file = self.file.as_mut().ok_or(FileSystemError::IsADirectory)?;
+        file.flush(offset, offset.saturating_add(len), stored, flush)
+    }
+
+    /// The access pattern advised for an in-memory file.
+    pub fn access(&self) -> Result<Advice, FileSystemError> {
+        let file = self.file.as_ref().ok_or(FileSystemError::IsADirectory)?;
+        Ok(file.access())
+    }
+
+    /// Advise how `len` bytes of an in-memory file from `offset` are going to
+    /// be used, see `File::advise()`.
+    pub fn advise(
+        &mut self,
+        offset: usize,
+        len: usize,
+        advice: Advice,
+    ) -> Result<(), FileSystemError> {
+        let file = self.file.as_mut().ok_or(FileSystemError::IsADirectory)?;
+        file.advise(offset, offset.saturating_add(len), advice);
+        Ok(())
+    }
+
+    /// Prefetch `len` bytes of an in-memory file from `offset`, see
+    /// `File::prefetch()`.
+    pub fn prefetch(&self, offset: usize, len: usize) -> Result<usize, FileSystemError> {
+        let file = self.file.as_ref().ok_or(FileSystemError::IsADirectory)?;
+        Ok(file.prefetch(offset, offset.saturating_add(len)))
+    }
+
+    /// Number of buffers of an in-memory file; zero for a directory.
+    pub fn buffers(&self) -> usize {
+        self.file.as_ref().map_or(0, |file| file.buffers())
+    }
+
+    /// Whether `len` bytes of an in-memory file from `offset` are in memory.
+    pub fn is_resident(&self, offset: usize, len: usize) -> bool {
+        match self.file.as_ref() {
+            Some(file) => file.is_resident(offset, offset.saturating_add(len)),
+            None => true,
+        }
+    }
+
+    /// Bring `len` bytes of an in-memory file from `offset` back into memory,
+    /// see `File::page_in()`.
+    pub fn page_in<F>(
+        &mut self,
+        offset: usize,
+        len: usize,
+        read: F,
+    ) -> Result<Vec<usize>, FileSystemError>
+    where
+        F: FnMut(usize, &mut [u8]) -> Result<(), FileSystemError>,
+    {
+        match self.file.as_mut() {
+            Some(file) => file.page_in(offset, offset.saturating_add(len), read),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Evict a buffer of an in-memory file, see `File::evict()`.
+    pub fn evict<F>(&mut self, buffer_num: usize, write: F) -> Result<Eviction, FileSystemError>
+    where
+        F: FnOnce(usize, &[u8]) -> Result<(), FileSystemError>,
+    {
+        match self.file.as_mut() {
+            Some(file) => file.evict(buffer_num, write),
+            None => Ok(Eviction::Gone),
+        }
+    }
+
+    /// Undo one `pin()`.
+    pub fn unpin(&mut self) {
+        if let Some(file) = self.file.as_mut() {
+            file.unpin();
+        }
     }
 
     /// Read from an in-memory file.
     pub fn read(&self, buffer: &mut [u8], offset: usize) -> Result<usize, FileSystemError> {
+        let file = self.file.as_ref().ok_or(FileSystemError::IsADirectory)?;
         // Return if the user doesn't have read permissions for the file.
-        if self.node_type != NodeType::File || !self.file.as_ref().unwrap().get_mode().is_readable()
-        {
+        if !file.get_mode().is_readable() {
             return Err(FileSystemError::PermissionError);
         }
 
         let len: usize = buffer.len();
-        let file_size = self.get_file_size();
+        let file_size = file.get_size();
         if offset > file_size {
             return Err(FileSystemError::InvalidOffset);
         }
@@ -97,25 +593,94 @@ impl MemNode {
 
         // Return error if start-offset is greater than or equal to new-offset OR
         // new offset is greater than the file size.
-        if offset >= new_offset || new_offset > self.get_file_size() as usize {
+        if offset >= new_offset || new_offset > file_size {
             return Err(FileSystemError::InvalidOffset);
         }
 
         // Read from file only if its not at EOF.
-        match self
-            .file
-            .as_ref()
-            .unwrap()
-            .read_file(&mut *buffer, offset, new_offset)
-        {
-            Ok(len) => return Ok(len),
-            Err(e) => return Err(e),
+        let read = file.read_file(&mut *buffer, offset, new_offset);
+        if read == Err(FileSystemError::DataCorruption) {
+            ratelimited!(
+                error,
+                "mnode {} is corrupt between {} and {}",
+                self.mnode_num,
+                offset,
+                new_offset
+            );
         }
+        read
     }
 
-    /// Get the file size
+    /// Get the file size; directories have a size of zero.
     pub fn get_file_size(&self) -> usize {
-        self.file.as_ref().unwrap().get_size()
+        self.file.as_ref().map_or(0, |file| file.get_size())
+    }
+
+    /// Get the modes of the mnode.
+    pub fn get_mode(&self) -> FileModes {
+        self.modes
+    }
+
+    /// Change the modes of the mnode and of its file.
+    pub fn set_modes(&mut self, modes: Modes) {
+        self.modes = FileModes::from(modes);
+        if let Some(file) = self.file.as_mut() {
+            file.set_mode(modes);
+        }
+    }
+
+    /// Get the time of the last status change.
+    pub fn get_ctime(&self) -> u64 {
+        self.ctime
+    }
+
+    /// Get the time of the last modification of the content.
+    pub fn get_mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    /// Record a modification of the content; this is a status change as well.
+    pub fn set_modified(&mut self, time: u64) {
+        self.mtime = time;
+        self.set_changed(time);
+    }
+
+    /// Record a status change, like a rename.
+    pub fn set_changed(&mut self, time: u64) {
+        self.ctime = time;
+        self.version += 1;
+    }
+
+    /// Get the status of the mnode.
+    pub fn stat(&self) -> Stat {
+        Stat {
+            node_type: self.node_type,
+            generation: self.generation,
+            size: self.get_file_size(),
+            modes: self.get_mode(),
+            ctime: self.get_ctime(),
+            mtime: self.get_mtime(),
+            version: self.version,
+        }
+    }
+
+    /// Bytes of heap memory held by the mnode, not counting the mnode itself.
+    pub fn allocated_size(&self) -> usize {
+        self.metadata_size() + self.file.as_ref().map_or(0, |file| file.data_size())
+    }
+
+    /// Bytes of heap memory held by the mnode besides the file data: its
+    /// name, its directory entries and the bookkeeping of its file.
+    pub fn metadata_size(&self) -> usize {
+        self.name.allocated_size()
+            + self.file.as_ref().map_or(0, |file| file.bookkeeping_size())
+            + self.children.allocated_size()
+            + self.children.len() * size_of::<Mnode>()
+    }
+
+    /// Bytes allocated for the data of a file that don't hold any.
+    pub fn slack(&self) -> usize {
+        self.file.as_ref().map_or(0, |file| file.slack())
     }
 
     /// Get the type of mnode; Directory or file.
@@ -125,13 +690,58 @@ impl MemNode {
 
     /// Truncate the file in reasponse of O_TRUNC flag.
     pub fn file_truncate(&mut self) -> Result<bool, FileSystemError> {
-        if self.node_type != NodeType::File || !self.file.as_ref().unwrap().get_mode().is_writable()
-        {
+        if self.unlinked {
+            return Err(FileSystemError::InvalidFile);
+        }
+        let file = self.file.as_mut().ok_or(FileSystemError::IsADirectory)?;
+        if !file.get_mode().is_writable() {
             return Err(FileSystemError::PermissionError);
         }
 
-        // The method doesn't fail after this point, so returning Ok().
-        self.file.as_mut().unwrap().file_truncate();
+        if file.is_pinned() {
+            return Err(FileSystemError::Busy);
+        }
+
+        file.file_truncate()?;
         Ok(true)
     }
+
+    /// Keep the previous versions of an in-memory file, see
+    /// `File::keep_versions()`.
+    pub fn keep_versions(
+        &mut self,
+        max_count: usize,
+        max_bytes: usize,
+    ) -> Result<(), FileSystemError> {
+        let file = self.file.as_mut().ok_or(FileSystemError::IsADirectory)?;
+        file.keep_versions(max_count, max_bytes);
+        Ok(())
+    }
+
+    /// Stop keeping the versions of an in-memory file.
+    pub fn drop_versions(&mut self) -> Result<(), FileSystemError> {
+        let file = self.file.as_mut().ok_or(FileSystemError::IsADirectory)?;
+        file.drop_versions();
+        Ok(())
+    }
+
+    /// The versions an in-memory file keeps, see `File::versions()`.
+    pub fn versions(&self) -> Result<Vec<VersionInfo>, FileSystemError> {
+        let file = self.file.as_ref().ok_or(FileSystemError::IsADirectory)?;
+        file.versions()
+    }
+
+    /// Read a version of an in-memory file, see `File::read_version()`.
+    pub fn read_version(
+        &self,
+        number: u64,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<usize, FileSystemError> {
+        let file = self.file.as_ref().ok_or(FileSystemError::IsADirectory)?;
+        if !file.get_mode().is_readable() {
+            return Err(FileSystemError::PermissionError);
+        }
+        file.read_version(number, buffer, offset)
+    }
 }
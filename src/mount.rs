@@ -0,0 +1,432 @@
+//! Declarative restrictions attached to a mount of a file-system.
+//!
+//! A [`Mount`] wraps a file-system and checks its [`Restrictions`] before
+//! passing a call on, so a kernel can confine a process to a jail-like view
+//! (no new files, read-only subtrees, a file size limit) without writing a
+//! `SecurityPolicy`. It implements `FileSystem` itself, so the front-ends,
+//! e.g. `Syscalls`, enforce the restrictions of the mount they are given.
+//!
+//! Writes by mnode aren't tied to a path: a file in a read-only subtree is
+//! protected by refusing to open it for writing, see `FileSystem::may_open()`.
+//!
+//! A path may name a read-only subtree without spelling it the same, e.g. on
+//! a file-system that folds case. So a path is also below a read-only prefix
+//! if one of its ancestors resolves to the same mnode as the prefix.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::mnode::NodeType;
+use crate::{
+    components, try_to_string, DirEntry, FileFlags, FileInfo, FileSystem, FileSystemError,
+    FileSystemRead, Mnode, Modes,
+};
+
+/// What a mount doesn't allow; nothing by default.
+#[derive(Debug, Clone)]
+pub struct Restrictions {
+    no_exec: bool,
+    no_create: bool,
+    read_only: Vec<String>,
+    max_file_size: usize,
+}
+
+impl Default for Restrictions {
+    fn default() -> Restrictions {
+        Restrictions::new()
+    }
+}
+
+impl Restrictions {
+    /// No restrictions.
+    pub fn new() -> Restrictions {
+        Restrictions {
+            no_exec: false,
+            no_create: false,
+            read_only: Vec::new(),
+            max_file_size: usize::MAX,
+        }
+    }
+
+    /// Don't execute files of the mount, see `Mount::may_exec()`.
+    pub fn no_exec(mut self) -> Restrictions {
+        self.no_exec = true;
+        self
+    }
+
+    /// Don't create files or directories; the existing ones can still be
+    /// written, renamed and deleted.
+    pub fn no_create(mut self) -> Restrictions {
+        self.no_create = true;
+        self
+    }
+
+    /// Don't change `prefix` or anything below it, which fails with
+    /// `ReadOnly`. Paths are compared by their components, as they are given
+    /// to the mount.
+    pub fn read_only(mut self, prefix: &str) -> Result<Restrictions, FileSystemError> {
+        self.read_only
+            .try_reserve(1)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        self.read_only.push(try_to_string(prefix)?);
+        Ok(self)
+    }
+
+    /// Don't let files grow past `max_file_size` bytes; writes stop at the
+    /// limit and fail with `FileTooLarge` once there, like with
+    /// `RLIMIT_FSIZE`.
+    pub fn max_file_size(mut self, max_file_size: usize) -> Restrictions {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Check that `path` may be changed, as it is spelled.
+    fn check_write(&self, path: &str) -> Result<(), FileSystemError> {
+        let below = |prefix: &String| {
+            let mut path = components(path);
+            components(prefix).all(|name| path.next() == Some(name))
+        };
+        match self.read_only.iter().any(below) {
+            true => Err(FileSystemError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    /// Check that `path` may be created, as it is spelled.
+    fn check_create(&self, path: &str) -> Result<(), FileSystemError> {
+        if self.no_create {
+            return Err(FileSystemError::PermissionError);
+        }
+        self.check_write(path)
+    }
+}
+
+/// A file-system seen through the `Restrictions` of a mount.
+pub struct Mount<F> {
+    fs: F,
+    restrictions: Arc<Restrictions>,
+}
+
+impl<F> Mount<F> {
+    /// Mount `fs` with `restrictions`; they can be shared by many mounts.
+    pub fn new(fs: F, restrictions: Arc<Restrictions>) -> Mount<F> {
+        Mount { fs, restrictions }
+    }
+
+    /// The file-system without the restrictions.
+    pub fn fs(&self) -> &F {
+        &self.fs
+    }
+
+    /// The restrictions of the mount.
+    pub fn restrictions(&self) -> &Restrictions {
+        &self.restrictions
+    }
+}
+
+impl<F: FileSystemRead> Mount<F> {
+    /// Check that the file `mnode` may be executed, for the loader of the
+    /// kernel; the modes of the file are up to the caller.
+    pub fn may_exec(&self, mnode: Mnode) -> Result<(), FileSystemError> {
+        if self.restrictions.no_exec {
+            return Err(FileSystemError::PermissionError);
+        }
+        match self.fs.file_info(mnode)?.ftype == NodeType::Directory.into() {
+            true => Err(FileSystemError::IsADirectory),
+            false => Ok(()),
+        }
+    }
+
+    /// Check that `path` may be changed, both as it is spelled and as the
+    /// file-system resolves it.
+    fn check_write(&self, path: &str) -> Result<(), FileSystemError> {
+        self.restrictions.check_write(path)?;
+        self.check_resolved(path)
+    }
+
+    /// Check that `path` may be created, like `check_write()`.
+    fn check_create(&self, path: &str) -> Result<(), FileSystemError> {
+        self.restrictions.check_create(path)?;
+        self.check_resolved(path)
+    }
+
+    /// Check that neither `path` nor any of its ancestors resolves to a
+    /// read-only prefix of the mount.
+    fn check_resolved(&self, path: &str) -> Result<(), FileSystemError> {
+        let read_only = &self.restrictions.read_only;
+        let mut protected = Vec::new();
+        protected
+            .try_reserve(read_only.len())
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        protected.extend(
+            read_only
+                .iter()
+                .filter_map(|prefix| self.fs.lookup(prefix))
+                .map(|mnode| *mnode),
+        );
+        if protected.is_empty() {
+            return Ok(());
+        }
+        // Every ancestor is a prefix of the path ending before a slash.
+        let ends = path
+            .match_indices('/')
+            .map(|(end, _)| end)
+            .chain(core::iter::once(path.len()));
+        for end in ends {
+            let ancestor = &path[..end];
+            if components(ancestor).next().is_none() {
+                continue;
+            }
+            match self.fs.lookup(ancestor) {
+                Some(mnode) if protected.contains(&*mnode) => {
+                    return Err(FileSystemError::ReadOnly)
+                }
+                Some(_) => {}
+                // Nothing below a missing ancestor resolves either.
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<F: FileSystemRead> FileSystemRead for Mount<F> {
+    fn read(
+        &self,
+        mnode_num: Mnode,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<usize, FileSystemError> {
+        self.fs.read(mnode_num, buffer, offset)
+    }
+
+    fn lookup(&self, pathname: &str) -> Option<Arc<Mnode>> {
+        self.fs.lookup(pathname)
+    }
+
+    fn file_info(&self, mnode: Mnode) -> Result<FileInfo, FileSystemError> {
+        self.fs.file_info(mnode)
+    }
+
+    fn readdir(
+        &self,
+        pathname: &str,
+        after: Option<&str>,
+        max: usize,
+    ) -> Result<Vec<DirEntry>, FileSystemError> {
+        self.fs.readdir(pathname, after, max)
+    }
+}
+
+impl<F: FileSystem> FileSystem for Mount<F> {
+    fn create(&self, pathname: &str, modes: Modes) -> Result<Mnode, FileSystemError> {
+        self.check_create(pathname)?;
+        self.fs.create(pathname, modes)
+    }
+
+    fn mkdir(&self, pathname: &str, modes: Modes) -> Result<Mnode, FileSystemError> {
+        self.check_create(pathname)?;
+        self.fs.mkdir(pathname, modes)
+    }
+
+    /// Write up to the size limit of the mount.
+    fn write(
+        &self,
+        mnode_num: Mnode,
+        buffer: &[u8],
+        offset: usize,
+    ) -> Result<usize, FileSystemError> {
+        let room = self.restrictions.max_file_size.saturating_sub(offset);
+        if room == 0 && !buffer.is_empty() {
+            return Err(FileSystemError::FileTooLarge);
+        }
+        let len = buffer.len().min(room);
+        self.fs.write(mnode_num, &buffer[..len], offset)
+    }
+
+    /// Append up to the size limit of the mount, as far as the size of the
+    /// file before the append tells; appends made at the same time can take
+    /// the file past it.
+    fn append(&self, mnode_num: Mnode, buffer: &[u8]) -> Result<(usize, usize), FileSystemError> {
+        let size = self.fs.file_info(mnode_num)?.fsize as usize;
+        let room = self.restrictions.max_file_size.saturating_sub(size);
+        if room == 0 && !buffer.is_empty() {
+            return Err(FileSystemError::FileTooLarge);
+        }
+        let len = buffer.len().min(room);
+        self.fs.append(mnode_num, &buffer[..len])
+    }
+
+    fn delete(&self, pathname: &str) -> Result<bool, FileSystemError> {
+        self.check_write(pathname)?;
+        self.fs.delete(pathname)
+    }
+
+    fn rmdir(&self, pathname: &str) -> Result<bool, FileSystemError> {
+        self.check_write(pathname)?;
+        self.fs.rmdir(pathname)
+    }
+
+    fn truncate(&self, pathname: &str) -> Result<bool, FileSystemError> {
+        self.check_write(pathname)?;
+        self.fs.truncate(pathname)
+    }
+
+    fn rename(&self, oldname: &str, newname: &str) -> Result<bool, FileSystemError> {
+        self.check_write(oldname)?;
+        self.check_write(newname)?;
+        self.fs.rename(oldname, newname)
+    }
+
+    fn fsync(&self, mnode: Mnode) -> Result<(), FileSystemError> {
+        self.fs.fsync(mnode)
+    }
+
+    fn fdatasync(&self, mnode: Mnode) -> Result<(), FileSystemError> {
+        self.fs.fdatasync(mnode)
+    }
+
+    /// Refuse to open files for writing in the read-only subtrees.
+    fn may_open(
+        &self,
+        pathname: &str,
+        mnode: Mnode,
+        flags: FileFlags,
+    ) -> Result<(), FileSystemError> {
+        if flags.is_write() {
+            self.check_write(pathname)?;
+        }
+        self.fs.may_open(pathname, mnode, flags)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FileModes, MemFS, MemFSBuilder};
+
+    #[test]
+    /// The restrictions of the mount are checked before the calls reach the
+    /// file-system.
+    fn test_mount() {
+        let memfs = MemFS::default();
+        let modes = FileModes::S_IRWXU.into();
+        memfs.mkdir("/etc", modes).unwrap();
+        let passwd = memfs.create("/etc/passwd", modes).unwrap();
+        let log = memfs.create("/log", modes).unwrap();
+
+        let restrictions = Restrictions::new()
+            .no_exec()
+            .read_only("/etc")
+            .unwrap()
+            .max_file_size(8);
+        let mount = Mount::new(memfs, Arc::new(restrictions));
+        let read_only = FileSystemError::ReadOnly;
+        assert_eq!(mount.create("/etc/shadow", modes), Err(read_only.clone()));
+        assert_eq!(mount.delete("/etc/passwd"), Err(read_only.clone()));
+        assert_eq!(mount.rename("/log", "/etc/log"), Err(read_only.clone()));
+        assert_eq!(mount.rmdir("//etc"), Err(read_only.clone()));
+        assert_eq!(
+            mount.may_open("/etc/passwd", passwd, FileFlags::O_WRONLY),
+            Err(read_only)
+        );
+        assert_eq!(
+            mount.may_open("/etc/passwd", passwd, FileFlags::O_RDONLY),
+            Ok(())
+        );
+        assert!(mount.create("/etcetera", modes).is_ok());
+
+        assert_eq!(mount.write(log, b"0123456789", 0), Ok(8));
+        assert_eq!(
+            mount.write(log, b"0", 8),
+            Err(FileSystemError::FileTooLarge)
+        );
+        assert_eq!(mount.file_info(log).unwrap().fsize, 8);
+        assert_eq!(mount.may_exec(log), Err(FileSystemError::PermissionError));
+
+        let mount = Mount::new(mount.fs, Arc::new(Restrictions::new().no_create()));
+        assert_eq!(
+            mount.mkdir("/tmp", modes),
+            Err(FileSystemError::PermissionError)
+        );
+        assert_eq!(mount.delete("/log"), Ok(true));
+        assert_eq!(mount.may_exec(passwd), Ok(()));
+    }
+
+    #[test]
+    /// Read-only subtrees can't be reached by spelling them differently.
+    fn test_mount_resolved_paths() {
+        let memfs = MemFSBuilder::new().case_sensitive(false).build();
+        let modes = FileModes::S_IRWXU.into();
+        memfs.mkdir("/etc", modes).unwrap();
+        memfs.mkdir("/tmp", modes).unwrap();
+        let passwd = memfs.create("/etc/passwd", modes).unwrap();
+        memfs.create("/log", modes).unwrap();
+        let restrictions = Restrictions::new().read_only("/etc").unwrap();
+        let mount = Mount::new(memfs, Arc::new(restrictions));
+
+        let read_only = FileSystemError::ReadOnly;
+        assert_eq!(mount.delete("/ETC/passwd"), Err(read_only.clone()));
+        assert_eq!(mount.truncate("/Etc/Passwd"), Err(read_only.clone()));
+        assert_eq!(mount.create("/eTc/shadow", modes), Err(read_only.clone()));
+        assert_eq!(mount.rename("/log", "/ETC/log"), Err(read_only.clone()));
+        assert_eq!(mount.rmdir("/ETC/"), Err(read_only.clone()));
+        assert_eq!(
+            mount.may_open("/ETC/PASSWD", passwd, FileFlags::O_WRONLY),
+            Err(read_only)
+        );
+
+        // `..` doesn't lead out of a directory into a read-only one.
+        assert!(mount.delete("/tmp/../etc/passwd").is_err());
+        assert!(mount.create("/tmp/../etc/shadow", modes).is_err());
+        assert_eq!(
+            mount.lookup("/etc/passwd").map(|mnode| *mnode),
+            Some(passwd)
+        );
+        assert!(mount.lookup("/etc/shadow").is_none());
+
+        assert!(mount.create("/tmp/file", modes).is_ok());
+        assert_eq!(mount.rename("/log", "/TMP/log"), Ok(true));
+    }
+
+    #[test]
+    /// Mounts of different types sit in one table behind the same pointer,
+    /// and keep their restrictions there.
+    fn test_dyn_mounts() {
+        let memfs = Arc::new(MemFS::default());
+        let modes = FileModes::S_IRWXU.into();
+        memfs.mkdir("/etc", modes).unwrap();
+        let passwd = memfs.create("/etc/passwd", modes).unwrap();
+        let restrictions = Arc::new(Restrictions::new().read_only("/etc").unwrap());
+        let table: Vec<Arc<dyn FileSystem + Send + Sync>> = alloc::vec![
+            Arc::clone(&memfs) as _,
+            Arc::new(Mount::new(Arc::clone(&memfs), restrictions)),
+        ];
+
+        for (n, fs) in table.iter().enumerate() {
+            let mnode = fs.create(&alloc::format!("/{}", n), modes).unwrap();
+            assert_eq!(fs.write(mnode, b"data", 0), Ok(4));
+            assert_eq!(fs.lookup("/etc/passwd").map(|mnode| *mnode), Some(passwd));
+        }
+        assert_eq!(table[0].truncate("/etc/passwd"), Ok(true));
+        assert_eq!(
+            table[1].truncate("/etc/passwd"),
+            Err(FileSystemError::ReadOnly)
+        );
+        assert_eq!(
+            table[1].may_open("/etc/passwd", passwd, FileFlags::O_RDWR),
+            Err(FileSystemError::ReadOnly)
+        );
+
+        // Mounts stack on a borrowed backend too.
+        let backend: &dyn FileSystem = &*table[1];
+        let view = Mount::new(backend, Arc::new(Restrictions::new().no_create()));
+        assert_eq!(
+            view.mkdir("/tmp", modes),
+            Err(FileSystemError::PermissionError)
+        );
+        assert_eq!(view.rmdir("/etc"), Err(FileSystemError::ReadOnly));
+        assert_eq!(view.readdir("/", None, 10).unwrap().len(), 3);
+    }
+}
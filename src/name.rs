@@ -0,0 +1,137 @@
+//! File names with inline storage for short names.
+//!
+//! Most file names are short, so a name of up to `INLINE` bytes is stored in
+//! place and doesn't allocate at all. Longer names are allocated once and
+//! shared between the mnode, the entry of its directory and the dentry cache.
+
+use alloc::sync::Arc;
+use core::borrow::Borrow;
+use core::cmp::Ordering;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::Deref;
+
+/// The longest name stored inline; keeps a `Name` at the size of three words.
+const INLINE: usize = 22;
+
+#[derive(Clone)]
+pub(crate) enum Name {
+    Inline { len: u8, bytes: [u8; INLINE] },
+    Shared(Arc<str>),
+}
+
+assert_eq_size!(Name, [usize; 3]);
+
+impl Name {
+    /// Copy `name`, allocating only if it doesn't fit inline.
+    pub(crate) fn new(name: &str) -> Name {
+        if name.len() > INLINE {
+            return Name::Shared(Arc::from(name));
+        }
+        let mut bytes = [0; INLINE];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Name::Inline {
+            len: name.len() as u8,
+            bytes,
+        }
+    }
+
+    /// Bytes of heap memory held by the name.
+    pub(crate) fn allocated_size(&self) -> usize {
+        match self {
+            Name::Inline { .. } => 0,
+            Name::Shared(name) => name.len(),
+        }
+    }
+}
+
+impl Deref for Name {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match self {
+            // The bytes were copied from a `str` up to a character boundary.
+            Name::Inline { len, bytes } => unsafe {
+                core::str::from_utf8_unchecked(&bytes[..*len as usize])
+            },
+            Name::Shared(name) => name,
+        }
+    }
+}
+
+impl From<&str> for Name {
+    fn from(name: &str) -> Name {
+        Name::new(name)
+    }
+}
+
+impl Borrow<str> for Name {
+    fn borrow(&self) -> &str {
+        self
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Name) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Name {}
+
+impl PartialOrd for Name {
+    fn partial_cmp(&self, other: &Name) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Name {
+    fn cmp(&self, other: &Name) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+// Hashes like a `str`, as required by `Borrow<str>`.
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    /// Short names are stored inline and compare like the strings they hold.
+    fn test_names() {
+        let short = Name::new("passwd");
+        assert!(matches!(short, Name::Inline { .. }));
+        assert_eq!(short.allocated_size(), 0);
+        assert_eq!(&*short, "passwd");
+
+        let fits = Name::new("abcdefghijklmnopqrstuv");
+        assert!(matches!(fits, Name::Inline { .. }));
+        let long = Name::new("abcdefghijklmnopqrstuvw");
+        assert!(matches!(long, Name::Shared(_)));
+        assert_eq!(long.allocated_size(), 23);
+        assert_eq!(&*long, "abcdefghijklmnopqrstuvw");
+
+        assert!(fits < long);
+        assert!(long < short);
+        assert_eq!(Name::new("ä"), Name::from("ä"));
+        assert_eq!(Name::new(""), Name::from(""));
+    }
+}
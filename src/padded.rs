@@ -0,0 +1,58 @@
+//! Cache-line padding for the per-CPU state of the locks and the RCU, so
+//! CPUs don't share the lines they write.
+//!
+//! A `single-threaded` build, e.g. for wasm sandboxes and unikernels, has no
+//! CPUs to keep apart, so there `CachePadded` is a plain wrapper.
+
+#[cfg(not(feature = "single-threaded"))]
+pub(crate) use crossbeam_utils::CachePadded;
+
+#[cfg(feature = "single-threaded")]
+pub(crate) use unpadded::CachePadded;
+
+#[cfg(feature = "single-threaded")]
+mod unpadded {
+    use core::ops::{Deref, DerefMut};
+
+    /// Has the interface of `crossbeam_utils::CachePadded` without the
+    /// padding.
+    #[derive(Debug, Default)]
+    pub(crate) struct CachePadded<T>(T);
+
+    impl<T> CachePadded<T> {
+        pub(crate) const fn new(value: T) -> CachePadded<T> {
+            CachePadded(value)
+        }
+    }
+
+    impl<T> Deref for CachePadded<T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.0
+        }
+    }
+
+    impl<T> DerefMut for CachePadded<T> {
+        fn deref_mut(&mut self) -> &mut T {
+            &mut self.0
+        }
+    }
+}
+
+#[cfg(all(test, feature = "single-threaded"))]
+mod test {
+    use super::*;
+    use core::mem::size_of;
+    use core::sync::atomic::AtomicUsize;
+
+    /// A single-threaded build has one CPU and doesn't pad its state.
+    #[test]
+    fn test_single_threaded() {
+        assert_eq!(crate::topology::machine().cpu_ids(), 1);
+        assert_eq!(
+            size_of::<CachePadded<AtomicUsize>>(),
+            size_of::<AtomicUsize>()
+        );
+    }
+}
@@ -0,0 +1,932 @@
+//! A backing store in byte-addressable persistent memory.
+//!
+//! The pages and the metadata of the files are kept in fixed slots of the
+//! region. Every slot has an entry that names what it holds together with a
+//! checksum of the entry and the data, so a write torn by a crash is detected
+//! and dropped when the region is opened again. Updates go to a free slot
+//! with a higher sequence number than the version they replace, and the old
+//! slot is only freed once the next fence made the update durable, so a torn
+//! update leaves the previous version in place.
+//!
+//! The metadata is stored with the path of the file, so a MemFS built over a
+//! recovered store can recreate the files, see `MemFS::restore()`.
+//!
+//! Stores only reach persistent memory once their cache lines are written
+//! back, which the [`Persist`] hook does, e.g. with `clwb`. Write-backs aren't
+//! ordered until the next fence, which is issued by `StorageBackend::flush()`.
+//!
+//! The region starts with a header that describes its geometry, followed by
+//! the entries and then by the data of the slots, page-aligned:
+//!
+//! ```text
+//! | header | entry 0 | entry 1 | ... | pad | slot 0 | slot 1 | ... |
+//! ```
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+use hashbrown::HashMap;
+use spin::Mutex;
+
+use crate::builder::StorageBackend;
+use crate::{FileSystemError, Metadata, Mnode, BASE_PAGE_SIZE};
+
+/// Makes the stores to persistent memory durable; the architecture-specific
+/// part of the [`PmemStore`].
+pub trait Persist: Send + Sync {
+    /// Write the cache line holding `ptr` back to persistent memory, e.g.
+    /// with `clwb`.
+    ///
+    /// # Safety
+    /// `ptr` must point into the mapped region of the store.
+    unsafe fn write_back(&self, ptr: *const u8);
+
+    /// Wait for the write-backs issued so far, e.g. with `sfence`.
+    fn fence(&self);
+}
+
+/// Writes cache lines back with `clflush`, which every x86-64 CPU has. CPUs
+/// with `clwb` do better with a hook that uses it, as it keeps the lines in
+/// the caches.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Clflush;
+
+impl Persist for Clflush {
+    unsafe fn write_back(&self, ptr: *const u8) {
+        #[cfg(target_arch = "x86_64")]
+        core::arch::x86_64::_mm_clflush(ptr);
+        #[cfg(not(target_arch = "x86_64"))]
+        let _ = ptr;
+    }
+
+    fn fence(&self) {
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            core::arch::x86_64::_mm_sfence();
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// Size of the cache lines written back by the `Persist` hook.
+const CACHE_LINE_SIZE: usize = 64;
+
+/// Identifies a region formatted by this version of the store.
+const MAGIC: u64 = u64::from_le_bytes(*b"NRFSPMEM");
+const VERSION: u64 = 2;
+
+/// The header at the start of the region; it is written last when the region
+/// is formatted, so a region is only used once it was formatted completely.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct Header {
+    magic: u64,
+    version: u64,
+    slots: u64,
+    checksum: u64,
+}
+
+/// What a slot holds.
+const FREE: u16 = 0;
+const PAGE: u16 = 1;
+const METADATA: u16 = 2;
+
+/// Describes the content of a slot.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct Entry {
+    kind: u16,
+    /// Bytes of the slot in use.
+    len: u16,
+    /// Orders the versions of the same page or metadata, which are in
+    /// different slots until the old one is freed; the newest one wins.
+    seq: u32,
+    mnode: u64,
+    /// Offset of the page in the file; zero for metadata.
+    offset: u64,
+    /// Covers the fields above and the data.
+    checksum: u64,
+}
+
+const_assert!(size_of::<Header>() <= CACHE_LINE_SIZE);
+// Entries don't straddle cache lines.
+const_assert!(CACHE_LINE_SIZE / size_of::<Entry>() * size_of::<Entry>() == CACHE_LINE_SIZE);
+
+/// The bytes metadata takes in a slot.
+const METADATA_LEN: usize = 7 * size_of::<u64>();
+
+/// 64-bit FNV-1a of `words` followed by `bytes`.
+fn checksum(words: &[u64], bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let words = words.iter().flat_map(|word| word.to_le_bytes());
+    for byte in words.chain(bytes.iter().copied()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100_0000_01b3);
+    }
+    hash
+}
+
+impl Entry {
+    fn checksum(&self, data: &[u8]) -> u64 {
+        let fields = [
+            self.kind as u64,
+            self.len as u64,
+            self.seq as u64,
+            self.mnode,
+            self.offset,
+        ];
+        checksum(&fields, data)
+    }
+}
+
+impl Header {
+    fn new(slots: usize) -> Header {
+        let mut header = Header {
+            magic: MAGIC,
+            version: VERSION,
+            slots: slots as u64,
+            checksum: 0,
+        };
+        header.checksum = header.checksum();
+        header
+    }
+
+    fn checksum(&self) -> u64 {
+        checksum(&[self.magic, self.version, self.slots], &[])
+    }
+}
+
+/// Where the data of the slots starts, for `slots` slots.
+fn data_start(slots: usize) -> usize {
+    let entries = CACHE_LINE_SIZE + slots * size_of::<Entry>();
+    (entries + BASE_PAGE_SIZE - 1) & !(BASE_PAGE_SIZE - 1)
+}
+
+/// The most slots that fit into `len` bytes.
+fn slots_for(len: usize) -> usize {
+    let mut slots = len / (size_of::<Entry>() + BASE_PAGE_SIZE);
+    while slots > 0 && data_start(slots) + slots * BASE_PAGE_SIZE > len {
+        slots -= 1;
+    }
+    slots
+}
+
+/// The slots in use with their sequence numbers, by (mnode, kind, offset),
+/// and the free ones.
+struct Index {
+    slots: HashMap<(Mnode, u16, u64), (usize, u32)>,
+    free: Vec<usize>,
+    /// Slots of replaced versions with their mnodes, freed by the next fence.
+    retired: Vec<(Mnode, usize)>,
+}
+
+/// A [`StorageBackend`] that keeps the pages and the metadata of the files in
+/// persistent memory, and finds them again after a restart.
+pub struct PmemStore {
+    base: NonNull<u8>,
+    slots: usize,
+    persist: Arc<dyn Persist>,
+    index: Mutex<Index>,
+    /// Whether `open()` found a valid image.
+    recovered: bool,
+}
+
+// The region is only accessed with the index locked.
+unsafe impl Send for PmemStore {}
+unsafe impl Sync for PmemStore {}
+
+impl PmemStore {
+    /// Use the `len` bytes of persistent memory at `base`, 64-byte aligned.
+    /// If they hold the image of a previous store of the same size, its
+    /// entries are validated and the intact ones reused; otherwise the
+    /// region is formatted. Fails with `NoSpace` if the region can't hold a
+    /// single page.
+    ///
+    /// # Safety
+    /// The region must be valid for reads and writes for the lifetime of the
+    /// store, and not be accessed otherwise meanwhile.
+    pub unsafe fn open(
+        base: NonNull<u8>,
+        len: usize,
+        persist: Arc<dyn Persist>,
+    ) -> Result<PmemStore, FileSystemError> {
+        let slots = slots_for(len);
+        if slots == 0 {
+            return Err(FileSystemError::NoSpace);
+        }
+        let mut free = Vec::new();
+        free.try_reserve(slots)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        let mut store = PmemStore {
+            base,
+            slots,
+            persist,
+            index: Mutex::new(Index {
+                slots: HashMap::new(),
+                free,
+                retired: Vec::new(),
+            }),
+            recovered: false,
+        };
+
+        let header = ptr::read(base.as_ptr() as *const Header);
+        store.recovered = header.magic == MAGIC
+            && header.version == VERSION
+            && header.slots == slots as u64
+            && header.checksum == header.checksum();
+        if !store.recovered {
+            store.format();
+        }
+
+        // Torn or free slots are reused, last ones first.
+        for slot in (0..slots).rev() {
+            let entry = store.entry(slot);
+            if entry.kind == FREE || entry.len as usize > BASE_PAGE_SIZE {
+                store.index.get_mut().free.push(slot);
+                continue;
+            }
+            let data = core::slice::from_raw_parts(store.data(slot), entry.len as usize);
+            if entry.checksum != entry.checksum(data) {
+                store.index.get_mut().free.push(slot);
+                continue;
+            }
+            let index = store.index.get_mut();
+            index
+                .slots
+                .try_reserve(1)
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            // A crash before the old version of an update was freed leaves
+            // both versions.
+            let key = (entry.mnode, entry.kind, entry.offset);
+            let stale = match index.slots.get(&key) {
+                Some(&(_, seq)) if (seq.wrapping_sub(entry.seq) as i32) > 0 => slot,
+                Some(&(other, _)) => {
+                    index.slots.insert(key, (slot, entry.seq));
+                    other
+                }
+                None => {
+                    index.slots.insert(key, (slot, entry.seq));
+                    continue;
+                }
+            };
+            index.free.push(stale);
+        }
+        Ok(store)
+    }
+
+    /// Whether `open()` reused the image of a previous store, whose files a
+    /// MemFS built over it gets back with `MemFS::restore()`.
+    pub fn recovered(&self) -> bool {
+        self.recovered
+    }
+
+    /// The metadata stored by `fsync`, e.g. to recreate the files after the
+    /// image was recovered.
+    pub fn metadata(&self) -> Result<Vec<Metadata>, FileSystemError> {
+        let files = self.files()?;
+        let mut metadata = Vec::new();
+        metadata
+            .try_reserve_exact(files.len())
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        metadata.extend(files.into_iter().map(|(_, metadata)| metadata));
+        Ok(metadata)
+    }
+
+    /// Store the metadata of a file followed by its path, which may be empty.
+    fn write_record(&self, path: &str, metadata: &Metadata) -> Result<(), FileSystemError> {
+        if METADATA_LEN + path.len() > BASE_PAGE_SIZE {
+            return Err(FileSystemError::NameTooLong);
+        }
+        let mut bytes = Vec::new();
+        bytes
+            .try_reserve_exact(METADATA_LEN + path.len())
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        let words = [
+            metadata.mnode,
+            metadata.ftype,
+            metadata.fsize,
+            metadata.modes,
+            metadata.ctime,
+            metadata.mtime,
+            metadata.version,
+        ];
+        for word in words {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend_from_slice(path.as_bytes());
+        self.write(metadata.mnode, METADATA, 0, &bytes)
+    }
+
+    /// Free all the slots and then write the header.
+    unsafe fn format(&mut self) {
+        for slot in 0..self.slots {
+            let entry = self.entry_ptr(slot);
+            ptr::write(&mut (*entry).kind, FREE);
+        }
+        let entries = self.base.as_ptr().add(CACHE_LINE_SIZE);
+        self.write_back(entries, self.slots * size_of::<Entry>());
+        self.persist.fence();
+        ptr::write(self.base.as_ptr() as *mut Header, Header::new(self.slots));
+        self.write_back(self.base.as_ptr(), size_of::<Header>());
+        self.persist.fence();
+    }
+
+    fn entry_ptr(&self, slot: usize) -> *mut Entry {
+        let offset = CACHE_LINE_SIZE + slot * size_of::<Entry>();
+        unsafe { self.base.as_ptr().add(offset) as *mut Entry }
+    }
+
+    fn entry(&self, slot: usize) -> Entry {
+        unsafe { ptr::read(self.entry_ptr(slot)) }
+    }
+
+    fn data(&self, slot: usize) -> *mut u8 {
+        let offset = data_start(self.slots) + slot * BASE_PAGE_SIZE;
+        unsafe { self.base.as_ptr().add(offset) }
+    }
+
+    /// Write back the cache lines holding `len` bytes from `ptr`.
+    fn write_back(&self, ptr: *const u8, len: usize) {
+        let start = ptr as usize & !(CACHE_LINE_SIZE - 1);
+        let end = ptr as usize + len;
+        for line in (start..end).step_by(CACHE_LINE_SIZE) {
+            unsafe { self.persist.write_back(line as *const u8) };
+        }
+    }
+
+    /// Copy the data of `slot` into `buffer`, zero-filled past its end.
+    fn read_slot(&self, slot: usize, buffer: &mut [u8]) {
+        let len = (self.entry(slot).len as usize).min(buffer.len());
+        unsafe { ptr::copy_nonoverlapping(self.data(slot), buffer.as_mut_ptr(), len) };
+        buffer[len..].fill(0);
+    }
+
+    /// Store `data` as the newest version of (mnode, kind, offset), in a
+    /// free slot. Not ordered before the next fence, which frees the slot of
+    /// the version it replaces.
+    fn write(
+        &self,
+        mnode: Mnode,
+        kind: u16,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), FileSystemError> {
+        if data.len() > BASE_PAGE_SIZE {
+            return Err(FileSystemError::NotSupported);
+        }
+        let mut index = self.index.lock();
+        let Index {
+            slots,
+            free,
+            retired,
+        } = &mut *index;
+        let old = slots.get(&(mnode, kind, offset)).copied();
+        slots
+            .try_reserve(1)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        retired
+            .try_reserve(1)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        let slot = free.pop().ok_or(FileSystemError::NoSpace)?;
+        let seq = old.map_or(0, |(_, seq)| seq.wrapping_add(1));
+
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.data(slot), data.len()) };
+        self.write_back(self.data(slot), data.len());
+        let mut entry = Entry {
+            kind,
+            len: data.len() as u16,
+            seq,
+            mnode,
+            offset,
+            checksum: 0,
+        };
+        entry.checksum = entry.checksum(data);
+        unsafe { ptr::write(self.entry_ptr(slot), entry) };
+        self.write_back(self.entry_ptr(slot) as *const u8, size_of::<Entry>());
+        slots.insert((mnode, kind, offset), (slot, seq));
+        if let Some((old, _)) = old {
+            retired.push((mnode, old));
+        }
+        Ok(())
+    }
+
+    /// Mark `slot` as free on the media; not ordered before the next fence.
+    fn free_slot(&self, slot: usize) {
+        unsafe { ptr::write(&mut (*self.entry_ptr(slot)).kind, FREE) };
+        self.write_back(self.entry_ptr(slot) as *const u8, size_of::<Entry>());
+    }
+}
+
+impl StorageBackend for PmemStore {
+    fn read_page(
+        &self,
+        mnode: Mnode,
+        offset: usize,
+        page: &mut [u8],
+    ) -> Result<(), FileSystemError> {
+        let index = self.index.lock();
+        match index.slots.get(&(mnode, PAGE, offset as u64)) {
+            Some(&(slot, _)) => {
+                self.read_slot(slot, page);
+                Ok(())
+            }
+            None => Err(FileSystemError::InvalidOffset),
+        }
+    }
+
+    fn write_page(&self, mnode: Mnode, offset: usize, page: &[u8]) -> Result<(), FileSystemError> {
+        self.write(mnode, PAGE, offset as u64, page)
+    }
+
+    fn write_metadata(&self, metadata: &Metadata) -> Result<(), FileSystemError> {
+        self.write_record("", metadata)
+    }
+
+    fn write_file_fua(&self, path: &str, metadata: &Metadata) -> Result<(), FileSystemError> {
+        self.write_record(path, metadata)?;
+        self.flush()
+    }
+
+    fn files(&self) -> Result<Vec<(String, Metadata)>, FileSystemError> {
+        let index = self.index.lock();
+        let mut files = Vec::new();
+        let mut bytes = Vec::new();
+        for (&(_, kind, _), &(slot, _)) in index.slots.iter() {
+            if kind != METADATA {
+                continue;
+            }
+            let len = self.entry(slot).len as usize;
+            bytes.clear();
+            bytes
+                .try_reserve(len.max(METADATA_LEN))
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            bytes.resize(len.max(METADATA_LEN), 0);
+            self.read_slot(slot, &mut bytes);
+            let mut words = bytes[..METADATA_LEN]
+                .chunks_exact(size_of::<u64>())
+                .map(|word| u64::from_le_bytes(word.try_into().unwrap()));
+            let mut next = || words.next().unwrap_or(0);
+            let metadata = Metadata {
+                mnode: next(),
+                ftype: next(),
+                fsize: next(),
+                modes: next(),
+                ctime: next(),
+                mtime: next(),
+                // Zero in metadata stored before the field existed.
+                version: next(),
+            };
+            // The checksum matched, so the path is the one written.
+            let path = core::str::from_utf8(&bytes[METADATA_LEN..]).unwrap_or("");
+            let mut owned = String::new();
+            owned
+                .try_reserve(path.len())
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            owned.push_str(path);
+            files
+                .try_reserve(1)
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            files.push((owned, metadata));
+        }
+        files.sort_by_key(|(_, metadata)| metadata.mnode);
+        Ok(files)
+    }
+
+    fn flush(&self) -> Result<(), FileSystemError> {
+        let mut index = self.index.lock();
+        self.persist.fence();
+        // The versions that replaced the retired ones are durable now.
+        let Index { free, retired, .. } = &mut *index;
+        for (_, slot) in retired.drain(..) {
+            self.free_slot(slot);
+            free.push(slot);
+        }
+        Ok(())
+    }
+
+    fn discard(&self, mnode: Mnode) -> Result<(), FileSystemError> {
+        let mut index = self.index.lock();
+        let Index {
+            slots,
+            free,
+            retired,
+        } = &mut *index;
+        free.try_reserve(slots.len() + retired.len())
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        slots.retain(|key, &mut (slot, _)| {
+            if key.0 != mnode {
+                return true;
+            }
+            self.free_slot(slot);
+            free.push(slot);
+            false
+        });
+        // The replaced versions go too, or they would be found again once
+        // the discard is durable.
+        retired.retain(|&(owner, slot)| {
+            if owner != mnode {
+                return true;
+            }
+            self.free_slot(slot);
+            free.push(slot);
+            false
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FileModes, FileSystem, FileSystemRead, MemFSBuilder};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the write-backs and the fences instead of issuing them.
+    #[derive(Default)]
+    struct Counter {
+        lines: AtomicUsize,
+        fences: AtomicUsize,
+    }
+
+    impl Persist for Counter {
+        unsafe fn write_back(&self, _: *const u8) {
+            self.lines.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn fence(&self) {
+            self.fences.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// A region of `pages` pages, 64-byte aligned.
+    fn region(pages: usize) -> Vec<u64> {
+        alloc::vec![0xdead_beef; pages * BASE_PAGE_SIZE / 8]
+    }
+
+    fn open(region: &mut [u64], persist: Arc<dyn Persist>) -> PmemStore {
+        let base = NonNull::new(region.as_mut_ptr() as *mut u8).unwrap();
+        unsafe { PmemStore::open(base, region.len() * 8, persist) }.unwrap()
+    }
+
+    /// A cache line written back: its offset in the region and its content
+    /// at the time, clipped to the region.
+    type Line = (usize, Vec<u8>);
+
+    /// Records the cache lines written back between two fences, which reach
+    /// the media in any order until the next fence.
+    struct Recorder {
+        region: core::ops::Range<usize>,
+        epochs: Mutex<Vec<Vec<Line>>>,
+    }
+
+    impl Recorder {
+        fn new(region: &[u64]) -> Recorder {
+            let start = region.as_ptr() as usize;
+            Recorder {
+                region: start..start + region.len() * 8,
+                epochs: Mutex::new(alloc::vec![Vec::new()]),
+            }
+        }
+    }
+
+    impl Persist for Recorder {
+        unsafe fn write_back(&self, ptr: *const u8) {
+            let start = (ptr as usize).max(self.region.start);
+            let end = (ptr as usize + CACHE_LINE_SIZE).min(self.region.end);
+            let line = core::slice::from_raw_parts(start as *const u8, end - start);
+            let line = (start - self.region.start, line.to_vec());
+            self.epochs.lock().last_mut().unwrap().push(line);
+        }
+
+        fn fence(&self) {
+            self.epochs.lock().push(Vec::new());
+        }
+    }
+
+    /// What the store is asked to do in the crash tests.
+    enum Op {
+        /// Write the page at (mnode, offset) filled with a byte.
+        Page(Mnode, u64, u8),
+        /// Write the metadata of an mnode with a file size.
+        Metadata(Mnode, u64),
+        Discard(Mnode),
+        Flush,
+    }
+
+    /// A page or the metadata of a file, and its version: the fill byte of
+    /// the page or the file size.
+    type Key = (Mnode, u16, u64);
+
+    /// The versions of each key a crash in an epoch may leave; the version
+    /// durable when the epoch started or any version written in it. `None`
+    /// stands for no version, and keys not in the map must have none.
+    type Allowed = HashMap<Key, Vec<Option<u64>>>;
+
+    /// Run `ops` against the store and return the versions allowed after a
+    /// crash in each epoch.
+    fn run(store: &PmemStore, ops: &[Op]) -> Vec<Allowed> {
+        let mut epochs = Vec::new();
+        let mut durable: HashMap<Key, u64> = HashMap::new();
+        let mut current = durable.clone();
+        let mut allowed = Allowed::new();
+        for op in ops {
+            let written = match *op {
+                Op::Page(mnode, offset, byte) => {
+                    let page = [byte; BASE_PAGE_SIZE];
+                    assert_eq!(store.write_page(mnode, offset as usize, &page), Ok(()));
+                    alloc::vec![((mnode, PAGE, offset), Some(byte as u64))]
+                }
+                Op::Metadata(mnode, fsize) => {
+                    let metadata = Metadata {
+                        mnode,
+                        fsize,
+                        ..Metadata::default()
+                    };
+                    assert_eq!(store.write_metadata(&metadata), Ok(()));
+                    alloc::vec![((mnode, METADATA, 0), Some(fsize))]
+                }
+                Op::Discard(mnode) => {
+                    assert_eq!(store.discard(mnode), Ok(()));
+                    current
+                        .keys()
+                        .filter(|key| key.0 == mnode)
+                        .map(|key| (*key, None))
+                        .collect()
+                }
+                Op::Flush => {
+                    assert_eq!(store.flush(), Ok(()));
+                    durable = current.clone();
+                    epochs.push(core::mem::take(&mut allowed));
+                    Vec::new()
+                }
+            };
+            for (key, version) in written {
+                allowed
+                    .entry(key)
+                    .or_insert_with(|| alloc::vec![durable.get(&key).copied()])
+                    .push(version);
+                match version {
+                    Some(version) => current.insert(key, version),
+                    None => current.remove(&key),
+                };
+            }
+            for (key, version) in durable.iter() {
+                allowed
+                    .entry(*key)
+                    .or_insert_with(|| alloc::vec![Some(*version)]);
+            }
+        }
+        epochs.push(allowed);
+        epochs
+    }
+
+    /// Open the store on a crash image and check that every key has one of
+    /// the allowed versions, intact, and that no slot was lost.
+    fn check(image: &mut [u64], allowed: &Allowed, crash: &str) {
+        let store = open(image, Arc::new(Counter::default()));
+        let mut found = HashMap::new();
+        let metadata = store.metadata().unwrap();
+        let index = store.index.lock();
+        for (&key, &(slot, _)) in index.slots.iter() {
+            let version = match key.1 {
+                PAGE => {
+                    let mut page = [0; BASE_PAGE_SIZE];
+                    store.read_slot(slot, &mut page);
+                    assert!(page.iter().all(|b| *b == page[0]), "{}: torn page", crash);
+                    page[0] as u64
+                }
+                _ => metadata.iter().find(|m| m.mnode == key.0).unwrap().fsize,
+            };
+            found.insert(key, version);
+        }
+        for key in found.keys().chain(allowed.keys()) {
+            let versions = allowed.get(key).map_or(&[None][..], |v| &v[..]);
+            let version = found.get(key).copied();
+            assert!(
+                versions.contains(&version),
+                "{}: {:?} is {:?}, expected one of {:?}",
+                crash,
+                key,
+                version,
+                versions
+            );
+        }
+        assert_eq!(
+            index.slots.len() + index.free.len(),
+            store.slots,
+            "{}",
+            crash
+        );
+        let free = !index.free.is_empty();
+        drop(index);
+        if free {
+            assert_eq!(store.write_page(9, 0, &[9; 1]), Ok(()), "{}", crash);
+        }
+    }
+
+    fn apply(image: &mut [u64], (offset, line): &Line) {
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(image.as_mut_ptr() as *mut u8, image.len() * 8)
+        };
+        bytes[*offset..*offset + line.len()].copy_from_slice(line);
+    }
+
+    #[test]
+    /// The region is formatted once; after that its pages and metadata are
+    /// found again, except for the ones torn by a crash.
+    fn test_recovery() {
+        let mut region = region(4);
+        let counter = Arc::new(Counter::default());
+        let store = open(&mut region, counter.clone());
+        assert!(!store.recovered());
+        assert_eq!(store.slots, 3);
+        let mut page = [0; BASE_PAGE_SIZE];
+        assert_eq!(
+            store.read_page(2, 0, &mut page),
+            Err(FileSystemError::InvalidOffset)
+        );
+
+        assert_eq!(store.write_page(2, 0, &[1; BASE_PAGE_SIZE]), Ok(()));
+        assert_eq!(store.write_page(2, 4096, &[2; 10]), Ok(()));
+        let metadata = Metadata {
+            mnode: 2,
+            fsize: 4106,
+            ..Metadata::default()
+        };
+        assert_eq!(store.write_metadata_fua(&metadata), Ok(()));
+        assert_eq!(
+            store.write_page(3, 0, &[3; 1]),
+            Err(FileSystemError::NoSpace)
+        );
+        assert!(counter.lines.load(Ordering::Relaxed) > BASE_PAGE_SIZE / 64);
+        assert_eq!(counter.fences.load(Ordering::Relaxed), 3);
+        drop(store);
+
+        let store = open(&mut region, counter.clone());
+        assert!(store.recovered());
+        assert_eq!(store.read_page(2, 4096, &mut page), Ok(()));
+        assert_eq!(page[..10], [2; 10]);
+        assert!(page[10..].iter().all(|b| *b == 0));
+        assert_eq!(store.metadata(), Ok(alloc::vec![metadata]));
+
+        // A page torn by a crash is dropped and its slot reused.
+        let data = store.data(store.index.lock().slots[&(2, PAGE, 0)].0);
+        unsafe { *data.add(100) = 7 };
+        drop(store);
+        let store = open(&mut region, counter);
+        assert_eq!(
+            store.read_page(2, 0, &mut page),
+            Err(FileSystemError::InvalidOffset)
+        );
+        assert_eq!(store.write_page(3, 0, &[3; 1]), Ok(()));
+        assert_eq!(store.discard(2), Ok(()));
+        assert_eq!(store.metadata(), Ok(Vec::new()));
+        assert_eq!(store.write_page(4, 0, &[4; 1]), Ok(()));
+    }
+
+    #[test]
+    /// A crash at any point leaves every page and metadata either at the
+    /// version made durable by the last flush or at one written since, never
+    /// torn. The crash images are built from the write-backs of the earlier
+    /// epochs plus every prefix and some random subsets of the write-backs of
+    /// the epoch the crash happened in.
+    fn test_crash_prefixes() {
+        let mut region = region(12);
+        let recorder = Arc::new(Recorder::new(&region));
+        let store = open(&mut region, recorder.clone());
+        let formatted = region.clone();
+        *recorder.epochs.lock() = alloc::vec![Vec::new()];
+
+        let ops = [
+            Op::Page(2, 0, 1),
+            Op::Page(2, 4096, 2),
+            Op::Metadata(2, 8192),
+            Op::Flush,
+            // Overwrites, twice in the same epoch.
+            Op::Page(2, 0, 3),
+            Op::Page(3, 0, 4),
+            Op::Page(2, 0, 5),
+            Op::Metadata(3, 4096),
+            Op::Flush,
+            // A discard while the replaced version still has its slot.
+            Op::Page(3, 0, 6),
+            Op::Discard(3),
+            Op::Page(4, 0, 7),
+            Op::Metadata(4, 4096),
+            Op::Metadata(2, 4096),
+            Op::Flush,
+            Op::Page(2, 4096, 8),
+            Op::Discard(2),
+            Op::Flush,
+            // Not flushed.
+            Op::Page(4, 0, 9),
+            Op::Metadata(4, 1),
+        ];
+        let allowed = run(&store, &ops);
+        let epochs = recorder.epochs.lock().clone();
+        assert_eq!(epochs.len(), allowed.len());
+
+        let mut durable = formatted;
+        let mut random = 0x2545_f491_4f6c_dd1d_u64;
+        for (epoch, (lines, allowed)) in epochs.iter().zip(allowed.iter()).enumerate() {
+            for prefix in 0..=lines.len() {
+                let mut image = durable.clone();
+                lines[..prefix]
+                    .iter()
+                    .for_each(|line| apply(&mut image, line));
+                let crash = alloc::format!("epoch {} prefix {}", epoch, prefix);
+                check(&mut image, allowed, &crash);
+            }
+            for subset in 0..32 {
+                let mut image = durable.clone();
+                for line in lines {
+                    random ^= random << 13;
+                    random ^= random >> 7;
+                    random ^= random << 17;
+                    if random & 1 == 1 {
+                        apply(&mut image, line);
+                    }
+                }
+                let crash = alloc::format!("epoch {} subset {}", epoch, subset);
+                check(&mut image, allowed, &crash);
+            }
+            lines.iter().for_each(|line| apply(&mut durable, line));
+        }
+    }
+
+    #[test]
+    /// The pages MemFS evicts to persistent memory come back intact.
+    fn test_pmem_backing_store() {
+        let mut region = region(8);
+        let store = Arc::new(open(&mut region, Arc::new(Clflush)));
+        let memfs = MemFSBuilder::new().backing_store(store.clone(), 1).build();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        for page in 0..3u8 {
+            let data = [page; BASE_PAGE_SIZE];
+            assert_eq!(
+                memfs.write(mnode, &data, page as usize * BASE_PAGE_SIZE),
+                Ok(BASE_PAGE_SIZE)
+            );
+        }
+        assert_eq!(memfs.fsync(mnode), Ok(()));
+        let mut buffer = [0; 3 * BASE_PAGE_SIZE];
+        assert_eq!(memfs.read(mnode, &mut buffer, 0), Ok(3 * BASE_PAGE_SIZE));
+        for (page, data) in buffer.chunks(BASE_PAGE_SIZE).enumerate() {
+            assert!(data.iter().all(|b| *b == page as u8));
+        }
+        assert_eq!(store.metadata().unwrap()[0].fsize, 3 * 4096);
+        assert_eq!(memfs.delete("/nrfs"), Ok(true));
+        assert_eq!(store.metadata(), Ok(Vec::new()));
+    }
+
+    #[test]
+    /// A MemFS over a recovered store gets back the files synced before,
+    /// with their mnode numbers, data and modes.
+    fn test_restore() {
+        let mut region = region(16);
+        let modes = FileModes::S_IRWXU.into();
+        let read_write = (FileModes::S_IRUSR | FileModes::S_IWUSR).into();
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let (dir, big, small) = {
+            let store = Arc::new(open(&mut region, Arc::new(Clflush)));
+            let memfs = MemFSBuilder::new().backing_store(store, 1).build();
+            let dir = memfs.mkdir("/d", modes).unwrap();
+            let big = memfs.create("/d/big", modes).unwrap();
+            memfs.mkdir("/d/e", modes).unwrap();
+            let small = memfs.create("/d/e/small", read_write).unwrap();
+            let lost = memfs.create("/lost", modes).unwrap();
+            assert_eq!(memfs.write(big, &data, 0), Ok(data.len()));
+            assert_eq!(memfs.write(small, b"nrfs", 0), Ok(4));
+            assert_eq!(memfs.write(lost, b"lost", 0), Ok(4));
+            for mnode in [dir, big, small] {
+                assert_eq!(memfs.fsync(mnode), Ok(()));
+            }
+            (dir, big, small)
+        };
+
+        let store = Arc::new(open(&mut region, Arc::new(Clflush)));
+        assert!(store.recovered());
+        let memfs = MemFSBuilder::new().backing_store(store.clone(), 1).build();
+        assert_eq!(memfs.restore(), Ok(3));
+        assert_eq!(memfs.restore(), Err(FileSystemError::Busy));
+        assert_eq!(*memfs.lookup("/d").unwrap(), dir);
+        assert_eq!(*memfs.lookup("/d/big").unwrap(), big);
+        assert_eq!(*memfs.lookup("/d/e/small").unwrap(), small);
+        assert!(memfs.lookup("/lost").is_none());
+        let mut buffer = alloc::vec![0; 8192];
+        assert_eq!(memfs.read(big, &mut buffer, 0), Ok(data.len()));
+        assert_eq!(buffer[..data.len()], data[..]);
+        assert_eq!(memfs.read(small, &mut buffer, 0), Ok(4));
+        assert_eq!(&buffer[..4], b"nrfs");
+        assert_eq!(memfs.metadata(small).unwrap().modes, read_write);
+
+        // New files get numbers of their own.
+        assert!(memfs.create("/new", modes).unwrap() > small);
+        assert_eq!(memfs.delete("/d/big"), Ok(true));
+        assert_eq!(store.metadata().unwrap().len(), 2);
+    }
+}
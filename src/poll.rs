@@ -0,0 +1,185 @@
+//! Operations for async kernels and executors.
+//!
+//! `MemFS::poll_read()` and `MemFS::poll_write()` never wait for the lock of
+//! a file: if it is taken, they return `Poll::Pending` and wake the task of
+//! the caller, so the executor runs other tasks before polling again instead
+//! of spinning on the lock. `read_async()` and `write_async()` wrap them in
+//! futures, which need neither std nor a particular executor.
+//!
+//! The backing store is synchronous, so pages evicted to it are read back
+//! within the poll, and making room for new data may wait for the locks of
+//! the files it evicts pages of.
+
+use core::future::Future;
+use core::hash::BuildHasher;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use lock_api::RawRwLock;
+
+use crate::{FileSystemError, MemFS, Mnode};
+
+/// Turn `WouldBlock` into `Pending`, asking to be polled again.
+fn pending_on_contention<T>(
+    result: Result<T, FileSystemError>,
+    cx: &mut Context<'_>,
+) -> Poll<Result<T, FileSystemError>> {
+    match result {
+        Err(FileSystemError::WouldBlock) => {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+        result => Poll::Ready(result),
+    }
+}
+
+impl<S: BuildHasher + Clone + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
+    /// Read from the file `mnode` at `offset` like `FileSystem::read()`, or
+    /// return `Pending` if the file is locked.
+    pub fn poll_read(
+        &self,
+        mnode: Mnode,
+        buffer: &mut [u8],
+        offset: usize,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<usize, FileSystemError>> {
+        pending_on_contention(self.read_with(mnode, buffer, offset, false), cx)
+    }
+
+    /// Write to the file `mnode` at `offset` like `FileSystem::write()`, or
+    /// return `Pending` if the file is locked.
+    pub fn poll_write(
+        &self,
+        mnode: Mnode,
+        buffer: &[u8],
+        offset: usize,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<usize, FileSystemError>> {
+        pending_on_contention(self.write_with(mnode, buffer, offset, false), cx)
+    }
+
+    /// A future of `poll_read()`.
+    pub fn read_async<'a>(
+        &'a self,
+        mnode: Mnode,
+        buffer: &'a mut [u8],
+        offset: usize,
+    ) -> ReadFuture<'a, S, L> {
+        ReadFuture {
+            fs: self,
+            mnode,
+            buffer,
+            offset,
+        }
+    }
+
+    /// A future of `poll_write()`.
+    pub fn write_async<'a>(
+        &'a self,
+        mnode: Mnode,
+        buffer: &'a [u8],
+        offset: usize,
+    ) -> WriteFuture<'a, S, L> {
+        WriteFuture {
+            fs: self,
+            mnode,
+            buffer,
+            offset,
+        }
+    }
+}
+
+/// A read of a file, see `MemFS::read_async()`.
+pub struct ReadFuture<'a, S: BuildHasher + Send + Sync, L: RawRwLock> {
+    fs: &'a MemFS<S, L>,
+    mnode: Mnode,
+    buffer: &'a mut [u8],
+    offset: usize,
+}
+
+impl<S: BuildHasher + Clone + Send + Sync, L: RawRwLock + Send + Sync> Future
+    for ReadFuture<'_, S, L>
+{
+    type Output = Result<usize, FileSystemError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.fs.poll_read(this.mnode, this.buffer, this.offset, cx)
+    }
+}
+
+/// A write to a file, see `MemFS::write_async()`.
+pub struct WriteFuture<'a, S: BuildHasher + Send + Sync, L: RawRwLock> {
+    fs: &'a MemFS<S, L>,
+    mnode: Mnode,
+    buffer: &'a [u8],
+    offset: usize,
+}
+
+impl<S: BuildHasher + Clone + Send + Sync, L: RawRwLock + Send + Sync> Future
+    for WriteFuture<'_, S, L>
+{
+    type Output = Result<usize, FileSystemError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.fs.poll_write(this.mnode, this.buffer, this.offset, cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FileModes, FileSystem};
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::task::Waker;
+    use std::task::Wake;
+
+    /// Counts its wakeups.
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    /// Polls of a locked file are pending and ask to be polled again; once
+    /// the lock is released, they complete.
+    fn test_poll() {
+        let memfs = MemFS::default();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(Arc::clone(&counter));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut write = memfs.write_async(mnode, b"nrfs", 0);
+        assert_eq!(Pin::new(&mut write).poll(&mut cx), Poll::Ready(Ok(4)));
+        let mut buf = [0; 4];
+        {
+            let memnode = memfs.memnode(mnode).unwrap();
+            let _locked = memnode.write();
+            assert_eq!(memfs.poll_read(mnode, &mut buf, 0, &mut cx), Poll::Pending);
+            assert_eq!(memfs.poll_write(mnode, b"x", 0, &mut cx), Poll::Pending);
+            assert_eq!(counter.0.load(Ordering::Relaxed), 2);
+        }
+        {
+            let memnode = memfs.memnode(mnode).unwrap();
+            let _locked = memnode.read();
+            let mut read = memfs.read_async(mnode, &mut buf, 0);
+            assert_eq!(Pin::new(&mut read).poll(&mut cx), Poll::Ready(Ok(4)));
+            assert_eq!(memfs.poll_write(mnode, b"x", 0, &mut cx), Poll::Pending);
+        }
+        assert_eq!(&buf, b"nrfs");
+        assert_eq!(
+            memfs.poll_read(42, &mut buf, 0, &mut cx),
+            Poll::Ready(Err(FileSystemError::InvalidFile))
+        );
+    }
+}
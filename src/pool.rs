@@ -0,0 +1,218 @@
+//! A cap on the memory holding the data of the files, so a growing
+//! file-system pushes back on its writers, or on the embedder, instead of
+//! running the kernel out of memory.
+//!
+//! Every page of file data, including the ones paged back in, shared by
+//! `dedup()` or kept by versions, is charged to the pool when it is allocated
+//! and given back when it is freed. An allocation past the cap is refused, and
+//! the write that needed it settles that with the [`Exhausted`] behavior of
+//! the pool once it released the lock of the file, then tries again.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Asks the embedder to make room in an exhausted pool, e.g. by dropping
+/// caches of its own files or by running the shrinkers of the kernel.
+pub trait Reclaim: Send + Sync {
+    /// Make room for `bytes` more bytes in the pool; returns whether the
+    /// allocation is worth trying again. Called without holding a lock of
+    /// the file-system, so it may call into it.
+    fn reclaim(&self, bytes: usize) -> bool;
+}
+
+/// What a write does when the pool can't hold the pages it needs.
+#[derive(Clone)]
+pub enum Exhausted {
+    /// Fail with `NoSpace`.
+    Fail,
+    /// Free pages the backing store holds already, without writing anything
+    /// back, and fail with `NoSpace` if that doesn't make room. Needs a
+    /// backing store; without one no page is clean.
+    Evict,
+    /// Call the embedder, and fail with `NoSpace` if it couldn't make room.
+    Reclaim(Arc<dyn Reclaim>),
+}
+
+/// A write tries again at most this many times after making room.
+pub(crate) const ATTEMPTS: usize = 3;
+
+/// Counts the memory of the file data against a cap.
+pub struct PagePool {
+    max_bytes: usize,
+    used: AtomicUsize,
+    /// Allocations refused so far, so a failed write can tell if the pool
+    /// refused one of its allocations.
+    refused: AtomicUsize,
+    exhausted: Exhausted,
+}
+
+impl PagePool {
+    /// A pool of `max_bytes` bytes that does `exhausted` when it is full.
+    pub fn new(max_bytes: usize, exhausted: Exhausted) -> PagePool {
+        PagePool {
+            max_bytes,
+            used: AtomicUsize::new(0),
+            refused: AtomicUsize::new(0),
+            exhausted,
+        }
+    }
+
+    /// The cap of the pool, in bytes.
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// The bytes allocated from the pool.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// The behavior of the pool when it is full.
+    pub fn exhausted(&self) -> &Exhausted {
+        &self.exhausted
+    }
+
+    /// The number of allocations refused since the pool was created.
+    pub fn refused(&self) -> usize {
+        self.refused.load(Ordering::Relaxed)
+    }
+
+    /// Charge `bytes` to the pool, unless that takes it past the cap.
+    pub(crate) fn charge(&self, bytes: usize) -> bool {
+        let max_bytes = self.max_bytes;
+        let charged = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|used| *used <= max_bytes)
+            })
+            .is_ok();
+        if !charged {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+        }
+        charged
+    }
+
+    /// Give back `bytes` charged before.
+    pub(crate) fn credit(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::{MemFSBuilder, StorageBackend};
+    use crate::{FileModes, FileSystem, FileSystemError, FileSystemRead, Mnode, BASE_PAGE_SIZE};
+    use alloc::collections::BTreeMap;
+    use alloc::vec::Vec;
+    use spin::Mutex;
+
+    /// Deletes a file to make room.
+    struct Unlink(Mutex<Option<Arc<crate::MemFS>>>);
+
+    impl Reclaim for Unlink {
+        fn reclaim(&self, _bytes: usize) -> bool {
+            match self.0.lock().take() {
+                Some(memfs) => memfs.delete("/old").is_ok(),
+                None => false,
+            }
+        }
+    }
+
+    /// Keeps the written back pages.
+    #[derive(Default)]
+    struct Store(Mutex<BTreeMap<(Mnode, usize), Vec<u8>>>);
+
+    impl StorageBackend for Store {
+        fn read_page(
+            &self,
+            mnode: Mnode,
+            offset: usize,
+            page: &mut [u8],
+        ) -> Result<(), FileSystemError> {
+            let pages = self.0.lock();
+            let data = pages
+                .get(&(mnode, offset))
+                .ok_or(FileSystemError::InvalidOffset)?;
+            page.copy_from_slice(&data[..page.len()]);
+            Ok(())
+        }
+
+        fn write_page(
+            &self,
+            mnode: Mnode,
+            offset: usize,
+            page: &[u8],
+        ) -> Result<(), FileSystemError> {
+            self.0.lock().insert((mnode, offset), page.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    /// A full pool fails the write, or makes room for it.
+    fn test_page_pool() {
+        let rwx = FileModes::S_IRWXU.into();
+        let memfs = MemFSBuilder::new()
+            .page_pool(4 * BASE_PAGE_SIZE, Exhausted::Fail)
+            .build();
+        let mnode = memfs.create("/file", rwx).unwrap();
+        assert_eq!(
+            memfs.write(mnode, &[0xb; 4 * BASE_PAGE_SIZE], 0),
+            Ok(4 * BASE_PAGE_SIZE)
+        );
+        let pool = memfs.page_pool().unwrap();
+        assert_eq!(pool.used(), 4 * BASE_PAGE_SIZE);
+        assert_eq!(
+            memfs.write(mnode, &[0xb; 10], 4 * BASE_PAGE_SIZE),
+            Err(FileSystemError::NoSpace)
+        );
+        assert_eq!(
+            memfs.file_info(mnode).unwrap().fsize,
+            4 * BASE_PAGE_SIZE as u64
+        );
+        memfs.delete("/file").unwrap();
+        assert_eq!(memfs.page_pool().unwrap().used(), 0);
+
+        // The embedder deletes a file to make room.
+        let unlink = Arc::new(Unlink(Mutex::new(None)));
+        let memfs = Arc::new(
+            MemFSBuilder::new()
+                .page_pool(4 * BASE_PAGE_SIZE, Exhausted::Reclaim(unlink.clone()))
+                .build(),
+        );
+        *unlink.0.lock() = Some(Arc::clone(&memfs));
+        let old = memfs.create("/old", rwx).unwrap();
+        memfs.write(old, &[0xb; 3 * BASE_PAGE_SIZE], 0).unwrap();
+        let new = memfs.create("/new", rwx).unwrap();
+        assert_eq!(
+            memfs.write(new, &[0xb; 2 * BASE_PAGE_SIZE], 0),
+            Ok(2 * BASE_PAGE_SIZE)
+        );
+        assert!(memfs.lookup("/old").is_none());
+        assert_eq!(
+            memfs.write(new, &[0xb; 5 * BASE_PAGE_SIZE], 0),
+            Err(FileSystemError::NoSpace)
+        );
+
+        // Clean pages are dropped, changed ones aren't.
+        let store = Arc::new(Store::default());
+        let memfs = MemFSBuilder::new()
+            .backing_store(store, usize::MAX)
+            .page_pool(4 * BASE_PAGE_SIZE, Exhausted::Evict)
+            .build();
+        let clean = memfs.create("/clean", rwx).unwrap();
+        memfs.write(clean, &[0xc; 3 * BASE_PAGE_SIZE], 0).unwrap();
+        let dirty = memfs.create("/dirty", rwx).unwrap();
+        memfs.write(dirty, &[0xd; BASE_PAGE_SIZE], 0).unwrap();
+        assert_eq!(
+            memfs.write(dirty, &[0xd; 10], BASE_PAGE_SIZE),
+            Err(FileSystemError::NoSpace)
+        );
+        memfs.fsync(clean).unwrap();
+        assert_eq!(memfs.write(dirty, &[0xd; 10], BASE_PAGE_SIZE), Ok(10));
+        let mut buffer = [0; 16];
+        memfs.read(dirty, &mut buffer, BASE_PAGE_SIZE - 6).unwrap();
+        assert_eq!(buffer, [0xd; 16]);
+    }
+}
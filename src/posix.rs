@@ -0,0 +1,289 @@
+//! A pjdfstest-style front-end for running POSIX conformance cases against MemFS.
+//!
+//! pjdfstest drives a file-system through a small helper that takes a syscall
+//! name and its arguments (e.g. `open /foo O_CREAT,O_RDWR 0644`) and prints
+//! either `0`, the requested value(s), or the name of the errno. The adapter
+//! exposes the same interface over [`MemFS`], so the cases of an established
+//! suite can be replayed as-is. Behaviors MemFS doesn't implement on purpose
+//! are listed in [`UNSUPPORTED`] and answered with `EOPNOTSUPP`; all the other
+//! errors are reported through the errno mapping of `FileSystemError`.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::errno::{self, Errno, EINVAL, EISDIR, ENOENT, EOPNOTSUPP};
+use crate::mnode::NodeType;
+use crate::{FileFlags, FileModes, FileSystem, FileSystemRead, MemFS, Modes};
+
+/// Operations that MemFS intentionally doesn't support, with the reason.
+pub const UNSUPPORTED: &[(&str, &str)] = &[
+    ("link", "hard links are not supported"),
+    ("symlink", "symbolic links are not supported"),
+    ("mkfifo", "special files are not supported"),
+    ("mknod", "special files are not supported"),
+    ("chmod", "modes are fixed when the file is created"),
+    ("chown", "files don't have owners"),
+    ("ftruncate", "only truncation by path is supported"),
+];
+
+/// Parse a pjdfstest flag list like `O_CREAT,O_RDWR`.
+fn parse_flags(flags: &str) -> Result<FileFlags, Errno> {
+    let mut parsed = FileFlags::O_NONE;
+    for flag in flags.split(',') {
+        parsed |= match flag {
+            "O_RDONLY" => FileFlags::O_RDONLY,
+            "O_WRONLY" => FileFlags::O_WRONLY,
+            "O_RDWR" => FileFlags::O_RDWR,
+            "O_CREAT" => FileFlags::O_CREAT,
+            "O_TRUNC" => FileFlags::O_TRUNC,
+            "O_APPEND" => FileFlags::O_APPEND,
+            "O_NONBLOCK" => FileFlags::O_NONBLOCK,
+            "O_CLOEXEC" => FileFlags::O_CLOEXEC,
+            "O_PATH" => FileFlags::O_PATH,
+            _ => return Err(EOPNOTSUPP),
+        };
+    }
+    Ok(parsed)
+}
+
+/// Parse an octal permission like `0644`; MemFS only keeps the owner bits.
+fn parse_mode(mode: &str) -> Result<Modes, Errno> {
+    let mode = u64::from_str_radix(mode, 8).map_err(|_| EINVAL)?;
+    Ok(FileModes::from((mode >> 6) & 0o7).into())
+}
+
+/// Executes pjdfstest-style command lines against a MemFS instance.
+pub struct PosixAdapter {
+    fs: MemFS,
+}
+
+impl PosixAdapter {
+    /// Wrap a file-system instance.
+    pub fn new(fs: MemFS) -> PosixAdapter {
+        PosixAdapter { fs }
+    }
+
+    /// Access the wrapped file-system.
+    pub fn fs(&self) -> &MemFS {
+        &self.fs
+    }
+
+    /// Check if the operation is implemented by MemFS.
+    pub fn is_supported(op: &str) -> bool {
+        UNSUPPORTED.iter().all(|(name, _reason)| *name != op)
+    }
+
+    /// Run a single command line and return what pjdfstest would print.
+    pub fn run(&self, cmdline: &str) -> String {
+        let args: Vec<&str> = cmdline.split_whitespace().collect();
+        match self.exec(&args) {
+            Ok(output) => output,
+            Err(errno) => errno::name(errno).to_string(),
+        }
+    }
+
+    fn exec(&self, args: &[&str]) -> Result<String, Errno> {
+        let (op, args) = args.split_first().ok_or(EINVAL)?;
+        if !PosixAdapter::is_supported(op) {
+            return Err(EOPNOTSUPP);
+        }
+
+        match (*op, args) {
+            ("create", [path, mode]) => {
+                self.fs.create(path, parse_mode(mode)?)?;
+            }
+            ("open", [path, flags]) => {
+                let mode = FileModes::S_IRUSR | FileModes::S_IWUSR;
+                self.open(path, parse_flags(flags)?, mode.into())?
+            }
+            ("open", [path, flags, mode]) => {
+                self.open(path, parse_flags(flags)?, parse_mode(mode)?)?
+            }
+            ("mkdir", [path, mode]) => {
+                self.fs.mkdir(path, parse_mode(mode)?)?;
+            }
+            ("unlink", [path]) => {
+                self.fs.delete(path)?;
+            }
+            ("rmdir", [path]) => {
+                self.fs.rmdir(path)?;
+            }
+            ("rename", [oldpath, newpath]) => {
+                self.fs.rename(oldpath, newpath)?;
+            }
+            ("truncate", [path, "0"]) => {
+                self.fs.truncate(path)?;
+            }
+            ("truncate", [_path, _len]) => return Err(EOPNOTSUPP),
+            ("stat", [path, fields]) => return self.stat(path, fields),
+            _ => return Err(EINVAL),
+        }
+        Ok("0".to_string())
+    }
+
+    fn open(&self, path: &str, flags: FileFlags, mode: Modes) -> Result<(), Errno> {
+        let mnode = match self.fs.lookup(path) {
+            // O_PATH ignores the access mode and the creation flags.
+            Some(mnode) if flags.is_path() => return Ok(self.fs.may_open(path, *mnode, flags)?),
+            Some(mnode) => *mnode,
+            None if flags.is_create() && !flags.is_path() => {
+                self.fs.create(path, mode)?;
+                return Ok(());
+            }
+            None => return Err(ENOENT),
+        };
+        self.fs.may_open(path, mnode, flags)?;
+
+        let is_dir = self.fs.file_info(mnode)?.ftype == NodeType::Directory.into();
+        if is_dir && flags.is_write() {
+            return Err(EISDIR);
+        }
+        if flags.is_truncate() {
+            self.fs.truncate(path)?;
+        }
+        Ok(())
+    }
+
+    fn stat(&self, path: &str, fields: &str) -> Result<String, Errno> {
+        let mnode = *self.fs.lookup(path).ok_or(ENOENT)?;
+        let info = self.fs.file_info(mnode)?;
+
+        let mut output = Vec::new();
+        for field in fields.split(',') {
+            output.push(match field {
+                "type" if info.ftype == NodeType::Directory.into() => "dir".to_string(),
+                "type" => "regular".to_string(),
+                "size" => info.fsize.to_string(),
+                _ => return Err(EOPNOTSUPP),
+            });
+        }
+        Ok(output.join(","))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MAX_NAME_LEN;
+
+    /// Run a list of (command, expected output) cases on a fresh file-system.
+    fn check(cases: &[(&str, &str)]) {
+        let adapter = PosixAdapter::new(MemFS::default());
+        for (cmd, expected) in cases {
+            assert_eq!(adapter.run(cmd), *expected, "{}", cmd);
+        }
+    }
+
+    #[test]
+    /// open(2) creates regular files with O_CREAT and fails with ENOENT otherwise.
+    fn test_open() {
+        check(&[
+            ("open /nrfs O_RDONLY", "ENOENT"),
+            ("open /nrfs O_CREAT,O_RDWR 0644", "0"),
+            ("stat /nrfs type,size", "regular,0"),
+            ("open /nrfs O_RDWR", "0"),
+            ("open /nrfs2 O_CREAT,O_WRONLY,O_TRUNC", "0"),
+            ("open /nrfs2 O_RDWR,O_TRUNC", "0"),
+            ("open / O_RDWR", "EISDIR"),
+            ("stat / type", "dir"),
+        ]);
+    }
+
+    #[test]
+    /// unlink(2) removes a file and reports ENOENT for missing ones.
+    fn test_unlink() {
+        check(&[
+            ("create /nrfs 0644", "0"),
+            ("create /nrfs 0644", "EEXIST"),
+            ("unlink /nrfs", "0"),
+            ("stat /nrfs type", "ENOENT"),
+            ("unlink /nrfs", "ENOENT"),
+        ]);
+    }
+
+    #[test]
+    /// rename(2) moves a file and replaces an existing target.
+    fn test_rename() {
+        check(&[
+            ("rename /nrfs /nrfs2", "ENOENT"),
+            ("create /nrfs 0644", "0"),
+            ("create /nrfs2 0644", "0"),
+            ("rename /nrfs /nrfs2", "0"),
+            ("stat /nrfs type", "ENOENT"),
+            ("stat /nrfs2 type", "regular"),
+        ]);
+    }
+
+    #[test]
+    /// truncate(2) to zero is supported; other lengths are reported as unsupported.
+    fn test_truncate() {
+        let adapter = PosixAdapter::new(MemFS::default());
+        assert_eq!(adapter.run("create /nrfs 0644"), "0");
+        let mnode = *adapter.fs().lookup("/nrfs").unwrap();
+        assert_eq!(adapter.fs().write(mnode, &[0xb; 100], 0), Ok(100));
+        assert_eq!(adapter.run("stat /nrfs size"), "100");
+        assert_eq!(adapter.run("truncate /nrfs 10"), "EOPNOTSUPP");
+        assert_eq!(adapter.run("truncate /nrfs 0"), "0");
+        assert_eq!(adapter.run("stat /nrfs size"), "0");
+        assert_eq!(adapter.run("create /ro 0444"), "0");
+        assert_eq!(adapter.run("open /ro O_WRONLY,O_TRUNC"), "EACCES");
+    }
+
+    #[test]
+    /// Directory and namespace failures map to their own errno.
+    fn test_namespace_errors() {
+        let long = "a".repeat(MAX_NAME_LEN + 1);
+        check(&[
+            ("unlink /", "EISDIR"),
+            ("truncate / 0", "EISDIR"),
+            ("rename / /root", "EOPNOTSUPP"),
+            ("create /nrfs 0644", "0"),
+            ("create /nrfs/child 0644", "ENOTDIR"),
+            ("rename /nrfs /nrfs/child", "ENOTDIR"),
+            ("rename /nrfs /", "ENOTEMPTY"),
+            (&("create /".to_string() + &long + " 0644"), "ENAMETOOLONG"),
+        ]);
+    }
+
+    #[test]
+    /// mkdir(2) and rmdir(2) manage directories; renames move whole subtrees.
+    fn test_directories() {
+        check(&[
+            ("mkdir /dir 0755", "0"),
+            ("mkdir /dir 0755", "EEXIST"),
+            ("mkdir /missing/dir 0755", "ENOENT"),
+            ("create /dir/nrfs 0644", "0"),
+            ("stat /dir type", "dir"),
+            ("rmdir /dir", "ENOTEMPTY"),
+            ("unlink /dir", "EISDIR"),
+            ("rmdir /dir/nrfs", "ENOTDIR"),
+            ("mkdir /dir/sub 0755", "0"),
+            ("rename /dir /dir/sub/dir", "EINVAL"),
+            ("rename /dir /moved", "0"),
+            ("stat /moved/nrfs type", "regular"),
+            ("stat /dir/nrfs type", "ENOENT"),
+            ("rename /moved/nrfs /moved/sub", "EISDIR"),
+            ("mkdir /empty 0755", "0"),
+            ("rename /moved/sub /empty", "0"),
+            ("rename /empty /moved", "ENOTEMPTY"),
+            ("unlink /moved/nrfs", "0"),
+            ("rmdir /moved", "0"),
+            ("rmdir /", "EOPNOTSUPP"),
+            ("mkdir /ro 0555", "0"),
+            ("create /ro/nrfs 0644", "EACCES"),
+        ]);
+    }
+
+    #[test]
+    /// Intentionally unsupported operations are answered with EOPNOTSUPP.
+    fn test_unsupported() {
+        assert!(!PosixAdapter::is_supported("link"));
+        assert!(PosixAdapter::is_supported("open"));
+        check(&[
+            ("link /nrfs /link", "EOPNOTSUPP"),
+            ("symlink /nrfs /link", "EOPNOTSUPP"),
+            ("open /nrfs O_CREAT,O_EXCL 0644", "EOPNOTSUPP"),
+            ("bogus", "EINVAL"),
+        ]);
+    }
+}
@@ -0,0 +1,282 @@
+//! An epoch-based (RCU-style) cell for data that is read far more often than
+//! it is written.
+//!
+//! Readers get the currently published version of the data and never wait
+//! for writers. Writers are serialized, publish an updated copy of the data
+//! and free the old version once the readers that might still see it are
+//! gone.
+//!
+//! A reader id below `readers()` is owned by one thread at a time, which
+//! announces the epoch it starts reading in with a plain store to its slot
+//! and clears it when it is done, so reading takes no shared atomic
+//! read-modify-write. Other reader ids share a slot and count themselves
+//! in it under one of two indices picked by the current epoch.
+//!
+//! After publishing a new version, a writer flips the epoch twice and waits
+//! for the index that was just retired to drain each time; readers that
+//! start in the meantime use the other index, so a writer only waits for
+//! readers that started before the update. Then it waits for the owners
+//! that announced an earlier epoch.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::sync::atomic::{fence, AtomicPtr, AtomicUsize, Ordering};
+
+use crate::padded::CachePadded;
+use spin::Mutex;
+
+pub(crate) struct Rcu<T> {
+    /// The published version of the data.
+    current: AtomicPtr<T>,
+    /// Counts the flips; its lowest bit is the index new shared readers use.
+    epoch: AtomicUsize,
+    /// The readers of every reader id.
+    slots: Vec<CachePadded<Slot>>,
+    /// Serializes the writers.
+    writer: Mutex<()>,
+    _marker: PhantomData<T>,
+}
+
+#[derive(Default)]
+struct Slot {
+    /// One more than the epoch the owner of the slot started reading in, 0
+    /// while it doesn't read.
+    announced: AtomicUsize,
+    /// Nested reads of the owner; only the owner touches it.
+    depth: AtomicUsize,
+    /// Readers that share the slot, by epoch index.
+    shared: [AtomicUsize; 2],
+}
+
+/// Old versions are dropped by whichever thread updated the data last.
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+pub(crate) struct RcuGuard<'a, T> {
+    reader: Reader<'a>,
+    data: &'a T,
+}
+
+/// What a guard undoes when it is dropped.
+enum Reader<'a> {
+    Owner(&'a Slot),
+    Shared(&'a AtomicUsize),
+}
+
+impl<T> Rcu<T> {
+    /// Publish `data` as the first version, readable with reader ids up to
+    /// `readers`.
+    pub(crate) fn new(data: T, readers: usize) -> Rcu<T> {
+        Rcu {
+            current: AtomicPtr::new(Box::into_raw(Box::new(data))),
+            epoch: AtomicUsize::new(0),
+            slots: (0..readers.max(1))
+                .map(|_| CachePadded::default())
+                .collect(),
+            writer: Mutex::new(()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Number of reader slots; reader ids below it are owned by one thread
+    /// at a time, see `read()`.
+    pub(crate) fn readers(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Get the published version of the data; it stays valid, but doesn't
+    /// see later updates, as long as the guard is held. A `tid` below
+    /// `readers()` must not be used by two threads at the same time, e.g.
+    /// it is the id of the thread or of a CPU that can't switch threads
+    /// while it reads; the owner may nest reads. Any other `tid` shares a
+    /// slot with the other readers that pick it.
+    pub(crate) fn read(&self, tid: usize) -> RcuGuard<'_, T> {
+        let reader = match self.slots.get(tid) {
+            Some(slot) => {
+                let depth = slot.depth.load(Ordering::Relaxed);
+                slot.depth.store(depth + 1, Ordering::Relaxed);
+                if depth == 0 {
+                    let epoch = self.epoch.load(Ordering::Relaxed);
+                    slot.announced.store(epoch + 1, Ordering::Relaxed);
+                    // A writer that doesn't see the announcement published
+                    // its version before the load below.
+                    fence(Ordering::SeqCst);
+                }
+                Reader::Owner(slot)
+            }
+            None => {
+                let idx = self.epoch.load(Ordering::SeqCst) & 1;
+                let count = &self.slots[tid % self.slots.len()].shared[idx];
+                count.fetch_add(1, Ordering::SeqCst);
+                Reader::Shared(count)
+            }
+        };
+        let data = unsafe { &*self.current.load(Ordering::SeqCst) };
+        RcuGuard { reader, data }
+    }
+
+    /// Publish the version `update` makes from the current one. Returns once
+    /// no reader can see the old version anymore; if `update` fails, the
+    /// data stays as it is.
+    pub(crate) fn update<E>(&self, update: impl FnOnce(&T) -> Result<T, E>) -> Result<(), E> {
+        let _writer = self.writer.lock();
+        let old = self.current.load(Ordering::SeqCst);
+        let new = Box::new(update(unsafe { &*old })?);
+        self.current.store(Box::into_raw(new), Ordering::SeqCst);
+        self.synchronize();
+        drop(unsafe { Box::from_raw(old) });
+        Ok(())
+    }
+
+    /// Change the current version in place with `change`, through the
+    /// atomics it holds, excluding the other writers. Readers may see the
+    /// change right away.
+    pub(crate) fn publish<R>(&self, change: impl FnOnce(&T) -> R) -> R {
+        let _writer = self.writer.lock();
+        change(unsafe { &*self.current.load(Ordering::SeqCst) })
+    }
+
+    /// Like `publish()`, but returns once no reader can see what `change`
+    /// unlinked from the current version anymore, so it can be freed.
+    pub(crate) fn retire<R>(&self, change: impl FnOnce(&T) -> R) -> R {
+        let _writer = self.writer.lock();
+        let retired = change(unsafe { &*self.current.load(Ordering::SeqCst) });
+        self.synchronize();
+        retired
+    }
+
+    /// Wait until all the readers that started before the call are gone.
+    fn synchronize(&self) {
+        fence(Ordering::SeqCst);
+        for _ in 0..2 {
+            let idx = self.epoch.fetch_add(1, Ordering::SeqCst) & 1;
+            for slot in &self.slots {
+                while slot.shared[idx].load(Ordering::SeqCst) != 0 {
+                    spin_loop();
+                }
+            }
+        }
+        // Owners that announced the current epoch or a later one started
+        // after the flips, and so after the version was published.
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        for slot in &self.slots {
+            loop {
+                match slot.announced.load(Ordering::Acquire) {
+                    announced if announced == 0 || announced > epoch => break,
+                    _ => spin_loop(),
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(*self.current.get_mut()) });
+    }
+}
+
+impl<T> Deref for RcuGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<T> Drop for RcuGuard<'_, T> {
+    fn drop(&mut self) {
+        match self.reader {
+            Reader::Owner(slot) => {
+                let depth = slot.depth.load(Ordering::Relaxed) - 1;
+                slot.depth.store(depth, Ordering::Relaxed);
+                if depth == 0 {
+                    slot.announced.store(0, Ordering::Release);
+                }
+            }
+            Reader::Shared(count) => {
+                count.fetch_sub(1, Ordering::Release);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::sync::Arc;
+    use std::thread;
+
+    #[test]
+    /// Readers always see a complete version, and versions never go back.
+    fn test_rcu() {
+        let first = Arc::new(0);
+        let rcu = Arc::new(Rcu::new(Arc::clone(&first), 2));
+        assert_eq!(**rcu.read(0), 0);
+        rcu.update::<()>(|n| Ok(Arc::new(**n + 1))).unwrap();
+        // The old version is freed once no reader can see it anymore.
+        assert_eq!(Arc::strong_count(&first), 1);
+        assert_eq!(**rcu.read(1), 1);
+        assert_eq!(rcu.update(|_| Err(())), Err(()));
+        assert_eq!(**rcu.read(0), 1);
+
+        let writer = {
+            let rcu = rcu.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    rcu.update::<()>(|n| Ok(Arc::new(**n + 1))).unwrap();
+                }
+            })
+        };
+        let mut last = 0;
+        for i in 0..10000 {
+            let n = **rcu.read(i % 2);
+            assert!(n >= last);
+            last = n;
+        }
+        writer.join().unwrap();
+        assert_eq!(**rcu.read(0), 1001);
+    }
+
+    #[test]
+    /// Writers wait for the owners of reader ids until their outermost read
+    /// is done, and for the readers that share a slot.
+    fn test_rcu_readers() {
+        let first = Arc::new(0);
+        let rcu = Arc::new(Rcu::new(Arc::clone(&first), 2));
+        let updated = Arc::new(AtomicUsize::new(0));
+        let update = || {
+            let (rcu, updated) = (rcu.clone(), updated.clone());
+            thread::spawn(move || {
+                rcu.update::<()>(|n| Ok(Arc::new(**n + 1))).unwrap();
+                updated.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+
+        let outer = rcu.read(1);
+        drop(rcu.read(1));
+        let writer = update();
+        // Readers that start later see the new version without waiting.
+        while **rcu.read(0) != 1 {
+            thread::yield_now();
+        }
+        assert_eq!(updated.load(Ordering::SeqCst), 0);
+        assert_eq!(**outer, 0);
+        drop(outer);
+        writer.join().unwrap();
+        assert_eq!(Arc::strong_count(&first), 1);
+
+        let shared = rcu.read(5);
+        let writer = update();
+        while **rcu.read(0) != 2 {
+            thread::yield_now();
+        }
+        assert_eq!(updated.load(Ordering::SeqCst), 1);
+        assert_eq!(**shared, 1);
+        drop(shared);
+        writer.join().unwrap();
+        assert_eq!(**rcu.read(7), 2);
+    }
+}
@@ -0,0 +1,406 @@
+//! A submission and completion ring, like io_uring.
+//!
+//! Callers queue [`Submission`]s on a [`Ring`] and `MemFS::process()` runs
+//! them in a batch, posting a [`Completion`] with the result of each. Runs of
+//! submissions that read or write a file where the previous one ended are
+//! combined into a single call, so the file is locked once for the run and
+//! small sequential writes become one larger write. Fsyncs of the same file
+//! in a row are done once. Submissions complete in the order they were
+//! queued.
+//!
+//! The ring owns the buffers: a read completes with the data it read, a
+//! write gives its data back with the completion.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+use lock_api::RawRwLock;
+
+use crate::{FileSystem, FileSystemError, FileSystemRead, MemFS, Mnode};
+
+/// Combined reads and writes are at most this long, in bytes.
+pub const MAX_COMBINED: usize = 64 * 1024;
+
+/// An operation on a file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RingOp {
+    /// Read `len` bytes at `offset`.
+    Read {
+        mnode: Mnode,
+        offset: usize,
+        len: usize,
+    },
+    /// Write `data` at `offset`.
+    Write {
+        mnode: Mnode,
+        offset: usize,
+        data: Vec<u8>,
+    },
+    /// Write the file back to the backing store, see `FileSystem::fsync()`.
+    Fsync { mnode: Mnode },
+}
+
+impl RingOp {
+    fn mnode(&self) -> Mnode {
+        match self {
+            RingOp::Read { mnode, .. } | RingOp::Write { mnode, .. } | RingOp::Fsync { mnode } => {
+                *mnode
+            }
+        }
+    }
+
+    /// The offset it reads or writes at.
+    fn offset(&self) -> usize {
+        match self {
+            RingOp::Read { offset, .. } | RingOp::Write { offset, .. } => *offset,
+            RingOp::Fsync { .. } => 0,
+        }
+    }
+
+    /// The bytes it reads or writes.
+    fn len(&self) -> usize {
+        match self {
+            RingOp::Read { len, .. } => *len,
+            RingOp::Write { data, .. } => data.len(),
+            RingOp::Fsync { .. } => 0,
+        }
+    }
+
+    /// Check if `next` can be run in the same call as a run of operations
+    /// like this one that ends at `end` and is `len` bytes long.
+    fn continues(&self, next: &RingOp, end: usize, len: usize) -> bool {
+        let same = match (self, next) {
+            (RingOp::Read { .. }, RingOp::Read { .. })
+            | (RingOp::Write { .. }, RingOp::Write { .. }) => {
+                next.offset() == end && len + next.len() <= MAX_COMBINED
+            }
+            (RingOp::Fsync { .. }, RingOp::Fsync { .. }) => true,
+            _ => false,
+        };
+        same && self.mnode() == next.mnode()
+    }
+}
+
+/// An operation queued on a ring.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Submission {
+    /// Passed through to the completion, to tell the operations apart.
+    pub user_data: u64,
+    pub op: RingOp,
+}
+
+/// The result of a submission.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    /// The `user_data` of the submission.
+    pub user_data: u64,
+    /// The bytes read or written.
+    pub result: Result<usize, FileSystemError>,
+    /// The data read, or the data of the write given back.
+    pub data: Vec<u8>,
+}
+
+/// What one call of `MemFS::process()` did.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct BatchStats {
+    /// Completions posted.
+    pub completed: usize,
+    /// Calls made to the file-system for them.
+    pub calls: usize,
+}
+
+/// Queues of submissions and completions, with room for `entries` of each.
+#[derive(Debug)]
+pub struct Ring {
+    submissions: VecDeque<Submission>,
+    completions: VecDeque<Completion>,
+    entries: usize,
+}
+
+impl Ring {
+    /// An empty ring with room for `entries` submissions and completions.
+    pub fn new(entries: usize) -> Result<Ring, FileSystemError> {
+        let mut submissions = VecDeque::new();
+        let mut completions = VecDeque::new();
+        submissions
+            .try_reserve(entries)
+            .and_then(|()| completions.try_reserve(entries))
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        Ok(Ring {
+            submissions,
+            completions,
+            entries,
+        })
+    }
+
+    /// Queue an operation; fails with `Busy`, giving it back, if the
+    /// submission queue is full.
+    pub fn submit(&mut self, submission: Submission) -> Result<(), (FileSystemError, Submission)> {
+        if self.submissions.len() == self.entries {
+            return Err((FileSystemError::Busy, submission));
+        }
+        self.submissions.push_back(submission);
+        Ok(())
+    }
+
+    /// Take the oldest completion.
+    pub fn complete(&mut self) -> Option<Completion> {
+        self.completions.pop_front()
+    }
+
+    /// Number of submissions waiting to be processed.
+    pub fn pending(&self) -> usize {
+        self.submissions.len()
+    }
+
+    /// Number of completions waiting to be taken.
+    pub fn completed(&self) -> usize {
+        self.completions.len()
+    }
+
+    fn post(&mut self, user_data: u64, result: Result<usize, FileSystemError>, data: Vec<u8>) {
+        // There is room, as only as many submissions are taken as there is
+        // room for completions.
+        self.completions.push_back(Completion {
+            user_data,
+            result,
+            data,
+        });
+    }
+}
+
+/// The share of the operation of `len` bytes at `start` in a run of them,
+/// of a combined call that returned `result`. A part of a read past the end
+/// of the file fails like a read at the end of the file does.
+fn share(
+    result: &Result<usize, FileSystemError>,
+    start: usize,
+    len: usize,
+    read: bool,
+) -> Result<usize, FileSystemError> {
+    match result {
+        Ok(done) => match done.saturating_sub(start).min(len) {
+            0 if read && len > 0 => Err(FileSystemError::InvalidOffset),
+            done => Ok(done),
+        },
+        Err(err) => Err(err.clone()),
+    }
+}
+
+impl<S: BuildHasher + Clone + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
+    /// Run the operations queued on `ring` and post their completions. Only
+    /// as many are run as there is room for their completions.
+    pub fn process(&self, ring: &mut Ring) -> BatchStats {
+        let mut stats = BatchStats::default();
+        let mut room = ring.entries - ring.completions.len();
+        let mut run = Vec::new();
+        while room > 0 {
+            let first = match ring.submissions.pop_front() {
+                Some(first) => first,
+                None => break,
+            };
+            room -= 1;
+            let mut len = first.op.len();
+            let mut end = first.op.offset() + len;
+            while room > 0 {
+                match ring.submissions.front() {
+                    Some(next) if first.op.continues(&next.op, end, len) => {
+                        end += next.op.len();
+                        len += next.op.len();
+                    }
+                    _ => break,
+                }
+                if run.try_reserve(1).is_err() {
+                    break;
+                }
+                run.extend(ring.submissions.pop_front());
+                room -= 1;
+            }
+            stats.completed += 1 + run.len();
+            stats.calls += self.run(ring, first, &mut run);
+        }
+        stats
+    }
+
+    /// Run `first` and the operations of `rest` that continue it, and
+    /// return the number of calls made.
+    fn run(&self, ring: &mut Ring, first: Submission, rest: &mut Vec<Submission>) -> usize {
+        let len = first.op.len() + rest.iter().map(|op| op.op.len()).sum::<usize>();
+        match first.op {
+            RingOp::Fsync { mnode } => {
+                let result = self.fsync(mnode).map(|()| 0);
+                ring.post(first.user_data, result.clone(), Vec::new());
+                for op in rest.drain(..) {
+                    ring.post(op.user_data, result.clone(), Vec::new());
+                }
+            }
+            RingOp::Read { mnode, offset, .. } => {
+                let mut data = Vec::new();
+                let result = match data.try_reserve_exact(len) {
+                    Ok(()) => {
+                        data.resize(len, 0);
+                        self.read(mnode, &mut data, offset)
+                    }
+                    Err(_) => Err(FileSystemError::OutOfMemory),
+                };
+                let mut start = 0;
+                for op in core::iter::once(first).chain(rest.drain(..)) {
+                    let op_len = op.op.len();
+                    let mut part = Vec::new();
+                    let result = share(&result, start, op_len, true).and_then(|done| {
+                        part.try_reserve_exact(done)
+                            .map_err(|_| FileSystemError::OutOfMemory)?;
+                        part.extend_from_slice(&data[start..start + done]);
+                        Ok(done)
+                    });
+                    ring.post(op.user_data, result, part);
+                    start += op_len;
+                }
+            }
+            RingOp::Write {
+                mnode,
+                offset,
+                data,
+            } => {
+                let result = match rest.is_empty() {
+                    true => self.write(mnode, &data, offset),
+                    false => {
+                        let mut combined = Vec::new();
+                        match combined.try_reserve_exact(len) {
+                            Ok(()) => {
+                                combined.extend_from_slice(&data);
+                                for op in rest.iter() {
+                                    if let RingOp::Write { data, .. } = &op.op {
+                                        combined.extend_from_slice(data);
+                                    }
+                                }
+                                self.write(mnode, &combined, offset)
+                            }
+                            Err(_) => Err(FileSystemError::OutOfMemory),
+                        }
+                    }
+                };
+                let mut start = data.len();
+                ring.post(first.user_data, share(&result, 0, start, false), data);
+                for op in rest.drain(..) {
+                    let op_len = op.op.len();
+                    let done = share(&result, start, op_len, false);
+                    if let RingOp::Write { data, .. } = op.op {
+                        ring.post(op.user_data, done, data);
+                    }
+                    start += op_len;
+                }
+            }
+        }
+        1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FileModes;
+
+    fn write(user_data: u64, mnode: Mnode, offset: usize, data: &[u8]) -> Submission {
+        Submission {
+            user_data,
+            op: RingOp::Write {
+                mnode,
+                offset,
+                data: data.to_vec(),
+            },
+        }
+    }
+
+    fn read(user_data: u64, mnode: Mnode, offset: usize, len: usize) -> Submission {
+        Submission {
+            user_data,
+            op: RingOp::Read { mnode, offset, len },
+        }
+    }
+
+    #[test]
+    /// Sequential operations on a file are combined, and every submission
+    /// completes with its own share, in order.
+    fn test_ring() {
+        let memfs = MemFS::default();
+        let a = memfs.create("/a", FileModes::S_IRWXU.into()).unwrap();
+        let b = memfs.create("/b", FileModes::S_IRWXU.into()).unwrap();
+        let mut ring = Ring::new(8).unwrap();
+        ring.submit(write(1, a, 0, b"nr")).unwrap();
+        ring.submit(write(2, a, 2, b"fs")).unwrap();
+        ring.submit(write(3, b, 0, b"b")).unwrap();
+        ring.submit(write(4, a, 4, b"!")).unwrap();
+        ring.submit(Submission {
+            user_data: 5,
+            op: RingOp::Fsync { mnode: a },
+        })
+        .unwrap();
+        ring.submit(Submission {
+            user_data: 6,
+            op: RingOp::Fsync { mnode: a },
+        })
+        .unwrap();
+        assert_eq!(
+            memfs.process(&mut ring),
+            BatchStats {
+                completed: 6,
+                calls: 4
+            }
+        );
+        let results: Vec<_> = core::iter::from_fn(|| ring.complete())
+            .map(|completion| (completion.user_data, completion.result))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                (1, Ok(2)),
+                (2, Ok(2)),
+                (3, Ok(1)),
+                (4, Ok(1)),
+                (5, Ok(0)),
+                (6, Ok(0))
+            ]
+        );
+
+        ring.submit(read(7, a, 0, 3)).unwrap();
+        ring.submit(read(8, a, 3, 4)).unwrap();
+        ring.submit(read(9, a, 7, 1)).unwrap();
+        assert_eq!(memfs.process(&mut ring).calls, 1);
+        let completion = ring.complete().unwrap();
+        assert_eq!(
+            (completion.result, &completion.data[..]),
+            (Ok(3), &b"nrf"[..])
+        );
+        let completion = ring.complete().unwrap();
+        assert_eq!(
+            (completion.result, &completion.data[..]),
+            (Ok(2), &b"s!"[..])
+        );
+        assert_eq!(
+            ring.complete().unwrap().result,
+            Err(FileSystemError::InvalidOffset)
+        );
+    }
+
+    #[test]
+    /// The queues are bounded, and submissions wait for room for their
+    /// completions.
+    fn test_ring_full() {
+        let memfs = MemFS::default();
+        let a = memfs.create("/a", FileModes::S_IRWXU.into()).unwrap();
+        let mut ring = Ring::new(2).unwrap();
+        ring.submit(write(1, a, 0, b"a")).unwrap();
+        ring.submit(write(2, a, 5, b"b")).unwrap();
+        let (err, submission) = ring.submit(write(3, a, 0, b"c")).unwrap_err();
+        assert_eq!((err, submission.user_data), (FileSystemError::Busy, 3));
+
+        assert_eq!(memfs.process(&mut ring).completed, 2);
+        ring.submit(write(3, a, 0, b"c")).unwrap();
+        assert_eq!(memfs.process(&mut ring).completed, 0);
+        assert_eq!(ring.pending(), 1);
+        assert_eq!(ring.complete().unwrap().data, b"a");
+        assert_eq!(memfs.process(&mut ring).completed, 1);
+        assert_eq!(ring.completed(), 2);
+    }
+}
@@ -7,16 +7,298 @@
 //! code. For clients there is no need to rely on this directly, as the RwLock
 //! is embedded inside the Replica.
 
-use core::cell::UnsafeCell;
+use alloc::boxed::Box;
 use core::default::Default;
+#[cfg(not(loom))]
+use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicUsize, Ordering};
 
-use crossbeam_utils::CachePadded;
+#[cfg(not(loom))]
+use core::cell::UnsafeCell;
+#[cfg(loom)]
+use core::mem::ManuallyDrop;
+#[cfg(not(loom))]
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+#[cfg(loom)]
+use loom::cell::{ConstPtr, MutPtr, UnsafeCell};
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use crate::padded::CachePadded;
+
+/// Loom models are limited to a handful of threads, so keep the reader
+/// array small to keep the state space tractable.
+#[cfg(loom)]
+const LOOM_READER_THREADS: usize = 4;
+
+/// Busy-wait hint used by all the spin loops in this module. Under loom the
+/// spinning thread has to yield, otherwise the model never makes progress.
+#[inline(always)]
+fn spin_wait() {
+    #[cfg(not(loom))]
+    core::hint::spin_loop();
+    #[cfg(loom)]
+    loom::thread::yield_now();
+}
+
+/// Most busy-wait hints issued in a row are `1 << MAX_BACKOFF`; once the
+/// backoff gets there, the waiting thread yields instead if it can.
+const MAX_BACKOFF: u32 = 6;
+
+/// How long `read_for()` and `write_for()` may wait for the lock.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Budget {
+    /// Give up after backing off this many times.
+    Spins(usize),
+    /// Give up after this many time-stamp counter ticks.
+    Ticks(u64),
+}
+
+/// Which threads get the lock first when readers and writers compete.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Fairness {
+    /// A writer stalls new readers as soon as it holds the writer lock, so
+    /// readers can't starve writers, but a stream of writers can starve the
+    /// readers.
+    Writers,
+    /// Like `Writers`, but a writer first lets in the readers that waited for
+    /// the previous writer, so readers and writers take turns.
+    Phases,
+}
+
+/// The lock couldn't be acquired within the budget.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct WouldBlock;
+
+impl From<WouldBlock> for crate::FileSystemError {
+    fn from(_: WouldBlock) -> crate::FileSystemError {
+        crate::FileSystemError::WouldBlock
+    }
+}
+
+/// A writer panicked while it held the lock, so the data may be half
+/// updated.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Poisoned;
+
+/// What is left of a `Budget` while waiting.
+enum Limit {
+    Never,
+    Spins(usize),
+    Ticks(u64),
+}
+
+/// The state of a thread waiting for the lock: its budget and backoff.
+struct Deadline<'a> {
+    limit: Limit,
+    /// The next wait spins `1 << backoff` times.
+    backoff: u32,
+    yield_now: Option<fn()>,
+    parking: Option<&'a Parking>,
+}
+
+impl<'a> Deadline<'a> {
+    fn new(
+        budget: Option<Budget>,
+        yield_now: Option<fn()>,
+        parking: Option<&'a Parking>,
+    ) -> Deadline<'a> {
+        let limit = match budget {
+            None => Limit::Never,
+            Some(Budget::Spins(spins)) => Limit::Spins(spins),
+            Some(Budget::Ticks(ticks)) => Limit::Ticks(ticks.saturating_add(now())),
+        };
+        Deadline {
+            limit,
+            backoff: 0,
+            yield_now,
+            parking,
+        }
+    }
+
+    /// Backs off once, or returns `WouldBlock` if the budget is used up. The
+    /// wait doubles every time until it reaches the maximum; from then on
+    /// the thread parks or yields if the lock lets it.
+    fn spin(&mut self) -> Result<(), WouldBlock> {
+        match &mut self.limit {
+            Limit::Never => {}
+            Limit::Spins(0) => return Err(WouldBlock),
+            Limit::Spins(spins) => *spins -= 1,
+            Limit::Ticks(until) => {
+                if now() >= *until {
+                    return Err(WouldBlock);
+                }
+            }
+        }
+        match (self.parking, self.yield_now) {
+            (Some(parking), _) if self.backoff == MAX_BACKOFF => parking.wait(),
+            (None, Some(yield_now)) if self.backoff == MAX_BACKOFF => yield_now(),
+            _ => {
+                // Under loom every wait is a yield already.
+                #[cfg(not(loom))]
+                for _ in 0..1 << self.backoff {
+                    spin_wait();
+                }
+                #[cfg(loom)]
+                spin_wait();
+                self.backoff = (self.backoff + 1).min(MAX_BACKOFF);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A write operation waiting for a combiner. The closure lives on the stack
+/// of the thread that published it, which waits until the operation is done.
+type Op<'a, T> = &'a mut (dyn FnMut(&mut T) + Send);
+
+/// The slot a thread publishes its write operations in for `combine()`.
+#[derive(Default)]
+struct Pending {
+    /// Held by the thread that currently uses the slot.
+    owner: AtomicBool,
+    /// The published `Op`, or null.
+    op: AtomicPtr<()>,
+    /// Set by the combiner once it applied the operation.
+    done: AtomicBool,
+}
+
+/// The threads that hold the lock, tracked in debug builds with std, so
+/// misuse that would spin forever panics instead.
+#[cfg(all(debug_assertions, not(loom), any(test, feature = "std")))]
+#[derive(Default)]
+struct Owners {
+    writer: spin::Mutex<Option<std::thread::ThreadId>>,
+    /// The readers with their reader ids; a thread is listed once per guard
+    /// it holds.
+    readers: spin::Mutex<alloc::vec::Vec<(std::thread::ThreadId, usize)>>,
+}
+
+#[cfg(all(debug_assertions, not(loom), any(test, feature = "std")))]
+impl Owners {
+    /// Checks that the calling thread can wait for the write lock.
+    fn check_write(&self) {
+        let me = std::thread::current().id();
+        if *self.writer.lock() == Some(me) {
+            panic!("recursive write acquisition: this thread already holds the write lock");
+        }
+        if let Some((_, tid)) = self.readers.lock().iter().find(|(thread, _)| *thread == me) {
+            panic!(
+                "write lock requested while this thread holds the read lock of reader {}; use upgradeable_read() instead",
+                tid
+            );
+        }
+    }
+
+    /// Checks that the calling thread can wait for a read lock.
+    fn check_read(&self) {
+        if *self.writer.lock() == Some(std::thread::current().id()) {
+            panic!("read lock requested while this thread holds the write lock");
+        }
+    }
+
+    fn locked_write(&self) {
+        *self.writer.lock() = Some(std::thread::current().id());
+    }
+
+    fn unlocked_write(&self) {
+        *self.writer.lock() = None;
+    }
+
+    fn locked_read(&self, tid: usize) {
+        self.readers.lock().push((std::thread::current().id(), tid));
+    }
+
+    fn unlocked_read(&self, tid: usize) {
+        let me = std::thread::current().id();
+        let mut readers = self.readers.lock();
+        match readers.iter().position(|owner| *owner == (me, tid)) {
+            Some(idx) => {
+                readers.swap_remove(idx);
+            }
+            None => panic!(
+                "read lock of reader {} released by a thread that doesn't hold it",
+                tid
+            ),
+        }
+    }
+}
+
+/// Without debug assertions or std nothing is tracked.
+#[cfg(not(all(debug_assertions, not(loom), any(test, feature = "std"))))]
+#[derive(Default)]
+struct Owners {}
+
+#[cfg(not(all(debug_assertions, not(loom), any(test, feature = "std"))))]
+impl Owners {
+    fn check_write(&self) {}
+    fn check_read(&self) {}
+    fn locked_write(&self) {}
+    fn unlocked_write(&self) {}
+    fn locked_read(&self, _tid: usize) {}
+    fn unlocked_read(&self, _tid: usize) {}
+}
+
+/// How long a parked thread sleeps at most; bounds the cost of a wake-up it
+/// missed because it came just before the thread parked.
+#[cfg(all(feature = "std", not(loom)))]
+const PARK_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(1);
+
+/// Where waiting threads sleep in blocking mode, until a thread unlocks.
+#[cfg(all(feature = "std", not(loom)))]
+#[derive(Default)]
+struct Parking {
+    waiters: AtomicUsize,
+    mutex: std::sync::Mutex<()>,
+    condvar: std::sync::Condvar,
+}
+
+#[cfg(all(feature = "std", not(loom)))]
+impl Parking {
+    fn wait(&self) {
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        let guard = self.mutex.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = self.condvar.wait_timeout(guard, PARK_TIMEOUT);
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn wake(&self) {
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            let _guard = self.mutex.lock().unwrap_or_else(|e| e.into_inner());
+            self.condvar.notify_all();
+        }
+    }
+}
+
+/// Without std threads can't park.
+#[cfg(not(all(feature = "std", not(loom))))]
+enum Parking {}
+
+#[cfg(not(all(feature = "std", not(loom))))]
+impl Parking {
+    fn wait(&self) {
+        match *self {}
+    }
 
-/// Maximum number of reader threads that this lock supports.
-const MAX_READER_THREADS: usize = 192;
-const_assert!(MAX_READER_THREADS > 0);
+    fn wake(&self) {
+        match *self {}
+    }
+}
+
+/// The current value of the time-stamp counter.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn now() -> u64 {
+    unsafe { x86::time::rdtsc() }
+}
+
+/// Targets without a time-stamp counter count the calls instead, so a
+/// `Budget::Ticks` is the number of times the waiting thread checks it.
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn now() -> u64 {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static TICKS: AtomicUsize = AtomicUsize::new(0);
+    TICKS.fetch_add(1, Ordering::Relaxed) as u64
+}
 
 /// A scalable reader-writer lock.
 ///
@@ -26,39 +308,97 @@ const_assert!(MAX_READER_THREADS > 0);
 /// `T` represents the underlying type protected by the lock.
 /// Calling `read()` returns a read-guard that can be used to safely read `T`.
 /// Calling `write()` returns a write-guard that can be used to safely mutate `T`.
+/// Calling `upgradeable_read()` returns a read-guard that can later be turned
+/// into a write-guard without releasing the lock in between.
 pub struct RwLock<T>
 where
-    T: Sized + Default + Sync,
+    T: Sized + Sync,
 {
     /// The writer lock. There can be at most one writer at any given point of time.
     wlock: CachePadded<AtomicBool>,
 
-    /// Each reader use an individual lock to access the underlying data-structure.
-    rlock: [CachePadded<AtomicUsize>; MAX_READER_THREADS],
+    /// Held by the writer or by the upgradeable reader, so an upgrade never
+    /// has to wait for another writer.
+    ulock: CachePadded<AtomicBool>,
+
+    /// Each reader use an individual lock to access the underlying data-structure;
+    /// there is one slot per reader id.
+    rlock: Box<[CachePadded<AtomicUsize>]>,
+
+    /// The reader id handed out by the next `register()`.
+    next_reader: AtomicUsize,
+
+    /// Called by waiting threads once their backoff is at its maximum.
+    yield_now: Option<fn()>,
+
+    /// Which threads get the lock first.
+    fairness: Fairness,
+
+    /// Readers that wait for the current writer; with `Fairness::Phases` the
+    /// next writer waits until they got in.
+    rwait: CachePadded<AtomicUsize>,
+
+    /// Tells if the thread is panicking; without it the lock is never poisoned.
+    panicking: Option<fn() -> bool>,
+
+    /// Set when a writer panicked while it held the lock.
+    poisoned: AtomicBool,
+
+    /// The threads that hold the lock, in debug builds.
+    owners: Owners,
+
+    /// Where waiting threads sleep, if they park instead of spinning.
+    parking: Option<Parking>,
+
+    /// Write operations waiting for a combiner, one slot per reader id; empty
+    /// unless combining is enabled.
+    pending: Box<[CachePadded<Pending>]>,
 
     /// The underlying data-structure.
     data: UnsafeCell<T>,
-
-    /// Max number of hyperthreads per socket
-    max_thread: usize,
 }
 
 /// A read-guard that can be used to read the underlying data structure. Writes on
 /// the data structure will be blocked as long as one of these is lying around.
-pub struct ReadGuard<'a, T: ?Sized + Default + Sync + 'a> {
+pub struct ReadGuard<'a, T: Sync + 'a> {
     /// Id of the thread that acquired this guard. Required at drop time so that
     /// we can release the appropriate read lock.
     tid: usize,
 
     /// A reference to the Rwlock wrapping the data-structure.
     lock: &'a RwLock<T>,
+
+    /// Loom-tracked pointer to the data, so that the model checker sees
+    /// the read access for the whole lifetime of the guard.
+    #[cfg(loom)]
+    data: ManuallyDrop<ConstPtr<T>>,
+}
+
+/// A read-guard that can be upgraded to a write-guard. It shares the lock with
+/// other readers, but not with writers or other upgradeable readers.
+pub struct UpgradeableGuard<'a, T: Sync + 'a> {
+    /// Id of the thread that acquired this guard, like for a `ReadGuard`.
+    tid: usize,
+
+    /// A reference to the Rwlock wrapping the data-structure.
+    lock: &'a RwLock<T>,
+
+    /// Loom-tracked pointer to the data, so that the model checker sees
+    /// the read access for the whole lifetime of the guard.
+    #[cfg(loom)]
+    data: ManuallyDrop<ConstPtr<T>>,
 }
 
 /// A write-guard that can be used to write to the underlying data structure. All
 /// reads will be blocked until this is dropped.
-pub struct WriteGuard<'a, T: ?Sized + Default + Sync + 'a> {
+pub struct WriteGuard<'a, T: Sync + 'a> {
     /// A reference to the Rwlock wrapping the data-structure.
     lock: &'a RwLock<T>,
+
+    /// Loom-tracked pointer to the data, so that the model checker sees
+    /// the write access for the whole lifetime of the guard.
+    #[cfg(loom)]
+    data: ManuallyDrop<MutPtr<T>>,
 }
 
 impl<T> Default for RwLock<T>
@@ -68,167 +408,691 @@ where
     /// Returns a new instance of a RwLock. Default constructs the
     /// underlying data structure.
     fn default() -> RwLock<T> {
-        use arr_macro::arr;
-
-        RwLock {
-            wlock: CachePadded::new(AtomicBool::new(false)),
-            rlock: arr![Default::default(); 192],
-            data: UnsafeCell::new(T::default()),
-            max_thread: crate::topology::MachineTopology::new()
-                .cpus_on_socket(0)
-                .len(),
-        }
+        RwLock::new(T::default())
     }
 }
 
 impl<T> RwLock<T>
 where
-    T: Sized + Default + Sync,
+    T: Sized + Sync,
 {
+    /// Returns a new instance of a RwLock wrapping `data`, with a reader slot
+    /// for every hyperthread of every socket, indexed by the CPU number.
+    pub fn new(data: T) -> RwLock<T> {
+        #[cfg(not(loom))]
+        let readers = crate::topology::machine().cpu_ids();
+        #[cfg(loom)]
+        let readers = LOOM_READER_THREADS;
+
+        RwLock::with_readers(data, readers)
+    }
+
+    /// Returns a new instance of a RwLock wrapping `data`, with a reader slot
+    /// for every CPU of `topology`, indexed by the CPU number.
+    pub fn with_topology(data: T, topology: &crate::topology::MachineTopology) -> RwLock<T> {
+        RwLock::with_readers(data, topology.cpu_ids())
+    }
+
+    /// Returns a new instance of a RwLock wrapping `data`, with `readers`
+    /// reader slots (at least one).
+    pub fn with_readers(data: T, readers: usize) -> RwLock<T> {
+        RwLock {
+            wlock: CachePadded::new(AtomicBool::new(false)),
+            ulock: CachePadded::new(AtomicBool::new(false)),
+            rlock: (0..readers.max(1)).map(|_| Default::default()).collect(),
+            next_reader: AtomicUsize::new(0),
+            yield_now: None,
+            fairness: Fairness::Writers,
+            rwait: CachePadded::new(AtomicUsize::new(0)),
+            panicking: None,
+            poisoned: AtomicBool::new(false),
+            owners: Owners::default(),
+            parking: None,
+            pending: Box::new([]),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Makes threads that wait for the lock for long call `yield_now`, e.g.
+    /// to halt the core or run another task, instead of spinning.
+    pub fn with_yield(mut self, yield_now: fn()) -> RwLock<T> {
+        self.yield_now = Some(yield_now);
+        self
+    }
+
+    /// Picks which threads get the lock first; the default is
+    /// `Fairness::Writers`.
+    pub fn with_fairness(mut self, fairness: Fairness) -> RwLock<T> {
+        self.fairness = fairness;
+        self
+    }
+
+    /// Makes threads that wait for the lock for long sleep until another
+    /// thread unlocks, instead of spinning or calling the yield hook.
+    #[cfg(all(feature = "std", not(loom)))]
+    pub fn with_parking(mut self) -> RwLock<T> {
+        self.parking = Some(Parking::default());
+        self
+    }
+
+    /// Wakes the parked threads, if there are any.
+    fn wake(&self) {
+        if let Some(parking) = &self.parking {
+            parking.wake();
+        }
+    }
+
+    /// Poisons the lock when a writer panics while it holds the lock, as told
+    /// by `panicking`, e.g. `std::thread::panicking` or a flag set by the
+    /// panic handler of a kernel.
+    pub fn with_poisoning(mut self, panicking: fn() -> bool) -> RwLock<T> {
+        self.panicking = Some(panicking);
+        self
+    }
+
+    /// Tells if a writer panicked while it held the lock.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Acquire)
+    }
+
+    /// Marks the data as consistent again, e.g. after repairing it.
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Release);
+    }
+
+    /// Makes `combine()` batch the write operations of concurrent threads
+    /// under a single acquisition of the write lock.
+    pub fn with_combining(mut self) -> RwLock<T> {
+        self.pending = (0..self.readers()).map(|_| Default::default()).collect();
+        self
+    }
+
+    /// Starts waiting for the lock, for at most `budget`.
+    fn deadline(&self, budget: Option<Budget>) -> Deadline<'_> {
+        Deadline::new(budget, self.yield_now, self.parking.as_ref())
+    }
+
     /// Locks the underlying data-structure for writes. The caller can retrieve
     /// a mutable reference from the returned `WriteGuard`.
     pub fn write(&self) -> WriteGuard<T> {
-        let n: usize = self.max_thread;
-        // First, wait until we can acquire the writer lock.
-        while self.wlock.compare_and_swap(false, true, Ordering::Acquire) {
-            spin_loop_hint();
+        self.owners.check_write();
+        let _ = self.write_lock(&mut self.deadline(None));
+        unsafe { WriteGuard::new(self) }
+    }
+
+    /// Locks the underlying data-structure for writes like `write()`, but gives
+    /// up once `budget` is used up.
+    pub fn write_for(&self, budget: Budget) -> Result<WriteGuard<'_, T>, WouldBlock> {
+        self.write_lock(&mut self.deadline(Some(budget)))?;
+        Ok(unsafe { WriteGuard::new(self) })
+    }
+
+    /// Acquires the write lock, unless the deadline passes first.
+    fn write_lock(&self, deadline: &mut Deadline) -> Result<(), WouldBlock> {
+        // First, wait until there is no other writer or upgradeable reader,
+        // and then acquire the writer lock.
+        self.upgrader_lock(deadline)?;
+        if self.fairness == Fairness::Phases {
+            while self.rwait.load(Ordering::Acquire) != 0 {
+                if let Err(e) = deadline.spin() {
+                    self.ulock.store(false, Ordering::Release);
+                    return Err(e);
+                }
+            }
         }
+        // No writer holds the writer lock while the upgrader lock is held.
+        self.wlock.store(true, Ordering::SeqCst);
 
-        // Next, wait until all readers have released their locks. This condition
-        // evaluates to true if each reader lock is free (i.e equal to zero).
-        while !self
-            .rlock
-            .iter()
-            .take(n)
-            .all(|item| item.load(Ordering::Relaxed) == 0)
+        if let Err(e) = self.wait_for_readers(deadline) {
+            unsafe { self.write_unlock() };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Locks the underlying data-structure for writes if that's possible
+    /// without waiting for other writers or for readers.
+    pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+        if self
+            .ulock
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
         {
-            spin_loop_hint();
+            return None;
+        }
+        if self.fairness == Fairness::Phases && self.rwait.load(Ordering::Acquire) != 0 {
+            self.ulock.store(false, Ordering::Release);
+            return None;
+        }
+        // No writer holds the writer lock while the upgrader lock is held.
+        self.wlock.store(true, Ordering::SeqCst);
+
+        if self.rlock.iter().all(|item| Self::slot_free(item)) {
+            Some(unsafe { WriteGuard::new(self) })
+        } else {
+            unsafe { self.write_unlock() };
+            None
         }
+    }
 
-        unsafe { WriteGuard::new(self) }
+    /// Applies `op` to the data under the write lock and returns its result.
+    ///
+    /// With combining enabled, the operation is published in the slot of
+    /// reader `tid`, and whichever thread gets the write lock next applies
+    /// all published operations, so concurrent writers share one hand-off of
+    /// the lock. Operations must not panic, since they may run on another
+    /// thread.
+    pub fn combine<R: Send>(&self, tid: usize, op: impl FnOnce(&mut T) -> R + Send) -> R {
+        if self.pending.is_empty() {
+            return op(&mut self.write());
+        }
+
+        let slot = &self.pending[tid];
+        let mut deadline = self.deadline(None);
+        while slot
+            .owner
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            let _ = deadline.spin();
+        }
+
+        let mut op = Some(op);
+        let mut result = None;
+        {
+            let mut call = |data: &mut T| result = op.take().map(|op| op(data));
+            let mut call: &mut (dyn FnMut(&mut T) + Send) = &mut call;
+            slot.done.store(false, Ordering::Relaxed);
+            // The closure outlives its use: we don't leave the block before
+            // the combiner is done with it. Publishing with a swap orders it
+            // after the combiner's last swap on the slot, also under loom.
+            slot.op
+                .swap(&mut call as *mut Op<T> as *mut (), Ordering::Release);
+
+            let mut deadline = self.deadline(None);
+            while !slot.done.load(Ordering::Acquire) {
+                // Another thread is about to combine; it will likely pick up
+                // our operation as well.
+                if self.ulock.load(Ordering::Relaxed) {
+                    let _ = deadline.spin();
+                    continue;
+                }
+                let mut data = self.write();
+                self.apply_pending(&mut data);
+            }
+        }
+        slot.owner.store(false, Ordering::Release);
+        result.expect("combined operation wasn't applied")
+    }
+
+    /// Applies and completes all published write operations.
+    fn apply_pending(&self, data: &mut T) {
+        for slot in self.pending.iter() {
+            let op = slot.op.swap(core::ptr::null_mut(), Ordering::Acquire);
+            if !op.is_null() {
+                unsafe { (*(op as *mut Op<T>))(data) };
+                slot.done.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    /// Locks the underlying data-structure for reads, with the option to
+    /// upgrade to a write lock later. Other readers can hold the lock at the
+    /// same time, but writers and other upgradeable readers block.
+    pub fn upgradeable_read(&self, tid: usize) -> UpgradeableGuard<'_, T> {
+        self.owners.check_read();
+        let _ = self.upgrader_lock(&mut self.deadline(None));
+        let _ = self.read_lock(tid, &mut self.deadline(None));
+        unsafe { UpgradeableGuard::new(self, tid) }
+    }
+
+    /// Acquires the lock that writers share with upgradeable readers.
+    fn upgrader_lock(&self, deadline: &mut Deadline) -> Result<(), WouldBlock> {
+        while self
+            .ulock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            deadline.spin()?;
+        }
+        Ok(())
+    }
+
+    /// Waits until all readers have released their locks, once the writer
+    /// lock is held.
+    fn wait_for_readers(&self, deadline: &mut Deadline) -> Result<(), WouldBlock> {
+        // Wait until all readers have released their locks. This condition
+        // evaluates to true if each reader lock is free (i.e equal to zero).
+        //
+        // The writer publishes `wlock` and then checks the reader slots, while a
+        // reader publishes its slot and then checks `wlock`. With plain loads on
+        // both sides each could miss the other's store, so a slot only counts as
+        // free once a read-modify-write on it confirms it: either a reader's
+        // later increment reads from our RMW (and then sees `wlock` set), or we
+        // see its increment and keep waiting.
+        for item in self.rlock.iter() {
+            while !Self::slot_free(item) {
+                deadline.spin()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks if a reader slot is free, once the writer lock is held. A
+    /// relaxed load first keeps the cache line shared while the slot is busy.
+    fn slot_free(item: &AtomicUsize) -> bool {
+        item.load(Ordering::Relaxed) == 0 && item.fetch_add(0, Ordering::AcqRel) == 0
+    }
+
+    /// Hands out a reader id for a new thread. Every thread gets a slot of its
+    /// own until they run out; after that the slots are shared, which is still
+    /// correct but makes the threads contend.
+    pub fn register(&self) -> usize {
+        self.next_reader.fetch_add(1, Ordering::Relaxed) % self.rlock.len()
+    }
+
+    /// Number of reader slots the writers wait on; valid reader ids are
+    /// `0..readers()`.
+    pub fn readers(&self) -> usize {
+        self.rlock.len()
     }
 
     /// Locks the underlying data-structure for reads. Allows multiple readers to acquire the lock.
     /// Blocks until there aren't any active writers.
     pub fn read(&self, tid: usize) -> ReadGuard<T> {
-        // We perform a small optimization. Before attempting to acquire a read lock, we issue
-        // naked reads to the write lock and wait until it is free. For that, we retrieve a
-        // raw pointer to the write lock over here.
-        let ptr = unsafe {
-            &*(&self.wlock as *const crossbeam_utils::CachePadded<core::sync::atomic::AtomicBool>
-                as *const bool)
-        };
+        self.owners.check_read();
+        let _ = self.read_lock(tid, &mut self.deadline(None));
+        unsafe { ReadGuard::new(self, tid) }
+    }
+
+    /// Locks the underlying data-structure for reads like `read()`, but gives
+    /// up once `budget` is used up.
+    pub fn read_for(&self, tid: usize, budget: Budget) -> Result<ReadGuard<'_, T>, WouldBlock> {
+        self.read_lock(tid, &mut self.deadline(Some(budget)))?;
+        Ok(unsafe { ReadGuard::new(self, tid) })
+    }
+
+    /// Locks the underlying data-structure for writes like `write()`, unless
+    /// the lock is poisoned.
+    pub fn checked_write(&self) -> Result<WriteGuard<'_, T>, Poisoned> {
+        let guard = self.write();
+        match self.is_poisoned() {
+            false => Ok(guard),
+            true => Err(Poisoned),
+        }
+    }
 
-        loop {
-            // First, wait until the write lock is free. This is the small
-            // optimization spoken of earlier.
-            unsafe {
-                while core::ptr::read_volatile(ptr) {
-                    spin_loop_hint();
+    /// Locks the underlying data-structure for reads like `read()`, unless
+    /// the lock is poisoned.
+    pub fn checked_read(&self, tid: usize) -> Result<ReadGuard<'_, T>, Poisoned> {
+        let guard = self.read(tid);
+        match self.is_poisoned() {
+            false => Ok(guard),
+            true => Err(Poisoned),
+        }
+    }
+
+    /// Locks the underlying data-structure for reads if there isn't an
+    /// active writer.
+    pub fn try_read(&self, tid: usize) -> Option<ReadGuard<'_, T>> {
+        if self.wlock.load(Ordering::Relaxed) || !self.try_read_lock(tid) {
+            return None;
+        }
+        Some(unsafe { ReadGuard::new(self, tid) })
+    }
+
+    /// Acquires the read lock of the reader `tid`, unless the deadline passes
+    /// first.
+    fn read_lock(&self, tid: usize, deadline: &mut Deadline) -> Result<(), WouldBlock> {
+        let mut waiting = false;
+        let locked = 'lock: loop {
+            // First, wait until the write lock is free. We perform a small optimization
+            // here: spinning on a relaxed load keeps the cache line shared and avoids
+            // bouncing our reader slot while a writer holds the lock.
+            while self.wlock.load(Ordering::Relaxed) {
+                if !waiting && self.fairness == Fairness::Phases {
+                    // Make the next writer wait for us.
+                    self.rwait.fetch_add(1, Ordering::Relaxed);
+                    waiting = true;
+                }
+                if let Err(e) = deadline.spin() {
+                    break 'lock Err(e);
                 }
             }
-
-            // Next, acquire this thread's read lock and actually check if the write lock
-            // is free. If it is, then we're good to go because any new writers will now
-            // see this acquired read lock and block. If it isn't free, then we got unlucky;
-            // release the read lock and retry.
-            self.rlock[tid].fetch_add(1, Ordering::Acquire);
-            if !self.wlock.load(Ordering::Relaxed) {
-                break;
+            if self.try_read_lock(tid) {
+                break Ok(());
             }
-
-            self.rlock[tid].fetch_sub(1, Ordering::Release);
+        };
+        if waiting {
+            self.rwait.fetch_sub(1, Ordering::Release);
         }
+        locked
+    }
 
-        unsafe { ReadGuard::new(self, tid) }
+    /// Acquires this thread's read lock and actually checks if the write lock is
+    /// free. If it is, then we're good to go because any new writers will now see
+    /// this acquired read lock and block. If it isn't free, then we got unlucky;
+    /// the read lock is released again.
+    fn try_read_lock(&self, tid: usize) -> bool {
+        self.rlock[tid].fetch_add(1, Ordering::Acquire);
+        if !self.wlock.load(Ordering::Acquire) {
+            return true;
+        }
+        self.rlock[tid].fetch_sub(1, Ordering::Release);
+        false
     }
 
     /// Unlocks the write lock; invoked by the drop() method.
     pub(in crate::rwlock) unsafe fn write_unlock(&self) {
-        if !self.wlock.compare_and_swap(true, false, Ordering::Acquire) {
+        if self
+            .wlock
+            .compare_exchange(true, false, Ordering::Release, Ordering::Relaxed)
+            .is_err()
+        {
             panic!("write_unlock() called without acquiring the write lock");
         }
+        self.owners.unlocked_write();
+        self.wake();
+        self.ulock.store(false, Ordering::Release);
     }
 
     /// Unlocks the read lock; called by the drop() method.
     pub(in crate::rwlock) unsafe fn read_unlock(&self, tid: usize) {
+        self.owners.unlocked_read(tid);
         if self.rlock[tid].fetch_sub(1, Ordering::Release) == 0 {
             panic!("read_unlock() called without acquiring the read lock");
         }
+        self.wake();
     }
 }
 
-impl<'rwlock, T: ?Sized + Default + Sync> ReadGuard<'rwlock, T> {
+impl<'rwlock, T: Sync> ReadGuard<'rwlock, T> {
     /// Returns a read guard over a passed in reader-writer lock.
     unsafe fn new(lock: &'rwlock RwLock<T>, tid: usize) -> ReadGuard<'rwlock, T> {
-        ReadGuard { tid, lock }
+        lock.owners.locked_read(tid);
+        ReadGuard {
+            tid,
+            lock,
+            #[cfg(loom)]
+            data: ManuallyDrop::new(lock.data.get()),
+        }
     }
 }
 
-impl<'rwlock, T: ?Sized + Default + Sync> WriteGuard<'rwlock, T> {
+impl<'rwlock, T: Sync> UpgradeableGuard<'rwlock, T> {
+    /// Returns an upgradeable guard over a passed in reader-writer lock.
+    unsafe fn new(lock: &'rwlock RwLock<T>, tid: usize) -> UpgradeableGuard<'rwlock, T> {
+        lock.owners.locked_read(tid);
+        UpgradeableGuard {
+            tid,
+            lock,
+            #[cfg(loom)]
+            data: ManuallyDrop::new(lock.data.get()),
+        }
+    }
+
+    /// Turns the guard into a write-guard. Since writers wait for the
+    /// upgradeable reader, nothing can change the data in between; this only
+    /// waits for the other readers to leave.
+    #[cfg_attr(not(loom), allow(unused_mut))]
+    pub fn upgrade(mut self) -> WriteGuard<'rwlock, T> {
+        let (lock, tid) = (self.lock, self.tid);
+        #[cfg(loom)]
+        unsafe {
+            ManuallyDrop::drop(&mut self.data);
+        }
+        core::mem::forget(self);
+
+        // No writer holds the writer lock while the upgrader lock is held.
+        lock.wlock.store(true, Ordering::SeqCst);
+        unsafe { lock.read_unlock(tid) };
+        let _ = lock.wait_for_readers(&mut lock.deadline(None));
+        unsafe { WriteGuard::new(lock) }
+    }
+}
+
+impl<'rwlock, T: Sync> WriteGuard<'rwlock, T> {
     /// Returns a write guard over a passed in reader-writer lock.
     unsafe fn new(lock: &'rwlock RwLock<T>) -> WriteGuard<'rwlock, T> {
-        WriteGuard { lock }
+        lock.owners.locked_write();
+        WriteGuard {
+            lock,
+            #[cfg(loom)]
+            data: ManuallyDrop::new(lock.data.get_mut()),
+        }
     }
 }
 
 /// `Sync` trait allows `RwLock` to be shared between threads. The `read()` and
 /// `write()` logic ensures that we will never have threads writing to and
 /// reading from the underlying data structure simultaneously.
-unsafe impl<T: ?Sized + Default + Sync> Sync for RwLock<T> {}
+unsafe impl<T: Sync> Sync for RwLock<T> {}
 
 /// This `Deref` trait allows a thread to use T from a ReadGuard.
 /// ReadGuard can only be dereferenced into an immutable reference.
-impl<T: ?Sized + Default + Sync> Deref for ReadGuard<'_, T> {
+impl<T: Sync> Deref for ReadGuard<'_, T> {
     type Target = T;
 
+    #[cfg(not(loom))]
     fn deref(&self) -> &T {
         unsafe { &*self.lock.data.get() }
     }
+
+    #[cfg(loom)]
+    fn deref(&self) -> &T {
+        unsafe { ConstPtr::deref(&self.data) }
+    }
+}
+
+/// This `Deref` trait allows a thread to use T from an UpgradeableGuard.
+impl<T: Sync> Deref for UpgradeableGuard<'_, T> {
+    type Target = T;
+
+    #[cfg(not(loom))]
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+
+    #[cfg(loom)]
+    fn deref(&self) -> &T {
+        unsafe { ConstPtr::deref(&self.data) }
+    }
 }
 
 /// This `Deref` trait allows a thread to use T from a WriteGuard.
 /// This allows us to dereference an immutable reference.
-impl<T: ?Sized + Default + Sync> Deref for WriteGuard<'_, T> {
+impl<T: Sync> Deref for WriteGuard<'_, T> {
     type Target = T;
 
+    #[cfg(not(loom))]
     fn deref(&self) -> &T {
         unsafe { &*self.lock.data.get() }
     }
+
+    #[cfg(loom)]
+    fn deref(&self) -> &T {
+        unsafe { MutPtr::deref(&self.data) }
+    }
 }
 
 /// This `DerefMut` trait allow a thread to use T from a WriteGuard.
 /// This allows us to dereference a mutable reference.
-impl<T: ?Sized + Default + Sync> DerefMut for WriteGuard<'_, T> {
+impl<T: Sync> DerefMut for WriteGuard<'_, T> {
+    #[cfg(not(loom))]
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.lock.data.get() }
     }
+
+    #[cfg(loom)]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { MutPtr::deref(&self.data) }
+    }
 }
 
 /// This `Drop` trait implements the unlock logic for a reader lock. Once the `ReadGuard`
 /// goes out of scope, the corresponding read lock is marked as released.
-impl<T: ?Sized + Default + Sync> Drop for ReadGuard<'_, T> {
+impl<T: Sync> Drop for ReadGuard<'_, T> {
     fn drop(&mut self) {
         unsafe {
+            // The tracked access has to end before the lock is handed over.
+            #[cfg(loom)]
+            ManuallyDrop::drop(&mut self.data);
+
             let tid = self.tid;
             self.lock.read_unlock(tid);
         }
     }
 }
 
+/// Releases the read lock and the upgrader lock of an `UpgradeableGuard` that
+/// wasn't upgraded.
+impl<T: Sync> Drop for UpgradeableGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            // The tracked access has to end before the lock is handed over.
+            #[cfg(loom)]
+            ManuallyDrop::drop(&mut self.data);
+
+            self.lock.read_unlock(self.tid);
+        }
+        self.lock.ulock.store(false, Ordering::Release);
+        self.lock.wake();
+    }
+}
+
 /// This `Drop` trait implements the unlock logic for a writer lock. Once the `WriteGuard`
 /// goes out of scope, the corresponding write lock is marked as released.
-impl<T: ?Sized + Default + Sync> Drop for WriteGuard<'_, T> {
+impl<T: Sync> Drop for WriteGuard<'_, T> {
     fn drop(&mut self) {
         unsafe {
+            // The tracked access has to end before the lock is handed over.
+            #[cfg(loom)]
+            ManuallyDrop::drop(&mut self.data);
+
+            if let Some(panicking) = self.lock.panicking {
+                if panicking() {
+                    self.lock.poisoned.store(true, Ordering::Release);
+                }
+            }
             self.lock.write_unlock();
         }
     }
 }
 
-#[cfg(test)]
+/// Picks the reader slot of a `RawRwLock` for the calling thread. A thread
+/// has to get the same slot when it unlocks as when it locked.
+pub trait ReaderSlot {
+    fn current() -> usize;
+}
+
+/// Puts all readers into the same slot.
+pub struct OneSlot;
+
+impl ReaderSlot for OneSlot {
+    fn current() -> usize {
+        0
+    }
+}
+
+/// The distributed lock without the data, for use with `lock_api`, e.g. as
+/// `lock_api::RwLock<RawRwLock<S, READERS>, T>`. Readers are spread over
+/// `READERS` slots by `S`; writers wait for all of them.
+#[cfg(not(loom))]
+pub struct RawRwLock<S: ReaderSlot = OneSlot, const READERS: usize = 1> {
+    wlock: CachePadded<AtomicBool>,
+    rlock: [CachePadded<AtomicUsize>; READERS],
+    _slot: PhantomData<fn() -> S>,
+}
+
+#[cfg(not(loom))]
+impl<S: ReaderSlot, const READERS: usize> RawRwLock<S, READERS> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FREE: CachePadded<AtomicUsize> = CachePadded::new(AtomicUsize::new(0));
+
+    /// The reader slot of the calling thread.
+    fn slot(&self) -> &AtomicUsize {
+        &self.rlock[S::current() % READERS]
+    }
+}
+
+#[cfg(not(loom))]
+unsafe impl<S: ReaderSlot, const READERS: usize> lock_api::RawRwLock for RawRwLock<S, READERS> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = RawRwLock {
+        wlock: CachePadded::new(AtomicBool::new(false)),
+        rlock: [Self::FREE; READERS],
+        _slot: PhantomData,
+    };
+
+    // The guards have to be dropped by the thread that got them, since the
+    // reader slot depends on the thread.
+    type GuardMarker = lock_api::GuardNoSend;
+
+    fn lock_shared(&self) {
+        let mut deadline = Deadline::new(None, None, None);
+        while !self.try_lock_shared() {
+            while self.wlock.load(Ordering::Relaxed) {
+                let _ = deadline.spin();
+            }
+        }
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        let slot = self.slot();
+        slot.fetch_add(1, Ordering::Acquire);
+        if !self.wlock.load(Ordering::Acquire) {
+            return true;
+        }
+        slot.fetch_sub(1, Ordering::Release);
+        false
+    }
+
+    unsafe fn unlock_shared(&self) {
+        if self.slot().fetch_sub(1, Ordering::Release) == 0 {
+            panic!("unlock_shared() called without acquiring the read lock");
+        }
+    }
+
+    fn lock_exclusive(&self) {
+        let mut deadline = Deadline::new(None, None, None);
+        while self
+            .wlock
+            .compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed)
+            .is_err()
+        {
+            let _ = deadline.spin();
+        }
+        // Like `RwLock::wait_for_readers()`.
+        for item in self.rlock.iter() {
+            while !RwLock::<()>::slot_free(item) {
+                let _ = deadline.spin();
+            }
+        }
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        if self
+            .wlock
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+        if self.rlock.iter().all(|item| RwLock::<()>::slot_free(item)) {
+            return true;
+        }
+        self.wlock.store(false, Ordering::Release);
+        false
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        self.wlock.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(all(test, not(loom)))]
 mod tests {
-    use super::{RwLock, MAX_READER_THREADS};
+    use super::RwLock;
     use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
     use std::thread;
@@ -240,12 +1104,21 @@ mod tests {
         let lock = RwLock::<usize>::default();
 
         assert_eq!(lock.wlock.load(Ordering::Relaxed), false);
-        for idx in 0..MAX_READER_THREADS {
+        for idx in 0..lock.readers() {
             assert_eq!(lock.rlock[idx].load(Ordering::Relaxed), 0);
         }
         assert_eq!(unsafe { *lock.data.get() }, usize::default());
     }
 
+    // Tests if a lock created with new() wraps the supplied value.
+    #[test]
+    fn test_rwlock_new() {
+        let lock = RwLock::<usize>::new(10);
+
+        assert_eq!(lock.wlock.load(Ordering::Relaxed), false);
+        assert_eq!(*lock.read(0), 10);
+    }
+
     // Tests if the mutable reference returned on acquiring a write lock
     // can be used to write to the underlying data structure.
     #[test]
@@ -307,7 +1180,7 @@ mod tests {
     // Tests that multiple readers can simultaneously acquire a readers lock
     #[test]
     fn test_multiple_readers() {
-        let lock = RwLock::<usize>::default();
+        let lock = RwLock::<usize>::with_readers(0, 4);
         let val = 10;
 
         unsafe {
@@ -326,11 +1199,419 @@ mod tests {
         assert_eq!(*t, val);
     }
 
+    // Tests that an upgradeable reader shares the lock with readers, and keeps
+    // it while upgrading to a writer.
+    #[test]
+    fn test_upgradeable_read() {
+        let lock = RwLock::<usize>::with_readers(10, 2);
+
+        {
+            let upgradeable = lock.upgradeable_read(0);
+            let reader = lock.read(1);
+            assert_eq!(*upgradeable, 10);
+            assert_eq!(*reader, 10);
+            assert_eq!(lock.ulock.load(Ordering::Relaxed), true);
+            assert_eq!(lock.rlock[0].load(Ordering::Relaxed), 1);
+        }
+        assert_eq!(lock.ulock.load(Ordering::Relaxed), false);
+        assert_eq!(lock.rlock[0].load(Ordering::Relaxed), 0);
+
+        {
+            let mut writer = lock.upgradeable_read(0).upgrade();
+            *writer += 1;
+            assert_eq!(lock.wlock.load(Ordering::Relaxed), true);
+            assert_eq!(lock.rlock[0].load(Ordering::Relaxed), 0);
+        }
+        assert_eq!(lock.wlock.load(Ordering::Relaxed), false);
+        assert_eq!(lock.ulock.load(Ordering::Relaxed), false);
+        assert_eq!(*lock.read(0), 11);
+    }
+
+    // Tests that a write lock cannot be held along with an upgradeable lock.
+    //
+    // The second lock operation in this test should block indefinitely, and
+    // the main thread should panic after waking up because the atomic wasn't
+    // written to.
+    #[test]
+    #[should_panic(expected = "This test should always panic")]
+    fn test_writer_after_upgradeable() {
+        let lock = RwLock::<usize>::default();
+        let shared = Arc::new(AtomicUsize::new(0));
+
+        let s = shared.clone();
+        let lock_thread = thread::spawn(move || {
+            let _u = lock.upgradeable_read(0);
+            let _w = lock.write();
+            s.store(1, Ordering::SeqCst);
+        });
+
+        thread::sleep(std::time::Duration::from_secs(2));
+        if shared.load(Ordering::SeqCst) == 0 {
+            panic!("This test should always panic");
+        }
+        lock_thread.join().unwrap();
+    }
+
+    // Tests that the try_ variants fail instead of blocking, and leave the lock
+    // untouched when they fail.
+    #[test]
+    fn test_try_lock() {
+        let lock = RwLock::<usize>::with_readers(0, 4);
+
+        {
+            let _r = lock.read(0);
+            assert!(lock.try_write().is_none());
+            assert_eq!(lock.wlock.load(Ordering::Relaxed), false);
+            assert_eq!(lock.ulock.load(Ordering::Relaxed), false);
+            assert!(lock.try_read(1).is_some());
+        }
+
+        {
+            let _w = lock.try_write().unwrap();
+            assert!(lock.try_read(0).is_none());
+            assert!(lock.try_write().is_none());
+            assert_eq!(lock.rlock[0].load(Ordering::Relaxed), 0);
+        }
+
+        {
+            let _u = lock.upgradeable_read(0);
+            assert!(lock.try_write().is_none());
+            assert!(lock.try_read(1).is_some());
+        }
+
+        assert!(lock.try_write().is_some());
+        assert!(lock.try_read(0).is_some());
+    }
+
+    // Tests that the bounded variants give up once their budget is used up, and
+    // leave the lock untouched when they do.
+    #[test]
+    fn test_bounded_lock() {
+        use super::{Budget, WouldBlock};
+        let lock = RwLock::<usize>::with_readers(0, 4);
+
+        {
+            let _r = lock.read(0);
+            assert!(matches!(
+                lock.write_for(Budget::Spins(100)),
+                Err(WouldBlock)
+            ));
+            assert!(matches!(
+                lock.write_for(Budget::Ticks(10000)),
+                Err(WouldBlock)
+            ));
+            assert_eq!(lock.wlock.load(Ordering::Relaxed), false);
+            assert_eq!(lock.ulock.load(Ordering::Relaxed), false);
+            assert!(lock.read_for(1, Budget::Spins(0)).is_ok());
+        }
+
+        {
+            let _w = lock.write_for(Budget::Spins(0)).unwrap();
+            assert!(matches!(
+                lock.read_for(0, Budget::Spins(100)),
+                Err(WouldBlock)
+            ));
+            assert!(matches!(
+                lock.read_for(0, Budget::Ticks(10000)),
+                Err(WouldBlock)
+            ));
+            assert_eq!(lock.rlock[0].load(Ordering::Relaxed), 0);
+        }
+
+        assert!(lock.read_for(0, Budget::Ticks(0)).is_ok());
+        assert_eq!(
+            crate::FileSystemError::from(WouldBlock),
+            crate::FileSystemError::WouldBlock
+        );
+    }
+
+    // Tests that every CPU, on any socket, has its own reader slot that writers
+    // wait on.
+    #[test]
+    fn test_readers_on_all_sockets() {
+        let topology = crate::topology::machine();
+        let lock = RwLock::<usize>::default();
+        for cpu in topology.cpus() {
+            let _r = lock.read(cpu.cpu as usize);
+            assert!(lock.try_write().is_none());
+        }
+        assert!(lock.readers() >= topology.cores());
+    }
+
+    // Tests that with phase-fair locking, a writer lets waiting readers in first.
+    #[test]
+    fn test_phase_fairness() {
+        use super::{Budget, Fairness};
+        let lock = Arc::new(RwLock::<usize>::with_readers(0, 2).with_fairness(Fairness::Phases));
+
+        let writer = lock.write();
+        let l = lock.clone();
+        let reader = thread::spawn(move || *l.read(1));
+        while lock.rwait.load(Ordering::Relaxed) == 0 {
+            thread::yield_now();
+        }
+        drop(writer);
+        assert_eq!(reader.join().unwrap(), 0);
+        assert_eq!(lock.rwait.load(Ordering::Relaxed), 0);
+
+        // While a reader waits, the next writer has to let it in first.
+        lock.rwait.store(1, Ordering::Relaxed);
+        assert!(lock.try_write().is_none());
+        assert!(lock.write_for(Budget::Spins(10)).is_err());
+        assert_eq!(lock.ulock.load(Ordering::Relaxed), false);
+        lock.rwait.store(0, Ordering::Relaxed);
+        assert!(lock.try_write().is_some());
+    }
+
+    // Tests that combined write operations are all applied exactly once.
+    #[test]
+    fn test_combine() {
+        let lock = RwLock::<usize>::with_readers(0, 1);
+        assert_eq!(lock.combine(0, |n| *n + 1), 1);
+        assert!(lock.pending.is_empty());
+
+        let t = 4;
+        let lock = Arc::new(RwLock::<usize>::with_readers(0, t).with_combining());
+        let threads: Vec<_> = (0..t)
+            .map(|tid| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let before = lock.combine(tid, |n| {
+                            *n += 1;
+                            *n - 1
+                        });
+                        assert!(before < t * 1000);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock.read(0), t * 1000);
+        assert_eq!(lock.ulock.load(Ordering::Relaxed), false);
+    }
+
+    // Tests the lock through `lock_api`, with a reader slot per thread.
+    #[test]
+    fn test_lock_api() {
+        use super::{RawRwLock, ReaderSlot};
+
+        let lock = lock_api::RwLock::<RawRwLock, usize>::new(1);
+        {
+            let r1 = lock.read();
+            let r2 = lock.try_read().unwrap();
+            assert_eq!(*r1 + *r2, 2);
+            assert!(lock.try_write().is_none());
+        }
+        *lock.write() += 1;
+        assert!(lock.try_read().is_some());
+        assert_eq!(*lock.read(), 2);
+
+        struct PerThread;
+        impl ReaderSlot for PerThread {
+            fn current() -> usize {
+                static NEXT: AtomicUsize = AtomicUsize::new(0);
+                std::thread_local!(static SLOT: usize = NEXT.fetch_add(1, Ordering::Relaxed));
+                SLOT.with(|slot| *slot)
+            }
+        }
+
+        let t = 4;
+        let lock =
+            Arc::new(lock_api::RwLock::<RawRwLock<PerThread, 4>, (usize, usize)>::new((0, 0)));
+        let threads: Vec<_> = (0..t)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let (a, b) = *lock.read();
+                        assert_eq!(a, b);
+                        let mut pair = lock.write();
+                        pair.0 += 1;
+                        pair.1 += 1;
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock.read(), (t * 1000, t * 1000));
+    }
+
+    // Tests that a writer that panics poisons the lock, if poisoning is enabled.
+    #[test]
+    fn test_poisoning() {
+        use super::Poisoned;
+
+        let lock = Arc::new(RwLock::<usize>::with_readers(0, 1).with_poisoning(thread::panicking));
+        assert!(lock.checked_write().is_ok());
+        let l = lock.clone();
+        assert!(thread::spawn(move || {
+            let mut guard = l.write();
+            *guard = 1;
+            panic!("half-way through an update");
+        })
+        .join()
+        .is_err());
+
+        assert!(lock.is_poisoned());
+        assert_eq!(lock.checked_write().err(), Some(Poisoned));
+        assert_eq!(lock.checked_read(0).err(), Some(Poisoned));
+        // The lock itself was released.
+        assert_eq!(*lock.read(0), 1);
+        lock.clear_poison();
+        assert_eq!(*lock.checked_read(0).unwrap(), 1);
+
+        let lock = Arc::new(RwLock::<usize>::with_readers(0, 1));
+        let l = lock.clone();
+        assert!(thread::spawn(move || {
+            let _guard = l.write();
+            panic!("without poisoning");
+        })
+        .join()
+        .is_err());
+        assert!(!lock.is_poisoned());
+        assert!(lock.checked_write().is_ok());
+    }
+
+    // Tests that a thread that takes the write lock twice panics in debug
+    // builds instead of spinning forever.
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "recursive write acquisition")]
+    fn test_recursive_write() {
+        let lock = RwLock::<usize>::with_readers(0, 1);
+        let _guard = lock.write();
+        let _ = lock.write();
+    }
+
+    // Tests that a reader that asks for the write lock panics in debug builds.
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "holds the read lock of reader 1")]
+    fn test_write_after_read() {
+        let lock = RwLock::<usize>::with_readers(0, 2);
+        let _guard = lock.read(1);
+        let _ = lock.write();
+    }
+
+    // Tests that releasing the wrong reader slot panics in debug builds.
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "read lock of reader 1 released by a thread that doesn't hold it")]
+    fn test_wrong_tid_unlock() {
+        let lock = RwLock::<usize>::with_readers(0, 2);
+        core::mem::forget(lock.read(0));
+        unsafe { lock.read_unlock(1) };
+    }
+
+    // Tests that in blocking mode waiting threads park until the lock is free.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_parking() {
+        let lock = Arc::new(RwLock::<usize>::with_readers(0, 2).with_parking());
+
+        let mut writer = lock.write();
+        let l = lock.clone();
+        let reader = thread::spawn(move || *l.read(1));
+        let parking = lock.parking.as_ref().unwrap();
+        while parking.waiters.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+        *writer = 1;
+        drop(writer);
+        assert_eq!(reader.join().unwrap(), 1);
+
+        let reader = lock.read(0);
+        let l = lock.clone();
+        let writer = thread::spawn(move || *l.write() += 1);
+        while parking.waiters.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+        drop(reader);
+        writer.join().unwrap();
+        assert_eq!(*lock.read(0), 2);
+        assert_eq!(parking.waiters.load(Ordering::SeqCst), 0);
+    }
+
+    // Tests that waiting threads back off and eventually call the yield hook.
+    #[test]
+    fn test_yield_hook() {
+        static YIELDS: AtomicUsize = AtomicUsize::new(0);
+        fn count_yield() {
+            YIELDS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let lock = RwLock::<usize>::with_readers(0, 1).with_yield(count_yield);
+        let _r = lock.read(0);
+        let mut deadline = lock.deadline(Some(super::Budget::Spins(10)));
+        while deadline.spin().is_ok() {}
+        assert_eq!(deadline.backoff, super::MAX_BACKOFF);
+        assert_eq!(
+            YIELDS.load(Ordering::Relaxed),
+            10 - super::MAX_BACKOFF as usize
+        );
+
+        assert!(lock.write_for(super::Budget::Spins(100)).is_err());
+        assert_eq!(
+            YIELDS.load(Ordering::Relaxed),
+            110 - 2 * super::MAX_BACKOFF as usize
+        );
+    }
+
+    // Tests that registered threads get their own slots while there are enough.
+    #[test]
+    fn test_register() {
+        let lock = RwLock::<usize>::with_readers(0, 3);
+        let ids: Vec<usize> = (0..4).map(|_| lock.register()).collect();
+        assert_eq!(ids, [0, 1, 2, 0]);
+
+        let _r = lock.read(ids[1]);
+        assert_eq!(lock.rlock[1].load(Ordering::Relaxed), 1);
+    }
+
+    // Tests that the number of reader slots is picked at construction.
+    #[test]
+    fn test_with_readers() {
+        let lock = RwLock::<usize>::with_readers(0, 300);
+        assert_eq!(lock.readers(), 300);
+        {
+            let _r = lock.read(299);
+            assert!(lock.try_write().is_none());
+        }
+        assert!(lock.try_write().is_some());
+        assert_eq!(RwLock::<usize>::with_readers(0, 0).readers(), 1);
+    }
+
+    // Tests that a lock sized from a topology has a slot per CPU.
+    #[test]
+    fn test_with_topology() {
+        let cpus = (0..4)
+            .map(|cpu| crate::topology::CpuInfo {
+                node: None,
+                socket: 0,
+                core: cpu,
+                cpu,
+                l1: cpu,
+                l2: cpu,
+                l3: 0,
+            })
+            .collect();
+        let topology = crate::topology::MachineTopology::from_cpus(cpus);
+        let lock = RwLock::<usize>::with_topology(0, &topology);
+        assert_eq!(lock.readers(), 4);
+        let _r = lock.read(3);
+        assert!(lock.try_write().is_none());
+    }
+
     // Tests that multiple writers and readers whose scopes don't interfere can
     // acquire the lock.
     #[test]
     fn test_lock_combinations() {
-        let l = RwLock::<usize>::default();
+        let l = RwLock::<usize>::with_readers(0, 4);
 
         {
             let _g = l.write();
@@ -380,8 +1661,8 @@ mod tests {
     // Tests that the multiple readers can read from the lock in parallel.
     #[test]
     fn test_parallel_readers() {
-        let lock = Arc::new(RwLock::<usize>::default());
         let t = 100;
+        let lock = Arc::new(RwLock::<usize>::with_readers(0, t));
 
         unsafe {
             *lock.data.get() = t;
@@ -509,3 +1790,171 @@ mod tests {
         lock_thread.join().unwrap();
     }
 }
+
+/// Model-checked tests; run them with:
+///
+/// `RUSTFLAGS="--cfg loom" cargo test --release --lib rwlock::loom_tests`
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::RwLock;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    // Tests that a writer and a reader never access the data at the same time.
+    // Loom panics on its own if the tracked accesses of the guards overlap.
+    #[test]
+    fn loom_reader_writer_exclusion() {
+        loom::model(|| {
+            let lock = Arc::new(RwLock::<usize>::default());
+
+            let l = lock.clone();
+            let writer = thread::spawn(move || {
+                *l.write() += 1;
+            });
+
+            let val = *lock.read(0);
+            assert!(val == 0 || val == 1);
+
+            writer.join().unwrap();
+            assert_eq!(*lock.read(0), 1);
+        });
+    }
+
+    // Tests that concurrent writers are serialized and no update is lost.
+    #[test]
+    fn loom_writer_writer_exclusion() {
+        loom::model(|| {
+            let lock = Arc::new(RwLock::<usize>::default());
+
+            let l = lock.clone();
+            let writer = thread::spawn(move || {
+                *l.write() += 1;
+            });
+
+            *lock.write() += 1;
+
+            writer.join().unwrap();
+            assert_eq!(*lock.read(0), 2);
+        });
+    }
+
+    // Tests that a reader never observes a half-finished update by a writer.
+    #[test]
+    fn loom_no_torn_reads() {
+        loom::model(|| {
+            let lock = Arc::new(RwLock::<(usize, usize)>::default());
+
+            let l = lock.clone();
+            let writer = thread::spawn(move || {
+                let mut guard = l.write();
+                guard.0 += 1;
+                guard.1 += 1;
+            });
+
+            {
+                let guard = lock.read(1);
+                assert_eq!(guard.0, guard.1);
+            }
+
+            writer.join().unwrap();
+        });
+    }
+
+    // Tests that no update is lost between an upgrade and a writer, and that a
+    // reader never overlaps with either of them.
+    #[test]
+    fn loom_upgrade() {
+        loom::model(|| {
+            let lock = Arc::new(RwLock::<usize>::default());
+
+            let l = lock.clone();
+            let writer = thread::spawn(move || {
+                *l.write() += 1;
+            });
+
+            {
+                let guard = lock.upgradeable_read(0);
+                let val = *guard;
+                *guard.upgrade() = val + 1;
+            }
+
+            writer.join().unwrap();
+            assert_eq!(*lock.read(1), 2);
+        });
+    }
+
+    // Tests that a try_write and a try_read never both succeed while the other
+    // guard is held.
+    #[test]
+    fn loom_try_lock_exclusion() {
+        loom::model(|| {
+            let lock = Arc::new(RwLock::<usize>::default());
+
+            let l = lock.clone();
+            let writer = thread::spawn(move || {
+                if let Some(mut guard) = l.try_write() {
+                    *guard += 1;
+                }
+            });
+
+            if let Some(guard) = lock.try_read(0) {
+                assert!(*guard == 0 || *guard == 1);
+            }
+
+            writer.join().unwrap();
+        });
+    }
+
+    // Tests that phase-fair locking still keeps readers and writers apart.
+    #[test]
+    fn loom_phase_fair_exclusion() {
+        loom::model(|| {
+            let lock = Arc::new(RwLock::<usize>::default().with_fairness(super::Fairness::Phases));
+
+            let l = lock.clone();
+            let writer = thread::spawn(move || {
+                *l.write() += 1;
+            });
+
+            let val = *lock.read(0);
+            assert!(val == 0 || val == 1);
+
+            writer.join().unwrap();
+            assert_eq!(*lock.read(0), 1);
+        });
+    }
+
+    // Tests that combined operations of two threads are both applied.
+    #[test]
+    fn loom_combine() {
+        loom::model(|| {
+            let lock = Arc::new(RwLock::<usize>::with_readers(0, 2).with_combining());
+
+            let l = lock.clone();
+            let other = thread::spawn(move || l.combine(1, |n| *n += 1));
+
+            lock.combine(0, |n| *n += 1);
+            other.join().unwrap();
+            assert_eq!(*lock.read(0), 2);
+        });
+    }
+
+    // Tests that readers on different slots can hold the lock at the same time.
+    #[test]
+    fn loom_parallel_readers() {
+        loom::model(|| {
+            let lock = Arc::new(RwLock::<usize>::default());
+
+            let l = lock.clone();
+            let reader = thread::spawn(move || {
+                let _guard = l.read(1);
+            });
+
+            {
+                let _guard = lock.read(0);
+            }
+
+            reader.join().unwrap();
+        });
+    }
+}
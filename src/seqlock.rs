@@ -0,0 +1,102 @@
+//! A sequence lock for small values that are read far more often than they
+//! are written.
+//!
+//! Readers never write to shared memory: they copy the value and retry if a
+//! writer was active in the meantime, which they notice from the sequence
+//! number. Writers make the sequence number odd while they update the value
+//! and even again once they are done.
+
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+pub(crate) struct SeqLock<T> {
+    seq: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub(crate) fn new(data: T) -> SeqLock<T> {
+        SeqLock {
+            seq: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Get a copy of the value.
+    pub(crate) fn read(&self) -> T {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 == 0 {
+                // The copy may be torn by a writer, so it's only used if the
+                // sequence number didn't change in the meantime.
+                let data = unsafe { ptr::read_volatile(self.data.get()) };
+                fence(Ordering::Acquire);
+                if self.seq.load(Ordering::Relaxed) == seq {
+                    return data;
+                }
+            }
+            spin_loop();
+        }
+    }
+
+    /// Replace the value.
+    pub(crate) fn write(&self, data: T) {
+        let mut seq = self.seq.load(Ordering::Relaxed);
+        loop {
+            if seq & 1 == 1 {
+                spin_loop();
+                seq = self.seq.load(Ordering::Relaxed);
+                continue;
+            }
+            match self.seq.compare_exchange_weak(
+                seq,
+                seq.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => seq = current,
+            }
+        }
+        // Readers must see the odd sequence number before any of the data.
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(self.data.get(), data) };
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use std::thread;
+
+    #[test]
+    /// Readers never see a value that is half written.
+    fn test_seqlock() {
+        let lock = Arc::new(SeqLock::new((0usize, 0usize)));
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for i in 0..10000 {
+                        lock.write((i, i));
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..10000 {
+            let (a, b) = lock.read();
+            assert_eq!(a, b);
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(lock.read(), (9999, 9999));
+    }
+}
@@ -0,0 +1,196 @@
+//! Slab allocation of the mnodes.
+//!
+//! Every mnode is a reference-counted cell of the same size. Instead of a heap
+//! allocation each, the cells are carved out of slabs of `SLOTS` slots, and a
+//! freed slot is reused by the next mnode created. Creating and deleting many
+//! small files (an initramfs, the temporary files of a build) then mostly
+//! pops and pushes a free list, and the cells don't fragment the heap. Slabs
+//! are kept once allocated, for the mnodes created later.
+
+use alloc::alloc::{AllocError, Allocator, Global, Layout};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+use core::mem::{align_of, size_of};
+use core::ptr::NonNull;
+use lock_api::RawRwLock;
+use spin::Mutex;
+
+use crate::MemFS;
+
+/// Slots in a slab.
+const SLOTS: usize = 64;
+
+/// How the slabs of the mnodes are used.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct SlabStats {
+    /// Slabs allocated.
+    pub slabs: usize,
+    /// Slots in all the slabs.
+    pub slots: usize,
+    /// Slots holding an mnode.
+    pub used: usize,
+    /// Bytes allocated for the slabs.
+    pub bytes: usize,
+}
+
+/// Allocates the cells of the mnodes; the cells keep a handle to it, so the
+/// slabs outlive the file-system as long as one of them does.
+#[derive(Clone)]
+pub(crate) struct MnodeSlab(Arc<Mutex<Slabs>>);
+
+struct Slabs {
+    /// The layout of the cells, set by the first allocation; other layouts
+    /// are passed on to the global allocator.
+    cell: Option<Layout>,
+    /// The layout of a slot: a cell, big enough to hold a free list link.
+    slot: Layout,
+    slabs: Vec<NonNull<u8>>,
+    /// The first free slot; each free slot holds the address of the next.
+    free: Option<NonNull<u8>>,
+    used: usize,
+}
+
+// The slabs are only reached through the lock.
+unsafe impl Send for Slabs {}
+
+impl MnodeSlab {
+    /// No slabs yet.
+    pub(crate) fn new() -> MnodeSlab {
+        MnodeSlab(Arc::new(Mutex::new(Slabs {
+            cell: None,
+            slot: Layout::new::<usize>(),
+            slabs: Vec::new(),
+            free: None,
+            used: 0,
+        })))
+    }
+
+    pub(crate) fn stats(&self) -> SlabStats {
+        let slabs = self.0.lock();
+        SlabStats {
+            slabs: slabs.slabs.len(),
+            slots: slabs.slabs.len() * SLOTS,
+            used: slabs.used,
+            bytes: slabs.slabs.len() * slabs.slab().map_or(0, |slab| slab.size()),
+        }
+    }
+}
+
+impl Slabs {
+    /// The layout of a slab, if the slots are known.
+    fn slab(&self) -> Option<Layout> {
+        self.cell?;
+        Layout::from_size_align(self.slot.size() * SLOTS, self.slot.align()).ok()
+    }
+
+    /// Allocate a slab and add its slots to the free list.
+    fn grow(&mut self) -> Result<(), AllocError> {
+        let slab = self.slab().ok_or(AllocError)?;
+        self.slabs.try_reserve(1).map_err(|_| AllocError)?;
+        let memory = Global.allocate(slab)?.cast::<u8>();
+        self.slabs.push(memory);
+        for n in (0..SLOTS).rev() {
+            // The slot is inside the slab and aligned for the link.
+            unsafe {
+                let slot = memory.as_ptr().add(n * self.slot.size());
+                slot.cast::<Option<NonNull<u8>>>().write(self.free);
+                self.free = Some(NonNull::new_unchecked(slot));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Slabs {
+    fn drop(&mut self) {
+        if let Some(slab) = self.slab() {
+            for memory in self.slabs.drain(..) {
+                // The slab was allocated with this layout, and no cell is
+                // left in it, as each one holds the allocator.
+                unsafe { Global.deallocate(memory, slab) };
+            }
+        }
+    }
+}
+
+unsafe impl Allocator for MnodeSlab {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let mut slabs = self.0.lock();
+        if slabs.cell.is_none() {
+            let slot = Layout::from_size_align(
+                layout.size().max(size_of::<usize>()),
+                layout.align().max(align_of::<usize>()),
+            )
+            .map_err(|_| AllocError)?;
+            slabs.cell = Some(layout);
+            slabs.slot = slot.pad_to_align();
+        }
+        if slabs.cell != Some(layout) {
+            drop(slabs);
+            return Global.allocate(layout);
+        }
+        if slabs.free.is_none() {
+            slabs.grow()?;
+        }
+        let slot = slabs.free.ok_or(AllocError)?;
+        // A free slot holds the link to the next one.
+        slabs.free = unsafe { slot.as_ptr().cast::<Option<NonNull<u8>>>().read() };
+        slabs.used += 1;
+        Ok(NonNull::slice_from_raw_parts(slot, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        let mut slabs = self.0.lock();
+        if slabs.cell != Some(layout) {
+            drop(slabs);
+            return Global.deallocate(ptr, layout);
+        }
+        ptr.as_ptr().cast::<Option<NonNull<u8>>>().write(slabs.free);
+        slabs.free = Some(ptr);
+        slabs.used -= 1;
+    }
+}
+
+impl<S: BuildHasher + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
+    /// How the slabs of the mnodes are used, the root included.
+    pub fn mnode_slab_stats(&self) -> SlabStats {
+        self.slab.stats()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FileModes, FileSystem};
+    use alloc::format;
+
+    #[test]
+    /// Deleted mnodes leave their slots to the ones created next.
+    fn test_mnode_slab() {
+        let memfs = MemFS::default();
+        let stats = memfs.mnode_slab_stats();
+        assert_eq!((stats.slabs, stats.used), (1, 1));
+
+        for n in 0..100 {
+            memfs
+                .create(&format!("/{}", n), FileModes::S_IRWXU.into())
+                .unwrap();
+        }
+        let stats = memfs.mnode_slab_stats();
+        assert_eq!(stats.used, 101);
+        assert_eq!(stats.slots, 2 * SLOTS);
+        assert!(stats.bytes >= stats.slots * size_of::<usize>());
+
+        for n in 0..100 {
+            memfs.delete(&format!("/{}", n)).unwrap();
+        }
+        assert_eq!(memfs.mnode_slab_stats().used, 1);
+        for n in 0..100 {
+            memfs
+                .mkdir(&format!("/{}", n), FileModes::S_IRWXU.into())
+                .unwrap();
+        }
+        assert_eq!(memfs.mnode_slab_stats(), stats);
+    }
+}
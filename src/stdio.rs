@@ -0,0 +1,207 @@
+//! `std::io` adapters, so host-side tools and tests can use the file-system
+//! with the standard IO ecosystem, e.g. `io::copy()` or readers that take an
+//! `impl Read`. Enabled with the `std` feature.
+
+use alloc::sync::Arc;
+use core::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::errno::{EBADF, EINVAL};
+use crate::fd::{Fd, FileDescriptor, SharedFdTable};
+use crate::{FileSystem, FileSystemError, FileSystemRead, MemFS, FD};
+
+impl From<FileSystemError> for io::Error {
+    fn from(err: FileSystemError) -> io::Error {
+        io::Error::from_raw_os_error(err.errno())
+    }
+}
+
+/// An open descriptor as a `Read + Write + Seek` file.
+///
+/// The file shares its offset with the descriptor it was made from, like a
+/// `dup()` of it, and stays open while the file is alive even if the
+/// descriptor is closed.
+pub struct NrfsFile<'a, F: FileSystemRead + ?Sized = MemFS> {
+    fs: &'a F,
+    file: Arc<Fd>,
+}
+
+impl<'a, F: FileSystemRead + ?Sized> NrfsFile<'a, F> {
+    /// The file open as `fd` in `fds`, on `fs`.
+    pub fn new(fs: &'a F, fds: &SharedFdTable, fd: FD) -> Result<NrfsFile<'a, F>, FileSystemError> {
+        Ok(NrfsFile {
+            fs,
+            file: fds.get(fd)?,
+        })
+    }
+
+    /// The current size of the file.
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.fs.file_info(self.file.get_mnode())?.fsize)
+    }
+}
+
+impl<F: FileSystemRead + ?Sized> Read for NrfsFile<'_, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.file.get_flags().is_read() {
+            return Err(io::Error::from_raw_os_error(EBADF));
+        }
+        let _offset = self.file.lock_offset();
+        let offset = self.file.get_offset();
+        // Reads at or past the end of the file read nothing, as in POSIX.
+        let read = match self.fs.read(self.file.get_mnode(), buf, offset) {
+            Err(FileSystemError::InvalidOffset) => 0,
+            read => read?,
+        };
+        self.file.update_offset(offset + read);
+        Ok(read)
+    }
+}
+
+impl<F: FileSystem + ?Sized> Write for NrfsFile<'_, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let flags = self.file.get_flags();
+        if !flags.is_write() {
+            return Err(io::Error::from_raw_os_error(EBADF));
+        }
+        let _offset = self.file.lock_offset();
+        let (end, written) = match flags.is_append() {
+            true => self.fs.append(self.file.get_mnode(), buf)?,
+            false => {
+                let offset = self.file.get_offset();
+                let written = self.fs.write(self.file.get_mnode(), buf, offset)?;
+                (offset + written, written)
+            }
+        };
+        self.file.update_offset(end);
+        Ok(written)
+    }
+
+    /// The writes are in the file-system once they return; this doesn't
+    /// sync the file, see `FileSystem::fsync()`.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<F: FileSystemRead + ?Sized> Seek for NrfsFile<'_, F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if self.file.is_path() {
+            return Err(io::Error::from_raw_os_error(EBADF));
+        }
+        let _offset = self.file.lock_offset();
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => checked_add(self.file.get_offset() as u64, delta),
+            SeekFrom::End(delta) => checked_add(self.size()?, delta),
+        };
+        let offset = offset
+            .and_then(|offset| usize::try_from(offset).ok())
+            .ok_or_else(|| io::Error::from_raw_os_error(EINVAL))?;
+        self.file.update_offset(offset);
+        Ok(offset as u64)
+    }
+}
+
+/// `base` moved by `delta`, unless that is before the start of the file.
+fn checked_add(base: u64, delta: i64) -> Option<u64> {
+    match delta < 0 {
+        true => base.checked_sub(delta.unsigned_abs()),
+        false => base.checked_add(delta as u64),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fd::FdTable;
+    use crate::{DirEntry, FileFlags, FileInfo, FileModes, Mnode};
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    #[test]
+    /// A file filled with `io::copy()`, then sought and read back.
+    fn test_nrfs_file() {
+        let memfs = MemFS::default();
+        let fds = SharedFdTable::new(FdTable::default(), 2);
+        let mnode = memfs.create("/file", FileModes::S_IRWXU.into()).unwrap();
+        let fd = fds.allocate(mnode, FileFlags::O_RDWR).unwrap();
+
+        let mut file = NrfsFile::new(&memfs, &fds, fd).unwrap();
+        let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        assert_eq!(io::copy(&mut &data[..], &mut file).unwrap(), 10000);
+        assert_eq!(fds.get(fd).unwrap().get_offset(), 10000);
+
+        assert_eq!(file.seek(SeekFrom::Start(0)).unwrap(), 0);
+        let mut read = Vec::new();
+        assert_eq!(file.read_to_end(&mut read).unwrap(), 10000);
+        assert_eq!(read, data);
+
+        assert_eq!(file.seek(SeekFrom::End(-4)).unwrap(), 9996);
+        assert_eq!(file.seek(SeekFrom::Current(-6)).unwrap(), 9990);
+        let mut buf = [0; 16];
+        assert_eq!(file.read(&mut buf).unwrap(), 10);
+        assert_eq!(&buf[..10], &data[9990..]);
+
+        let err = file.seek(SeekFrom::Current(-10001)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(EINVAL));
+        assert_eq!(file.stream_position().unwrap(), 10000);
+
+        let fd = fds.allocate(mnode, FileFlags::O_RDONLY).unwrap();
+        let mut file = NrfsFile::new(&memfs, &fds, fd).unwrap();
+        let err = file.write(b"nrfs").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(EBADF));
+    }
+
+    /// A read-only file-system with a single file.
+    struct Motd;
+
+    impl FileSystemRead for Motd {
+        fn read(
+            &self,
+            _: Mnode,
+            buffer: &mut [u8],
+            offset: usize,
+        ) -> Result<usize, FileSystemError> {
+            let data = b"hello"
+                .get(offset..)
+                .ok_or(FileSystemError::InvalidOffset)?;
+            let len = data.len().min(buffer.len());
+            buffer[..len].copy_from_slice(&data[..len]);
+            Ok(len)
+        }
+
+        fn lookup(&self, pathname: &str) -> Option<Arc<Mnode>> {
+            match pathname {
+                "/motd" => Some(Arc::new(2)),
+                _ => None,
+            }
+        }
+
+        fn file_info(&self, _: Mnode) -> Result<FileInfo, FileSystemError> {
+            Ok(FileInfo { ftype: 0, fsize: 5 })
+        }
+
+        fn readdir(
+            &self,
+            _: &str,
+            _: Option<&str>,
+            _: usize,
+        ) -> Result<Vec<DirEntry>, FileSystemError> {
+            Err(FileSystemError::NotADirectory)
+        }
+    }
+
+    #[test]
+    /// A read-only file-system only implements `FileSystemRead`.
+    fn test_read_only() {
+        let fds = SharedFdTable::new(FdTable::default(), 1);
+        let mnode = *Motd.lookup("/motd").unwrap();
+        let fd = fds.allocate(mnode, FileFlags::O_RDONLY).unwrap();
+        let mut file = NrfsFile::new(&Motd, &fds, fd).unwrap();
+        let mut motd = String::new();
+        file.read_to_string(&mut motd).unwrap();
+        assert_eq!(motd, "hello");
+        assert_eq!(file.seek(SeekFrom::End(-1)).unwrap(), 4);
+    }
+}
@@ -0,0 +1,421 @@
+//! Dispatch of the file-system system calls of a kernel.
+//!
+//! A kernel embedding MemFS gets an operation number and raw arguments from
+//! userspace: descriptors, addresses of buffers and file names ([`Buffer`],
+//! [`Filename`]) and lengths ([`Len`]). [`Syscalls::dispatch`] reads and
+//! writes the user memory through the [`UserMemory`] of the kernel, calls the
+//! [`FileSystem`] operation and returns the result or an errno, which
+//! [`to_return`] turns into the value of the system call.
+//!
+//! Like in POSIX, a read or write that faults after some of the data was
+//! copied returns the number of bytes copied; one that faults at once fails
+//! with `EFAULT`.
+
+use alloc::string::String;
+use core::convert::TryFrom;
+
+use crate::errno::{Errno, EINVAL};
+use crate::fd::{FileDescriptor, SharedFdTable};
+use crate::mnode::NodeType;
+pub use crate::user::UserMemory;
+use crate::user::UserSlice;
+use crate::{
+    Buffer, FileFlags, FileSystem, FileSystemError, Filename, Len, Offset, FD, MAX_PATH_LEN,
+};
+
+/// The numbers of the operations, with their arguments.
+#[repr(u64)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SyscallOp {
+    /// `open(Filename, Flags, Modes) -> FD`
+    Open = 1,
+    /// `close(FD)`
+    Close = 2,
+    /// `read(FD, Buffer, Len) -> Len`, at the offset of the descriptor.
+    Read = 3,
+    /// `write(FD, Buffer, Len) -> Len`, at the offset of the descriptor.
+    Write = 4,
+    /// `pread(FD, Buffer, Len, Offset) -> Len`
+    ReadAt = 5,
+    /// `pwrite(FD, Buffer, Len, Offset) -> Len`
+    WriteAt = 6,
+    /// `getinfo(Filename, Buffer)`, which gets a `FileInfo`.
+    GetInfo = 7,
+    /// `unlink(Filename)`
+    Delete = 8,
+    /// `mkdir(Filename, Modes)`
+    MkDir = 9,
+    /// `rmdir(Filename)`
+    RmDir = 10,
+    /// `rename(Filename, Filename)`
+    Rename = 11,
+    /// `truncate(Filename)`, to zero length.
+    Truncate = 12,
+    /// `fsync(FD)`
+    Fsync = 13,
+    /// `fdatasync(FD)`
+    Fdatasync = 14,
+}
+
+impl SyscallOp {
+    /// The operation with the number `op`, if there is one.
+    pub fn from_number(op: u64) -> Option<SyscallOp> {
+        Some(match op {
+            1 => SyscallOp::Open,
+            2 => SyscallOp::Close,
+            3 => SyscallOp::Read,
+            4 => SyscallOp::Write,
+            5 => SyscallOp::ReadAt,
+            6 => SyscallOp::WriteAt,
+            7 => SyscallOp::GetInfo,
+            8 => SyscallOp::Delete,
+            9 => SyscallOp::MkDir,
+            10 => SyscallOp::RmDir,
+            11 => SyscallOp::Rename,
+            12 => SyscallOp::Truncate,
+            13 => SyscallOp::Fsync,
+            14 => SyscallOp::Fdatasync,
+            _ => return None,
+        })
+    }
+}
+
+/// The value a system call returns to userspace: the result, or the
+/// negated errno.
+pub fn to_return(result: Result<u64, Errno>) -> i64 {
+    match result {
+        Ok(value) => value as i64,
+        Err(errno) => -(errno as i64),
+    }
+}
+
+/// Runs the system calls of one process on a file-system.
+pub struct Syscalls<'a, F: FileSystem + ?Sized, M: UserMemory> {
+    fs: &'a F,
+    fds: &'a SharedFdTable,
+    memory: &'a M,
+}
+
+impl<'a, F: FileSystem + ?Sized, M: UserMemory> Syscalls<'a, F, M> {
+    /// Run the calls on `fs` with the descriptors `fds`, accessing the
+    /// memory of the process through `memory`.
+    pub fn new(fs: &'a F, fds: &'a SharedFdTable, memory: &'a M) -> Self {
+        Syscalls { fs, fds, memory }
+    }
+
+    /// Run the operation number `op` with the raw arguments `args`; unused
+    /// arguments are ignored.
+    pub fn dispatch(&self, op: u64, args: [u64; 4]) -> Result<u64, Errno> {
+        let op = SyscallOp::from_number(op).ok_or(EINVAL)?;
+        let [a, b, c, d] = args;
+        match op {
+            SyscallOp::Open => self.open(a, b, c),
+            SyscallOp::Close => {
+                self.fds.deallocate(a)?;
+                Ok(0)
+            }
+            SyscallOp::Read => self.read(a, b, c, None),
+            SyscallOp::Write => self.write(a, b, c, None),
+            SyscallOp::ReadAt => self.read(a, b, c, Some(d as Offset)),
+            SyscallOp::WriteAt => self.write(a, b, c, Some(d as Offset)),
+            SyscallOp::GetInfo => self.getinfo(a, b),
+            SyscallOp::Delete => {
+                self.fs.delete(&self.filename(a)?)?;
+                Ok(0)
+            }
+            SyscallOp::MkDir => {
+                self.fs.mkdir(&self.filename(a)?, b)?;
+                Ok(0)
+            }
+            SyscallOp::RmDir => {
+                self.fs.rmdir(&self.filename(a)?)?;
+                Ok(0)
+            }
+            SyscallOp::Rename => {
+                self.fs.rename(&self.filename(a)?, &self.filename(b)?)?;
+                Ok(0)
+            }
+            SyscallOp::Truncate => {
+                self.fs.truncate(&self.filename(a)?)?;
+                Ok(0)
+            }
+            SyscallOp::Fsync => {
+                self.fs.fsync(self.fds.get(a)?.get_mnode())?;
+                Ok(0)
+            }
+            SyscallOp::Fdatasync => {
+                self.fs.fdatasync(self.fds.get(a)?.get_mnode())?;
+                Ok(0)
+            }
+        }
+    }
+
+    /// The path at the user address `filename`.
+    fn filename(&self, filename: Filename) -> Result<String, Errno> {
+        let path = self.memory.read_str(filename, MAX_PATH_LEN)?;
+        String::from_utf8(path).map_err(|_| EINVAL)
+    }
+
+    fn open(&self, filename: Filename, flags: u64, modes: u64) -> Result<u64, Errno> {
+        let path = self.filename(filename)?;
+        let flags = FileFlags::from_bits(flags).ok_or(EINVAL)?;
+        let mnode = match self.fs.lookup(&path) {
+            Some(mnode) => *mnode,
+            None if flags.is_create() && !flags.is_path() => match self.fs.create(&path, modes) {
+                // Another thread created the file since the lookup.
+                Err(FileSystemError::AlreadyPresent) => {
+                    *self.fs.lookup(&path).ok_or(FileSystemError::InvalidFile)?
+                }
+                created => created?,
+            },
+            None => return Err(FileSystemError::InvalidFile.into()),
+        };
+        self.fs.may_open(&path, mnode, flags)?;
+        // O_PATH ignores the access mode and the creation flags.
+        if !flags.is_path() {
+            let info = self.fs.file_info(mnode)?;
+            if info.ftype == NodeType::Directory.into() && flags.is_write() {
+                return Err(FileSystemError::IsADirectory.into());
+            }
+            if flags.is_truncate() && flags.is_write() {
+                self.fs.truncate(&path)?;
+            }
+        }
+        Ok(self.fds.allocate(mnode, flags)?)
+    }
+
+    fn read(&self, fd: FD, buffer: Buffer, len: Len, at: Option<Offset>) -> Result<u64, Errno> {
+        let file = self.fds.get(fd)?;
+        if !file.get_flags().is_read() {
+            return Err(FileSystemError::InvalidFileDescriptor.into());
+        }
+        let buffer = UserSlice::new(self.memory, buffer, len);
+        // Reads at or past the end of the file read nothing, as in POSIX.
+        let read_at = |offset| match self.fs.read_user(file.get_mnode(), &buffer, offset) {
+            Err(FileSystemError::InvalidOffset) => Ok(0),
+            read => read,
+        };
+        if let Some(at) = at {
+            let offset = usize::try_from(at).map_err(|_| EINVAL)?;
+            return Ok(read_at(offset)? as u64);
+        }
+        let _offset = file.lock_offset();
+        let offset = file.get_offset();
+        let read = read_at(offset)?;
+        file.update_offset(offset + read);
+        Ok(read as u64)
+    }
+
+    fn write(&self, fd: FD, buffer: Buffer, len: Len, at: Option<Offset>) -> Result<u64, Errno> {
+        let file = self.fds.get(fd)?;
+        let flags = file.get_flags();
+        if !flags.is_write() {
+            return Err(FileSystemError::InvalidFileDescriptor.into());
+        }
+        let buffer = UserSlice::new(self.memory, buffer, len);
+        if let Some(at) = at {
+            let offset = usize::try_from(at).map_err(|_| EINVAL)?;
+            return Ok(self.fs.write_user(file.get_mnode(), &buffer, offset)? as u64);
+        }
+        let _offset = file.lock_offset();
+        // Appends find the end of the file under its lock, so they don't
+        // overwrite each other.
+        let (end, written) = match flags.is_append() {
+            true => self.fs.append_user(file.get_mnode(), &buffer)?,
+            false => {
+                let offset = file.get_offset();
+                let written = self.fs.write_user(file.get_mnode(), &buffer, offset)?;
+                (offset + written, written)
+            }
+        };
+        file.update_offset(end);
+        Ok(written as u64)
+    }
+
+    fn getinfo(&self, filename: Filename, buffer: Buffer) -> Result<u64, Errno> {
+        let path = self.filename(filename)?;
+        let mnode = *self.fs.lookup(&path).ok_or(FileSystemError::InvalidFile)?;
+        let info = self.fs.file_info(mnode)?;
+        let mut raw = [0; 16];
+        raw[..8].copy_from_slice(&info.ftype.to_ne_bytes());
+        raw[8..].copy_from_slice(&info.fsize.to_ne_bytes());
+        self.memory.write_bytes(buffer, &raw)?;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::errno::{EBADF, EFAULT, EISDIR, ENOENT};
+    use crate::fd::FdTable;
+    use crate::user::USER_COPY_CHUNK;
+    use crate::{FileModes, FileSystemRead, MemFS};
+    use alloc::vec::Vec;
+    use spin::Mutex;
+
+    /// User memory at the addresses 0x1000 to 0x2000.
+    struct TestMemory {
+        bytes: Mutex<Vec<u8>>,
+    }
+
+    const BASE: u64 = 0x1000;
+
+    impl TestMemory {
+        fn new() -> TestMemory {
+            TestMemory {
+                bytes: Mutex::new(alloc::vec![0; 0x1000]),
+            }
+        }
+
+        fn range(&self, addr: u64, len: usize) -> Result<core::ops::Range<usize>, Errno> {
+            let start = addr.checked_sub(BASE).ok_or(EFAULT)? as usize;
+            match start + len <= self.bytes.lock().len() {
+                true => Ok(start..start + len),
+                false => Err(EFAULT),
+            }
+        }
+    }
+
+    impl UserMemory for TestMemory {
+        fn read_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<(), Errno> {
+            let range = self.range(addr, buf.len())?;
+            buf.copy_from_slice(&self.bytes.lock()[range]);
+            Ok(())
+        }
+
+        fn write_bytes(&self, addr: u64, data: &[u8]) -> Result<(), Errno> {
+            let range = self.range(addr, data.len())?;
+            self.bytes.lock()[range].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    #[test]
+    /// Calls with raw arguments reach the file-system, and fail with the
+    /// errno of the error.
+    fn test_dispatch() {
+        let fs = MemFS::default();
+        let fds = SharedFdTable::new(FdTable::default(), 1);
+        let memory = TestMemory::new();
+        let calls = Syscalls::new(&fs, &fds, &memory);
+        memory.write_bytes(BASE, b"/dir\0/dir/nrfs\0").unwrap();
+        memory.write_bytes(BASE + 0x100, b"hello").unwrap();
+        let (dir, file, data, out) = (BASE, BASE + 5, BASE + 0x100, BASE + 0x200);
+        let modes = u64::from(FileModes::S_IRWXU);
+        let rdwr = u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT);
+
+        assert_eq!(
+            calls.dispatch(SyscallOp::MkDir as u64, [dir, modes, 0, 0]),
+            Ok(0)
+        );
+        assert_eq!(
+            calls.dispatch(SyscallOp::Open as u64, [file, rdwr, modes, 0]),
+            Ok(0)
+        );
+        assert_eq!(
+            calls.dispatch(SyscallOp::Write as u64, [0, data, 5, 0]),
+            Ok(5)
+        );
+        assert_eq!(
+            calls.dispatch(SyscallOp::Write as u64, [0, data, 3, 0]),
+            Ok(3)
+        );
+        assert_eq!(
+            calls.dispatch(SyscallOp::ReadAt as u64, [0, out, 100, 3]),
+            Ok(5)
+        );
+        let mut read = [0; 5];
+        memory.read_bytes(out, &mut read).unwrap();
+        assert_eq!(&read, b"lohel");
+
+        assert_eq!(
+            calls.dispatch(SyscallOp::GetInfo as u64, [file, out, 0, 0]),
+            Ok(0)
+        );
+        let mut info = [0; 8];
+        memory.read_bytes(out + 8, &mut info).unwrap();
+        assert_eq!(u64::from_ne_bytes(info), 8);
+
+        let call = |op: SyscallOp, args| to_return(calls.dispatch(op as u64, args));
+        assert_eq!(call(SyscallOp::Read, [0, out, 0x1000, 0]), 0);
+        let end = BASE + 0x1000;
+        assert_eq!(call(SyscallOp::ReadAt, [0, end, 8, 0]), -(EFAULT as i64));
+        assert_eq!(
+            call(SyscallOp::ReadAt, [0, end - 2, 8, 0]),
+            -(EFAULT as i64)
+        );
+        // A copy faulting after the first chunk returns that chunk.
+        let chunk = USER_COPY_CHUNK as u64;
+        assert_eq!(
+            call(SyscallOp::WriteAt, [0, end - chunk - 1, 2 * chunk, 0]),
+            chunk as i64
+        );
+        assert_eq!(
+            fs.file_info(fds.get(0).unwrap().get_mnode()).unwrap().fsize,
+            chunk
+        );
+        assert_eq!(call(SyscallOp::Read, [7, out, 1, 0]), -(EBADF as i64));
+        assert_eq!(
+            call(SyscallOp::Open, [dir, rdwr, modes, 0]),
+            -(EISDIR as i64)
+        );
+        assert_eq!(call(SyscallOp::Open, [0x10, 0, 0, 0]), -(EFAULT as i64));
+        assert_eq!(call(SyscallOp::Fsync, [0, 0, 0, 0]), 0);
+        assert_eq!(call(SyscallOp::Close, [0, 0, 0, 0]), 0);
+        assert_eq!(call(SyscallOp::Delete, [file, 0, 0, 0]), 0);
+        assert_eq!(call(SyscallOp::Open, [file, 1, 0, 0]), -(ENOENT as i64));
+        assert_eq!(to_return(calls.dispatch(99, [0; 4])), -(EINVAL as i64));
+    }
+
+    #[test]
+    /// Threads sharing an open file write at offsets of their own, appends
+    /// through different open files don't overwrite each other, and opens
+    /// racing to create a file all open it.
+    fn test_shared_offsets() {
+        let fs = MemFS::default();
+        let fds = SharedFdTable::new(FdTable::default(), 1);
+        let memory = TestMemory::new();
+        let calls = Syscalls::new(&fs, &fds, &memory);
+        memory.write_bytes(BASE, b"/shared\0/log\0").unwrap();
+        for thread in 0..4 {
+            memory
+                .write_bytes(BASE + 0x100 * (thread + 1), &[thread as u8 + 1; 16])
+                .unwrap();
+        }
+        let modes = u64::from(FileModes::S_IRWXU);
+        let create = u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT);
+        let append = u64::from(FileFlags::O_WRONLY | FileFlags::O_CREAT | FileFlags::O_APPEND);
+        assert_eq!(
+            calls.dispatch(SyscallOp::Open as u64, [BASE, create, modes, 0]),
+            Ok(0)
+        );
+
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let calls = &calls;
+                scope.spawn(move || {
+                    let data = BASE + 0x100 * (thread + 1);
+                    let log = calls.dispatch(SyscallOp::Open as u64, [BASE + 8, append, modes, 0]);
+                    let log = log.unwrap();
+                    for _ in 0..100 {
+                        let write = [0, data, 16, 0];
+                        assert_eq!(calls.dispatch(SyscallOp::Write as u64, write), Ok(16));
+                        let write = [log, data, 16, 0];
+                        assert_eq!(calls.dispatch(SyscallOp::Write as u64, write), Ok(16));
+                    }
+                });
+            }
+        });
+
+        for path in ["/shared", "/log"] {
+            let mnode = *fs.lookup(path).unwrap();
+            let mut content = alloc::vec![0; 4 * 100 * 16 + 1];
+            assert_eq!(fs.read(mnode, &mut content, 0), Ok(4 * 100 * 16));
+            for record in content[..4 * 100 * 16].chunks(16) {
+                assert!(record[0] > 0 && record.iter().all(|byte| *byte == record[0]));
+            }
+        }
+        assert_eq!(fds.get(0).unwrap().get_offset(), 4 * 100 * 16);
+    }
+}
@@ -0,0 +1,302 @@
+//! The mnode table, indexed by mnode number.
+//!
+//! Mnode numbers are handed out in order, so the table is an array of chunks
+//! of `CHUNK` slots, one slot per mnode number. Lookups go through the RCU
+//! like before, but creating or removing an mnode only fills or clears its
+//! slot instead of copying the table: a new mnode is visible as soon as its
+//! slot is set, and a removed one is freed once the readers that might still
+//! see it are gone. Only the array of chunks is copied, when a chunk is
+//! added or all the mnodes of one were removed, so a create costs no more
+//! than a slot store most of the time.
+//!
+//! The array keeps an empty entry for every chunk that was dropped below
+//! the last one in use, a pointer per `CHUNK` mnodes ever created.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use lock_api::RawRwLock;
+
+use crate::lru::MnodeRef;
+use crate::rcu::{Rcu, RcuGuard};
+use crate::{FileSystemError, Mnode};
+
+/// Number of mnode numbers per chunk.
+const CHUNK: usize = 256;
+
+/// The slots of `CHUNK` consecutive mnode numbers.
+struct Chunk<L: RawRwLock> {
+    slots: Vec<AtomicPtr<MnodeRef<L>>>,
+    /// Mnodes in the chunk.
+    used: AtomicUsize,
+    /// Mnodes ever inserted into the chunk; once all of its numbers were
+    /// used, the chunk is dropped when it is empty.
+    inserted: AtomicUsize,
+}
+
+impl<L: RawRwLock> Chunk<L> {
+    fn new() -> Result<Arc<Chunk<L>>, FileSystemError> {
+        let mut slots = Vec::new();
+        slots
+            .try_reserve_exact(CHUNK)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        slots.resize_with(CHUNK, || AtomicPtr::new(ptr::null_mut()));
+        Arc::try_new(Chunk {
+            slots,
+            used: AtomicUsize::new(0),
+            inserted: AtomicUsize::new(0),
+        })
+        .map_err(|_| FileSystemError::OutOfMemory)
+    }
+}
+
+impl<L: RawRwLock> Drop for Chunk<L> {
+    fn drop(&mut self) {
+        for slot in &mut self.slots {
+            let memnode = *slot.get_mut();
+            if !memnode.is_null() {
+                drop(unsafe { Box::from_raw(memnode) });
+            }
+        }
+    }
+}
+
+/// A version of the array of chunks, as seen by the readers.
+pub(crate) struct MnodeMap<L: RawRwLock> {
+    chunks: Vec<Option<Arc<Chunk<L>>>>,
+}
+
+impl<L: RawRwLock> MnodeMap<L> {
+    /// The chunk holding the slot of `mnode`, and the index of the slot.
+    fn chunk(&self, mnode: Mnode) -> Option<(&Chunk<L>, usize)> {
+        let mnode = usize::try_from(mnode).ok()?;
+        let chunk = self.chunks.get(mnode / CHUNK)?.as_ref()?;
+        Some((chunk, mnode % CHUNK))
+    }
+
+    /// Get the mnode `mnode`.
+    pub(crate) fn get(&self, mnode: Mnode) -> Option<&MnodeRef<L>> {
+        let (chunk, slot) = self.chunk(mnode)?;
+        unsafe { chunk.slots[slot].load(Ordering::Acquire).as_ref() }
+    }
+
+    /// All the mnodes, in the order of their numbers.
+    pub(crate) fn values(&self) -> impl Iterator<Item = &MnodeRef<L>> {
+        self.chunks
+            .iter()
+            .flatten()
+            .flat_map(|chunk| chunk.slots.iter())
+            .filter_map(|slot| unsafe { slot.load(Ordering::Acquire).as_ref() })
+    }
+
+    /// Number of mnodes the chunks have slots for.
+    #[cfg(test)]
+    pub(crate) fn capacity(&self) -> usize {
+        self.chunks.iter().flatten().count() * CHUNK
+    }
+
+    /// Bytes allocated for the array, the chunks and the slots in use.
+    pub(crate) fn allocated_size(&self) -> usize {
+        let chunks = self.chunks.iter().flatten();
+        self.chunks.capacity() * size_of::<Option<Arc<Chunk<L>>>>()
+            + chunks
+                .map(|chunk| {
+                    size_of::<Chunk<L>>()
+                        + CHUNK * size_of::<AtomicPtr<MnodeRef<L>>>()
+                        + chunk.used.load(Ordering::Relaxed) * size_of::<MnodeRef<L>>()
+                })
+                .sum::<usize>()
+    }
+
+    /// Copy the array with room for `len` chunks, keeping the chunks
+    /// `keep` accepts by index.
+    fn copy(
+        &self,
+        len: usize,
+        keep: impl Fn(usize, &Chunk<L>) -> bool,
+    ) -> Result<MnodeMap<L>, FileSystemError> {
+        let mut chunks = Vec::new();
+        chunks
+            .try_reserve_exact(len)
+            .map_err(|_| FileSystemError::OutOfMemory)?;
+        chunks.extend(
+            self.chunks
+                .iter()
+                .take(len)
+                .enumerate()
+                .map(|(index, chunk)| chunk.as_ref().filter(|chunk| keep(index, chunk)).cloned()),
+        );
+        chunks.resize_with(len, || None);
+        while let Some(None) = chunks.last() {
+            chunks.pop();
+        }
+        Ok(MnodeMap { chunks })
+    }
+}
+
+pub(crate) struct MnodeTable<L: RawRwLock> {
+    map: Rcu<MnodeMap<L>>,
+}
+
+impl<L: RawRwLock> MnodeTable<L> {
+    /// A table with slots for the mnode numbers up to `capacity`, read with
+    /// reader ids up to `readers`, see `Rcu::read()`.
+    pub(crate) fn new(capacity: usize, readers: usize) -> MnodeTable<L> {
+        let chunks = (0..capacity / CHUNK + 1)
+            .map(|_| Some(Chunk::new().unwrap()))
+            .collect();
+        MnodeTable {
+            map: Rcu::new(MnodeMap { chunks }, readers),
+        }
+    }
+
+    /// Number of reader slots, see `Rcu::readers()`.
+    pub(crate) fn readers(&self) -> usize {
+        self.map.readers()
+    }
+
+    /// Get the current version of the table, see `Rcu::read()`.
+    pub(crate) fn read(&self, tid: usize) -> RcuGuard<'_, MnodeMap<L>> {
+        self.map.read(tid)
+    }
+
+    /// Add the mnode `mnode`, whose number wasn't used before. Readers see
+    /// it right away.
+    pub(crate) fn insert(&self, mnode: Mnode, memnode: MnodeRef<L>) -> Result<(), FileSystemError> {
+        let memnode =
+            Box::into_raw(Box::try_new(memnode).map_err(|_| FileSystemError::OutOfMemory)?);
+        loop {
+            let inserted = self.map.publish(|map| match map.chunk(mnode) {
+                Some((chunk, slot)) => {
+                    chunk.slots[slot].store(memnode, Ordering::Release);
+                    chunk.used.fetch_add(1, Ordering::Relaxed);
+                    chunk.inserted.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                None => false,
+            });
+            if inserted {
+                return Ok(());
+            }
+            // Another thread might have added the chunk in the meantime.
+            let added = self.map.update(|map| match map.chunk(mnode) {
+                Some(_) => Err(None),
+                None => {
+                    let index = usize::try_from(mnode).map_err(|_| FileSystemError::NoSpace)?;
+                    let len = core::cmp::max(index / CHUNK + 1, map.chunks.len());
+                    let mut copy = map.copy(len, |_, _| true)?;
+                    copy.chunks.resize_with(len, || None);
+                    copy.chunks[index / CHUNK] = Some(Chunk::new()?);
+                    Ok(copy)
+                }
+            });
+            if let Err(Some(e)) = added {
+                drop(unsafe { Box::from_raw(memnode) });
+                return Err(e);
+            }
+        }
+    }
+
+    /// Remove the mnode `mnode`. Returns once no reader can see it in the
+    /// table anymore.
+    pub(crate) fn remove(&self, mnode: Mnode) -> Option<MnodeRef<L>> {
+        let (memnode, empty) = self.map.retire(|map| {
+            let (chunk, slot) = map.chunk(mnode)?;
+            let memnode = chunk.slots[slot].swap(ptr::null_mut(), Ordering::Relaxed);
+            if memnode.is_null() {
+                return None;
+            }
+            let used = chunk.used.fetch_sub(1, Ordering::Relaxed) - 1;
+            Some((
+                memnode,
+                used == 0 && chunk.inserted.load(Ordering::Relaxed) == CHUNK,
+            ))
+        })?;
+        if empty {
+            // If the array can't be copied, the chunk stays until `compact()`.
+            let index = mnode as usize / CHUNK;
+            let _ = self
+                .map
+                .update(|map| map.copy(map.chunks.len(), |i, _| i != index));
+        }
+        Some(*unsafe { Box::from_raw(memnode) })
+    }
+
+    /// Drop the empty chunks and the spare room of the array. Returns the
+    /// number of bytes freed.
+    pub(crate) fn compact(&self) -> usize {
+        let mut freed = 0;
+        let _ = self.map.update(|map| {
+            let copy = map
+                .copy(map.chunks.len(), |_, chunk| {
+                    chunk.used.load(Ordering::Relaxed) > 0
+                })
+                .map_err(|_| ())?;
+            freed = map.allocated_size().saturating_sub(copy.allocated_size());
+            match freed {
+                0 => Err(()),
+                _ => Ok(copy),
+            }
+        });
+        freed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mnode::{MemNode, MnodeCell, NodeType};
+    use crate::slab::MnodeSlab;
+    use crate::Name;
+
+    fn memnode(mnode: Mnode) -> MnodeRef {
+        let memnode = MemNode::new(mnode, Name::new("f"), 0, NodeType::File).unwrap();
+        Arc::new_in(MnodeCell::new(memnode), MnodeSlab::new())
+    }
+
+    #[test]
+    /// Mnodes are found by number, chunks are added for new numbers and
+    /// dropped once all their mnodes were removed.
+    fn test_table() {
+        let table = MnodeTable::new(0, 2);
+        assert_eq!(table.read(0).capacity(), CHUNK);
+        let first = memnode(CHUNK as Mnode);
+        table.insert(CHUNK as Mnode, first.clone()).unwrap();
+        for mnode in (1..3 * CHUNK as Mnode).filter(|mnode| *mnode != CHUNK as Mnode) {
+            table.insert(mnode, memnode(mnode)).unwrap();
+        }
+        let map = table.read(0);
+        assert_eq!(map.capacity(), 3 * CHUNK);
+        assert_eq!(map.values().count(), 3 * CHUNK - 1);
+        assert!(Arc::ptr_eq(map.get(CHUNK as Mnode).unwrap(), &first));
+        assert!(map.get(0).is_none());
+        assert!(map.get(5 * CHUNK as Mnode).is_none());
+        drop(map);
+
+        // Chunks stay while some of their numbers weren't used.
+        assert!(table.remove(1).is_some());
+        assert!(table.remove(1).is_none());
+        assert!(table.read(0).get(1).is_none());
+        for mnode in 2..CHUNK as Mnode {
+            table.remove(mnode).unwrap();
+        }
+        assert_eq!(table.read(0).capacity(), 3 * CHUNK);
+        for mnode in CHUNK as Mnode..2 * CHUNK as Mnode {
+            table.remove(mnode).unwrap();
+        }
+        assert_eq!(table.read(1).capacity(), 2 * CHUNK);
+        assert!(table.read(1).get(2 * CHUNK as Mnode).is_some());
+
+        // Compacting drops the empty chunks, and inserting adds them again.
+        assert!(table.compact() > 0);
+        assert_eq!(table.read(0).capacity(), CHUNK);
+        assert_eq!(table.compact(), 0);
+        table.insert(1, memnode(1)).unwrap();
+        assert_eq!(table.read(0).capacity(), 2 * CHUNK);
+        assert!(table.read(7).get(1).is_some());
+    }
+}
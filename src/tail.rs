@@ -0,0 +1,210 @@
+//! Packing of the tails of small files into shared pages.
+//!
+//! A file a little too large to keep its data inline still takes a whole page
+//! for its last, partial one, e.g. most of a page for each ~1 KiB file of a
+//! source tree. `MemFS::pack_tails()` copies those tails one after the other
+//! into pages shared by several files, and frees the pages they had. A packed
+//! tail is refcounted like a page shared by `dedup()`: it gets its own page
+//! again when it is written, and the shared page is freed when the last tail
+//! in it lets it go.
+
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+use core::mem::size_of;
+use lock_api::RawRwLock;
+use spin::Mutex;
+
+use crate::file::{Pages, SharedPage};
+use crate::lru::MnodeRef;
+use crate::{MemFS, BASE_PAGE_SIZE};
+
+/// Tails of at most this many bytes are packed.
+pub(crate) const MAX_TAIL: usize = BASE_PAGE_SIZE / 2;
+
+/// The pages holding the packed tails of a MemFS.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct TailStats {
+    /// Pages holding packed tails.
+    pub pages: usize,
+    /// Tails packed in them.
+    pub tails: usize,
+    /// Bytes used in the pages.
+    pub bytes: usize,
+}
+
+/// The pages the tails were packed in, as weak references.
+pub(crate) struct TailPages {
+    pages: Mutex<Vec<Weak<Vec<u8, Pages>>>>,
+}
+
+impl TailPages {
+    pub(crate) fn new() -> TailPages {
+        TailPages {
+            pages: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Record a page tails were packed in; a page that can't be recorded for
+    /// lack of memory is only missing from the stats.
+    fn record(&self, page: &SharedPage) {
+        let mut pages = self.pages.lock();
+        pages.retain(|page| page.strong_count() > 0);
+        if pages.try_reserve(1).is_ok() {
+            pages.push(Arc::downgrade(page));
+        }
+    }
+
+    /// Forget the pages nobody uses anymore and free the spare capacity of
+    /// the list. Returns the number of bytes freed.
+    pub(crate) fn compact(&self) -> usize {
+        let mut pages = self.pages.lock();
+        pages.retain(|page| page.strong_count() > 0);
+        let capacity = pages.capacity();
+        pages.shrink_to_fit();
+        (capacity - pages.capacity()) * size_of::<Weak<Vec<u8, Pages>>>()
+    }
+
+    /// Bytes of heap memory held by the list, not counting the pages.
+    pub(crate) fn allocated_size(&self) -> usize {
+        self.pages.lock().capacity() * size_of::<Weak<Vec<u8, Pages>>>()
+    }
+
+    /// Count the pages and the tails in them, forgetting the pages nobody
+    /// uses anymore.
+    fn stats(&self) -> TailStats {
+        let mut stats = TailStats::default();
+        let mut pages = self.pages.lock();
+        pages.retain(|page| page.strong_count() > 0);
+        for page in pages.iter() {
+            stats.pages += 1;
+            stats.tails += page.strong_count();
+            stats.bytes += page.upgrade().map_or(0, |page| page.len());
+        }
+        stats
+    }
+}
+
+impl<S: BuildHasher + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
+    /// Pack the tails of the small files into shared pages, and return what
+    /// is packed now. Only resident tails of at most `MAX_TAIL` bytes of
+    /// files in base pages that aren't pinned take part. A tail stays packed
+    /// until it is written, so the scan can be repeated, e.g. when memory
+    /// runs low.
+    pub fn pack_tails(&self) -> TailStats {
+        let mut page = Vec::new_in(self.chunks().pages);
+        let mut packed = Vec::new();
+        for memnode in self.mnodes.read(self.reader_tid()).values() {
+            let mut locked = memnode.read();
+            let len = match locked.tail() {
+                Some(tail) => tail.len(),
+                None => continue,
+            };
+            if page.len() + len > BASE_PAGE_SIZE {
+                // Files are locked one at a time.
+                drop(locked);
+                let full = core::mem::replace(&mut page, Vec::new_in(self.chunks().pages));
+                self.publish_tails(full, &mut packed);
+                locked = memnode.read();
+            }
+            let tail = match locked.tail() {
+                Some(tail) if page.len() + tail.len() <= BASE_PAGE_SIZE => tail,
+                _ => continue,
+            };
+            if (page.capacity() == 0 && page.try_reserve_exact(BASE_PAGE_SIZE).is_err())
+                || packed.try_reserve(1).is_err()
+            {
+                break;
+            }
+            packed.push((Arc::clone(memnode), page.len(), tail.len()));
+            page.extend_from_slice(tail);
+        }
+        self.publish_tails(page, &mut packed);
+        self.tail_stats()
+    }
+
+    /// Share `page` with the files `packed` copied their tails to, the
+    /// offset and the length of each tail in the page with it. A page
+    /// holding a single tail would save nothing, so it is dropped.
+    fn publish_tails(&self, page: Vec<u8, Pages>, packed: &mut Vec<(MnodeRef<L>, usize, usize)>) {
+        if packed.len() < 2 {
+            packed.clear();
+            return;
+        }
+        let page = match Arc::try_new(page) {
+            Ok(page) => page,
+            Err(_) => {
+                packed.clear();
+                return;
+            }
+        };
+        for (memnode, offset, len) in packed.drain(..) {
+            // A file whose tail changed in the meantime keeps its own.
+            memnode.write().pack_tail(&page, offset, len);
+        }
+        if Arc::strong_count(&page) > 1 {
+            self.tails.record(&page);
+        }
+    }
+
+    /// The tails packed by `pack_tails()` now.
+    pub fn tail_stats(&self) -> TailStats {
+        self.tails.stats()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FileModes, FileSystem, FileSystemRead};
+    use alloc::format;
+
+    #[test]
+    /// The tails of small files share pages until they are written.
+    fn test_pack_tails() {
+        let memfs = MemFS::default();
+        let rwx = FileModes::S_IRWXU.into();
+        let mut files = Vec::new();
+        for n in 0..6 {
+            let mnode = memfs.create(&format!("/{}", n), rwx).unwrap();
+            assert_eq!(memfs.write(mnode, &[n as u8; 1000], 0), Ok(1000));
+            files.push(mnode);
+        }
+        let big = memfs.create("/big", rwx).unwrap();
+        assert_eq!(memfs.write(big, &[9; 3000], 0), Ok(3000));
+        let before = memfs.memory_breakdown().file_slack;
+
+        let stats = memfs.pack_tails();
+        assert_eq!(
+            stats,
+            TailStats {
+                pages: 2,
+                tails: 6,
+                bytes: 6000
+            }
+        );
+        assert_eq!(memfs.pack_tails(), stats);
+        assert_eq!(memfs.memory_breakdown().file_slack, before - 6 * 3096);
+        let mut data = [0; 1000];
+        for (n, mnode) in files.iter().enumerate() {
+            assert_eq!(memfs.read(*mnode, &mut data, 0), Ok(1000));
+            assert_eq!(data, [n as u8; 1000]);
+        }
+
+        // Written tails get their own page again, grown or not.
+        assert_eq!(memfs.write(files[0], &[7; 1], 10), Ok(1));
+        assert_eq!(memfs.write(files[1], &[7; 1], 5000), Ok(1));
+        assert_eq!(memfs.read(files[0], &mut data, 0), Ok(1000));
+        assert_eq!((data[9], data[10], data[11]), (0, 7, 0));
+        assert_eq!(memfs.read(files[1], &mut data, 0), Ok(1000));
+        assert_eq!(data, [1; 1000]);
+        assert_eq!(memfs.read(files[1], &mut data, 4001), Ok(1000));
+        assert_eq!(data[999], 7);
+        assert_eq!(memfs.tail_stats().tails, 4);
+
+        for n in 2..6 {
+            assert_eq!(memfs.delete(&format!("/{}", n)), Ok(true));
+        }
+        assert_eq!(memfs.tail_stats(), TailStats::default());
+    }
+}
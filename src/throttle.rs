@@ -0,0 +1,161 @@
+//! Per-process IO throttling.
+//!
+//! Every process, told apart by the `pid` of its `Credentials`, gets a token
+//! bucket for bytes and one for operations, refilled at the `Rate` set with
+//! `MemFSBuilder::throttle()`. A call that finds a bucket empty fails with
+//! `WouldBlock` before it takes any lock, so one process can't monopolize the
+//! bandwidth and the locks of the file-system; the `poll` futures retry such
+//! calls, other callers can sleep and retry them.
+
+use hashbrown::hash_map::DefaultHashBuilder;
+use hashbrown::HashMap;
+use spin::Mutex;
+
+use crate::FileSystemError;
+
+/// How much a process may do per `period`, in the unit of the `Clock` hook.
+/// A process that was idle can do a whole period's worth at once.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Rate {
+    /// Bytes read or written.
+    pub bytes: u64,
+    /// Reads, writes and changes of the namespace.
+    pub ops: u64,
+    pub period: u64,
+}
+
+/// The tokens of a process. The bytes can go negative: a call is let through
+/// while there are any left, and a large one then waits for its debt to be
+/// refilled before the next, so no call is too large to ever get through.
+#[derive(Debug, Copy, Clone)]
+struct Bucket {
+    bytes: i128,
+    ops: u64,
+    refilled: u64,
+}
+
+pub(crate) struct Throttle {
+    rate: Rate,
+    buckets: Mutex<HashMap<u64, Bucket, DefaultHashBuilder>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(rate: Rate) -> Throttle {
+        Throttle {
+            rate,
+            buckets: Mutex::new(HashMap::default()),
+        }
+    }
+
+    /// Take an operation and `bytes` from the buckets of `pid` at `now`, or
+    /// fail with `WouldBlock` if they are empty.
+    pub(crate) fn charge(&self, pid: u64, now: u64, bytes: usize) -> Result<(), FileSystemError> {
+        let rate = self.rate;
+        let mut buckets = self.buckets.lock();
+        if !buckets.contains_key(&pid) {
+            buckets
+                .try_reserve(1)
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+        }
+        let bucket = buckets.entry(pid).or_insert(Bucket {
+            bytes: rate.bytes.into(),
+            ops: rate.ops,
+            refilled: now,
+        });
+
+        // Refill for the whole periods that passed, so no tokens are lost to
+        // rounding.
+        let periods = now.saturating_sub(bucket.refilled) / rate.period.max(1);
+        if periods > 0 {
+            let bytes = i128::from(rate.bytes) * i128::from(periods);
+            bucket.bytes = (bucket.bytes + bytes).min(rate.bytes.into());
+            bucket.ops = bucket
+                .ops
+                .saturating_add(rate.ops.saturating_mul(periods))
+                .min(rate.ops);
+            bucket.refilled += periods * rate.period.max(1);
+        }
+
+        if bucket.ops == 0 || (bytes > 0 && bucket.bytes <= 0) {
+            return Err(FileSystemError::WouldBlock);
+        }
+        bucket.ops -= 1;
+        bucket.bytes -= bytes as i128;
+        Ok(())
+    }
+
+    /// Drop the buckets of `pid`, e.g. once the process exited.
+    pub(crate) fn forget(&self, pid: u64) {
+        self.buckets.lock().remove(&pid);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::{Caller, Clock, Credentials};
+    use crate::{FileModes, FileSystem, MemFSBuilder};
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    /// A clock and a current process, both set by the test.
+    #[derive(Default)]
+    struct Process {
+        time: AtomicU64,
+        pid: AtomicU64,
+    }
+
+    impl Clock for Process {
+        fn now(&self) -> u64 {
+            self.time.load(Ordering::Relaxed)
+        }
+    }
+
+    impl Caller for Process {
+        fn credentials(&self) -> Credentials {
+            Credentials {
+                pid: self.pid.load(Ordering::Relaxed),
+                ..Credentials::default()
+            }
+        }
+    }
+
+    #[test]
+    /// Every process has its own buckets, refilled once per period.
+    fn test_throttle() {
+        let process = Arc::new(Process::default());
+        let memfs = MemFSBuilder::new()
+            .clock(process.clone())
+            .caller(process.clone())
+            .throttle(Rate {
+                bytes: 100,
+                ops: 4,
+                period: 10,
+            })
+            .build();
+        let blocked = Err(FileSystemError::WouldBlock);
+
+        let mnode = memfs.create("/file", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[0; 60], 0), Ok(60));
+        // The second write goes into debt, which the third has to wait for.
+        assert_eq!(memfs.write(mnode, &[0; 60], 60), Ok(60));
+        assert_eq!(memfs.write(mnode, &[0; 1], 120), blocked);
+
+        process.pid.store(1, Ordering::Relaxed);
+        assert_eq!(memfs.write(mnode, &[0; 100], 0), Ok(100));
+
+        process.pid.store(0, Ordering::Relaxed);
+        process.time.store(9, Ordering::Relaxed);
+        assert_eq!(memfs.write(mnode, &[0; 1], 120), blocked);
+        process.time.store(10, Ordering::Relaxed);
+        assert_eq!(memfs.write(mnode, &[0; 1], 120), Ok(1));
+        // Three more operations are left of the refilled four.
+        assert_eq!(memfs.rename("/file", "/a"), Ok(true));
+        assert_eq!(memfs.rename("/a", "/b"), Ok(true));
+        assert_eq!(memfs.rename("/b", "/c"), Ok(true));
+        assert_eq!(memfs.delete("/c"), Err(FileSystemError::WouldBlock));
+
+        memfs.forget_process(0);
+        assert_eq!(memfs.delete("/c"), Ok(true));
+    }
+}
@@ -1,16 +1,29 @@
 // Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-//! Allows to query information about the CPU topology.
-#![allow(unused)]
+//! Allows to query information about the CPU topology: the sockets, cores,
+//! hyperthreads and NUMA nodes of the machine, so the embedder can decide
+//! where to place threads and memory.
+//!
+//! With std the topology comes from hwloc. Without it, a kernel can build it
+//! from its ACPI tables (MADT and SRAT) with `from_cpus()`, and otherwise
+//! CPUID gives an estimate.
 
 use alloc::fmt::{Debug, Formatter, Result};
 use alloc::vec::Vec;
+#[cfg(all(feature = "std", not(feature = "single-threaded")))]
 use hwloc2::*;
+use spin::Lazy;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use x86::cpuid::{CpuId, TopologyType};
 
+/// NUMA node number, as used by the OS.
 pub type Node = u64;
+/// Logical index of a socket.
 pub type Socket = u64;
+/// Logical index of a core; the hyperthreads of a core share it.
 pub type Core = u64;
+/// CPU (hyperthread) number, as used by the OS.
 pub type Cpu = u64;
 pub type L1 = u64;
 pub type L2 = u64;
@@ -47,13 +60,106 @@ impl Debug for CpuInfo {
     }
 }
 
-#[derive(Debug)]
+/// The topology of the machine, probed on first use.
+static MACHINE: Lazy<MachineTopology> = Lazy::new(MachineTopology::new);
+
+/// The topology of the machine we run on, shared crate-wide so it is only
+/// probed once.
+pub fn machine() -> &'static MachineTopology {
+    &MACHINE
+}
+
+/// The CPUs of the machine.
+#[derive(Debug, Clone)]
 pub struct MachineTopology {
     data: Vec<CpuInfo>,
 }
 
+impl Default for MachineTopology {
+    fn default() -> MachineTopology {
+        MachineTopology::new()
+    }
+}
+
 impl MachineTopology {
+    /// Query the topology of the machine we run on, from hwloc with std and
+    /// from CPUID without. A `single-threaded` build doesn't probe and uses
+    /// `single_cpu()`.
     pub fn new() -> MachineTopology {
+        #[cfg(feature = "single-threaded")]
+        return MachineTopology::single_cpu();
+        #[cfg(all(feature = "std", not(feature = "single-threaded")))]
+        return MachineTopology::from_hwloc();
+        #[cfg(not(any(feature = "std", feature = "single-threaded")))]
+        return MachineTopology::from_cpuid();
+    }
+
+    /// A machine with a single CPU, on one socket without NUMA nodes.
+    pub fn single_cpu() -> MachineTopology {
+        MachineTopology::from_cpus(alloc::vec![CpuInfo {
+            node: None,
+            socket: 0,
+            core: 0,
+            cpu: 0,
+            l1: 0,
+            l2: 0,
+            l3: 0,
+        }])
+    }
+
+    /// Use the CPUs the embedder found, e.g. in the MADT and SRAT tables of
+    /// ACPI.
+    pub fn from_cpus(cpus: Vec<CpuInfo>) -> MachineTopology {
+        MachineTopology { data: cpus }
+    }
+
+    /// Estimate the topology with CPUID on the current CPU. CPUID only
+    /// describes the socket it runs on, so this assumes a single socket
+    /// without NUMA nodes, with CPUs numbered from zero.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn from_cpuid() -> MachineTopology {
+        let cpuid = CpuId::new();
+        let (mut threads, mut cpus) = (1, 0);
+        if let Some(levels) = cpuid.get_extended_topology_info() {
+            for level in levels {
+                match level.level_type() {
+                    TopologyType::SMT => threads = level.processors() as u64,
+                    TopologyType::Core => cpus = level.processors() as u64,
+                    _ => {}
+                }
+            }
+        }
+        if cpus == 0 {
+            cpus = cpuid
+                .get_feature_info()
+                .map_or(1, |info| info.max_logical_processor_ids() as u64);
+        }
+        let threads = threads.max(1);
+
+        MachineTopology::from_cpus(
+            (0..cpus.max(1))
+                .map(|cpu| CpuInfo {
+                    node: None,
+                    socket: 0,
+                    core: cpu / threads,
+                    cpu,
+                    l1: cpu / threads,
+                    l2: cpu / threads,
+                    l3: 0,
+                })
+                .collect(),
+        )
+    }
+
+    /// Other architectures have no CPUID, so this is a single CPU; kernels
+    /// with more should pass them to `from_cpus()`.
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    pub fn from_cpuid() -> MachineTopology {
+        MachineTopology::single_cpu()
+    }
+
+    #[cfg(all(feature = "std", not(feature = "single-threaded")))]
+    fn from_hwloc() -> MachineTopology {
         let mut data: Vec<CpuInfo> = Default::default();
 
         let topo = Topology::new().expect("Can't retrieve Topology");
@@ -127,14 +233,122 @@ impl MachineTopology {
         self.data.len()
     }
 
+    /// Return how many ids are needed to index every processing unit of all
+    /// the sockets by its number; at least one.
+    pub fn cpu_ids(&self) -> usize {
+        self.data
+            .iter()
+            .map(|t| t.cpu as usize + 1)
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// Return all the processing units of the system.
+    pub fn cpus(&self) -> Vec<&CpuInfo> {
+        self.data.iter().collect()
+    }
+
+    /// Return the processing unit `cpu`, if the system has it.
+    pub fn cpu(&self, cpu: Cpu) -> Option<&CpuInfo> {
+        self.data.iter().find(|t| t.cpu == cpu)
+    }
+
+    /// Return the sockets of the system, in order.
     pub fn sockets(&self) -> Vec<Socket> {
         let mut sockets: Vec<Cpu> = self.data.iter().map(|t| t.socket).collect();
-        sockets.sort();
+        sockets.sort_unstable();
         sockets.dedup();
         sockets
     }
 
+    /// Return the processing units of a socket.
     pub fn cpus_on_socket(&self, socket: Socket) -> Vec<&CpuInfo> {
         self.data.iter().filter(|t| t.socket == socket).collect()
     }
+
+    /// Return the cores of a socket, in order.
+    pub fn cores_on_socket(&self, socket: Socket) -> Vec<Core> {
+        let mut cores: Vec<Core> = self.cpus_on_socket(socket).iter().map(|t| t.core).collect();
+        cores.sort_unstable();
+        cores.dedup();
+        cores
+    }
+
+    /// Return the hyperthreads that share a core with `cpu`, including `cpu`
+    /// itself.
+    pub fn siblings(&self, cpu: Cpu) -> Vec<&CpuInfo> {
+        match self.cpu(cpu) {
+            Some(info) => self.data.iter().filter(|t| t.core == info.core).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Return the NUMA nodes of the system with their memory, in order; empty
+    /// if the system doesn't report any.
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.data.iter().filter_map(|t| t.node).collect();
+        nodes.sort_unstable();
+        nodes.dedup();
+        nodes
+    }
+
+    /// Return the processing units closest to the memory of `node`.
+    pub fn cpus_on_node(&self, node: Node) -> Vec<&CpuInfo> {
+        self.data
+            .iter()
+            .filter(|t| t.node.map(|n| n.node) == Some(node))
+            .collect()
+    }
+
+    /// Return the NUMA node whose memory is closest to `cpu`.
+    pub fn node_of(&self, cpu: Cpu) -> Option<NodeInfo> {
+        self.cpu(cpu).and_then(|t| t.node)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    /// Every CPU is found on its socket, its core and its node.
+    fn test_topology() {
+        let topology = MachineTopology::new();
+        assert!(core::ptr::eq(machine(), machine()));
+        assert_eq!(machine().cpu_ids(), topology.cpu_ids());
+        assert!(topology.cores() > 0);
+        for cpu in topology.cpus() {
+            assert_eq!(topology.cpu(cpu.cpu), Some(cpu));
+            assert!(topology.sockets().contains(&cpu.socket));
+            assert!(topology.cores_on_socket(cpu.socket).contains(&cpu.core));
+            assert!(topology.siblings(cpu.cpu).contains(&cpu));
+            assert_eq!(topology.node_of(cpu.cpu), cpu.node);
+            if let Some(node) = cpu.node {
+                assert!(topology.nodes().contains(&node));
+                assert!(topology.cpus_on_node(node.node).contains(&cpu));
+            }
+        }
+        let sockets = topology.sockets();
+        let cpus: usize = sockets
+            .iter()
+            .map(|s| topology.cpus_on_socket(*s).len())
+            .sum();
+        assert_eq!(cpus, topology.cores());
+    }
+
+    #[test]
+    /// CPUID gives at least one CPU on one socket, and a core per hyperthread
+    /// group.
+    fn test_cpuid_topology() {
+        let topology = MachineTopology::from_cpuid();
+        assert!(topology.cores() > 0);
+        assert_eq!(topology.sockets(), [0]);
+        assert_eq!(topology.cpu_ids(), topology.cores());
+        assert!(topology.nodes().is_empty());
+        let threads = topology.siblings(0).len();
+        assert_eq!(
+            topology.cores_on_socket(0).len() * threads,
+            topology.cores()
+        );
+    }
 }
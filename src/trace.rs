@@ -0,0 +1,314 @@
+//! Replay of recorded application IO traces.
+//!
+//! A trace is a text file with one operation per line:
+//!
+//! ```text
+//! # op     path        offset  len
+//! create   /db/log
+//! write    /db/log     0       4096
+//! read     /db/log     0       512
+//! truncate /db/log
+//! rename   /db/log     /db/log.1
+//! unlink   /db/log.1
+//! ```
+//!
+//! Fields are separated by whitespace and `#` starts a comment. The format is
+//! easy to produce from `strace -e trace=file,read,write,pread64,pwrite64`
+//! output by resolving the fds to paths and keeping track of file offsets.
+//! Replaying such traces drives MemFS with real application IO patterns.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use hashbrown::HashMap;
+
+use crate::error::{ErrorContext, Operation, ResultExt};
+use crate::{FileModes, FileSystem, FileSystemError, Mnode};
+
+/// A single traced operation.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TraceOp {
+    Create {
+        path: String,
+    },
+    Read {
+        path: String,
+        offset: usize,
+        len: usize,
+    },
+    Write {
+        path: String,
+        offset: usize,
+        len: usize,
+    },
+    Truncate {
+        path: String,
+    },
+    Unlink {
+        path: String,
+    },
+    Rename {
+        oldpath: String,
+        newpath: String,
+    },
+}
+
+/// Returned when a line of the trace can't be parsed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TraceParseError {
+    /// The (1-based) line number.
+    pub line: usize,
+}
+
+/// Parse a whole trace.
+pub fn parse(trace: &str) -> Result<Vec<TraceOp>, TraceParseError> {
+    let mut ops = Vec::new();
+    for (idx, line) in trace.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        ops.push(parse_op(&fields).ok_or(TraceParseError { line: idx + 1 })?);
+    }
+    Ok(ops)
+}
+
+fn parse_op(fields: &[&str]) -> Option<TraceOp> {
+    let op = match fields {
+        ["create", path] => TraceOp::Create {
+            path: path.to_string(),
+        },
+        ["read", path, offset, len] => TraceOp::Read {
+            path: path.to_string(),
+            offset: offset.parse().ok()?,
+            len: len.parse().ok()?,
+        },
+        ["write", path, offset, len] => TraceOp::Write {
+            path: path.to_string(),
+            offset: offset.parse().ok()?,
+            len: len.parse().ok()?,
+        },
+        ["truncate", path] => TraceOp::Truncate {
+            path: path.to_string(),
+        },
+        ["unlink", path] => TraceOp::Unlink {
+            path: path.to_string(),
+        },
+        ["rename", oldpath, newpath] => TraceOp::Rename {
+            oldpath: oldpath.to_string(),
+            newpath: newpath.to_string(),
+        },
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// What happened during a replay.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ReplayStats {
+    /// Operations that succeeded.
+    pub ops: u64,
+    /// Operations that returned an error; traces of real applications contain
+    /// failing calls too, so errors don't abort the replay.
+    pub errors: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// Replays traces against a file-system.
+///
+/// Writes to files that were never created in the trace (because they existed
+/// before tracing started) create the file on first use.
+pub struct Replayer<'a, F: FileSystem + ?Sized> {
+    fs: &'a F,
+    /// Cache of the path to mnode resolution.
+    mnodes: HashMap<String, Mnode>,
+    buffer: Vec<u8>,
+    /// Failed operations as (index in the trace, error).
+    errors: Vec<(usize, ErrorContext)>,
+}
+
+impl<'a, F: FileSystem + ?Sized> Replayer<'a, F> {
+    pub fn new(fs: &'a F) -> Replayer<'a, F> {
+        Replayer {
+            fs,
+            mnodes: HashMap::new(),
+            buffer: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Replay all the operations in order.
+    pub fn replay(&mut self, ops: &[TraceOp]) -> ReplayStats {
+        let mut stats = ReplayStats::default();
+        for (idx, op) in ops.iter().enumerate() {
+            match self.replay_op(op, &mut stats) {
+                Ok(()) => stats.ops += 1,
+                Err(err) => {
+                    stats.errors += 1;
+                    self.errors.push((idx, err));
+                }
+            }
+        }
+        stats
+    }
+
+    /// The operations that failed so far, with the index of the operation in
+    /// the replayed trace.
+    pub fn errors(&self) -> &[(usize, ErrorContext)] {
+        &self.errors
+    }
+
+    fn resolve(&mut self, path: &str, create: bool) -> Result<Mnode, ErrorContext> {
+        if let Some(mnode) = self.mnodes.get(path) {
+            return Ok(*mnode);
+        }
+        let mnode = match self.fs.lookup(path) {
+            Some(mnode) => *mnode,
+            None if create => self
+                .fs
+                .create(path, FileModes::S_IRWXU.into())
+                .context(Operation::Create)
+                .map_err(|e| e.path(path))?,
+            None => {
+                return Err(
+                    ErrorContext::new(FileSystemError::InvalidFile, Operation::Lookup).path(path),
+                )
+            }
+        };
+        self.mnodes.insert(path.to_string(), mnode);
+        Ok(mnode)
+    }
+
+    fn replay_op(&mut self, op: &TraceOp, stats: &mut ReplayStats) -> Result<(), ErrorContext> {
+        match op {
+            TraceOp::Create { path } => {
+                let mnode = self
+                    .fs
+                    .create(path, FileModes::S_IRWXU.into())
+                    .context(Operation::Create)
+                    .map_err(|e| e.path(path))?;
+                self.mnodes.insert(path.clone(), mnode);
+            }
+            TraceOp::Read { path, offset, len } => {
+                let mnode = self.resolve(path, false)?;
+                self.buffer.resize(*len, 0);
+                let read = self
+                    .fs
+                    .read(mnode, &mut self.buffer, *offset)
+                    .context(Operation::Read)
+                    .map_err(|e| e.path(path).mnode(mnode))?;
+                stats.bytes_read += read as u64;
+            }
+            TraceOp::Write { path, offset, len } => {
+                let mnode = self.resolve(path, true)?;
+                self.buffer.resize(*len, 0);
+                let written = self
+                    .fs
+                    .write(mnode, &self.buffer, *offset)
+                    .context(Operation::Write)
+                    .map_err(|e| e.path(path).mnode(mnode))?;
+                stats.bytes_written += written as u64;
+            }
+            TraceOp::Truncate { path } => {
+                self.fs
+                    .truncate(path)
+                    .context(Operation::Truncate)
+                    .map_err(|e| e.path(path))?;
+            }
+            TraceOp::Unlink { path } => {
+                self.mnodes.remove(path.as_str());
+                self.fs
+                    .delete(path)
+                    .context(Operation::Delete)
+                    .map_err(|e| e.path(path))?;
+            }
+            TraceOp::Rename { oldpath, newpath } => {
+                self.mnodes.remove(oldpath.as_str());
+                self.mnodes.remove(newpath.as_str());
+                self.fs
+                    .rename(oldpath, newpath)
+                    .context(Operation::Rename)
+                    .map_err(|e| e.path(oldpath))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::{FileSystemRead, MemFS};
+
+    #[test]
+    /// Comments and blank lines are skipped, malformed lines are reported.
+    fn test_parse() {
+        let ops = parse("# header\n\ncreate /a\nwrite /a 0 10 # append\nrename /a /b\n").unwrap();
+        assert_eq!(
+            ops,
+            [
+                TraceOp::Create {
+                    path: "/a".to_string()
+                },
+                TraceOp::Write {
+                    path: "/a".to_string(),
+                    offset: 0,
+                    len: 10
+                },
+                TraceOp::Rename {
+                    oldpath: "/a".to_string(),
+                    newpath: "/b".to_string()
+                },
+            ]
+        );
+
+        assert_eq!(
+            parse("create /a\nread /a zero 10\n"),
+            Err(TraceParseError { line: 2 })
+        );
+        assert_eq!(parse("mkdir /a\n"), Err(TraceParseError { line: 1 }));
+    }
+
+    #[test]
+    /// A replay applies the operations and counts the failing ones.
+    fn test_replay() {
+        let memfs = MemFS::default();
+        let ops = parse(
+            "create /log\n\
+             write /log 0 4096\n\
+             write /log 4096 100\n\
+             read /log 0 8192\n\
+             write /preexisting 0 10\n\
+             read /missing 0 10\n\
+             rename /log /log.1\n\
+             truncate /log.1\n\
+             unlink /log\n",
+        )
+        .unwrap();
+
+        let mut replayer = Replayer::new(&memfs);
+        let stats = replayer.replay(&ops);
+        assert_eq!(stats.ops, 7);
+        assert_eq!(stats.errors, 2);
+        let errors = replayer.errors();
+        assert_eq!(errors[0].0, 5);
+        assert_eq!(errors[0].1.op, Operation::Lookup);
+        assert_eq!(errors[0].1.path.as_deref(), Some("/missing"));
+        assert_eq!(errors[1].0, 8);
+        assert_eq!(
+            errors[1].1.to_string(),
+            "delete of '/log' failed: Supplied file was invalid"
+        );
+        assert_eq!(stats.bytes_written, 4096 + 100 + 10);
+        assert_eq!(stats.bytes_read, 4196);
+
+        let log = *memfs.lookup("/log.1").unwrap();
+        assert_eq!(memfs.file_info(log).unwrap().fsize, 0);
+        assert!(memfs.lookup("/log").is_none());
+        assert!(memfs.lookup("/preexisting").is_some());
+    }
+}
@@ -0,0 +1,188 @@
+//! Access to the memory of userspace.
+//!
+//! The addresses passed in system calls come from userspace and can point
+//! anywhere, so the file-system never turns them into slices. The embedder
+//! copies from and to them through [`UserMemory`], and the file-system copies
+//! through a [`UserSlice`] in chunks, see `FileSystem::read_user()`,
+//! `FileSystem::write_user()` and `FileSystem::append_user()`.
+
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::convert::TryFrom;
+
+use crate::errno::{Errno, ENAMETOOLONG};
+use crate::{Buffer, FileSystem, FileSystemError, FileSystemRead, Len, Mnode};
+
+/// Bytes copied at a time between user memory and a file, through a buffer
+/// on the stack.
+pub const USER_COPY_CHUNK: usize = 1024;
+
+/// Access of the kernel to the memory of the calling process, like
+/// `copy_from_user`/`copy_to_user`. Invalid addresses fail with `EFAULT`.
+pub trait UserMemory {
+    /// Copy `buf.len()` bytes from the user address `addr` into `buf`.
+    fn read_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<(), Errno>;
+
+    /// Copy `data` to the user address `addr`.
+    fn write_bytes(&self, addr: u64, data: &[u8]) -> Result<(), Errno>;
+
+    /// Copy the NUL-terminated string at `addr`, without the NUL, failing
+    /// with `ENAMETOOLONG` if it is longer than `max` bytes. Reads a byte at
+    /// a time so it doesn't fault past the string; kernels with a
+    /// `strncpy_from_user` should use it instead.
+    fn read_str(&self, addr: u64, max: usize) -> Result<Vec<u8>, Errno> {
+        let mut string = Vec::new();
+        loop {
+            let mut byte = [0];
+            self.read_bytes(addr + string.len() as u64, &mut byte)?;
+            if byte[0] == 0 {
+                return Ok(string);
+            }
+            if string.len() == max {
+                return Err(ENAMETOOLONG);
+            }
+            string
+                .try_reserve(1)
+                .map_err(|_| FileSystemError::OutOfMemory)?;
+            string.push(byte[0]);
+        }
+    }
+}
+
+/// A buffer in user memory, `len` bytes at `addr`. It is only accessed by
+/// copying, and a copy that faults fails with `BadAddress`.
+#[derive(Clone, Copy)]
+pub struct UserSlice<'a> {
+    memory: &'a dyn UserMemory,
+    addr: Buffer,
+    len: usize,
+}
+
+impl<'a> UserSlice<'a> {
+    /// The `len` bytes at `addr` in `memory`.
+    pub fn new(memory: &'a dyn UserMemory, addr: Buffer, len: Len) -> UserSlice<'a> {
+        UserSlice {
+            memory,
+            addr,
+            // A length past the address space faults on the first byte
+            // that isn't mapped.
+            len: usize::try_from(len).unwrap_or(usize::MAX),
+        }
+    }
+
+    /// The length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copy the bytes at `offset` in the buffer into `buf`.
+    pub fn copy_in(&self, offset: usize, buf: &mut [u8]) -> Result<(), FileSystemError> {
+        self.check(offset, buf.len())?;
+        self.memory
+            .read_bytes(self.addr + offset as u64, buf)
+            .map_err(|_| FileSystemError::BadAddress)
+    }
+
+    /// Copy `data` to `offset` in the buffer.
+    pub fn copy_out(&self, offset: usize, data: &[u8]) -> Result<(), FileSystemError> {
+        self.check(offset, data.len())?;
+        self.memory
+            .write_bytes(self.addr + offset as u64, data)
+            .map_err(|_| FileSystemError::BadAddress)
+    }
+
+    /// Check that `len` bytes at `offset` are within the buffer.
+    fn check(&self, offset: usize, len: usize) -> Result<(), FileSystemError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(FileSystemError::BadAddress),
+        }
+    }
+}
+
+/// Read from `mnode` at `offset` into `buffer`, see
+/// `FileSystemRead::read_user()`.
+pub(crate) fn read<F: FileSystemRead + ?Sized>(
+    fs: &F,
+    mnode: Mnode,
+    buffer: &UserSlice,
+    offset: usize,
+) -> Result<usize, FileSystemError> {
+    let mut chunk = [0; USER_COPY_CHUNK];
+    let mut done = 0;
+    while done < buffer.len() {
+        let len = min(USER_COPY_CHUNK, buffer.len() - done);
+        let read = match fs.read(mnode, &mut chunk[..len], offset + done) {
+            Ok(read) => read,
+            // The end of the file was at the end of the last chunk.
+            Err(FileSystemError::InvalidOffset) if done > 0 => break,
+            Err(err) => return Err(err),
+        };
+        match buffer.copy_out(done, &chunk[..read]) {
+            Ok(()) => done += read,
+            Err(_) if done > 0 => break,
+            Err(err) => return Err(err),
+        }
+        if read < len {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+/// Write `buffer` to `mnode` at `offset`, see `FileSystem::write_user()`.
+pub(crate) fn write<F: FileSystem + ?Sized>(
+    fs: &F,
+    mnode: Mnode,
+    buffer: &UserSlice,
+    offset: usize,
+) -> Result<usize, FileSystemError> {
+    let mut chunk = [0; USER_COPY_CHUNK];
+    let mut done = 0;
+    while done < buffer.len() {
+        let len = min(USER_COPY_CHUNK, buffer.len() - done);
+        let written = buffer
+            .copy_in(done, &mut chunk[..len])
+            .and_then(|()| fs.write(mnode, &chunk[..len], offset + done));
+        match written {
+            Ok(written) => done += written,
+            Err(_) if done > 0 => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(done)
+}
+
+/// Append `buffer` to `mnode`, see `FileSystem::append_user()`.
+pub(crate) fn append<F: FileSystem + ?Sized>(
+    fs: &F,
+    mnode: Mnode,
+    buffer: &UserSlice,
+) -> Result<(usize, usize), FileSystemError> {
+    // An empty append still tells where the end of the file is.
+    if buffer.is_empty() {
+        return fs.append(mnode, &[]);
+    }
+    let mut chunk = [0; USER_COPY_CHUNK];
+    let (mut end, mut done) = (0, 0);
+    while done < buffer.len() {
+        let len = min(USER_COPY_CHUNK, buffer.len() - done);
+        let appended = buffer
+            .copy_in(done, &mut chunk[..len])
+            .and_then(|()| fs.append(mnode, &chunk[..len]));
+        match appended {
+            Ok((after, written)) => {
+                end = after;
+                done += written;
+            }
+            Err(_) if done > 0 => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok((end, done))
+}
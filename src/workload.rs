@@ -0,0 +1,366 @@
+//! fio-style workload descriptions and an executor for any [`FileSystem`].
+//!
+//! A [`Workload`] declares the file set (count and size distribution) and the
+//! IO pattern (block size, read/write ratio, sequential or random offsets,
+//! number of threads). The same description can be executed against different
+//! file-system implementations or locking strategies, which makes their numbers
+//! directly comparable.
+
+use alloc::format;
+use alloc::vec::Vec;
+
+use crate::{FileModes, FileSystem, FileSystemError, Mnode};
+
+/// Distribution of the initial file sizes.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SizeDistribution {
+    /// All the files have the same size.
+    Fixed(usize),
+    /// File sizes are drawn uniformly from `min..=max`.
+    Uniform { min: usize, max: usize },
+}
+
+impl SizeDistribution {
+    fn min(&self) -> usize {
+        match *self {
+            SizeDistribution::Fixed(size) => size,
+            SizeDistribution::Uniform { min, .. } => min,
+        }
+    }
+
+    fn sample(&self, rng: &mut XorShift) -> usize {
+        match *self {
+            SizeDistribution::Fixed(size) => size,
+            SizeDistribution::Uniform { min, max } => min + rng.below(max - min + 1),
+        }
+    }
+}
+
+/// How offsets and files are chosen for every operation.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AccessPattern {
+    /// Every thread walks through the files block by block.
+    Sequential,
+    /// Files and block-aligned offsets are picked at random.
+    Random,
+}
+
+/// Declarative description of a workload.
+#[derive(Debug, Clone)]
+pub struct Workload {
+    /// Number of files created before the IO phase starts.
+    pub nfiles: usize,
+    /// Size of the files at creation.
+    pub file_size: SizeDistribution,
+    /// Bytes read or written per operation.
+    pub block_size: usize,
+    /// Percentage of the operations that are reads; the rest are writes.
+    pub read_percent: u8,
+    /// Sequential or random access.
+    pub pattern: AccessPattern,
+    /// Number of threads issuing operations.
+    pub threads: usize,
+    /// Operations issued by every thread.
+    pub ops_per_thread: u64,
+    /// Seed for the file sizes and random offsets, so runs are repeatable.
+    pub seed: u64,
+}
+
+impl Default for Workload {
+    fn default() -> Workload {
+        Workload {
+            nfiles: 16,
+            file_size: SizeDistribution::Fixed(64 * 1024),
+            block_size: 4096,
+            read_percent: 70,
+            pattern: AccessPattern::Random,
+            threads: 1,
+            ops_per_thread: 10_000,
+            seed: 0x6e72_6673,
+        }
+    }
+}
+
+impl Workload {
+    /// Check that the description can be executed.
+    pub fn validate(&self) -> Result<(), FileSystemError> {
+        let sizes_ok = match self.file_size {
+            SizeDistribution::Fixed(_) => true,
+            SizeDistribution::Uniform { min, max } => min <= max,
+        };
+        if self.nfiles == 0
+            || self.threads == 0
+            || self.block_size == 0
+            || self.read_percent > 100
+            || !sizes_ok
+            || self.file_size.min() < self.block_size
+        {
+            return Err(FileSystemError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// Create and populate the file set; returns the mnode and size of every file.
+    pub fn setup<F: FileSystem + ?Sized>(
+        &self,
+        fs: &F,
+    ) -> Result<Vec<(Mnode, usize)>, FileSystemError> {
+        self.validate()?;
+
+        let mut rng = XorShift::new(self.seed);
+        let block = alloc::vec![0xb; self.block_size];
+        let mut files = Vec::with_capacity(self.nfiles);
+        for i in 0..self.nfiles {
+            let size = self.file_size.sample(&mut rng);
+            let mnode = fs.create(&format!("/workload-{}", i), FileModes::S_IRWXU.into())?;
+            let mut offset = 0;
+            while offset < size {
+                let len = core::cmp::min(self.block_size, size - offset);
+                fs.write(mnode, &block[..len], offset)?;
+                offset += len;
+            }
+            files.push((mnode, size));
+        }
+        Ok(files)
+    }
+
+    /// Issue the operations of one thread against a file set created by `setup`.
+    pub fn run_thread<F: FileSystem + ?Sized>(
+        &self,
+        fs: &F,
+        files: &[(Mnode, usize)],
+        tid: usize,
+    ) -> Result<WorkloadStats, FileSystemError> {
+        let mut rng = XorShift::new(self.seed.wrapping_add(tid as u64 + 1));
+        let mut buffer = alloc::vec![0; self.block_size];
+        let mut stats = WorkloadStats::default();
+
+        // Sequential threads start on different files to spread the load.
+        let mut file = tid % files.len();
+        let mut offset = 0;
+        for _op in 0..self.ops_per_thread {
+            if self.pattern == AccessPattern::Random {
+                file = rng.below(files.len());
+                offset = rng.below(files[file].1 / self.block_size) * self.block_size;
+            } else if offset + self.block_size > files[file].1 {
+                file = (file + 1) % files.len();
+                offset = 0;
+            }
+
+            let (mnode, _size) = files[file];
+            if rng.below(100) < self.read_percent as usize {
+                stats.bytes_read += fs.read(mnode, &mut buffer, offset)? as u64;
+                stats.reads += 1;
+            } else {
+                stats.bytes_written += fs.write(mnode, &buffer, offset)? as u64;
+                stats.writes += 1;
+            }
+
+            if self.pattern == AccessPattern::Sequential {
+                offset += self.block_size;
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// Operations completed by a workload (or one of its threads).
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct WorkloadStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl WorkloadStats {
+    #[cfg(any(test, feature = "std"))]
+    fn merge(&mut self, other: &WorkloadStats) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+    }
+}
+
+/// Result of executing a workload.
+#[cfg(any(test, feature = "std"))]
+#[derive(Debug, Clone)]
+pub struct WorkloadResult {
+    /// Totals over all the threads.
+    pub total: WorkloadStats,
+    /// Per-thread numbers.
+    pub threads: Vec<WorkloadStats>,
+    /// Wall-clock time of the IO phase.
+    pub elapsed: std::time::Duration,
+}
+
+#[cfg(any(test, feature = "std"))]
+impl WorkloadResult {
+    /// Operations per second over all the threads.
+    pub fn iops(&self) -> f64 {
+        (self.total.reads + self.total.writes) as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Bytes per second over all the threads.
+    pub fn bandwidth(&self) -> f64 {
+        (self.total.bytes_read + self.total.bytes_written) as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Create the file set of `workload` on `fs` and run its threads to completion.
+#[cfg(any(test, feature = "std"))]
+pub fn execute<F>(
+    fs: alloc::sync::Arc<F>,
+    workload: &Workload,
+) -> Result<WorkloadResult, FileSystemError>
+where
+    F: FileSystem + Send + Sync + 'static,
+{
+    use alloc::sync::Arc;
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Instant;
+
+    let files = Arc::new(workload.setup(&*fs)?);
+    let barrier = Arc::new(Barrier::new(workload.threads + 1));
+
+    let mut threads = Vec::with_capacity(workload.threads);
+    for tid in 0..workload.threads {
+        let fs = fs.clone();
+        let files = files.clone();
+        let barrier = barrier.clone();
+        let workload = workload.clone();
+        threads.push(thread::spawn(move || {
+            barrier.wait();
+            workload.run_thread(&*fs, &files, tid)
+        }));
+    }
+
+    barrier.wait();
+    let start = Instant::now();
+    let mut result = WorkloadResult {
+        total: Default::default(),
+        threads: Vec::with_capacity(workload.threads),
+        elapsed: Default::default(),
+    };
+    for thread in threads {
+        let stats = thread.join().expect("workload thread panicked")?;
+        result.total.merge(&stats);
+        result.threads.push(stats);
+    }
+    result.elapsed = start.elapsed();
+
+    Ok(result)
+}
+
+/// Small xorshift64* generator; good enough for picking offsets and sizes.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        // The state must never be zero.
+        XorShift(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number in `0..bound`.
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::{FileSystemRead, MemFS};
+    use alloc::sync::Arc;
+
+    #[test]
+    /// Invalid descriptions are rejected before touching the file-system.
+    fn test_validate() {
+        assert_eq!(Workload::default().validate(), Ok(()));
+
+        let workload = Workload {
+            block_size: 128 * 1024,
+            ..Default::default()
+        };
+        assert_eq!(workload.validate(), Err(FileSystemError::InvalidArgument));
+
+        let workload = Workload {
+            file_size: SizeDistribution::Uniform {
+                min: 8192,
+                max: 4096,
+            },
+            ..Default::default()
+        };
+        assert_eq!(workload.validate(), Err(FileSystemError::InvalidArgument));
+    }
+
+    #[test]
+    /// The file set follows the size distribution.
+    fn test_setup() {
+        let memfs = MemFS::default();
+        let workload = Workload {
+            nfiles: 8,
+            file_size: SizeDistribution::Uniform {
+                min: 4096,
+                max: 3 * 4096 + 7,
+            },
+            ..Default::default()
+        };
+
+        let files = workload.setup(&memfs).unwrap();
+        assert_eq!(files.len(), 8);
+        for (mnode, size) in files {
+            assert!((4096..=3 * 4096 + 7).contains(&size));
+            assert_eq!(memfs.file_info(mnode).unwrap().fsize, size as u64);
+        }
+    }
+
+    #[test]
+    /// A read-only sequential workload reads every block exactly once per pass.
+    fn test_sequential_reads() {
+        let memfs = MemFS::default();
+        let workload = Workload {
+            nfiles: 2,
+            file_size: SizeDistribution::Fixed(4 * 4096),
+            read_percent: 100,
+            pattern: AccessPattern::Sequential,
+            ops_per_thread: 8,
+            ..Default::default()
+        };
+
+        let files = workload.setup(&memfs).unwrap();
+        let stats = workload.run_thread(&memfs, &files, 0).unwrap();
+        assert_eq!(stats.reads, 8);
+        assert_eq!(stats.writes, 0);
+        assert_eq!(stats.bytes_read, 8 * 4096);
+    }
+
+    #[test]
+    /// Threads of a mixed random workload together issue all the operations.
+    fn test_execute() {
+        let workload = Workload {
+            threads: 4,
+            ops_per_thread: 1000,
+            read_percent: 50,
+            ..Default::default()
+        };
+
+        let result = execute(Arc::new(MemFS::default()), &workload).unwrap();
+        assert_eq!(result.threads.len(), 4);
+        assert_eq!(result.total.reads + result.total.writes, 4000);
+        assert!(result.total.reads > 0 && result.total.writes > 0);
+        assert_eq!(
+            result.total.bytes_read + result.total.bytes_written,
+            4000 * 4096
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! Background writeback of changed pages to the backing store.
+//!
+//! Files are queued when their pages change, once until they are clean again.
+//! `flush_some()` writes back a bounded number of pages from the front of the
+//! queue, so the embedder can drive writeback from its own timer or thread
+//! without stalling it. A file that isn't clean after its turn goes to the
+//! back of the queue.
+//!
+//! Writeback doesn't flush the store: the pages it writes are only durable
+//! after the next barrier, e.g. from `fsync`. Evicted pages don't need one
+//! either, as they are read back from the store, not from the device.
+
+use core::hash::BuildHasher;
+use lock_api::RawRwLock;
+
+use crate::mnode::MemNode;
+use crate::{FileSystemError, MemFS, Mnode, BASE_PAGE_SIZE};
+
+/// What one call of `MemFS::flush_some()` did.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct WritebackStats {
+    /// Pages written back and clean now.
+    pub pages: usize,
+    /// Files visited.
+    pub files: usize,
+    /// Files still queued with changed pages.
+    pub queued: usize,
+}
+
+impl<S: BuildHasher + Send + Sync, L: RawRwLock + Send + Sync> MemFS<S, L> {
+    /// Queue the file `mnode` for writeback if it has changed pages. Only
+    /// done with a backing store; a file that can't be queued is queued again
+    /// by its next change.
+    pub(crate) fn queue_writeback(&self, mnode: Mnode, memnode: &mut MemNode) {
+        if self.policy.backing_store.is_none() || !memnode.queue_writeback() {
+            return;
+        }
+        let mut queue = self.writeback.lock();
+        match queue.try_reserve(1) {
+            Ok(()) => queue.push_back(mnode),
+            Err(_) => memnode.dequeue_writeback(),
+        }
+    }
+
+    /// Write back at most `budget` changed pages to the backing store, e.g.
+    /// from a periodic writeback thread of the embedder, so that fewer pages
+    /// have to be written back when they are evicted. Every queued file is
+    /// visited at most once per call. Fails with `NotSupported` without a
+    /// backing store, see `MemFSBuilder::backing_store()`, and stops at the
+    /// first error of the store.
+    pub fn flush_some(&self, budget: usize) -> Result<WritebackStats, FileSystemError> {
+        let store = match &self.policy.backing_store {
+            Some((store, _)) => store,
+            None => return Err(FileSystemError::NotSupported),
+        };
+        let mut stats = WritebackStats::default();
+        let mut visits = self.writeback.lock().len();
+        while stats.pages < budget && visits > 0 {
+            visits -= 1;
+            let mnode = match self.writeback.lock().pop_front() {
+                Some(mnode) => mnode,
+                None => break,
+            };
+            // Removed files drop out of the queue.
+            let memnode = match self.memnode(mnode) {
+                Some(memnode) => memnode,
+                None => continue,
+            };
+            let mut locked = memnode.write();
+            stats.files += 1;
+            let flushed = locked.dirty_pages().and_then(|pages| {
+                let end = match pages.get(budget - stats.pages - 1) {
+                    Some(page) => page + BASE_PAGE_SIZE,
+                    None => usize::MAX,
+                };
+                locked.flush(0, end, true, |offset, page| {
+                    store.write_page(mnode, offset, page)
+                })
+            });
+            locked.dequeue_writeback();
+            self.queue_writeback(mnode, &mut locked);
+            stats.pages += flushed?;
+        }
+        stats.queued = self.writeback.lock().len();
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::StorageBackend;
+    use crate::{FileModes, FileSystem, MemFSBuilder, Metadata};
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use spin::Mutex;
+
+    /// Records the pages and metadata written to it.
+    #[derive(Default)]
+    struct LogStore {
+        writes: Mutex<Vec<(Mnode, usize)>>,
+        metadata: Mutex<Vec<Metadata>>,
+        /// The number of pages and of metadata written at every flush.
+        barriers: Mutex<Vec<(usize, usize)>>,
+    }
+
+    impl StorageBackend for LogStore {
+        fn read_page(&self, _: Mnode, _: usize, _: &mut [u8]) -> Result<(), FileSystemError> {
+            Err(FileSystemError::InvalidOffset)
+        }
+
+        fn write_page(&self, mnode: Mnode, offset: usize, _: &[u8]) -> Result<(), FileSystemError> {
+            self.writes.lock().push((mnode, offset));
+            Ok(())
+        }
+
+        fn write_metadata(&self, metadata: &Metadata) -> Result<(), FileSystemError> {
+            self.metadata.lock().push(*metadata);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), FileSystemError> {
+            let written = (self.writes.lock().len(), self.metadata.lock().len());
+            self.barriers.lock().push(written);
+            Ok(())
+        }
+    }
+
+    #[test]
+    /// Every call writes back at most its budget, taking turns between files.
+    fn test_flush_some() {
+        assert_eq!(
+            MemFS::default().flush_some(1),
+            Err(FileSystemError::NotSupported)
+        );
+
+        let store = Arc::new(LogStore::default());
+        let memfs = MemFSBuilder::new()
+            .backing_store(store.clone(), 100)
+            .build();
+        let a = memfs.create("/a", FileModes::S_IRWXU.into()).unwrap();
+        let b = memfs.create("/b", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(a, &[1; 3 * 4096], 0), Ok(3 * 4096));
+        assert_eq!(memfs.write(b, &[2; 10], 0), Ok(10));
+        assert_eq!(memfs.write(a, &[3; 10], 0), Ok(10));
+
+        let stats = memfs.flush_some(2).unwrap();
+        assert_eq!(
+            stats,
+            WritebackStats {
+                pages: 2,
+                files: 1,
+                queued: 2
+            }
+        );
+        assert_eq!(*store.writes.lock(), [(a, 0), (a, 4096)]);
+
+        let stats = memfs.flush_some(10).unwrap();
+        assert_eq!(
+            stats,
+            WritebackStats {
+                pages: 2,
+                files: 2,
+                queued: 0
+            }
+        );
+        assert_eq!(store.writes.lock()[2..], [(b, 0), (a, 8192)]);
+        assert_eq!(memfs.dirty_pages(a), Ok(Vec::new()));
+        assert_eq!(memfs.flush_some(10), Ok(WritebackStats::default()));
+
+        assert_eq!(memfs.write(b, &[4; 10], 4096), Ok(10));
+        assert_eq!(memfs.flush_some(10).unwrap().pages, 1);
+        assert!(store.barriers.lock().is_empty());
+    }
+
+    #[test]
+    /// fsync writes back the data and the metadata, fdatasync only the data.
+    /// The data is durable before the metadata is written.
+    fn test_fsync() {
+        let memfs = MemFS::default();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[1; 10], 0), Ok(10));
+        assert_eq!(memfs.fsync(mnode), Ok(()));
+        assert_eq!(memfs.dirty_pages(mnode), Ok(alloc::vec![0]));
+        assert_eq!(memfs.fsync(42), Err(FileSystemError::InvalidFile));
+
+        let store = Arc::new(LogStore::default());
+        let memfs = MemFSBuilder::new()
+            .backing_store(store.clone(), 100)
+            .build();
+        let mnode = memfs.create("/nrfs", FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(mnode, &[1; 4096 + 10], 0), Ok(4096 + 10));
+        assert_eq!(memfs.fdatasync(mnode), Ok(()));
+        assert_eq!(*store.writes.lock(), [(mnode, 0), (mnode, 4096)]);
+        assert!(store.metadata.lock().is_empty());
+        assert_eq!(*store.barriers.lock(), [(2, 0)]);
+
+        assert_eq!(memfs.write(mnode, &[2; 10], 0), Ok(10));
+        assert_eq!(memfs.fsync(mnode), Ok(()));
+        assert_eq!(store.writes.lock()[2..], [(mnode, 0)]);
+        assert_eq!(*store.metadata.lock(), [memfs.metadata(mnode).unwrap()]);
+        assert_eq!(*store.barriers.lock(), [(2, 0), (3, 0), (3, 1)]);
+        assert_eq!(memfs.fsync(1), Ok(()));
+        assert_eq!(store.metadata.lock().len(), 2);
+    }
+}